@@ -0,0 +1,457 @@
+//! Barostats, which couple the box to a pressure bath
+//!
+//! A barostat is given to an integrator through `Hooks::barostat`, which
+//! applies it at the end of each step, or to `State::sample_npt` for
+//! Monte Carlo sampling. Barostats change the box with `State::scale`,
+//! which scales the positions with it and keeps the pairlist up to date.
+//!
+//! The box can be coupled isotropically, semi-isotropically or
+//! anisotropically, always towards the same reference pressure in every
+//! direction. Only the diagonal elements of triclinic box vectors are
+//! coupled, and the rest are scaled along with them.
+
+use rand::{Rng, SeedableRng, FromEntropy};
+use rand::rngs::StdRng;
+use rand::distributions::StandardNormal;
+
+use crate::units::*;
+use crate::units::f32consts::*;
+use crate::state::State;
+use crate::integrators::Barostat;
+
+/// Which directions of the box are scaled together
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Coupling {
+    /// x, y and z scale together, keeping the shape of the box
+    Isotropic,
+    /// x and y scale together, and z separately, as for membranes in the
+    /// xy plane
+    SemiIsotropic,
+    /// x, y and z each scale separately
+    Anisotropic
+}
+
+impl Coupling {
+    /// The groups of directions that scale together
+    fn groups(self) -> &'static [&'static [usize]] {
+        match self {
+            Coupling::Isotropic => &[&[0, 1, 2]],
+            Coupling::SemiIsotropic => &[&[0, 1], &[2]],
+            Coupling::Anisotropic => &[&[0], &[1], &[2]]
+        }
+    }
+}
+
+/// Scale factors along x, y and z that change the log of the box length
+/// in each direction of `group` by `log_scale`
+fn group_factors(group: &[usize], log_scale: f32) -> (f32, f32, f32) {
+    let mut factors = [1.0; 3];
+    for &d in group {
+        factors[d] = log_scale.exp();
+    }
+    (factors[0], factors[1], factors[2])
+}
+
+/// The Monte Carlo barostat, which attempts a random change of volume
+/// every `frequency` steps and accepts it with the Metropolis criterion
+///
+/// It only needs energies, so it works with `State::sample_npt` as well
+/// as with molecular dynamics. The attempted changes are uniform in
+/// volume, and their size adapts to keep between a quarter and three
+/// quarters of them accepted. Volumes are sampled with weight
+/// `V^N exp(-(E + P V)/kT)`, where `N` is the number of atoms, or of
+/// molecules with `by_molecule`.
+#[derive(Debug, Clone)]
+pub struct MonteCarloBarostat {
+    pub pressure: KilojoulePerMolePerNanometer3<f32>,
+    pub temperature: Kelvin<f32>,
+    pub coupling: Coupling,
+    /// Attempt a change every this many steps
+    pub frequency: usize,
+    /// Scale the centres of molecules rather than atoms
+    pub by_molecule: bool,
+    /// The largest attempted change of volume, which starts at 1% of the
+    /// volume at the first attempt
+    max_change: Option<Nanometer3<f32>>,
+    calls: usize,
+    attempted: usize,
+    accepted: usize,
+    rng: StdRng
+}
+
+impl MonteCarloBarostat {
+    pub fn new(pressure: KilojoulePerMolePerNanometer3<f32>, temperature: Kelvin<f32>, coupling: Coupling) -> MonteCarloBarostat {
+        MonteCarloBarostat {
+            pressure,
+            temperature,
+            coupling,
+            frequency: 25,
+            by_molecule: false,
+            max_change: None,
+            calls: 0,
+            attempted: 0,
+            accepted: 0,
+            rng: StdRng::from_entropy()
+        }
+    }
+
+    /// Restart the random numbers from `seed`
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// The fraction of attempted changes that were accepted
+    pub fn acceptance(&self) -> f32 {
+        self.accepted as f32 / self.attempted.max(1) as f32
+    }
+
+    /// Attempt one change of volume
+    fn attempt(&mut self, state: &mut State) {
+        let groups = self.coupling.groups();
+        let group = groups[self.rng.gen_range(0, groups.len())];
+
+        let volume = state.simbox().volume();
+        let max_change = *self.max_change.get_or_insert(0.01 * volume);
+        let new_volume = volume + max_change * self.rng.gen_range(-1.0f32, 1.0);
+        let ratio: f32 = (new_volume / volume).value_unsafe;
+        let n_particles = if self.by_molecule {
+            let in_molecules: usize = state.topology.molecules().iter()
+                .map(|block| block.n_atoms * block.count)
+                .sum();
+            let n_molecules: usize = state.topology.molecules().iter()
+                .map(|block| block.count)
+                .sum();
+            n_molecules + state.positions.len() - in_molecules
+        } else {
+            state.positions.len()
+        };
+
+        let energy = state.calc_energy();
+        let saved = state.clone();
+        state.scale(group_factors(group, ratio.ln() / group.len() as f32), self.by_molecule);
+        let new_energy = state.calc_energy();
+
+        let kt = KB * self.temperature;
+        let work = new_energy - energy
+            + self.pressure * (new_volume - volume)
+            - n_particles as f32 * kt * ratio.ln();
+
+        self.attempted += 1;
+        if (-work / kt).exp() >= self.rng.gen() {
+            self.accepted += 1;
+        } else {
+            *state = saved;
+        }
+
+        if self.attempted.is_multiple_of(10) {
+            let acceptance = self.acceptance();
+            if acceptance < 0.25 {
+                self.max_change = Some(max_change / 1.1);
+            } else if acceptance > 0.75 {
+                // Never so large that the volume could go negative
+                let largest = 0.3 * state.simbox().volume();
+                self.max_change = Some(if max_change * 1.1 < largest { max_change * 1.1 } else { largest });
+            }
+        }
+    }
+}
+
+impl Barostat for MonteCarloBarostat {
+    fn apply(&mut self, state: &mut State, _dt: Picosecond<f32>) {
+        self.calls += 1;
+        if self.calls.is_multiple_of(self.frequency) {
+            self.attempt(state);
+        }
+    }
+}
+
+/// The stochastic cell rescaling barostat of Bernetti and Bussi, which
+/// relaxes the pressure to its target with time constant `tau` while
+/// sampling the isothermal-isobaric ensemble
+///
+/// It's the barostat analogue of the Bussi thermostat: a Berendsen
+/// barostat with a noise term that gives the volume its correct
+/// fluctuations. Velocities are scaled inversely with the positions.
+/// `compressibility` only sets the strength of the coupling with `tau`,
+/// so an estimate like `4.5e-5 / BAR` for water is good enough.
+#[derive(Debug, Clone)]
+pub struct StochasticCellRescaling {
+    pub pressure: KilojoulePerMolePerNanometer3<f32>,
+    pub temperature: Kelvin<f32>,
+    pub tau: Picosecond<f32>,
+    pub compressibility: Nanometer3PerKilojoulePerMole<f32>,
+    pub coupling: Coupling,
+    /// Scale the centres of molecules rather than atoms
+    pub by_molecule: bool,
+    rng: StdRng
+}
+
+impl StochasticCellRescaling {
+    pub fn new(
+        pressure: KilojoulePerMolePerNanometer3<f32>,
+        temperature: Kelvin<f32>,
+        tau: Picosecond<f32>,
+        compressibility: Nanometer3PerKilojoulePerMole<f32>,
+        coupling: Coupling
+    ) -> StochasticCellRescaling {
+        StochasticCellRescaling {
+            pressure,
+            temperature,
+            tau,
+            compressibility,
+            coupling,
+            by_molecule: false,
+            rng: StdRng::from_entropy()
+        }
+    }
+
+    /// Restart the random numbers from `seed`
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+}
+
+impl Barostat for StochasticCellRescaling {
+    fn apply(&mut self, state: &mut State, dt: Picosecond<f32>) {
+        let pressure = match state.take_virial() {
+            Some(virial) => state.pressure_tensor_with(&virial),
+            None => state.pressure_tensor()
+        }.diagonal();
+        let pressure = [pressure.x, pressure.y, pressure.z];
+        let volume = state.simbox().volume();
+        let kt = KB * self.temperature;
+
+        // Each group of directions scales the log of its share of the
+        // volume, with the drift and noise of the isotropic equation
+        // in proportion to its number of directions
+        let mut log_factors = [0.0f32; 3];
+        for group in self.coupling.groups() {
+            let share = group.len() as f32 / 3.0;
+            let group_pressure = group.iter()
+                .fold(0.0 * KJPMNM3, |acc, &d| acc + pressure[d]) / group.len() as f32;
+            let rate: PerPicosecond<f32> = share * self.compressibility / self.tau * (group_pressure - self.pressure);
+            let noise: Unitless<f32> = 2.0 * share * kt * self.compressibility * dt / (volume * self.tau);
+            let gaussian = self.rng.sample(StandardNormal) as f32;
+            let log_scale = (rate * dt).value_unsafe + noise.value_unsafe.sqrt() * gaussian;
+            for &d in group.iter() {
+                log_factors[d] = log_scale / group.len() as f32;
+            }
+        }
+
+        let factors = (log_factors[0].exp(), log_factors[1].exp(), log_factors[2].exp());
+        state.scale(factors, self.by_molecule);
+        for v in state.velocities.iter_mut() {
+            v.x /= factors.0;
+            v.y /= factors.1;
+            v.z /= factors.2;
+        }
+    }
+
+    fn uses_virial(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::{PosVec, VelocVec, SimulationBox};
+    use crate::topology::Top;
+    use crate::integrators::{Integrator, Hooks, VelocityVerlet};
+    use crate::thermostats::Bussi;
+    use crate::state::tests::state;
+
+    /// An ideal gas of `n` atoms in a cubic box of 4 nm
+    fn ideal_gas(n: usize) -> (Top, Vec<PosVec>) {
+        let top = Top::gen_lj_fluid(n, 16.0 * DA, 0.0 * KJPM, 0.3 * NM);
+        let positions = (0..n)
+            .map(|i| PosVec::from((i % 5) as f32 * 0.8 + 0.4, ((i / 5) % 5) as f32 * 0.8 + 0.4, (i / 25) as f32 * 0.8 + 0.4))
+            .collect();
+        (top, positions)
+    }
+
+    /// The mean volume of an ideal gas of `n` atoms at 300 K and
+    /// `pressure`
+    fn ideal_volume(n: usize, pressure: KilojoulePerMolePerNanometer3<f32>) -> Nanometer3<f32> {
+        (n + 1) as f32 * KB * 300.0 * K / pressure
+    }
+
+    #[test]
+    fn scaling_molecules_keeps_their_shape() {
+        let mut top = Top::gen_lj_fluid(5, 16.0 * DA, 0.0 * KJPM, 0.3 * NM);
+        top.atoms[1].mass = 1.0 * DA;
+        top.add_molecules("OH", 2, 2);
+        let positions = vec![
+            PosVec::from(1.0, 1.0, 1.0),
+            PosVec::from(1.1, 1.0, 1.0),
+            // Split over the boundary
+            PosVec::from(3.95, 2.0, 2.0),
+            PosVec::from(0.05, 2.0, 2.0),
+            PosVec::from(2.0, 3.0, 1.0)
+        ];
+        let velocities = vec![VelocVec::zero(); 5];
+        let mut state = state(&top, positions, velocities, SimulationBox::cubic(4.0 * NM));
+        state.scale((1.1, 1.1, 1.1), true);
+
+        assert_eq!(state.simbox(), &SimulationBox::cubic(4.4 * NM));
+        let bond = state.dist2(&state.positions[0], &state.positions[1]).0;
+        assert!((bond - PosVec::from(-0.1, 0.0, 0.0)).norm().value_unsafe < 1e-5);
+        let bond = state.dist2(&state.positions[2], &state.positions[3]).0;
+        assert!((bond - PosVec::from(-0.1, 0.0, 0.0)).norm().value_unsafe < 1e-5);
+        let centre = state.positions[0].clone() + PosVec::from(0.1 / 17.0, 0.0, 0.0);
+        assert!((centre - PosVec::from(1.1 * (1.0 + 0.1 / 17.0), 1.1, 1.1)).norm().value_unsafe < 1e-5);
+        assert!((state.positions[4].clone() - PosVec::from(2.2, 3.3, 1.1)).norm().value_unsafe < 1e-5);
+    }
+
+    #[test]
+    fn monte_carlo_samples_ideal_gas_volume() {
+        let n = 50;
+        let pressure = 2.0 * KJPMNM3;
+        let (top, positions) = ideal_gas(n);
+        let mut state = state(&top, positions, vec![VelocVec::zero(); n], SimulationBox::cubic(4.0 * NM));
+        let mut barostat = MonteCarloBarostat::new(pressure, 300.0 * K, Coupling::Isotropic);
+        barostat.frequency = 1;
+        barostat.set_seed(11);
+
+        for _ in 0..2000 {
+            barostat.apply(&mut state, 0.0 * PS);
+        }
+        let mut sum = 0.0 * NM3;
+        for _ in 0..20000 {
+            barostat.apply(&mut state, 0.0 * PS);
+            sum += state.simbox().volume();
+        }
+        let mean = sum / 20000.0;
+        let expected = ideal_volume(n, pressure);
+        assert!((mean / expected - 1.0).value_unsafe.abs() < 0.03, "{} nm3 is not {} nm3", mean.value_unsafe, expected.value_unsafe);
+        assert!(barostat.acceptance() > 0.2 && barostat.acceptance() < 0.8);
+    }
+
+    #[test]
+    fn semi_isotropic_coupling_keeps_xy_square() {
+        let n = 20;
+        let (top, positions) = ideal_gas(n);
+        let mut state = state(&top, positions, vec![VelocVec::zero(); n], SimulationBox::cubic(4.0 * NM));
+        let mut barostat = MonteCarloBarostat::new(2.0 * KJPMNM3, 300.0 * K, Coupling::SemiIsotropic);
+        barostat.frequency = 1;
+        barostat.set_seed(2);
+        for _ in 0..200 {
+            barostat.apply(&mut state, 0.0 * PS);
+        }
+        let (a, b, c) = state.simbox().lengths();
+        assert!(((a - b) / a).value_unsafe.abs() < 1e-5);
+        assert!(((a - c) / a).value_unsafe.abs() > 1e-3);
+    }
+
+    #[test]
+    fn cell_rescaling_samples_ideal_gas_volume() {
+        let n = 50;
+        let pressure = 2.0 * KJPMNM3;
+        let (top, positions) = ideal_gas(n);
+        // The thermostat only scales the velocities, so start them in
+        // random directions
+        let mut rng = StdRng::seed_from_u64(5);
+        let velocities = (0..n)
+            .map(|_| VelocVec::from(
+                0.4 * rng.sample(StandardNormal) as f32,
+                0.4 * rng.sample(StandardNormal) as f32,
+                0.4 * rng.sample(StandardNormal) as f32
+            ))
+            .collect();
+        let mut state = state(&top, positions, velocities, SimulationBox::cubic(4.0 * NM));
+
+        let mut thermostat = Bussi::new(300.0 * K, 0.1 * PS);
+        thermostat.set_seed(3);
+        let mut barostat = StochasticCellRescaling::new(pressure, 300.0 * K, 0.5 * PS, 0.5 * NM3PKJPM, Coupling::Isotropic);
+        barostat.set_seed(4);
+        let mut hooks = Hooks {
+            thermostat: Some(Box::new(thermostat)),
+            barostat: Some(Box::new(barostat)),
+            ..Hooks::new()
+        };
+        let mut integrator = VelocityVerlet::new();
+
+        for _ in 0..2000 {
+            integrator.step(&mut state, &mut hooks, 0.005 * PS);
+        }
+        let mut sum = 0.0 * NM3;
+        for _ in 0..20000 {
+            integrator.step(&mut state, &mut hooks, 0.005 * PS);
+            sum += state.simbox().volume();
+        }
+        let mean = sum / 20000.0;
+        let expected = ideal_volume(n, pressure);
+        assert!((mean / expected - 1.0).value_unsafe.abs() < 0.05, "{} nm3 is not {} nm3", mean.value_unsafe, expected.value_unsafe);
+    }
+
+    #[test]
+    fn cell_rescaling_fluctuates_with_compressibility_in_bar() {
+        let n = 50;
+        let pressure = 100.0 * BAR;
+        let (top, positions) = ideal_gas(n);
+        let mut rng = StdRng::seed_from_u64(8);
+        let velocities = (0..n)
+            .map(|_| VelocVec::from(
+                0.4 * rng.sample(StandardNormal) as f32,
+                0.4 * rng.sample(StandardNormal) as f32,
+                0.4 * rng.sample(StandardNormal) as f32
+            ))
+            .collect();
+        let mut state = state(&top, positions, velocities, SimulationBox::cubic(4.0 * NM));
+
+        // An ideal gas's compressibility is 1/P
+        let mut thermostat = Bussi::new(300.0 * K, 0.1 * PS);
+        thermostat.set_seed(6);
+        let mut barostat = StochasticCellRescaling::new(pressure, 300.0 * K, 0.5 * PS, 0.01 / BAR, Coupling::Isotropic);
+        barostat.set_seed(7);
+        let mut hooks = Hooks {
+            thermostat: Some(Box::new(thermostat)),
+            barostat: Some(Box::new(barostat)),
+            ..Hooks::new()
+        };
+        let mut integrator = VelocityVerlet::new();
+
+        for _ in 0..2000 {
+            integrator.step(&mut state, &mut hooks, 0.005 * PS);
+        }
+        let (mut sum, mut sum2) = (0.0f64, 0.0f64);
+        let n_samples = 20000;
+        for _ in 0..n_samples {
+            integrator.step(&mut state, &mut hooks, 0.005 * PS);
+            let volume = state.simbox().volume().value_unsafe as f64;
+            sum += volume;
+            sum2 += volume * volume;
+        }
+        let mean = sum / n_samples as f64;
+        let spread = (sum2 / n_samples as f64 - mean * mean).sqrt() / mean;
+
+        // The volume has a gamma distribution with shape n + 1, and its
+        // mean is 51 kT / 100 bar, 21.1 nm^3
+        let expected = 21.12;
+        assert!((mean / expected - 1.0).abs() < 0.05, "{} nm3 is not {} nm3", mean, expected);
+        let expected = 1.0 / ((n + 1) as f64).sqrt();
+        assert!((spread / expected - 1.0).abs() < 0.2, "relative spread {} is not {}", spread, expected);
+    }
+
+    #[test]
+    fn cell_rescaling_takes_the_virial_of_the_step() {
+        let n = 20;
+        let (top, positions) = ideal_gas(n);
+        let mut state = state(&top, positions, vec![VelocVec::from(0.1, 0.0, 0.0); n], SimulationBox::cubic(4.0 * NM));
+        let barostat = StochasticCellRescaling::new(2.0 * KJPMNM3, 300.0 * K, 0.5 * PS, 0.5 * NM3PKJPM, Coupling::Isotropic);
+
+        // Without a barostat that needs it, no virial is calculated
+        let mut hooks = Hooks::new();
+        VelocityVerlet::new().step(&mut state, &mut hooks, 0.001 * PS);
+        assert!(state.take_virial().is_none());
+
+        hooks.barostat = Some(Box::new(barostat));
+        let forces = hooks.forces(&mut state);
+        assert!(forces == state.forces());
+        assert!(state.take_virial() == Some(state.virial()));
+
+        // The barostat uses up the virial from the integrator's forces
+        VelocityVerlet::new().step(&mut state, &mut hooks, 0.001 * PS);
+        assert!(state.take_virial().is_none());
+    }
+}
@@ -7,7 +7,7 @@ use noether::geom::*;
 
 extern crate rand;
 extern crate chemfiles;
-use chemfiles::{Trajectory, Frame, Atom, UnitCell};
+use chemfiles::Trajectory;
 
 use rand::Rng;
 
@@ -21,11 +21,7 @@ fn main() {
     let mut rng = rand::thread_rng();
 
     let l = (SYS_SIZE as f32).cbrt();
-    let boxvecs = (
-        PosVec::from(  l, 0.0, 0.0),
-        PosVec::from(0.0,   l, 0.0),
-        PosVec::from(0.0, 0.0,   l)
-    );
+    let simbox = SimulationBox::cubic(l * NM);
 
     let state = State::new(
         &top,
@@ -41,7 +37,7 @@ fn main() {
                 0.0,
                 0.0
             )).collect(),
-        boxvecs,
+        simbox,
         "trajout.pdb".to_string()
     );
 
@@ -52,7 +48,7 @@ fn main() {
     let nsteps = 10_000;
     let temp = 300.0 * K;
 
-    write_state(String::from("mc_start.pdb"), &state, l);
+    write_state(String::from("mc_start.pdb"), &state);
 
     println!("Monte carlo to get us started! Let's do {} steps at {:?}.", nsteps, temp);
 
@@ -62,33 +58,23 @@ fn main() {
     let nsteps = 100_000;
     let dt = 0.01 * PS;
 
-    write_state(String::from("md_start.pdb"), &state, l);
+    write_state(String::from("md_start.pdb"), &state);
 
     println!("MD time! We'll go from our state with energy {:?} and simulate {} steps for a {:?} simulation.", energy, nsteps, nsteps as f32 * dt);
 
-    let state = state.simulate(nsteps, dt);
+    let state = state.simulate(nsteps, dt, temp);
 
 
     let out = String::from("finish.pdb");
     println!("Found a state with energy {}! Writing to {}. Bye bye!", energy, out);
 
-    write_state(out, &state, l);
+    write_state(out, &state);
 
 
 }
 
-fn write_state(filename:String, state:&State, box_l:f32) {
-    let mut frame = Frame::new().unwrap();
-    for posvec in state.positions.iter() {
-        let (x, y, z) = (
-            posvec.x.value_unsafe as f64 * 10.0,
-            posvec.y.value_unsafe as f64 * 10.0,
-            posvec.z.value_unsafe as f64 * 10.0
-        );
-        frame.add_atom(&Atom::new("LJ").unwrap(), [x, y, z], None).unwrap();
-    }
-    let unit_cell = UnitCell::new([box_l as f64 * 10.0; 3]).unwrap();
-    frame.set_cell(&unit_cell).unwrap();
+fn write_state(filename:String, state:&State) {
+    let frame = state.frame().unwrap();
 
     let mut trajectory = Trajectory::open(filename, 'w').unwrap();
     trajectory.write(&frame).unwrap();
@@ -0,0 +1,42 @@
+//! Chemical elements
+
+/// The symbols of the elements, in order of atomic number
+const SYMBOLS: [&str; 118] = [
+    "H", "He",
+    "Li", "Be", "B", "C", "N", "O", "F", "Ne",
+    "Na", "Mg", "Al", "Si", "P", "S", "Cl", "Ar",
+    "K", "Ca", "Sc", "Ti", "V", "Cr", "Mn", "Fe", "Co", "Ni", "Cu", "Zn",
+    "Ga", "Ge", "As", "Se", "Br", "Kr",
+    "Rb", "Sr", "Y", "Zr", "Nb", "Mo", "Tc", "Ru", "Rh", "Pd", "Ag", "Cd",
+    "In", "Sn", "Sb", "Te", "I", "Xe",
+    "Cs", "Ba",
+    "La", "Ce", "Pr", "Nd", "Pm", "Sm", "Eu", "Gd", "Tb", "Dy", "Ho", "Er", "Tm", "Yb", "Lu",
+    "Hf", "Ta", "W", "Re", "Os", "Ir", "Pt", "Au", "Hg",
+    "Tl", "Pb", "Bi", "Po", "At", "Rn",
+    "Fr", "Ra",
+    "Ac", "Th", "Pa", "U", "Np", "Pu", "Am", "Cm", "Bk", "Cf", "Es", "Fm", "Md", "No", "Lr",
+    "Rf", "Db", "Sg", "Bh", "Hs", "Mt", "Ds", "Rg", "Cn",
+    "Nh", "Fl", "Mc", "Lv", "Ts", "Og"
+];
+
+/// The symbol of the element with atomic number `atomic_number`, or `None`
+/// for zero, which topologies use for virtual sites, or numbers past the
+/// end of the periodic table
+pub fn symbol(atomic_number: usize) -> Option<&'static str> {
+    atomic_number.checked_sub(1).and_then(|z| SYMBOLS.get(z)).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbols_by_atomic_number() {
+        assert_eq!(symbol(0), None);
+        assert_eq!(symbol(1), Some("H"));
+        assert_eq!(symbol(6), Some("C"));
+        assert_eq!(symbol(26), Some("Fe"));
+        assert_eq!(symbol(118), Some("Og"));
+        assert_eq!(symbol(119), None);
+    }
+}
@@ -0,0 +1,193 @@
+//! Fast Fourier transforms for particle-mesh Ewald
+//!
+//! A mixed radix Cooley-Tukey transform that handles any length, and is
+//! fast when the length has only small prime factors. Transforms are
+//! unnormalised; a forward transform followed by an inverse transform
+//! multiplies the data by its length.
+
+use std::f64::consts::PI;
+use std::ops::{
+    Add,
+    AddAssign,
+    Mul
+};
+
+/// A double precision complex number
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64
+}
+
+impl Complex {
+    pub fn new(re: f64, im: f64) -> Complex {
+        Complex { re, im }
+    }
+
+    /// `exp(i theta)`
+    pub fn cis(theta: f64) -> Complex {
+        Complex::new(theta.cos(), theta.sin())
+    }
+
+    pub fn norm2(self) -> f64 {
+        self.re * self.re + self.im * self.im
+    }
+}
+
+impl Add for Complex {
+    type Output = Complex;
+
+    fn add(self, other: Complex) -> Complex {
+        Complex::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl AddAssign for Complex {
+    fn add_assign(&mut self, other: Complex) {
+        self.re += other.re;
+        self.im += other.im;
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+
+    fn mul(self, other: Complex) -> Complex {
+        Complex::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re
+        )
+    }
+}
+
+impl Mul<f64> for Complex {
+    type Output = Complex;
+
+    fn mul(self, other: f64) -> Complex {
+        Complex::new(self.re * other, self.im * other)
+    }
+}
+
+fn smallest_factor(n: usize) -> usize {
+    (2..).take_while(|p| p * p <= n)
+        .find(|&p| n.is_multiple_of(p))
+        .unwrap_or(n)
+}
+
+/// Transform `data` in place. The forward transform uses `exp(-2πi jk/n)`
+/// and the inverse `exp(+2πi jk/n)`.
+pub fn fft(data: &mut [Complex], inverse: bool) {
+    let n = data.len();
+    if n <= 1 {
+        return;
+    }
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let twiddle = |jk: usize| Complex::cis(sign * 2.0 * PI * (jk % n) as f64 / n as f64);
+
+    let p = smallest_factor(n);
+    if p == n {
+        // Prime length, so do the DFT directly
+        let input = data.to_vec();
+        for (k, out) in data.iter_mut().enumerate() {
+            *out = input.iter()
+                .enumerate()
+                .fold(Complex::default(), |acc, (j, &x)| acc + x * twiddle(j * k));
+        }
+        return;
+    }
+
+    // Split into p interleaved subsequences, transform each, and combine
+    let m = n / p;
+    let subs: Vec<Vec<Complex>> = (0..p)
+        .map(|r| {
+            let mut sub: Vec<Complex> = (0..m).map(|j| data[j * p + r]).collect();
+            fft(&mut sub, inverse);
+            sub
+        }).collect();
+    for (k, out) in data.iter_mut().enumerate() {
+        *out = subs.iter()
+            .enumerate()
+            .fold(Complex::default(), |acc, (r, sub)| acc + sub[k % m] * twiddle(r * k));
+    }
+}
+
+/// Transform a 3D grid in place, stored with the last dimension
+/// contiguous.
+pub fn fft3d(data: &mut [Complex], dims: [usize; 3], inverse: bool) {
+    let [n1, n2, n3] = dims;
+    assert_eq!(data.len(), n1 * n2 * n3);
+
+    let mut line = vec![];
+    for (stride, n, others) in [
+        (1, n3, n1 * n2),
+        (n3, n2, n1 * n3),
+        (n2 * n3, n1, n2 * n3)
+    ].iter().cloned() {
+        for other in 0..others {
+            // The first element of this line
+            let start = (other / stride) * stride * n + other % stride;
+            line.clear();
+            line.extend((0..n).map(|i| data[start + i * stride]));
+            fft(&mut line, inverse);
+            for (i, &x) in line.iter().enumerate() {
+                data[start + i * stride] = x;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn naive_dft(data: &[Complex]) -> Vec<Complex> {
+        let n = data.len();
+        (0..n).map(|k| {
+            data.iter()
+                .enumerate()
+                .fold(Complex::default(), |acc, (j, &x)| {
+                    acc + x * Complex::cis(-2.0 * PI * (j * k) as f64 / n as f64)
+                })
+        }).collect()
+    }
+
+    #[test]
+    fn fft_matches_naive_dft() {
+        for &n in [1, 2, 7, 12, 30, 64].iter() {
+            let data: Vec<Complex> = (0..n)
+                .map(|i| Complex::new((i as f64 * 0.7).sin(), (i as f64 * 1.3).cos()))
+                .collect();
+            let mut transformed = data.clone();
+            fft(&mut transformed, false);
+            for (a, b) in transformed.iter().zip(naive_dft(&data).iter()) {
+                assert!((a.re - b.re).abs() < 1e-9 && (a.im - b.im).abs() < 1e-9);
+            }
+
+            fft(&mut transformed, true);
+            for (a, b) in transformed.iter().zip(data.iter()) {
+                assert!((a.re / n as f64 - b.re).abs() < 1e-12);
+                assert!((a.im / n as f64 - b.im).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn fft3d_transforms_each_axis() {
+        let dims = [3, 4, 5];
+        let mut data = vec![Complex::default(); 60];
+        // A single plane wave along each axis
+        for i in 0..3 {
+            for j in 0..4 {
+                for k in 0..5 {
+                    let phase = 2.0 * PI * (i as f64 / 3.0 + 2.0 * j as f64 / 4.0 + 3.0 * k as f64 / 5.0);
+                    data[(i * 4 + j) * 5 + k] = Complex::cis(phase);
+                }
+            }
+        }
+        fft3d(&mut data, dims, false);
+        for (index, x) in data.iter().enumerate() {
+            let expected = if index == (4 + 2) * 5 + 3 { 60.0 } else { 0.0 };
+            assert!((x.re - expected).abs() < 1e-9 && x.im.abs() < 1e-9);
+        }
+    }
+}
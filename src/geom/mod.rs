@@ -1,9 +1,13 @@
 // pub mod vec3;
 pub mod vec3d;
+pub mod simbox;
+pub mod tensor;
 
 use crate::units;
 
 pub use self::vec3d::Vec3D;
+pub use self::simbox::SimulationBox;
+pub use self::tensor::Tensor3D;
 
 type V = f32;
 
@@ -15,6 +19,8 @@ pub type MDForce = units::KilojoulePerMolePerNanometer<V>;
 pub type MDVeloc = units::NanometerPerPicosecond<V>;
 /// 32 bit dimensionless quantity in GMX units
 pub type MDNodim = units::Unitless<V>;
+/// 32 bit energy in GMX units
+pub type MDEnergy = units::KilojoulePerMole<V>;
 
 /// Vector holding 32 bit positions
 pub type PosVec = Vec3D<MDPos>;
@@ -24,3 +30,8 @@ pub type ForceVec = Vec3D<MDForce>;
 pub type VelocVec = Vec3D<MDVeloc>;
 /// Vector holding 32 bit dimensionless quantities
 pub type NodimVec = Vec3D<MDNodim>;
+/// Vector holding 32 bit reciprocal lengths, eg a reciprocal box vector
+pub type RecipVec = Vec3D<units::PerNanometer<V>>;
+
+/// Tensor holding 32 bit energies, eg a virial
+pub type EnergyTensor = Tensor3D<MDEnergy>;
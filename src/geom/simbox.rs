@@ -0,0 +1,391 @@
+use crate::units::{
+    Nanometer,
+    Nanometer3
+};
+use crate::units::f32consts::*;
+
+use super::{
+    PosVec,
+    NodimVec,
+    RecipVec
+};
+
+/// A periodic simulation box.
+///
+/// The box is stored as three box vectors `a`, `b` and `c` in the
+/// same reduced, lower-triangular form that GROMACS uses:
+///
+/// ```text
+/// a = (ax,  0,  0)
+/// b = (bx, by,  0)
+/// c = (cx, cy, cz)
+/// ```
+///
+/// with `|bx| <= ax/2`, `|cx| <= ax/2` and `|cy| <= by/2`. Any
+/// lower-triangular set of vectors describing the same lattice is
+/// reduced to this form on construction. Rectangular boxes are just
+/// the special case where all off-diagonal elements are zero, and are
+/// handled with cheaper code paths.
+///
+/// # Examples
+///
+/// ```
+/// use noether::geom::{PosVec, SimulationBox};
+/// use noether::units::f32consts::*;
+///
+/// let simbox = SimulationBox::cubic(2.0 * NM);
+/// assert!(simbox.is_rectangular());
+/// assert_eq!(simbox.volume(), 8.0 * NM3);
+///
+/// let wrapped = simbox.wrap(PosVec::from(-0.5, 2.5, 1.0));
+/// assert_eq!(wrapped, PosVec::from(1.5, 0.5, 1.0));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationBox {
+    a: PosVec,
+    b: PosVec,
+    c: PosVec,
+    rectangular: bool
+}
+
+impl SimulationBox {
+    /// A cubic box with sides of length `length`.
+    pub fn cubic(length: Nanometer<f32>) -> SimulationBox {
+        SimulationBox::rectangular(length, length, length)
+    }
+
+    /// A rectangular box with sides of the given lengths.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of the lengths is not positive.
+    pub fn rectangular(
+        x: Nanometer<f32>,
+        y: Nanometer<f32>,
+        z: Nanometer<f32>
+    ) -> SimulationBox {
+        SimulationBox::triclinic(
+            PosVec::new(x, 0.0 * NM, 0.0 * NM),
+            PosVec::new(0.0 * NM, y, 0.0 * NM),
+            PosVec::new(0.0 * NM, 0.0 * NM, z)
+        )
+    }
+
+    /// A general triclinic box from three lower-triangular box vectors.
+    ///
+    /// The vectors are reduced so that each off-diagonal element is at
+    /// most half the corresponding diagonal element, which does not
+    /// change the lattice but is required for the minimum image search.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the vectors are not lower-triangular (ie, if `a.y`,
+    /// `a.z` or `b.z` is non-zero) or if any diagonal element is not
+    /// positive.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use noether::geom::{PosVec, SimulationBox};
+    ///
+    /// let simbox = SimulationBox::triclinic(
+    ///     PosVec::from(2.0, 0.0, 0.0),
+    ///     PosVec::from(1.5, 2.0, 0.0),
+    ///     PosVec::from(0.0, 0.0, 2.0)
+    /// );
+    /// let (_, b, _) = simbox.vectors();
+    /// assert_eq!(b, &PosVec::from(-0.5, 2.0, 0.0));
+    /// assert!(!simbox.is_rectangular());
+    /// ```
+    pub fn triclinic(a: PosVec, b: PosVec, c: PosVec) -> SimulationBox {
+        if a.y != 0.0 * NM || a.z != 0.0 * NM || b.z != 0.0 * NM {
+            panic!("Box vectors must be in lower-triangular form!");
+        }
+        if a.x <= 0.0 * NM || b.y <= 0.0 * NM || c.z <= 0.0 * NM {
+            panic!("Box vectors must have positive diagonal elements!");
+        }
+
+        let mut b = b;
+        let mut c = c;
+
+        c -= b.clone() * (c.y / b.y).value_unsafe.round();
+        c -= a.clone() * (c.x / a.x).value_unsafe.round();
+        b -= a.clone() * (b.x / a.x).value_unsafe.round();
+
+        let rectangular = b.x == 0.0 * NM && c.x == 0.0 * NM && c.y == 0.0 * NM;
+
+        SimulationBox {
+            a,
+            b,
+            c,
+            rectangular
+        }
+    }
+
+    /// A box from its three edge lengths and the angles between them,
+    /// in the convention used by PDB files and chemfiles.
+    ///
+    /// `alpha` is the angle between `b` and `c`, `beta` between `a` and
+    /// `c`, and `gamma` between `a` and `b`. Angles are in degrees.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use noether::geom::SimulationBox;
+    /// use noether::units::f32consts::*;
+    ///
+    /// let simbox = SimulationBox::from_lengths_angles(
+    ///     (3.0 * NM, 3.0 * NM, 3.0 * NM),
+    ///     (90.0, 90.0, 90.0)
+    /// );
+    /// assert_eq!(simbox, SimulationBox::cubic(3.0 * NM));
+    /// ```
+    pub fn from_lengths_angles(
+        lengths: (Nanometer<f32>, Nanometer<f32>, Nanometer<f32>),
+        angles: (f32, f32, f32)
+    ) -> SimulationBox {
+        let (len_a, len_b, len_c) = lengths;
+        let (alpha, beta, gamma) = angles;
+
+        if alpha == 90.0 && beta == 90.0 && gamma == 90.0 {
+            return SimulationBox::rectangular(len_a, len_b, len_c);
+        }
+
+        let (cos_alpha, cos_beta) = (alpha.to_radians().cos(), beta.to_radians().cos());
+        let (sin_gamma, cos_gamma) = gamma.to_radians().sin_cos();
+
+        let cx = len_c * cos_beta;
+        let cy = len_c * (cos_alpha - cos_beta * cos_gamma) / sin_gamma;
+        let cz = (len_c * len_c - cx * cx - cy * cy).value_unsafe.sqrt() * NM;
+
+        SimulationBox::triclinic(
+            PosVec::new(len_a, 0.0 * NM, 0.0 * NM),
+            PosVec::new(len_b * cos_gamma, len_b * sin_gamma, 0.0 * NM),
+            PosVec::new(cx, cy, cz)
+        )
+    }
+
+    /// The three box vectors in reduced form.
+    pub fn vectors(&self) -> (&PosVec, &PosVec, &PosVec) {
+        (&self.a, &self.b, &self.c)
+    }
+
+    /// The lengths of the three box vectors.
+    pub fn lengths(&self) -> (Nanometer<f32>, Nanometer<f32>, Nanometer<f32>) {
+        (self.a.norm(), self.b.norm(), self.c.norm())
+    }
+
+    /// The angles between the box vectors in degrees, as
+    /// `(alpha, beta, gamma)`. See `from_lengths_angles`.
+    pub fn angles(&self) -> (f32, f32, f32) {
+        if self.rectangular {
+            return (90.0, 90.0, 90.0);
+        }
+
+        let angle = |u: &PosVec, v: &PosVec| {
+            let cos = (u.clone() * v.clone()) / (u.norm() * v.norm());
+            cos.value_unsafe.clamp(-1.0, 1.0).acos().to_degrees()
+        };
+
+        (
+            angle(&self.b, &self.c),
+            angle(&self.a, &self.c),
+            angle(&self.a, &self.b)
+        )
+    }
+
+    /// Whether all three box vectors are orthogonal.
+    pub fn is_rectangular(&self) -> bool {
+        self.rectangular
+    }
+
+    /// The volume of the box.
+    pub fn volume(&self) -> Nanometer3<f32> {
+        self.a.x * self.b.y * self.c.z
+    }
+
+    /// The box with the x, y and z components of every box vector
+    /// multiplied by `factors`, which keeps it lower-triangular.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use noether::geom::SimulationBox;
+    /// use noether::units::f32consts::*;
+    ///
+    /// let simbox = SimulationBox::cubic(2.0 * NM).scale((1.5, 1.5, 0.5));
+    /// assert_eq!(simbox, SimulationBox::rectangular(3.0 * NM, 3.0 * NM, 1.0 * NM));
+    /// ```
+    pub fn scale(&self, factors: (f32, f32, f32)) -> SimulationBox {
+        let scale = |v: &PosVec| PosVec::new(v.x * factors.0, v.y * factors.1, v.z * factors.2);
+        SimulationBox::triclinic(scale(&self.a), scale(&self.b), scale(&self.c))
+    }
+
+    /// The reciprocal box vectors `a*`, `b*` and `c*`, such that
+    /// `a · a* = 1` and `a · b* = 0` and so on. Fractional coordinates are
+    /// the dot products of a position with the reciprocal vectors.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use noether::geom::{PosVec, SimulationBox};
+    ///
+    /// let simbox = SimulationBox::triclinic(
+    ///     PosVec::from(2.0, 0.0, 0.0),
+    ///     PosVec::from(0.5, 2.0, 0.0),
+    ///     PosVec::from(0.3, 0.7, 1.5)
+    /// );
+    /// let (a, b, c) = simbox.vectors();
+    /// let (a_star, b_star, c_star) = simbox.reciprocal_vectors();
+    ///
+    /// assert!(((a.clone() * a_star.clone()).value_unsafe - 1.0).abs() < 1e-6);
+    /// assert!((b.clone() * a_star.clone()).value_unsafe.abs() < 1e-6);
+    /// assert!(((c.clone() * c_star.clone()).value_unsafe - 1.0).abs() < 1e-6);
+    /// assert!((a.clone() * c_star).value_unsafe.abs() < 1e-6);
+    /// assert!(((b.clone() * b_star).value_unsafe - 1.0).abs() < 1e-6);
+    /// ```
+    pub fn reciprocal_vectors(&self) -> (RecipVec, RecipVec, RecipVec) {
+        let volume = self.volume();
+        (
+            (self.b.clone() % self.c.clone()) / volume,
+            (self.c.clone() % self.a.clone()) / volume,
+            (self.a.clone() % self.b.clone()) / volume
+        )
+    }
+
+    /// The perpendicular distances between opposite faces of the box,
+    /// ie the spacing of the lattice planes spanned by `(b, c)`, `(a, c)`
+    /// and `(a, b)` respectively.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use noether::geom::{PosVec, SimulationBox};
+    /// use noether::units::f32consts::*;
+    ///
+    /// let simbox = SimulationBox::triclinic(
+    ///     PosVec::from(2.0, 0.0, 0.0),
+    ///     PosVec::from(0.0, 2.0, 0.0),
+    ///     PosVec::from(0.0, 1.0, 2.0)
+    /// );
+    /// let (h_a, _, h_c) = simbox.heights();
+    /// assert_eq!(h_a, 2.0 * NM);
+    /// assert_eq!(h_c, 2.0 * NM);
+    /// ```
+    pub fn heights(&self) -> (Nanometer<f32>, Nanometer<f32>, Nanometer<f32>) {
+        let volume = self.volume();
+        (
+            volume / (self.b.clone() % self.c.clone()).norm(),
+            volume / (self.c.clone() % self.a.clone()).norm(),
+            volume / (self.a.clone() % self.b.clone()).norm()
+        )
+    }
+
+    /// The minimum image of a displacement vector under the periodic
+    /// boundary conditions of the box.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use noether::geom::{PosVec, SimulationBox};
+    /// use noether::units::f32consts::*;
+    ///
+    /// let simbox = SimulationBox::cubic(2.0 * NM);
+    /// assert_eq!(
+    ///     simbox.min_image(PosVec::from(1.5, -1.5, 0.25)),
+    ///     PosVec::from(-0.5, 0.5, 0.25)
+    /// );
+    /// ```
+    pub fn min_image(&self, diff: PosVec) -> PosVec {
+        let mut diff = diff;
+
+        diff -= self.c.clone() * (diff.z / self.c.z).value_unsafe.round();
+        diff -= self.b.clone() * (diff.y / self.b.y).value_unsafe.round();
+        diff -= self.a.clone() * (diff.x / self.a.x).value_unsafe.round();
+
+        if self.rectangular {
+            return diff;
+        }
+
+        // In a reduced triclinic box the shortest image is always
+        // within one box vector of the shift found above
+        let mut best = diff.clone();
+        let mut best2 = diff.norm2();
+        for i in [-1.0, 0.0, 1.0].iter() {
+            for j in [-1.0, 0.0, 1.0].iter() {
+                for k in [-1.0, 0.0, 1.0].iter() {
+                    let image = diff.clone()
+                        + self.a.clone() * *i
+                        + self.b.clone() * *j
+                        + self.c.clone() * *k;
+                    let image2 = image.norm2();
+                    if image2 < best2 {
+                        best = image;
+                        best2 = image2;
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    /// Convert a cartesian position to fractional coordinates
+    /// in units of the box vectors.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use noether::geom::{PosVec, NodimVec, SimulationBox};
+    /// use noether::units::f32consts::*;
+    ///
+    /// let simbox = SimulationBox::rectangular(2.0 * NM, 4.0 * NM, 1.0 * NM);
+    /// let frac = simbox.to_fractional(&PosVec::from(1.0, 1.0, 3.0));
+    /// assert_eq!(frac, NodimVec::from(0.5, 0.25, 3.0));
+    /// assert_eq!(simbox.from_fractional(&frac), PosVec::from(1.0, 1.0, 3.0));
+    /// ```
+    pub fn to_fractional(&self, pos: &PosVec) -> NodimVec {
+        let sz = pos.z / self.c.z;
+        let sy = (pos.y - self.c.y * sz) / self.b.y;
+        let sx = (pos.x - self.b.x * sy - self.c.x * sz) / self.a.x;
+        NodimVec::new(sx, sy, sz)
+    }
+
+    /// Convert fractional coordinates in units of the box vectors
+    /// to a cartesian position.
+    pub fn from_fractional(&self, frac: &NodimVec) -> PosVec {
+        self.a.clone() * frac.x
+        + self.b.clone() * frac.y
+        + self.c.clone() * frac.z
+    }
+
+    /// Wrap a position into the primary unit cell of the box.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use noether::geom::{PosVec, SimulationBox};
+    ///
+    /// let simbox = SimulationBox::triclinic(
+    ///     PosVec::from(2.0, 0.0, 0.0),
+    ///     PosVec::from(0.5, 2.0, 0.0),
+    ///     PosVec::from(0.0, 0.0, 2.0)
+    /// );
+    /// assert_eq!(
+    ///     simbox.wrap(PosVec::from(0.25, -1.0, 2.5)),
+    ///     PosVec::from(0.75, 1.0, 0.5)
+    /// );
+    /// ```
+    pub fn wrap(&self, pos: PosVec) -> PosVec {
+        let frac = self.to_fractional(&pos);
+        let reduce = |s: f32| {
+            let s = s - s.floor();
+            // Tiny negative values round up to exactly 1.0
+            if s >= 1.0 { 0.0 } else { s }
+        };
+        self.from_fractional(&NodimVec::from(
+            reduce(frac.x.value_unsafe),
+            reduce(frac.y.value_unsafe),
+            reduce(frac.z.value_unsafe)
+        ))
+    }
+}
@@ -0,0 +1,217 @@
+use std::ops::{
+    Add,
+    AddAssign,
+    Sub,
+    Mul,
+    Div,
+    Neg,
+};
+
+use crate::dim::Dimensioned;
+
+use super::Vec3D;
+
+/// A rank 2 tensor in 3 dimensions, stored as three row vectors.
+///
+/// # Examples
+///
+/// ```
+/// use noether::geom::{Vec3D, Tensor3D};
+/// use noether::units::{Nanometer, Nanometer2};
+/// use noether::units::f32consts::*;
+///
+/// type PosVec = Vec3D<Nanometer<f32>>;
+///
+/// let a = PosVec::from(1.0, 2.0, 3.0);
+/// let b = PosVec::from(0.0, 1.0, 0.0);
+/// let t = Tensor3D::outer(&a, &b);
+///
+/// assert_eq!(t.y, Vec3D::from(0.0, 2.0, 0.0));
+/// assert_eq!(t.trace(), 2.0 * NM2);
+/// ```
+#[derive(PartialEq, Clone, Debug)]
+pub struct Tensor3D<Q: Dimensioned> {
+    pub x: Vec3D<Q>,
+    pub y: Vec3D<Q>,
+    pub z: Vec3D<Q>
+}
+
+impl<Q, V> Tensor3D<Q>
+    where
+        Q: Copy + Dimensioned<Value=V>,
+        V: Copy + From<f32>
+{
+    /// A tensor from its three rows.
+    pub fn new(x: Vec3D<Q>, y: Vec3D<Q>, z: Vec3D<Q>) -> Tensor3D<Q> {
+        Tensor3D {
+            x,
+            y,
+            z
+        }
+    }
+
+    /// Create a tensor of zeroes.
+    pub fn zero() -> Tensor3D<Q> {
+        Tensor3D {
+            x: Vec3D::zero(),
+            y: Vec3D::zero(),
+            z: Vec3D::zero()
+        }
+    }
+}
+
+impl<Q> Tensor3D<Q>
+    where
+        Q: Copy + Dimensioned + Add<Q, Output=Q>
+{
+    /// The sum of the diagonal elements.
+    pub fn trace(&self) -> Q {
+        self.x.x + self.y.y + self.z.z
+    }
+
+    /// The diagonal elements as a vector.
+    pub fn diagonal(&self) -> Vec3D<Q> {
+        Vec3D {
+            x: self.x.x,
+            y: self.y.y,
+            z: self.z.z
+        }
+    }
+
+    /// The transposed tensor.
+    pub fn transpose(&self) -> Tensor3D<Q> {
+        Tensor3D {
+            x: Vec3D { x: self.x.x, y: self.y.x, z: self.z.x },
+            y: Vec3D { x: self.x.y, y: self.y.y, z: self.z.y },
+            z: Vec3D { x: self.x.z, y: self.y.z, z: self.z.z }
+        }
+    }
+}
+
+impl<Q> Tensor3D<Q>
+    where
+        Q: Copy + Dimensioned
+{
+    /// The outer product `a ⊗ b` of two vectors, whose element
+    /// `[m][n]` is `a[m] * b[n]`.
+    pub fn outer<A, B>(a: &Vec3D<A>, b: &Vec3D<B>) -> Tensor3D<Q>
+        where
+            A: Copy + Dimensioned + Mul<B, Output=Q>,
+            B: Copy + Dimensioned
+    {
+        Tensor3D {
+            x: Vec3D { x: a.x * b.x, y: a.x * b.y, z: a.x * b.z },
+            y: Vec3D { x: a.y * b.x, y: a.y * b.y, z: a.y * b.z },
+            z: Vec3D { x: a.z * b.x, y: a.z * b.y, z: a.z * b.z }
+        }
+    }
+}
+
+impl<Q> Add for Tensor3D<Q>
+    where
+        Q: Copy + Dimensioned + Add<Q, Output=Q>
+{
+    type Output = Tensor3D<Q>;
+
+    /// Elementwise addition with the `+` operator.
+    fn add(self, other: Tensor3D<Q>) -> Tensor3D<Q> {
+        Tensor3D {
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z
+        }
+    }
+}
+
+impl<Q> AddAssign for Tensor3D<Q>
+    where
+        Q: Copy + Dimensioned + AddAssign<Q>
+{
+    /// Elementwise addition assignment with the `+=` operator.
+    fn add_assign(&mut self, other: Tensor3D<Q>) {
+        self.x += other.x;
+        self.y += other.y;
+        self.z += other.z;
+    }
+}
+
+impl<Q> Sub for Tensor3D<Q>
+    where
+        Q: Copy + Dimensioned + Sub<Q, Output=Q>
+{
+    type Output = Tensor3D<Q>;
+
+    /// Elementwise subtraction with the `-` operator.
+    fn sub(self, other: Tensor3D<Q>) -> Tensor3D<Q> {
+        Tensor3D {
+            x: self.x - other.x,
+            y: self.y - other.y,
+            z: self.z - other.z
+        }
+    }
+}
+
+impl<Q, QR, QQR> Mul<QR> for Tensor3D<Q>
+    where
+        Q: Copy + Dimensioned + Mul<QR, Output=QQR>,
+        QR: Copy,
+        QQR: Copy + Dimensioned
+{
+    type Output = Tensor3D<QQR>;
+
+    /// Multiplication of a tensor by a scalar with the `*` operator.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use noether::geom::{Vec3D, Tensor3D};
+    /// use noether::units::Nanometer;
+    /// use noether::units::f32consts::*;
+    ///
+    /// type PosVec = Vec3D<Nanometer<f32>>;
+    ///
+    /// let t = Tensor3D::outer(&PosVec::from(1.0, 0.0, 0.0), &PosVec::from(1.0, 0.0, 0.0));
+    /// assert_eq!((t * 2.0 * KJPM / NM2).trace(), 2.0 * KJPM);
+    /// ```
+    fn mul(self, other: QR) -> Tensor3D<QQR> {
+        Tensor3D {
+            x: self.x * other,
+            y: self.y * other,
+            z: self.z * other
+        }
+    }
+}
+
+impl<QQR, QR, Q> Div<QR> for Tensor3D<QQR>
+    where
+        QQR: Copy + Dimensioned + Div<QR, Output=Q>,
+        QR: Copy,
+        Q: Copy + Dimensioned
+{
+    type Output = Tensor3D<Q>;
+
+    /// Division of a tensor by a scalar with the `/` operator.
+    fn div(self, other: QR) -> Tensor3D<Q> {
+        Tensor3D {
+            x: self.x / other,
+            y: self.y / other,
+            z: self.z / other
+        }
+    }
+}
+
+impl<Q> Neg for Tensor3D<Q>
+    where
+        Q: Copy + Dimensioned + Neg<Output=Q>
+{
+    type Output = Tensor3D<Q>;
+
+    /// Elementwise negation with the `-` operator.
+    fn neg(self) -> Tensor3D<Q> {
+        Tensor3D {
+            x: -self.x,
+            y: -self.y,
+            z: -self.z
+        }
+    }
+}
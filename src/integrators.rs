@@ -0,0 +1,360 @@
+//! Integrators for molecular dynamics
+//!
+//! An `Integrator` advances a `State` by one time step at a time, and
+//! `State::run` drives it for a number of steps. Thermostats, barostats
+//! and constraints plug into every integrator through `Hooks`, which the
+//! integrator calls at defined points of its step:
+//!
+//! - `Hooks::constrain_positions` after every update of the positions,
+//!   with the positions from before the update
+//! - `Hooks::constrain_velocities` after the last update of the
+//!   velocities
+//! - `Hooks::apply_thermostat` once the velocities are complete
+//! - `Hooks::apply_barostat` at the very end of the step
+//!
+//! The last force evaluation of each step goes through `Hooks::forces`,
+//! which calculates the virial along with the forces when the barostat
+//! needs it.
+//!
+//! Integrators also wrap the positions back into the box after moving
+//! them.
+
+use crate::units::*;
+use crate::units::f32consts::*;
+use crate::geom::{PosVec, ForceVec, SimulationBox};
+use crate::state::State;
+
+/// A scheme for advancing a state by one time step
+pub trait Integrator {
+    /// Advance `state` by a time step of `dt`, calling `hooks` as
+    /// described in the module documentation
+    fn step(&mut self, state: &mut State, hooks: &mut Hooks, dt: Picosecond<f32>);
+}
+
+/// Couples the velocities to a heat bath
+pub trait Thermostat {
+    /// Adjust the velocities for a step of `dt`
+    fn apply(&mut self, state: &mut State, dt: Picosecond<f32>);
+
+    /// The energy the thermostat has taken from the atoms, and any energy
+    /// of its own, which together with the potential and kinetic energies
+    /// is conserved
+    fn conserved_energy(&self) -> KilojoulePerMole<f32>;
+}
+
+/// Couples the box and positions to a pressure bath
+pub trait Barostat {
+    /// Adjust the box and the positions for a step of `dt`
+    fn apply(&mut self, state: &mut State, dt: Picosecond<f32>);
+
+    /// Whether `apply` uses the virial, which integrators then calculate
+    /// with the forces and leave in the state for `State::take_virial`
+    fn uses_virial(&self) -> bool {
+        false
+    }
+}
+
+/// Holds some degrees of freedom fixed, like bond lengths
+pub trait Constraints {
+    /// Move the positions back onto the constraints after an update of
+    /// `dt` from `reference`, which satisfied them, and correct the
+    /// velocities to match
+    fn constrain_positions(&mut self, state: &mut State, reference: &[PosVec], dt: Picosecond<f32>);
+
+    /// Remove the parts of the velocities that would break the
+    /// constraints
+    fn constrain_velocities(&mut self, state: &mut State);
+}
+
+/// The thermostat, barostat and constraints of a run, any of which may be
+/// left out
+///
+/// # Examples
+///
+/// ```
+/// use noether::integrators::Hooks;
+/// use noether::thermostats::Bussi;
+/// use noether::units::f32consts::*;
+///
+/// let hooks = Hooks {
+///     thermostat: Some(Box::new(Bussi::new(300.0 * K, 1.0 * PS))),
+///     ..Hooks::new()
+/// };
+/// assert!(hooks.barostat.is_none());
+/// ```
+#[derive(Default)]
+pub struct Hooks {
+    pub thermostat: Option<Box<dyn Thermostat>>,
+    pub barostat: Option<Box<dyn Barostat>>,
+    pub constraints: Option<Box<dyn Constraints>>
+}
+
+impl Hooks {
+    /// No thermostat, barostat or constraints, for constant energy
+    /// dynamics
+    pub fn new() -> Hooks {
+        Hooks::default()
+    }
+
+    pub fn apply_thermostat(&mut self, state: &mut State, dt: Picosecond<f32>) {
+        if let Some(thermostat) = self.thermostat.as_mut() {
+            thermostat.apply(state, dt);
+        }
+    }
+
+    /// The thermostat's contribution to the conserved energy, or zero
+    /// without a thermostat
+    pub fn thermostat_energy(&self) -> KilojoulePerMole<f32> {
+        match self.thermostat.as_ref() {
+            Some(thermostat) => thermostat.conserved_energy(),
+            None => 0.0 * KJPM
+        }
+    }
+
+    /// The forces at the current positions, calculated together with the
+    /// virial if the barostat uses it
+    pub fn forces(&self, state: &mut State) -> Vec<ForceVec> {
+        if self.barostat.as_ref().is_some_and(|barostat| barostat.uses_virial()) {
+            state.forces_and_virial().0
+        } else {
+            state.forces()
+        }
+    }
+
+    pub fn apply_barostat(&mut self, state: &mut State, dt: Picosecond<f32>) {
+        if let Some(barostat) = self.barostat.as_mut() {
+            barostat.apply(state, dt);
+        }
+    }
+
+    pub fn constrain_positions(&mut self, state: &mut State, reference: &[PosVec], dt: Picosecond<f32>) {
+        if let Some(constraints) = self.constraints.as_mut() {
+            constraints.constrain_positions(state, reference, dt);
+        }
+    }
+
+    pub fn constrain_velocities(&mut self, state: &mut State) {
+        if let Some(constraints) = self.constraints.as_mut() {
+            constraints.constrain_velocities(state);
+        }
+    }
+}
+
+/// Move the positions along the velocities for a time `dt`
+pub(crate) fn drift(state: &mut State, dt: Picosecond<f32>) {
+    for (r, v) in state.positions.iter_mut().zip(state.velocities.iter()) {
+        *r += v.clone() * dt;
+    }
+}
+
+/// Accelerate the atoms with `forces` for a time `dt`
+pub(crate) fn kick(state: &mut State, forces: &[ForceVec], dt: Picosecond<f32>) {
+    let atoms = &state.topology.atoms;
+    for ((v, f), atom) in state.velocities.iter_mut().zip(forces.iter()).zip(atoms.iter()) {
+        *v += f.clone() * dt / atom.mass;
+    }
+}
+
+/// The forces from the end of one step, kept for the start of the next
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ForceCache {
+    /// The forces, and the positions and box they were calculated for
+    cached: Option<(Vec<PosVec>, SimulationBox, Vec<ForceVec>)>
+}
+
+impl ForceCache {
+    /// The cached forces if they were calculated for the current
+    /// positions and box, or freshly calculated ones if not
+    pub(crate) fn take(&mut self, state: &mut State) -> Vec<ForceVec> {
+        match self.cached.take() {
+            Some((positions, simbox, forces)) if positions == state.positions && &simbox == state.simbox() => forces,
+            _ => state.forces()
+        }
+    }
+
+    /// Keep `forces`, which were calculated for the current positions
+    /// and box
+    pub(crate) fn store(&mut self, state: &State, forces: Vec<ForceVec>) {
+        self.cached = Some((state.positions.clone(), state.simbox().clone(), forces));
+    }
+}
+
+/// Half a drift, a kick and another half drift per step, with the
+/// velocities at the same time as the positions
+///
+/// This is the scheme `State::simulate` has always used. It needs one
+/// force evaluation per step, in the middle of the step.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct PositionVerlet;
+
+impl Integrator for PositionVerlet {
+    fn step(&mut self, state: &mut State, hooks: &mut Hooks, dt: Picosecond<f32>) {
+        let reference = state.positions.clone();
+        drift(state, dt / 2.0);
+        let forces = hooks.forces(state);
+        kick(state, &forces, dt);
+        drift(state, dt / 2.0);
+        hooks.constrain_positions(state, &reference, dt);
+        hooks.constrain_velocities(state);
+        hooks.apply_thermostat(state, dt);
+        state.wrap_positions();
+        hooks.apply_barostat(state, dt);
+    }
+}
+
+/// Half a kick, a drift and another half kick per step, with the
+/// velocities at the same time as the positions
+///
+/// The forces from the end of each step are kept for the start of the
+/// next, so there is one force evaluation per step unless something else
+/// moves the atoms or changes the box between steps.
+#[derive(Debug, Clone, Default)]
+pub struct VelocityVerlet {
+    cache: ForceCache
+}
+
+impl VelocityVerlet {
+    pub fn new() -> VelocityVerlet {
+        VelocityVerlet::default()
+    }
+}
+
+impl Integrator for VelocityVerlet {
+    fn step(&mut self, state: &mut State, hooks: &mut Hooks, dt: Picosecond<f32>) {
+        let forces = self.cache.take(state);
+        kick(state, &forces, dt / 2.0);
+        let reference = state.positions.clone();
+        drift(state, dt);
+        hooks.constrain_positions(state, &reference, dt);
+        state.wrap_positions();
+        let forces = hooks.forces(state);
+        kick(state, &forces, dt / 2.0);
+        hooks.constrain_velocities(state);
+        hooks.apply_thermostat(state, dt);
+
+        // Before the barostat moves the atoms away from the forces
+        self.cache.store(state, forces);
+        hooks.apply_barostat(state, dt);
+    }
+}
+
+/// A full kick and then a full drift per step, with the velocities half a
+/// step behind the positions
+///
+/// The velocities in the state are those of the half step before the
+/// positions, as in GROMACS' `md` integrator, so kinetic energies
+/// calculated from them are slightly off.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Leapfrog;
+
+impl Integrator for Leapfrog {
+    fn step(&mut self, state: &mut State, hooks: &mut Hooks, dt: Picosecond<f32>) {
+        let forces = hooks.forces(state);
+        kick(state, &forces, dt);
+        hooks.constrain_velocities(state);
+        hooks.apply_thermostat(state, dt);
+        let reference = state.positions.clone();
+        drift(state, dt);
+        hooks.constrain_positions(state, &reference, dt);
+        state.wrap_positions();
+        hooks.apply_barostat(state, dt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::VelocVec;
+    use crate::topology::Top;
+    use crate::potentials::bonded::Bond;
+    use crate::state::tests::state;
+
+    /// Two atoms joined by a stiff bond, with no Lennard-Jones
+    fn diatomic() -> Top {
+        let mut top = Top::gen_lj_fluid(2, 16.0 * DA, 0.0 * KJPM, 0.3 * NM);
+        top.bonds.push(Bond { atoms: [0, 1], length: 0.12 * NM, force_constant: 50000.0 * KJPM / NM2 });
+        top
+    }
+
+    /// The diatomic stretching and tumbling in a 4 nm box
+    fn moving_diatomic(top: &Top) -> State<'_> {
+        state(
+            top,
+            vec![PosVec::from(2.0, 2.0, 2.0), PosVec::from(2.13, 2.0, 2.0)],
+            vec![VelocVec::from(0.0, 0.5, 0.0), VelocVec::from(0.0, -0.5, 0.3)],
+            SimulationBox::cubic(4.0 * NM)
+        )
+    }
+
+    fn total_energy(state: &State) -> f32 {
+        (state.calc_energy() + state.kinetic_energy()).value_unsafe
+    }
+
+    #[test]
+    fn velocity_verlet_conserves_energy() {
+        let top = diatomic();
+        let mut state = moving_diatomic(&top);
+        let mut integrator = VelocityVerlet::new();
+        let mut hooks = Hooks::new();
+        let start = total_energy(&state);
+        for _ in 0..1000 {
+            integrator.step(&mut state, &mut hooks, 0.001 * PS);
+            assert!((total_energy(&state) - start).abs() < 1e-2 * start.abs());
+        }
+    }
+
+    #[test]
+    fn leapfrog_follows_velocity_verlet() {
+        let top = diatomic();
+        let dt = 0.001 * PS;
+        let mut verlet = moving_diatomic(&top);
+        let mut leapfrog = moving_diatomic(&top);
+
+        // Leapfrog starts from the velocities half a step back
+        let forces = leapfrog.forces();
+        kick(&mut leapfrog, &forces, -dt / 2.0);
+
+        let mut hooks = Hooks::new();
+        let mut integrator = VelocityVerlet::new();
+        for _ in 0..200 {
+            integrator.step(&mut verlet, &mut hooks, dt);
+            Leapfrog.step(&mut leapfrog, &mut hooks, dt);
+        }
+        for (a, b) in verlet.positions.iter().zip(leapfrog.positions.iter()) {
+            assert!((a - b).norm2().value_unsafe < 1e-8);
+        }
+    }
+
+    /// Holds atom 0 in place
+    struct Pin;
+
+    impl Constraints for Pin {
+        fn constrain_positions(&mut self, state: &mut State, reference: &[PosVec], _dt: Picosecond<f32>) {
+            state.positions[0] = reference[0].clone();
+        }
+
+        fn constrain_velocities(&mut self, state: &mut State) {
+            state.velocities[0] = VelocVec::from(0.0, 0.0, 0.0);
+        }
+    }
+
+    #[test]
+    fn integrators_apply_constraints() {
+        let top = diatomic();
+        let integrators: Vec<Box<dyn Integrator>> = vec![
+            Box::new(PositionVerlet),
+            Box::new(VelocityVerlet::new()),
+            Box::new(Leapfrog)
+        ];
+        for mut integrator in integrators {
+            let mut state = moving_diatomic(&top);
+            let start = state.positions[0].clone();
+            let mut hooks = Hooks { constraints: Some(Box::new(Pin)), ..Hooks::new() };
+            for _ in 0..50 {
+                integrator.step(&mut state, &mut hooks, 0.001 * PS);
+            }
+            assert_eq!(state.positions[0], start);
+            assert_ne!(state.positions[1], PosVec::from(2.13, 2.0, 2.0));
+        }
+    }
+}
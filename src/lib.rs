@@ -3,24 +3,24 @@ extern crate dimensioned as dim;
 
 pub mod geom;
 pub mod units;
-
-mod potentials {
-    mod bonded {
-    }
-
-    mod nonbonded {
-    }
-}
-
-mod samplers {
+pub mod pairlist;
+mod special;
+mod fft;
+mod elements;
+
+pub mod potentials;
+pub mod readers;
+pub mod integrators;
+pub mod thermostats;
+pub mod barostats;
+
+pub mod samplers {
     mod mc {
         // Monte Carlo sampler
 
     }
 
-    mod ld {
-        // Langevin Dynamics sampler
-    }
+    pub mod ld;
 
     mod point_energy{
     }
@@ -32,34 +32,45 @@ pub mod state {
 
     use crate::geom::{
         PosVec,
-        VelocVec
+        VelocVec,
+        ForceVec,
+        EnergyTensor,
+        Tensor3D,
+        SimulationBox
     };
-    use crate::topology::Top;
+    use crate::topology::{Top, ForceKernel, DispersionCorrection};
+    use crate::integrators::{Integrator, Hooks, Barostat, PositionVerlet};
+    use crate::thermostats::Bussi;
+    use crate::pairlist::VerletList;
     use rand;
     use rand::Rng;
-    use itertools::Itertools;
-    use rayon::prelude::*;
     use chemfiles;
     use chemfiles::{Trajectory, Frame, Atom, UnitCell};
 
-    use crate::dim::Sqrt;
-
+    #[derive(Clone)]
     pub struct State<'a> {
         pub topology: &'a Top,
         pub positions: Vec<PosVec>,
         pub velocities: Vec<VelocVec>,
-        pairlist: Vec<(usize, usize)>,
-        // pairlist: Vec<(usize, Vec<usize>)>,
-        boxvecs: (PosVec, PosVec, PosVec),
-        trajout: String
+        pairlist: VerletList,
+        simbox: SimulationBox,
+        trajout: String,
+        step: usize,
+        /// The virial from the last `forces_and_virial`, until it's taken
+        virial: Option<EnergyTensor>
     }
 
     impl<'a> State<'a> {
+        /// Create a new state and open its trajectory file for writing.
+        ///
+        /// The pairlist is built with the topology's LJ cutoff and a
+        /// buffer of 0.1 nm, which can be changed with
+        /// `set_pairlist_buffer` or `estimate_pairlist_buffer`.
         pub fn new(
             topology:&Top,
             positions: Vec<PosVec>,
             velocities: Vec<VelocVec>,
-            boxvecs: (PosVec, PosVec, PosVec),
+            simbox: SimulationBox,
             filename: String
         ) -> State {
 
@@ -67,185 +78,361 @@ pub mod state {
                 Trajectory::open(&filename, 'w').unwrap();
             }
 
-            let mut state = State {
+            println!("Generating pairlist");
+
+            let mut pairlist = VerletList::new(&positions, &simbox, topology.lj_cutoff(), 0.1 * NM);
+            if topology.exclusions.iter().any(|excluded| !excluded.is_empty()) {
+                pairlist.set_exclusions(topology.exclusions.clone(), &positions, &simbox);
+            }
+            if topology.force_kernel == ForceKernel::FullList {
+                pairlist.set_full(true);
+            }
+            println!("New pairlist has {} entries", pairlist.list().len());
+
+            State {
                 topology,
                 positions,
                 velocities,
-                boxvecs,
+                simbox,
                 trajout: filename,
-                pairlist: vec![]
-            };
-
-            println!("Generating pairlist without cutoff");
-
-            state.gen_pairs(0.0 * NM);
+                pairlist,
+                step: 0,
+                virial: None
+            }
+        }
 
-            state
+        /// The periodic simulation box
+        pub fn simbox(&self) -> &SimulationBox {
+            &self.simbox
         }
 
         /// Get the minimum image convention distance between two vectors,
         /// and the corresponding distance vector
         pub fn dist2(&self, first:&PosVec, second:&PosVec) -> (PosVec, Nanometer2<f32>) {
-            let (vec_a, vec_b, vec_c) = self.boxvecs.clone();
-            let mut r2s: Vec<(PosVec, Nanometer2<f32>)> = vec![];
-
-            for a in [-1.0, 0.0, 1.0].iter() {
-                for b in [-1.0, 0.0, 1.0].iter() {
-                    for c in [-1.0, 0.0, 1.0].iter() {
-                        let offset = vec_a.clone() * a.clone()
-                            + vec_b.clone() * b.clone()
-                            + vec_c.clone() * c.clone();
-                        let diff = first - second + offset;
-                        let diff2 = diff.clone().norm2();
-                        r2s.push((diff, diff2));
+            let diff = self.simbox.min_image(first - second);
+            let diff2 = diff.norm2();
+            (diff, diff2)
+        }
+
+        /// Regenerate the verlet pairlist from the current positions
+        pub fn gen_pairs(&mut self) {
+            self.pairlist.rebuild(&self.positions, &self.simbox);
+            println!("New pairlist has {} entries", self.pairlist.list().len());
+        }
+
+        /// Regenerate the verlet pairlist if any atom could have moved
+        /// more than half the buffer since it was last generated.
+        /// Returns whether the pairlist was regenerated.
+        pub fn update_pairlist(&mut self) -> bool {
+            self.pairlist.update(&self.positions, &self.simbox)
+        }
+
+        /// The number of molecular dynamics steps run so far
+        pub fn step(&self) -> usize {
+            self.step
+        }
+
+        /// The forces on each atom at the current positions, regenerating
+        /// the pairlist first if it's out of date
+        pub fn forces(&mut self) -> Vec<ForceVec> {
+            if self.update_pairlist() {
+                println!("Regenerated pairlist at step {}", self.step);
+            }
+            self.topology.calc_forces(
+                &self.positions,
+                self.pairlist.list(),
+                &self.simbox
+            )
+        }
+
+        /// The total kinetic energy of the atoms
+        pub fn kinetic_energy(&self) -> KilojoulePerMole<f32> {
+            self.velocities.iter()
+                .zip(&self.topology.atoms)
+                .fold(
+                    0.0 * KJPM,
+                    |acc, (v, atom)| acc + 0.5 * atom.mass * v.norm2()
+                )
+        }
+
+        /// The forces on each atom and the virial tensor at the current
+        /// positions, calculated together, regenerating the pairlist
+        /// first if it's out of date
+        ///
+        /// The virial is also kept for `take_virial`.
+        pub fn forces_and_virial(&mut self) -> (Vec<ForceVec>, EnergyTensor) {
+            if self.update_pairlist() {
+                println!("Regenerated pairlist at step {}", self.step);
+            }
+            let (forces, virial) = self.topology.calc_forces_and_virial(
+                &self.positions,
+                self.pairlist.list(),
+                &self.simbox
+            );
+            self.virial = Some(virial.clone());
+            (forces, virial)
+        }
+
+        /// The virial kept by the last `forces_and_virial`, unless it has
+        /// been taken already
+        ///
+        /// Integrators calculate the virial with the forces of each step
+        /// when their barostat uses it, so that the barostat can take it
+        /// from here instead of going over the potentials again.
+        pub fn take_virial(&mut self) -> Option<EnergyTensor> {
+            self.virial.take()
+        }
+
+        /// The virial tensor of the current positions
+        pub fn virial(&self) -> EnergyTensor {
+            self.topology.calc_virial(
+                &self.positions,
+                self.pairlist.list(),
+                &self.simbox
+            )
+        }
+
+        /// The kinetic energy tensor, half the sum of the masses times the
+        /// outer products of the velocities, whose trace is the kinetic
+        /// energy
+        pub fn kinetic_energy_tensor(&self) -> EnergyTensor {
+            self.velocities.iter()
+                .zip(&self.topology.atoms)
+                .fold(
+                    EnergyTensor::zero(),
+                    |acc, (v, atom)| acc + Tensor3D::outer(v, v) * (0.5 * atom.mass)
+                )
+        }
+
+        /// The pressure tensor `2/V (K - Ξ)` from the kinetic energy and
+        /// virial tensors
+        pub fn pressure_tensor(&self) -> Tensor3D<KilojoulePerMolePerNanometer3<f32>> {
+            self.pressure_tensor_with(&self.virial())
+        }
+
+        /// The pressure tensor from a `virial` already calculated for the
+        /// current positions, such as by `forces_and_virial`
+        pub fn pressure_tensor_with(&self, virial: &EnergyTensor) -> Tensor3D<KilojoulePerMolePerNanometer3<f32>> {
+            (self.kinetic_energy_tensor() - virial.clone()) * 2.0 / self.simbox.volume()
+        }
+
+        /// The scalar pressure, a third of the trace of the pressure
+        /// tensor
+        pub fn pressure(&self) -> KilojoulePerMolePerNanometer3<f32> {
+            self.pressure_tensor().trace() / 3.0
+        }
+
+        /// The scalar pressure in bar
+        pub fn pressure_bar(&self) -> f32 {
+            (self.pressure() / BAR).value_unsafe
+        }
+
+        /// Scale the box and the positions by `factors` along x, y and z,
+        /// as in `SimulationBox::scale`, and regenerate the pairlist if
+        /// that leaves it out of date
+        ///
+        /// With `by_molecule`, the centre of mass of each molecule in
+        /// `Top::molecules` is scaled and the molecule moved with it
+        /// without changing its shape. Atoms outside the molecules are
+        /// always scaled individually.
+        pub fn scale(&mut self, factors: (f32, f32, f32), by_molecule: bool) {
+            let scale = |pos: &PosVec| PosVec::new(pos.x * factors.0, pos.y * factors.1, pos.z * factors.2);
+            let topology = self.topology;
+
+            let mut first_unscaled = 0;
+            if by_molecule {
+                for (index, block) in topology.molecules().iter().enumerate() {
+                    for copy in 0..block.count {
+                        let atoms = topology.molecule_atoms(index, copy);
+                        let total_mass = atoms.clone()
+                            .fold(0.0 * DA, |acc, i| acc + topology.atoms[i].mass);
+
+                        // Molecules may be split over the boundaries, so
+                        // find the centre from the nearest images
+                        let first = self.positions[atoms.start].clone();
+                        let mut centre = first.clone();
+                        for i in atoms.clone() {
+                            let weight = (topology.atoms[i].mass / total_mass).value_unsafe;
+                            centre += self.simbox.min_image(&self.positions[i] - &first) * weight;
+                        }
+
+                        // Move the atoms with the centre, keeping them
+                        // whole until they're wrapped into the new box
+                        let shift = scale(&centre) - centre;
+                        for i in atoms.clone() {
+                            let nearest = first.clone() + self.simbox.min_image(&self.positions[i] - &first);
+                            self.positions[i] = nearest + shift.clone();
+                        }
+                        first_unscaled = atoms.end;
                     }
                 }
             }
-            r2s.into_iter()
-                .min_by(|(_, a), (_, b)| a.partial_cmp(b).expect("Tried to compare a NaN"))
-                .unwrap()
+            for pos in self.positions[first_unscaled..].iter_mut() {
+                *pos = scale(pos);
+            }
+
+            self.simbox = self.simbox.scale(factors);
+            self.wrap_positions();
+            self.pairlist.scale(factors);
+            self.update_pairlist();
         }
 
-        /// Generate a verlet pairlist
-        pub fn gen_pairs(&mut self, cutoff:Nanometer<f32>) {
-            let cutoff2 = cutoff * cutoff;
+        /// The temperature from the equipartition of the kinetic energy
+        /// over three degrees of freedom per atom
+        pub fn temperature(&self) -> Kelvin<f32> {
+            self.kinetic_energy() / (1.5 * self.velocities.len() as f32 * KB)
+        }
 
-            let pair_vec:Vec<((_, _),(_, _))> = self.positions.iter()
-                .enumerate()
-                .tuple_combinations()
-                .collect();
-            self.pairlist = pair_vec.par_iter()
-                .filter(|((_, ri), (_, rj))| cutoff == 0.0 * NM || self.dist2(&ri, &rj).1 <= cutoff2)
-                .map(|((i, _), (j, _))| (i.clone(), j.clone()))
-                .collect();
-            println!("New pairlist has {} entries", self.pairlist.len());
-        }
-
-        // /// Generate a verlet pairlist
-        // pub fn gen_pairs(&mut self, cutoff:Nanometer<f32>) {
-        //     let cutoff2 = cutoff * cutoff;
-
-        //     let pair_vec:Vec<((_, _),(_, _))> = self.positions.iter()
-        //         .enumerate()
-        //         .tuple_combinations()
-        //         .collect();
-        //     self.pairlist = pair_vec.par_iter()
-        //         .filter(|((_, ri), (_, rj))| cutoff == 0.0 * NM || self.dist2(&ri, &rj).1 <= cutoff2)
-        //         .map(|((i, _), (j, _))| (i.clone(), vec![j.clone()]))
-        //         .coalesce(|(p, ps), (c, cs)| {
-        //             if p == c {
-        //                 Ok((p, ps.extend(cs)))
-        //             } else {
-        //                 Err(((p, ps), (c, cs)))
-        //             }
-        //         }).collect();
-        //     println!("New pairlist has {} entries", self.pairlist.len());
-        // }
+        /// Wrap every position back into the box
+        pub fn wrap_positions(&mut self) {
+            let simbox = &self.simbox;
+            for pos in self.positions.iter_mut() {
+                *pos = simbox.wrap(pos.clone());
+            }
+        }
+
+        /// The buffer beyond the LJ cutoff used for the pairlist
+        pub fn pairlist_buffer(&self) -> Nanometer<f32> {
+            self.pairlist.buffer()
+        }
+
+        /// Change the pairlist buffer and regenerate the pairlist
+        pub fn set_pairlist_buffer(&mut self, buffer: Nanometer<f32>) {
+            println!("Generating pairlist with buffer {} nm", buffer.value_unsafe);
+            self.pairlist.set_buffer(buffer, &self.positions, &self.simbox);
+            println!("New pairlist has {} entries", self.pairlist.list().len());
+        }
+
+        /// Choose the pairlist buffer with `Top::estimate_verlet_buffer`
+        /// for this state's box, and regenerate the pairlist
+        pub fn estimate_pairlist_buffer(
+            &mut self,
+            temp: Kelvin<f32>,
+            lifetime: Picosecond<f32>,
+            tolerance: KilojoulePerMolePerPicosecond<f32>
+        ) -> Nanometer<f32> {
+            let buffer = self.topology.estimate_verlet_buffer(
+                self.simbox.volume(),
+                temp,
+                lifetime,
+                tolerance
+            );
+            self.set_pairlist_buffer(buffer);
+            buffer
+        }
 
         pub fn calc_energy(&self) -> KilojoulePerMole<f32> {
             self.topology.calc_energy(
                 &self.positions,
-                &self.pairlist,
-                |ri, rj| self.dist2(&ri, &rj)
+                self.pairlist.list(),
+                &self.simbox
             )
         }
 
-        pub fn write_traj(&self) -> chemfiles::Result<()> {
+        /// The part of `calc_energy` from the dispersion correction
+        pub fn dispersion_energy(&self) -> KilojoulePerMole<f32> {
+            self.topology.dispersion_energy(self.simbox.volume())
+        }
+
+        /// Print the dispersion correction, if there is one
+        fn print_dispersion(&self) {
+            if self.topology.dispersion_correction != DispersionCorrection::None {
+                print!("dispersion correction is {}, ", self.dispersion_energy());
+            }
+        }
+
+        /// Build a chemfiles frame of the current positions and box, with
+        /// the topology's atom names, elements, residues and bonds
+        ///
+        /// Chains aren't written, because chemfiles can't store them.
+        pub fn frame(&self) -> chemfiles::Result<Frame> {
             let mut frame = Frame::new()?;
 
-            for posvec in self.positions.iter() {
+            for (posvec, atom) in self.positions.iter().zip(self.topology.atoms.iter()) {
                 let (x, y, z) = (
                     posvec.x.value_unsafe as f64 * 10.0,
                     posvec.y.value_unsafe as f64 * 10.0,
                     posvec.z.value_unsafe as f64 * 10.0
                 );
-                let atom = Atom::new("LJ")?;
-                frame.add_atom(&atom, [x, y, z], None)?;
+                let mut chfl_atom = Atom::new(atom.name.as_str())?;
+                if let Some(element) = atom.element.as_ref() {
+                    chfl_atom.set_atomic_type(element.as_str())?;
+                }
+                chfl_atom.set_mass(atom.mass.value_unsafe as f64)?;
+                chfl_atom.set_charge(atom.charge.value_unsafe as f64)?;
+                frame.add_atom(&chfl_atom, [x, y, z], None)?;
             }
 
-            // TODO: Stop assuming cubic box
-            let box_l = self.boxvecs.0.x.value_unsafe;
-
-            let unit_cell = UnitCell::new([box_l as f64 * 10.0; 3])?;
-            frame.set_cell(&unit_cell)?;
-
-            let mut trajout = Trajectory::open(&self.trajout, 'a')?;
-            trajout.write(&frame)?;
-            Ok(())
-        }
-
-        /// Thermalize with the Bussi thermostat
-        fn thermalize(&mut self, target_temp:Kelvin<f32>, tau_t:Picosecond<f32>, delta_t:Picosecond<f32>) {
-            let kin_energy = self.velocities.iter()
-                .zip(&self.topology.atoms)
-                .fold(
-                    0.0 * KJPM,
-                    |acc, (v, atom)| acc + v.clone() * v.clone() * atom.mass/2.0
-                );
-            let n_dof = self.velocities.len() * 3 - 1;
-
-            let factor;
-            if tau_t == 0.0 * PS {
-                factor = 0.0;
-            } else {
-                factor = (-delta_t/tau_t).exp();
+            for residue in self.topology.residues() {
+                let mut chfl_residue = if residue.id >= 0 {
+                    chemfiles::Residue::with_id(residue.name.as_str(), residue.id as u64)?
+                } else {
+                    chemfiles::Residue::new(residue.name.as_str())?
+                };
+                for i in residue.atoms.clone() {
+                    chfl_residue.add_atom(i as u64)?;
+                }
+                frame.add_residue(&chfl_residue)?;
             }
 
-            if factor.is_infinite() {
-                panic!("tau_t is too small!");
+            for bond in self.topology.bonds.iter() {
+                frame.add_bond(bond.atoms[0], bond.atoms[1])?;
             }
 
-            let kkn: Unitless<f32> = target_temp * KB * self.velocities.len() as f32 / (n_dof as f32 * kin_energy);
-
-            let mut rng = rand::thread_rng();
-            let gaussian = rand::distributions::StandardNormal;
+            let (len_a, len_b, len_c) = self.simbox.lengths();
+            let lengths = [
+                len_a.value_unsafe as f64 * 10.0,
+                len_b.value_unsafe as f64 * 10.0,
+                len_c.value_unsafe as f64 * 10.0
+            ];
+            let unit_cell = if self.simbox.is_rectangular() {
+                UnitCell::new(lengths)?
+            } else {
+                let (alpha, beta, gamma) = self.simbox.angles();
+                UnitCell::triclinic(lengths, [alpha as f64, beta as f64, gamma as f64])?
+            };
+            frame.set_cell(&unit_cell)?;
 
-            let r1 = rng.sample(gaussian) as f32;
+            Ok(frame)
+        }
 
-            let sum_noises:f32 = rng
-                .sample_iter(&gaussian)
-                .take(n_dof - 1)
-                .map(|r| r as f32 * r as f32)
-                .sum();
+        pub fn write_traj(&self) -> chemfiles::Result<()> {
+            let frame = self.frame()?;
 
-            let alpha2 = 1.0 + (1.0 - factor)*(kkn * (sum_noises + r1*r1) - 1.0) + 2.0 * r1 * (kkn * (1.0 - factor) * factor).sqrt();
+            let mut trajout = Trajectory::open(&self.trajout, 'a')?;
+            trajout.write(&frame)?;
+            Ok(())
+        }
 
-            let alpha:Unitless<f32> = alpha2.sqrt();
+        /// Run `nsteps` steps of Monte Carlo sampling at constant volume,
+        /// moving every atom at once
+        pub fn sample(self, nsteps: usize, temp: Kelvin<f32>) -> Self {
+            self.sample_with(nsteps, temp, None)
+        }
 
-            self.velocities.iter_mut().foreach(|v| *v *= alpha);
+        /// Run `nsteps` steps of Monte Carlo sampling at constant
+        /// pressure, giving `barostat` the chance to change the box after
+        /// every step
+        pub fn sample_npt(self, nsteps: usize, temp: Kelvin<f32>, barostat: &mut dyn Barostat) -> Self {
+            self.sample_with(nsteps, temp, Some(barostat))
         }
 
-        pub fn sample(mut self, nsteps: usize, temp: Kelvin<f32>) -> Self {
+        fn sample_with(mut self, nsteps: usize, temp: Kelvin<f32>, mut barostat: Option<&mut dyn Barostat>) -> Self {
             let mut rng = rand::thread_rng();
 
             let move_std_dev = 0.001f32;
             let distrib = rand::distributions::Normal::new(0.0, move_std_dev as f64);
 
-            let steps_between_pairlist_updates = 1000;
-
-            let pairlist_cutoff = self.topology.lj_cutoff + (steps_between_pairlist_updates/5) as f32 *  move_std_dev * NM;
-
-            println!("Generating pairlist with cutoff {}", pairlist_cutoff);
-            self.gen_pairs(pairlist_cutoff);
             println!("Calculating initial energy");
             let mut prev_energy = self.calc_energy();
-            let mut new_energy;
-            let mut accepts_since_pairlist_regen = 0;
 
             for n in 0..nsteps {
-                if accepts_since_pairlist_regen % steps_between_pairlist_updates == 0 && accepts_since_pairlist_regen != 0 {
-                    println!("Regenerating pairlist at step {}", n);
-                    self.gen_pairs(pairlist_cutoff);
-                    new_energy = self.calc_energy();
-                    if new_energy != prev_energy {
-                        println!("New pairlist changed energies: {:?}, {:?}", prev_energy, new_energy);
-                    }
-                    prev_energy = new_energy;
-                    accepts_since_pairlist_regen = 0;
-                }
-
                 if n % 5000 == 0 {
                     print!("Step {}, energy is {}, ", n, prev_energy);
+                    self.print_dispersion();
+                    if barostat.is_some() {
+                        print!("volume is {}, ", self.simbox.volume());
+                    }
                     match self.write_traj() {
                         Ok(()) => println!("frame written to file {}", &self.trajout),
                         Err(e) => println!("frame could not be written to file: {}", e)
@@ -261,10 +448,16 @@ pub mod state {
                     );
                 }
 
+                // A pairlist valid for the attempted positions is
+                // also valid for the current ones
+                if self.pairlist.update(&attempt_pos, &self.simbox) {
+                    println!("Regenerated pairlist at step {}", n);
+                }
+
                 let attempt_energy = self.topology.calc_energy(
                     &attempt_pos,
-                    &self.pairlist,
-                    |ri, rj| self.dist2(ri, rj)
+                    self.pairlist.list(),
+                    &self.simbox
                 );
                 let energy_diff = prev_energy - attempt_energy;
                 let accept_prob = (energy_diff / (KB * temp)).exp();
@@ -273,64 +466,48 @@ pub mod state {
                     // println!("Accepted move with delta {}, P {:.1}.", -energy_diff, accept_prob);
                     self.positions = attempt_pos;
                     prev_energy = self.calc_energy();
-                    accepts_since_pairlist_regen += 1;
                 } else {
                     // println!("Rejected move with delta {}, P {:.1}.", -energy_diff, accept_prob);
                 }
+
+                if let Some(barostat) = barostat.as_mut() {
+                    let volume = self.simbox.volume();
+                    barostat.apply(&mut self, 0.0 * PS);
+                    if self.simbox.volume() != volume {
+                        prev_energy = self.calc_energy();
+                    }
+                }
             }
             self
         }
 
-        pub fn simulate(mut self, nsteps: usize, timestep: Picosecond<f32>) -> Self {
-            // let mut rng = rand::thread_rng();
-
-            let steps_between_pairlist_updates = 10;
-
-            let pairlist_cutoff = self.topology.lj_cutoff + 1.0 * NM;
-
-            let buffer_tolerance = 0.005 * KJPM;
-
-            println!("Generating pairlist with cutoff {}", pairlist_cutoff);
-            self.gen_pairs(pairlist_cutoff);
-            println!("Calculating initial energy");
-
-            let dt = timestep;
+        /// Run `nsteps` steps of molecular dynamics with `integrator`, with
+        /// the thermostat, barostat and constraints in `hooks`
+        ///
+        /// The energies and temperature are printed, and a frame written
+        /// to the trajectory, every 10 steps. The conserved energy is the
+        /// total energy plus the thermostat's contribution, and should
+        /// only drift as fast as the integrator's error allows.
+        pub fn run<I: Integrator + ?Sized>(
+            mut self,
+            integrator: &mut I,
+            hooks: &mut Hooks,
+            nsteps: usize,
+            timestep: Picosecond<f32>
+        ) -> Self {
             let n_atoms = self.velocities.len();
 
             assert_eq!(n_atoms, self.positions.len());
             assert_eq!(n_atoms, self.topology.atoms.len());
 
-            for n in 0..nsteps {
-                if n % steps_between_pairlist_updates == 0 && n != 0 {
-                    println!("Regenerating pairlist at step {}", n);
-                    let prev_energy = self.calc_energy();
-                    self.gen_pairs(pairlist_cutoff);
-                    let new_energy = self.calc_energy();
-                    if new_energy != prev_energy {
-                        println!("New pairlist changed energies: {:?}, {:?}", prev_energy, new_energy);
-                    }
-                    if (new_energy - prev_energy).value_unsafe.abs() > buffer_tolerance.value_unsafe {
-                        panic!("Energy difference greater than buffer tolerance!");
-                    }
-                }
+            for _ in 0..nsteps {
+                if self.step.is_multiple_of(10) {
+                    let potential = self.calc_energy();
+                    let conserved = potential + self.kinetic_energy() + hooks.thermostat_energy();
 
-                if n % 10 == 0 {
-                    let temp:Kelvin<f32> = self.velocities.iter()
-                        .zip(&self.topology.atoms)
-                        .map(|(v, atom)| {
-                            let mass = atom.mass;
-                            0.5 * mass * v.norm2()
-                        }).fold(
-                            0.0 * KJPM,
-                            |acc, k| acc + k
-                        ) / n_atoms as f32 / KB;
-
-                    print!(
-                        "Step {}, potential energy is {}, temperature is {}, ",
-                        n,
-                        self.calc_energy(),
-                        temp
-                    );
+                    print!("Step {}, potential energy is {}, ", self.step, potential);
+                    self.print_dispersion();
+                    print!("temperature is {}, conserved energy is {}, ", self.temperature(), conserved);
 
                     match self.write_traj() {
                         Ok(()) => println!("frame written to file {}", &self.trajout),
@@ -338,53 +515,40 @@ pub mod state {
                     }
                 }
 
-                self.positions = self.velocities.iter()
-                    .zip(self.positions)
-                    .map(|(v, r)| {
-                        r + v.clone() * dt/2.0
-                    }).collect();
-
-                let forces = self.topology.calc_forces(
-                    &self.positions,
-                    &self.pairlist,
-                    |ri, rj| self.dist2(ri, rj)
-                );
-
-                self.velocities = forces.into_iter()
-                    .zip(&self.velocities)
-                    .zip(&self.topology.atoms)
-                    .map(|((f, v), atom)| {
-                        let mass = atom.mass;
-                        v.clone() + f * dt / mass
-                    }).collect();
-
-                self.positions = self.velocities.iter()
-                    .zip(self.positions)
-                    .map(|(v, r)| {
-                        r + v.clone() * dt/2.0
-                    }).collect();
-
-                self.thermalize(300.0 * K, 5.0 * PS, dt);
-
+                integrator.step(&mut self, hooks, timestep);
+                self.step += 1;
+            }
+            self
+        }
 
-                // TODO: Stop assuming rectangular box
-                let box_x = self.boxvecs.0.x;
-                let box_y = self.boxvecs.1.y;
-                let box_z = self.boxvecs.2.z;
+        /// Run `nsteps` steps of molecular dynamics with `PositionVerlet`
+        /// and a Bussi thermostat at `temp` with a time constant of 5 ps
+        pub fn simulate(self, nsteps: usize, timestep: Picosecond<f32>, temp: Kelvin<f32>) -> Self {
+            let mut hooks = Hooks {
+                thermostat: Some(Box::new(Bussi::new(temp, 5.0 * PS))),
+                ..Hooks::new()
+            };
+            self.run(&mut PositionVerlet, &mut hooks, nsteps, timestep)
+        }
+    }
 
-                self.positions = self.positions.into_iter()
-                    .map(|mut pos| {
-                        while pos.x < 0.0 * NM {pos.x += box_x};
-                        while pos.y < 0.0 * NM {pos.y += box_y};
-                        while pos.z < 0.0 * NM {pos.z += box_z};
-                        pos.x %= box_x;
-                        pos.y %= box_y;
-                        pos.z %= box_z;
-                        pos
-                    }).collect();
+    #[cfg(test)]
+    pub(crate) mod tests {
+        use super::*;
+        use std::sync::atomic::{AtomicUsize, Ordering};
 
-            }
-            self
+        /// A state whose trajectory goes to a file of its own, so that
+        /// tests running in parallel don't write to the same one
+        pub fn state(
+            topology: &Top,
+            positions: Vec<PosVec>,
+            velocities: Vec<VelocVec>,
+            simbox: SimulationBox
+        ) -> State<'_> {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            let name = format!("noether_{}_{}.pdb", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed));
+            let trajout = std::env::temp_dir().join(name);
+            State::new(topology, positions, velocities, simbox, trajout.to_string_lossy().into_owned())
         }
     }
 }
@@ -393,109 +557,1108 @@ pub mod topology {
     use crate::units::*;
     use crate::units::f32consts::*;
     use crate::geom::{
+        Vec3D,
         PosVec,
-        ForceVec
+        ForceVec,
+        EnergyTensor,
+        Tensor3D,
+        SimulationBox
+    };
+    use crate::pairlist::NeighborList;
+    use crate::potentials::{
+        Potential,
+        Configuration
     };
-    use rayon::prelude::*;
+    use crate::potentials::nonbonded::{
+        self,
+        LennardJones,
+        LjTable
+    };
+    use crate::potentials::tabulated::PairTable;
+    use crate::potentials::bonded::{
+        Bond,
+        Angle,
+        Dihedral,
+        Improper,
+        Pair,
+        HarmonicBonds,
+        HarmonicAngles,
+        PeriodicDihedrals,
+        HarmonicImpropers,
+        OneFourPairs
+    };
+    use crate::special;
     use std;
+    use std::{fmt, error};
+    use std::ops::Range;
+    use std::collections::HashSet;
     use crate::dim::Sqrt;
+    use itertools::iproduct;
 
     #[derive(Debug)]
     pub struct Top {
+        /// The parameters of each type of atom, which atoms refer to by
+        /// index. Private so that the pair tables built from it can't go
+        /// out of date.
+        atom_types: TypeLibrary,
         pub atoms: Vec<Atom>,
-        pub lj_cutoff: Nanometer<f32>
+        /// Private, like `lj_modifier`, so that a switching modifier's
+        /// `rswitch` always stays below it
+        lj_cutoff: Nanometer<f32>,
+        /// How the Lennard-Jones potential is brought to zero at
+        /// `lj_cutoff`, set with `set_lj_modifier`
+        lj_modifier: LjModifier,
+        /// Whether to correct for the dispersion missing beyond `lj_cutoff`
+        pub dispersion_correction: DispersionCorrection,
+        pub force_kernel: ForceKernel,
+        pub potentials: Vec<Box<dyn Potential>>,
+        pub bonds: Vec<Bond>,
+        pub angles: Vec<Angle>,
+        pub dihedrals: Vec<Dihedral>,
+        pub impropers: Vec<Improper>,
+        /// The sorted excluded partners of each atom, which are left out of
+        /// the non-bonded pairlist
+        pub exclusions: Vec<Vec<usize>>,
+        /// 1-4 pairs, whose non-bonded interactions are calculated
+        /// separately and scaled
+        pub pairs: Vec<Pair>,
+        /// Scale factor for Lennard-Jones interactions of 1-4 pairs
+        pub fudge_lj: f32,
+        /// Scale factor for Coulomb interactions of 1-4 pairs
+        pub fudge_qq: f32,
+        combination_rule: CombinationRule,
+        nbfix: Vec<NbFix>,
+        lj_table: LjTable,
+        pair_tables: Vec<([String; 2], PairTable)>,
+        /// The entry in `pair_tables` for each pair of atom types
+        pair_table_index: Vec<Option<usize>>,
+        residues: Vec<Residue>,
+        chains: Vec<Chain>,
+        molecules: Vec<MoleculeBlock>
+    }
+
+    /// Strategy for accumulating pair forces in `Top::calc_forces`
+    ///
+    /// Both parallel kernels give bitwise identical results from run to
+    /// run with the same number of threads. They differ from the serial
+    /// kernel and from each other only by floating point rounding.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum ForceKernel {
+        /// Loop over a half pairlist on one thread
+        Serial,
+        /// Split a half pairlist into one interleaved share of the atoms
+        /// per thread, each accumulating into its own force buffer, and
+        /// then sum the buffers in a fixed order
+        ThreadBuffers,
+        /// Compute the force on each atom independently from a full
+        /// pairlist. Does each pair twice, but needs no extra memory
+        /// and gives the same result for any number of threads
+        FullList
+    }
+
+    /// How Lennard-Jones parameters are combined for pairs of different
+    /// atoms
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum CombinationRule {
+        /// Arithmetic mean `sigma` and geometric mean `epsilon`, as in
+        /// AMBER and CHARMM
+        LorentzBerthelot,
+        /// Geometric mean `sigma` and `epsilon`, as in OPLS
+        Geometric,
+        /// Arithmetic mean `sigma` and `epsilon`
+        Arithmetic,
+        /// The sixth power mean of `sigma`, with `epsilon` chosen to
+        /// conserve the geometric mean `C6`
+        WaldmanHagler
+    }
+
+    impl CombinationRule {
+        /// Combine the `(epsilon, sigma)` of two atoms
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use noether::topology::CombinationRule;
+        /// use noether::units::f32consts::*;
+        ///
+        /// let a = (0.4 * KJPM, 0.3 * NM);
+        /// let b = (0.9 * KJPM, 0.3 * NM);
+        ///
+        /// let (eps, sig) = CombinationRule::Geometric.combine(a, b);
+        /// assert!((eps - 0.6 * KJPM).value_unsafe.abs() < 1e-6);
+        /// assert!((sig - 0.3 * NM).value_unsafe.abs() < 1e-6);
+        ///
+        /// let (eps, _) = CombinationRule::Arithmetic.combine(a, b);
+        /// assert!((eps - 0.65 * KJPM).value_unsafe.abs() < 1e-6);
+        ///
+        /// // Waldman-Hagler reduces to the geometric mean for equal sigmas
+        /// let (eps, sig) = CombinationRule::WaldmanHagler.combine(a, b);
+        /// assert!((eps - 0.6 * KJPM).value_unsafe.abs() < 1e-6);
+        /// assert!((sig - 0.3 * NM).value_unsafe.abs() < 1e-6);
+        /// ```
+        pub fn combine(
+            self,
+            (eps_i, sig_i): (KilojoulePerMole<f32>, Nanometer<f32>),
+            (eps_j, sig_j): (KilojoulePerMole<f32>, Nanometer<f32>)
+        ) -> (KilojoulePerMole<f32>, Nanometer<f32>) {
+            let geometric_eps = (eps_i * eps_j).sqrt();
+            match self {
+                CombinationRule::LorentzBerthelot => (geometric_eps, (sig_i + sig_j) / 2.0),
+                CombinationRule::Geometric => (geometric_eps, (sig_i * sig_j).sqrt()),
+                CombinationRule::Arithmetic => ((eps_i + eps_j) / 2.0, (sig_i + sig_j) / 2.0),
+                CombinationRule::WaldmanHagler => {
+                    let sig3_i = sig_i.value_unsafe.powi(3);
+                    let sig3_j = sig_j.value_unsafe.powi(3);
+                    let sig6_sum = sig3_i * sig3_i + sig3_j * sig3_j;
+                    if sig6_sum == 0.0 {
+                        return (geometric_eps, 0.0 * NM);
+                    }
+                    let eps = geometric_eps * (2.0 * sig3_i * sig3_j / sig6_sum);
+                    let sig = (sig6_sum / 2.0).powf(1.0 / 6.0) * NM;
+                    (eps, sig)
+                }
+            }
+        }
+    }
+
+    /// Modifications to the Lennard-Jones potential near the cutoff, as in
+    /// GROMACS' `vdw-modifier`
+    ///
+    /// The switching modifiers act between `rswitch` and the cutoff, and
+    /// `Top::set_lj_modifier` and `Top::set_lj_cutoff` check that
+    /// `rswitch` is less than the cutoff.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum LjModifier {
+        /// Plain truncation at the cutoff
+        None,
+        /// Shift the potential so that it is zero at the cutoff, leaving
+        /// the forces unchanged
+        PotentialShift,
+        /// Smoothly switch the force to zero from `rswitch` to the cutoff,
+        /// and shift the potential to match
+        ForceSwitch { rswitch: Nanometer<f32> },
+        /// Multiply the potential by a fifth order polynomial that takes it
+        /// smoothly to zero from `rswitch` to the cutoff
+        PotentialSwitch { rswitch: Nanometer<f32> }
+    }
+
+    /// A switching `LjModifier` whose `rswitch` isn't less than the
+    /// cutoff
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct LjModifierError {
+        pub rswitch: Nanometer<f32>,
+        pub cutoff: Nanometer<f32>
+    }
+
+    impl fmt::Display for LjModifierError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                f, "rswitch {} nm must be less than the cutoff {} nm",
+                self.rswitch.value_unsafe, self.cutoff.value_unsafe
+            )
+        }
+    }
+
+    impl error::Error for LjModifierError {}
+
+    /// Check that a switching `modifier` starts before `cutoff`
+    fn check_switch(modifier: LjModifier, cutoff: Nanometer<f32>) -> Result<(), LjModifierError> {
+        match modifier {
+            LjModifier::ForceSwitch { rswitch } | LjModifier::PotentialSwitch { rswitch }
+                if rswitch >= cutoff => Err(LjModifierError { rswitch, cutoff }),
+            _ => Ok(())
+        }
+    }
+
+    /// Analytical corrections for the dispersion energy missing beyond the
+    /// cutoff, as in GROMACS' `DispCorr`
+    ///
+    /// The corrections assume the system is homogeneous and isotropic
+    /// beyond the cutoff, and are computed from the average `C6` over all
+    /// distinct pairs of atoms that aren't excluded.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum DispersionCorrection {
+        None,
+        /// Correct the energy only
+        Energy,
+        /// Correct both the energy and the pressure
+        EnergyPressure
+    }
+
+    /// Lennard-Jones parameters for a pair of atom types that replace the
+    /// combination rule, like CHARMM's NBFIX or GROMACS' `nonbond_params`
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct NbFix {
+        pub types: [String; 2],
+        pub epsilon: KilojoulePerMole<f32>,
+        pub sigma: Nanometer<f32>
     }
 
     impl Top {
+        /// A topology of `atoms`, whose types are in `atom_types`, with no
+        /// bonded interactions or exclusions, and whose potential energy
+        /// function has Lennard-Jones and all of the bonded terms
+        pub fn new(atom_types: TypeLibrary, atoms: Vec<Atom>) -> Top {
+            let n_atoms = atoms.len();
+            let n_types = atom_types.len();
+            let combination_rule = CombinationRule::LorentzBerthelot;
+            let top = Top {
+                lj_table: LjTable::new(&atom_types, combination_rule, &[]),
+                atom_types,
+                atoms,
+                lj_cutoff: 1.0 * NM,
+                lj_modifier: LjModifier::None,
+                dispersion_correction: DispersionCorrection::None,
+                force_kernel: ForceKernel::ThreadBuffers,
+                potentials: vec![
+                    Box::new(LennardJones),
+                    Box::new(HarmonicBonds),
+                    Box::new(HarmonicAngles),
+                    Box::new(PeriodicDihedrals),
+                    Box::new(HarmonicImpropers),
+                    Box::new(OneFourPairs)
+                ],
+                bonds: vec![],
+                angles: vec![],
+                dihedrals: vec![],
+                impropers: vec![],
+                exclusions: vec![vec![]; n_atoms],
+                pairs: vec![],
+                fudge_lj: 1.0,
+                fudge_qq: 1.0,
+                combination_rule,
+                nbfix: vec![],
+                pair_tables: vec![],
+                pair_table_index: vec![None; n_types * n_types],
+                residues: vec![],
+                chains: vec![],
+                molecules: vec![]
+            };
+            top.check_atom_types();
+            top
+        }
+
+        /// A topology of `num` identical uncharged atoms of a single type
+        /// called `LJ`
         pub fn gen_lj_fluid(
             num:usize,
             mass: Dalton<f32>,
             epsilon: KilojoulePerMole<f32>,
             sigma: Nanometer<f32>
         ) -> Top {
-            let atom = Atom {
+            let mut atom_types = TypeLibrary::new();
+            let lj = atom_types.add(AtomType {
+                name: "LJ".to_string(),
                 mass,
+                charge: 0.0 * E,
                 epsilon,
-                sigma,
-                charge: 0.0 * E
+                sigma
+            });
+            let atoms = vec![atom_types.atom(lj); num];
+            Top::new(atom_types, atoms)
+        }
+
+        /// Panic if any atom's type isn't in the library
+        fn check_atom_types(&self) {
+            let n_types = self.atom_types.len();
+            if let Some((i, atom)) = self.atoms.iter().enumerate().find(|(_, atom)| atom.atom_type >= n_types) {
+                panic!("Atom {} has type {}, but there are only {} atom types!", i, atom.atom_type, n_types);
+            }
+        }
+
+        /// Add a term to the potential energy function, or return the
+        /// reason it can't be evaluated for this topology's atoms
+        pub fn add_potential<P: Potential + 'static>(&mut self, potential: P) -> Result<(), String> {
+            potential.check(self)?;
+            self.potentials.push(Box::new(potential));
+            Ok(())
+        }
+
+        /// How Lennard-Jones parameters are combined
+        pub fn combination_rule(&self) -> CombinationRule {
+            self.combination_rule
+        }
+
+        /// Change how Lennard-Jones parameters are combined
+        pub fn set_combination_rule(&mut self, rule: CombinationRule) {
+            self.combination_rule = rule;
+            self.update_lj_table();
+        }
+
+        /// The pair-specific Lennard-Jones parameters
+        pub fn nbfix(&self) -> &[NbFix] {
+            &self.nbfix
+        }
+
+        /// Use `epsilon` and `sigma` for pairs of atoms of types `a` and
+        /// `b` instead of the combination rule
+        pub fn add_nbfix(
+            &mut self,
+            a: &str,
+            b: &str,
+            epsilon: KilojoulePerMole<f32>,
+            sigma: Nanometer<f32>
+        ) {
+            self.nbfix.retain(|fix| {
+                let [ref x, ref y] = fix.types;
+                !((x == a && y == b) || (x == b && y == a))
+            });
+            self.nbfix.push(NbFix {
+                types: [a.to_string(), b.to_string()],
+                epsilon,
+                sigma
+            });
+            self.update_lj_table();
+        }
+
+        /// The distance beyond which atoms don't interact through the
+        /// Lennard-Jones potential
+        pub fn lj_cutoff(&self) -> Nanometer<f32> {
+            self.lj_cutoff
+        }
+
+        /// Change the Lennard-Jones cutoff. Returns an error, leaving the
+        /// cutoff unchanged, if it isn't beyond the `rswitch` of a
+        /// switching modifier.
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use noether::topology::{Top, LjModifier};
+        /// use noether::units::f32consts::*;
+        ///
+        /// let mut top = Top::gen_lj_fluid(10, 40.0 * DA, 1.0 * KJPM, 0.34 * NM);
+        /// top.set_lj_modifier(LjModifier::PotentialSwitch { rswitch: 0.8 * NM }).unwrap();
+        /// assert!(top.set_lj_cutoff(0.7 * NM).is_err());
+        /// assert!(top.set_lj_cutoff(1.2 * NM).is_ok());
+        /// assert_eq!(top.lj_cutoff(), 1.2 * NM);
+        /// ```
+        pub fn set_lj_cutoff(&mut self, cutoff: Nanometer<f32>) -> Result<(), LjModifierError> {
+            check_switch(self.lj_modifier, cutoff)?;
+            self.lj_cutoff = cutoff;
+            Ok(())
+        }
+
+        /// How the Lennard-Jones potential is brought to zero at
+        /// `lj_cutoff`
+        pub fn lj_modifier(&self) -> LjModifier {
+            self.lj_modifier
+        }
+
+        /// Change how the Lennard-Jones potential is brought to zero at
+        /// `lj_cutoff`. Returns an error, leaving the modifier unchanged,
+        /// if a switching modifier's `rswitch` isn't less than the
+        /// current `lj_cutoff`.
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use noether::topology::{Top, LjModifier};
+        /// use noether::units::f32consts::*;
+        ///
+        /// let mut top = Top::gen_lj_fluid(10, 40.0 * DA, 1.0 * KJPM, 0.34 * NM);
+        /// assert!(top.set_lj_modifier(LjModifier::ForceSwitch { rswitch: 0.8 * NM }).is_ok());
+        /// assert!(top.set_lj_modifier(LjModifier::PotentialSwitch { rswitch: 1.2 * NM }).is_err());
+        /// assert_eq!(top.lj_modifier(), LjModifier::ForceSwitch { rswitch: 0.8 * NM });
+        /// ```
+        pub fn set_lj_modifier(&mut self, modifier: LjModifier) -> Result<(), LjModifierError> {
+            check_switch(modifier, self.lj_cutoff)?;
+            self.lj_modifier = modifier;
+            Ok(())
+        }
+
+        /// The parameters of each type of atom, which atoms refer to by
+        /// index
+        pub fn atom_types(&self) -> &TypeLibrary {
+            &self.atom_types
+        }
+
+        /// Add `atom_type` to the library, or replace the type with the
+        /// same name, and rebuild the pair tables. Returns the index of
+        /// the type for atoms to refer to.
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use noether::topology::{Top, AtomType};
+        /// use noether::units::f32consts::*;
+        ///
+        /// let mut top = Top::gen_lj_fluid(2, 40.0 * DA, 1.0 * KJPM, 0.34 * NM);
+        /// let heavy = AtomType { name: "HEAVY".to_string(), mass: 80.0 * DA, ..top.atom_types().get(0).clone() };
+        /// top.atoms[1].atom_type = top.add_atom_type(heavy);
+        /// assert_eq!(top.atom_types().len(), 2);
+        /// assert_eq!(top.lj_table().c6_c12(0, 1), top.lj_table().c6_c12(0, 0));
+        /// ```
+        pub fn add_atom_type(&mut self, atom_type: AtomType) -> usize {
+            let index = match self.atom_types.index(&atom_type.name) {
+                Some(index) => {
+                    *self.atom_types.get_mut(index) = atom_type;
+                    index
+                },
+                None => self.atom_types.add(atom_type)
             };
-            let atoms = vec![atom.clone(); num];
-            Top {
-                atoms,
-                lj_cutoff: 1.0 * NM
-            }
-        }
-
-        pub fn calc_energy<F>(&self, positions: &Vec<PosVec>, pairlist: &Vec<(usize, usize)>, dist2: F) -> KilojoulePerMole<f32>
-            where
-                F: Fn(&PosVec, &PosVec) -> (PosVec, Nanometer2<f32>) + std::marker::Sync
-        {
-            let atoms = &self.atoms;
-            let lj_cutoff_squared = self.lj_cutoff * self.lj_cutoff;
-
-            pairlist
-                .par_iter()
-                .map(|(i, j)| {
-                    let (_, r2) = dist2(&positions[*i], &positions[*j]);
-                    (i, j, r2)
-                }).filter(|(_, _, r2)| r2 <= &lj_cutoff_squared)
-                .map(|(i, j, r2)| {
-                    // TODO: Allow other LJ combination rules than averaging
-                    let eps = (atoms[*i].epsilon + atoms[*j].epsilon) / 2.0;
-                    let sig = (atoms[*i].sigma + atoms[*j].sigma) / 2.0;
-
-                    let sig6 = sig.value_unsafe.powi(6);
-                    let r6 = r2.value_unsafe.powi(3);
-                    let sig12 = sig6 * sig6;
-                    let r12 = r6 * r6;
-
-                    4.0*ONE * eps * ((sig12 / r12) - (sig6 / r6))
-                }).reduce(
-                    || 0.0 * KJPM,
-                    |acc, e| acc + e
-                )
+            self.update_lj_table();
+            index
+        }
+
+        /// The Lennard-Jones parameters of every pair of atoms
+        pub fn lj_table(&self) -> &LjTable {
+            &self.lj_table
+        }
+
+        /// Rebuild the Lennard-Jones pair table and the index of tabulated
+        /// pairs from the atom types, and check that every atom's type is
+        /// in the library. The methods that change the types or how they
+        /// combine call this themselves.
+        pub fn update_lj_table(&mut self) {
+            self.check_atom_types();
+            self.lj_table = LjTable::new(&self.atom_types, self.combination_rule, &self.nbfix);
+
+            let names: Vec<&str> = self.atom_types.iter().map(|t| t.name.as_str()).collect();
+            self.pair_table_index = iproduct!(names.iter(), names.iter())
+                .map(|(a, b)| {
+                    self.pair_tables.iter().position(|([x, y], _)| {
+                        (x == *a && y == *b) || (x == *b && y == *a)
+                    })
+                }).collect();
+        }
+
+        /// The tabulated potentials used in place of Lennard-Jones, and
+        /// the pair of atom types each applies to
+        pub fn pair_tables(&self) -> &[([String; 2], PairTable)] {
+            &self.pair_tables
+        }
+
+        /// Use `table` instead of Lennard-Jones for pairs of atoms of
+        /// types `a` and `b`
+        ///
+        /// Tabulated pairs are still cut off at `lj_cutoff`, and aren't
+        /// affected by `lj_modifier`. Set the Lennard-Jones parameters of
+        /// atoms that only interact through tables to zero so they don't
+        /// contribute to the dispersion correction.
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use noether::topology::Top;
+        /// use noether::potentials::tabulated::PairTable;
+        /// use noether::units::f32consts::*;
+        ///
+        /// let mut top = Top::gen_lj_fluid(2, 40.0 * DA, 1.0 * KJPM, 0.34 * NM);
+        /// let table = PairTable::new(vec![0.2, 0.5, 1.0], vec![3.0, 1.0, 0.0], None);
+        /// top.add_pair_table("LJ", "LJ", table.clone());
+        /// assert_eq!(top.pair_table(0, 1), Some(&table));
+        /// ```
+        pub fn add_pair_table(&mut self, a: &str, b: &str, table: PairTable) {
+            self.pair_tables.retain(|([x, y], _)| {
+                !((x == a && y == b) || (x == b && y == a))
+            });
+            self.pair_tables.push(([a.to_string(), b.to_string()], table));
+            self.update_lj_table();
+        }
+
+        /// The tabulated potential between atoms `i` and `j`, if there is
+        /// one
+        pub fn pair_table(&self, i: usize, j: usize) -> Option<&PairTable> {
+            let n_types = self.lj_table.n_types();
+            let index = self.atoms[i].atom_type * n_types + self.atoms[j].atom_type;
+            self.pair_table_index[index].map(|t| &self.pair_tables[t].1)
+        }
+
+        /// The Lennard-Jones `C6` and `C12` between atoms `i` and `j`
+        pub fn c6_c12(&self, i: usize, j: usize) -> (KilojouleNanometer6PerMole<f32>, KilojouleNanometer12PerMole<f32>) {
+            self.lj_table.c6_c12(self.atoms[i].atom_type, self.atoms[j].atom_type)
+        }
+
+        /// The residues, in the order of their atoms
+        pub fn residues(&self) -> &[Residue] {
+            &self.residues
+        }
+
+        /// Add a residue of the atoms `atoms` and return its index
+        ///
+        /// Residues must be added in order, and panic if they overlap the
+        /// previous residue or go past the last atom. Atoms don't have to
+        /// be in a residue.
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use noether::topology::Top;
+        /// use noether::units::f32consts::*;
+        ///
+        /// let mut top = Top::gen_lj_fluid(6, 40.0 * DA, 1.0 * KJPM, 0.34 * NM);
+        /// let first = top.add_residue("ARG", 1, 0..3);
+        /// let second = top.add_residue("ARG", 2, 3..5);
+        /// top.add_chain("A", first..second + 1);
+        /// top.add_molecules("Argon trimer", 3, 2);
+        ///
+        /// assert_eq!(top.residue_of(4), Some(second));
+        /// assert_eq!(top.residue_of(5), None);
+        /// assert_eq!(top.chain_of(second), Some(0));
+        /// assert_eq!(top.molecule_of(4), Some((0, 1)));
+        /// assert_eq!(top.molecule_atoms(0, 1), 3..6);
+        /// ```
+        pub fn add_residue(&mut self, name: &str, id: i64, atoms: Range<usize>) -> usize {
+            let start = self.residues.last().map_or(0, |residue| residue.atoms.end);
+            if atoms.start < start || atoms.end > self.atoms.len() || atoms.is_empty() {
+                panic!("Residue {} {} has atoms {:?}, which must be after {} and before {}!", name, id, atoms, start, self.atoms.len());
+            }
+            self.residues.push(Residue { name: name.to_string(), id, atoms });
+            self.residues.len() - 1
+        }
 
+        /// The index of the residue atom `atom` is in, if it is in one
+        pub fn residue_of(&self, atom: usize) -> Option<usize> {
+            let index = self.residues.partition_point(|residue| residue.atoms.end <= atom);
+            self.residues.get(index)
+                .filter(|residue| residue.atoms.contains(&atom))
+                .map(|_| index)
         }
 
-        pub fn calc_forces<F>(&self, positions: &Vec<PosVec>, pairlist: &Vec<(usize, usize)>, dist2: F) -> Vec<ForceVec>
-            where
-                F: Fn(&PosVec, &PosVec) -> (PosVec, Nanometer2<f32>) + std::marker::Sync
-        {
-            let atoms = &self.atoms;
-            let lj_cutoff_squared = self.lj_cutoff * self.lj_cutoff;
-            let mut forces:Vec<ForceVec> = vec![ForceVec::zero(); atoms.len()];
+        /// The chains, in the order of their residues
+        pub fn chains(&self) -> &[Chain] {
+            &self.chains
+        }
 
+        /// Add a chain of the residues `residues` and return its index
+        ///
+        /// Chains must be added in order, and panic if they overlap the
+        /// previous chain or go past the last residue.
+        pub fn add_chain(&mut self, name: &str, residues: Range<usize>) -> usize {
+            let start = self.chains.last().map_or(0, |chain| chain.residues.end);
+            if residues.start < start || residues.end > self.residues.len() || residues.is_empty() {
+                panic!("Chain {} has residues {:?}, which must be after {} and before {}!", name, residues, start, self.residues.len());
+            }
+            self.chains.push(Chain { name: name.to_string(), residues });
+            self.chains.len() - 1
+        }
 
-            for (i, j) in pairlist.iter() {
-                let (r, r2) = dist2(&positions[*i], &positions[*j]);
-                if r2 <= lj_cutoff_squared {
-                    // TODO: Allow other LJ combination rules than averaging
-                    let eps = (atoms[*i].epsilon + atoms[*j].epsilon) / 2.0;
-                    let sig = (atoms[*i].sigma + atoms[*j].sigma) / 2.0;
+        /// The index of the chain residue `residue` is in, if it is in one
+        pub fn chain_of(&self, residue: usize) -> Option<usize> {
+            let index = self.chains.partition_point(|chain| chain.residues.end <= residue);
+            self.chains.get(index)
+                .filter(|chain| chain.residues.contains(&residue))
+                .map(|_| index)
+        }
 
-                    let sig6 = sig.value_unsafe.powi(6);
-                    let r6 = r2.value_unsafe.powi(3);
-                    let sig12 = sig6 * sig6;
-                    let r12 = r6 * r6;
+        /// The blocks of molecules, in the order of their atoms
+        pub fn molecules(&self) -> &[MoleculeBlock] {
+            &self.molecules
+        }
 
-                    let f = r.normalize_into()/NM * 48.0*ONE * (eps / r2.sqrt()) * ((sig12 / r12) - (sig6 / r6));
+        /// Add `count` copies of a molecule of `n_atoms` atoms after the
+        /// molecules already in the topology. Panics if they would go past
+        /// the last atom.
+        pub fn add_molecules(&mut self, name: &str, n_atoms: usize, count: usize) {
+            let start: usize = self.molecules.iter().map(|block| block.n_atoms * block.count).sum();
+            if n_atoms == 0 || start + n_atoms * count > self.atoms.len() {
+                panic!("{} copies of {} with {} atoms don't fit in the topology!", count, name, n_atoms);
+            }
+            self.molecules.push(MoleculeBlock { name: name.to_string(), n_atoms, count });
+        }
 
-                    // println!("{:?}", f);
-                    forces[*i] += f.clone();
-                    forces[*j] -= f;
+        /// The block of molecules atom `atom` is in, and which copy of the
+        /// molecule within the block, if it is in one
+        pub fn molecule_of(&self, atom: usize) -> Option<(usize, usize)> {
+            let mut start = 0;
+            for (index, block) in self.molecules.iter().enumerate() {
+                let end = start + block.n_atoms * block.count;
+                if atom < end {
+                    return Some((index, (atom - start) / block.n_atoms));
                 }
+                start = end;
+            }
+            None
+        }
+
+        /// The atoms of copy `copy` of the molecule in block `block`
+        pub fn molecule_atoms(&self, block: usize, copy: usize) -> Range<usize> {
+            let start: usize = self.molecules[..block].iter().map(|b| b.n_atoms * b.count).sum();
+            let n_atoms = self.molecules[block].n_atoms;
+            if copy >= self.molecules[block].count {
+                panic!("Block {} has only {} molecules!", block, self.molecules[block].count);
+            }
+            start + copy * n_atoms..start + (copy + 1) * n_atoms
+        }
+
+        /// Leave the pair `i`, `j` out of the non-bonded pairlist
+        pub fn add_exclusion(&mut self, i: usize, j: usize) {
+            if i == j {
+                return;
+            }
+            if self.exclusions.len() < self.atoms.len() {
+                self.exclusions.resize(self.atoms.len(), vec![]);
+            }
+            for &(a, b) in [(i, j), (j, i)].iter() {
+                let excluded = &mut self.exclusions[a];
+                if let Err(pos) = excluded.binary_search(&b) {
+                    excluded.insert(pos, b);
+                }
+            }
+        }
+
+        /// Whether the pair `i`, `j` is left out of the non-bonded pairlist
+        pub fn is_excluded(&self, i: usize, j: usize) -> bool {
+            self.exclusions.get(i)
+                .is_some_and(|excluded| excluded.binary_search(&j).is_ok())
+        }
+
+        /// The atoms within `n_bonds` bonds of each atom, and how many
+        /// bonds away they are along the shortest path
+        pub fn bond_separations(&self, n_bonds: usize) -> Vec<Vec<(usize, usize)>> {
+            let n_atoms = self.atoms.len();
+            let mut bonded = vec![vec![]; n_atoms];
+            for bond in self.bonds.iter() {
+                let [i, j] = bond.atoms;
+                bonded[i].push(j);
+                bonded[j].push(i);
+            }
+
+            (0..n_atoms).map(|start| {
+                // Breadth first search, so each atom is first reached by
+                // the shortest path
+                let mut separations = vec![];
+                let mut seen = vec![false; n_atoms];
+                seen[start] = true;
+                let mut shell = vec![start];
+                for distance in 1..=n_bonds {
+                    let mut next = vec![];
+                    for &i in shell.iter() {
+                        for &j in bonded[i].iter() {
+                            if !seen[j] {
+                                seen[j] = true;
+                                next.push(j);
+                                separations.push((j, distance));
+                            }
+                        }
+                    }
+                    shell = next;
+                }
+                separations.sort_unstable();
+                separations
+            }).collect()
+        }
+
+        /// Exclude every pair of atoms within `n_bonds` bonds of each other,
+        /// like GROMACS' `nrexcl`
+        pub fn generate_exclusions(&mut self, n_bonds: usize) {
+            for (i, separations) in self.bond_separations(n_bonds).into_iter().enumerate() {
+                for (j, _) in separations.into_iter().filter(|&(j, _)| i < j) {
+                    self.add_exclusion(i, j);
+                }
+            }
+        }
+
+        /// Add a 1-4 pair for every pair of atoms exactly three bonds apart
+        /// that isn't already in `pairs`
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use noether::topology::Top;
+        /// use noether::potentials::bonded::{Bond, Pair};
+        /// use noether::units::f32consts::*;
+        ///
+        /// let mut top = Top::gen_lj_fluid(5, 12.0 * DA, 0.3 * KJPM, 0.3 * NM);
+        /// for i in 0..4 {
+        ///     top.bonds.push(Bond {
+        ///         atoms: [i, i + 1],
+        ///         length: 0.15 * NM,
+        ///         force_constant: 2.5e5 * KJPM / NM2
+        ///     });
+        /// }
+        /// top.generate_exclusions(3);
+        /// top.generate_pairs();
+        ///
+        /// assert_eq!(top.exclusions[0], vec![1, 2, 3]);
+        /// assert_eq!(top.exclusions[2], vec![0, 1, 3, 4]);
+        /// assert!(!top.is_excluded(0, 4));
+        /// assert_eq!(top.pairs, vec![Pair { atoms: [0, 3] }, Pair { atoms: [1, 4] }]);
+        /// ```
+        pub fn generate_pairs(&mut self) {
+            let mut existing: HashSet<(usize, usize)> = self.pairs.iter()
+                .map(|p| (p.atoms[0].min(p.atoms[1]), p.atoms[0].max(p.atoms[1])))
+                .collect();
+            for (i, separations) in self.bond_separations(3).into_iter().enumerate() {
+                for (j, _) in separations.into_iter().filter(|&(j, distance)| i < j && distance == 3) {
+                    if existing.insert((i, j)) {
+                        self.pairs.push(Pair { atoms: [i, j] });
+                    }
+                }
+            }
+        }
+
+        /// Everything the potentials need to evaluate themselves
+        pub fn configuration<'b>(
+            &'b self,
+            positions: &'b [PosVec],
+            pairlist: &'b NeighborList,
+            simbox: &'b SimulationBox
+        ) -> Configuration<'b> {
+            Configuration {
+                topology: self,
+                positions,
+                simbox,
+                pairlist
+            }
+        }
+
+        /// Estimate the pairlist buffer needed to keep the energy drift
+        /// from pairs missing from the pairlist below `tolerance` per atom.
+        ///
+        /// Like GROMACS' `verlet-buffer-tolerance`, this assumes atoms move
+        /// ballistically with Maxwell-Boltzmann velocities at `temp` over a
+        /// pairlist lifetime of `lifetime`, that atoms are uniformly
+        /// distributed at the density given by `volume`, and that the LJ
+        /// potential is linear just inside the cutoff. The error is then
+        /// the expected energy of pairs that start outside the pairlist
+        /// and end up within the cutoff.
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use noether::topology::Top;
+        /// use noether::units::f32consts::*;
+        ///
+        /// let top = Top::gen_lj_fluid(1000, 40.0 * DA, 1.0 * KJPM, 0.34 * NM);
+        /// let volume = 40.0 * NM3;
+        ///
+        /// let strict = top.estimate_verlet_buffer(volume, 300.0 * K, 0.05 * PS, 0.001 * KJPMPS);
+        /// let loose = top.estimate_verlet_buffer(volume, 300.0 * K, 0.05 * PS, 0.01 * KJPMPS);
+        /// let hot = top.estimate_verlet_buffer(volume, 600.0 * K, 0.05 * PS, 0.001 * KJPMPS);
+        ///
+        /// assert!(loose < strict);
+        /// assert!(hot > strict);
+        /// assert!(strict > 0.0 * NM && strict < top.lj_cutoff());
+        /// ```
+        pub fn estimate_verlet_buffer(
+            &self,
+            volume: Nanometer3<f32>,
+            temp: Kelvin<f32>,
+            lifetime: Picosecond<f32>,
+            tolerance: KilojoulePerMolePerPicosecond<f32>
+        ) -> Nanometer<f32> {
+            let n_atoms = self.atoms.len() as f64;
+            let density = n_atoms / volume.value_unsafe as f64;
+            let kt = (KB * temp).value_unsafe as f64;
+            let t = lifetime.value_unsafe as f64;
+            let rc = self.lj_cutoff.value_unsafe as f64;
+            let tolerance = tolerance.value_unsafe as f64;
+
+            // Group atoms with identical parameters so we don't loop over all
+            // pairs. Each group is represented by its first atom
+            let mut groups: Vec<(usize, f64)> = vec![];
+            for (i, atom) in self.atoms.iter().enumerate() {
+                match groups.iter_mut().find(|(other, _)| {
+                    self.atoms[*other].mass == atom.mass
+                        && self.atoms[*other].atom_type == atom.atom_type
+                }) {
+                    Some((_, count)) => *count += 1.0,
+                    None => groups.push((i, 1.0))
+                }
+            }
+
+            let drift = |buffer: f64| -> f64 {
+                let mut err = 0.0;
+                for &(i, ni) in groups.iter() {
+                    for &(j, nj) in groups.iter() {
+                        let (c6, c12) = self.c6_c12(i, j);
+                        let c6 = c6.value_unsafe as f64;
+                        let c12 = c12.value_unsafe as f64;
+                        let rc6 = rc.powi(6);
+                        let pot = c12 / (rc6 * rc6) - c6 / rc6;
+                        let dpot = (-12.0 * c12 / (rc6 * rc6) + 6.0 * c6 / rc6) / rc;
+
+                        let inv_mass = 1.0 / self.atoms[i].mass.value_unsafe as f64
+                            + 1.0 / self.atoms[j].mass.value_unsafe as f64;
+                        let sigma = (kt * inv_mass).sqrt() * t;
+                        let beta = buffer / sigma;
+                        let pdf = special::normal_pdf(beta);
+                        let sf = special::normal_sf(beta);
+
+                        let pot_term = pot.abs() * sigma * (pdf - beta * sf);
+                        let dpot_term = dpot.abs()
+                            * ((buffer * buffer + sigma * sigma) / 2.0 * sf - sigma * buffer / 2.0 * pdf);
+
+                        err += ni * nj / (n_atoms * n_atoms) * (pot_term + dpot_term);
+                    }
+                }
+                2.0 * std::f64::consts::PI * density * rc * rc * err / t
+            };
+
+            // Modifiers only reduce the potential and its derivative at the
+            // cutoff, so the unmodified potential gives an upper bound on
+            // the drift with any of them.
+            //
+            // The drift decreases monotonically with the buffer, so bisect
+            let (mut lower, mut upper) = (0.0, rc);
+            if drift(lower) <= tolerance {
+                return 0.0 * NM;
+            }
+            for _ in 0..50 {
+                let mid = (lower + upper) / 2.0;
+                if drift(mid) > tolerance {
+                    lower = mid;
+                } else {
+                    upper = mid;
+                }
+            }
+            upper as f32 * NM
+        }
+
+        /// The dispersion correction to the energy of the whole system in
+        /// a box of `volume`, or zero if it is turned off
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use noether::topology::{Top, DispersionCorrection};
+        /// use noether::units::f32consts::*;
+        ///
+        /// let mut top = Top::gen_lj_fluid(1000, 40.0 * DA, 1.0 * KJPM, 0.34 * NM);
+        /// let volume = 40.0 * NM3;
+        /// assert_eq!(top.dispersion_energy(volume), 0.0 * KJPM);
+        ///
+        /// top.dispersion_correction = DispersionCorrection::EnergyPressure;
+        /// assert!(top.dispersion_energy(volume) < 0.0 * KJPM);
+        /// assert!(top.dispersion_pressure(volume) < 0.0 * BAR);
+        /// ```
+        pub fn dispersion_energy(&self, volume: Nanometer3<f32>) -> KilojoulePerMole<f32> {
+            if self.dispersion_correction == DispersionCorrection::None {
+                return 0.0 * KJPM;
+            }
+            let n_atoms = self.atoms.len() as f64;
+            let density = n_atoms / volume.value_unsafe as f64;
+            let c6 = self.lj_table.mean_c6(&self.atoms, &self.exclusions).value_unsafe as f64;
+            let (integral, _) = nonbonded::dispersion_integrals(self.lj_modifier, self.lj_cutoff);
+
+            (0.5 * n_atoms * density * c6 * integral) as f32 * KJPM
+        }
+
+        /// The dispersion correction to the isotropic pressure in a box of
+        /// `volume`, or zero unless the pressure correction is turned on
+        pub fn dispersion_pressure(&self, volume: Nanometer3<f32>) -> KilojoulePerMolePerNanometer3<f32> {
+            if self.dispersion_correction != DispersionCorrection::EnergyPressure {
+                return 0.0 * KJPMNM3;
             }
+            let density = self.atoms.len() as f64 / volume.value_unsafe as f64;
+            let c6 = self.lj_table.mean_c6(&self.atoms, &self.exclusions).value_unsafe as f64;
+            let (_, integral) = nonbonded::dispersion_integrals(self.lj_modifier, self.lj_cutoff);
+
+            (-density * density * c6 * integral / 6.0) as f32 * KJPMNM3
+        }
+
+        /// The dispersion correction to the virial in a box of `volume`,
+        /// which adds `dispersion_pressure` to each diagonal element of the
+        /// pressure tensor
+        pub fn dispersion_virial(&self, volume: Nanometer3<f32>) -> EnergyTensor {
+            let xi = -0.5 * volume * self.dispersion_pressure(volume);
+            let zero = 0.0 * KJPM;
+            Tensor3D::new(
+                Vec3D::new(xi, zero, zero),
+                Vec3D::new(zero, xi, zero),
+                Vec3D::new(zero, zero, xi)
+            )
+        }
+
+        /// The total potential energy of all terms, including the
+        /// dispersion correction
+        pub fn calc_energy(&self, positions: &[PosVec], pairlist: &NeighborList, simbox: &SimulationBox) -> KilojoulePerMole<f32> {
+            let config = self.configuration(positions, pairlist, simbox);
+            self.potentials.iter()
+                .fold(
+                    self.dispersion_energy(simbox.volume()),
+                    |acc, potential| acc + potential.energy(&config)
+                )
+        }
 
+        /// The total force on each atom from all terms
+        pub fn calc_forces(&self, positions: &[PosVec], pairlist: &NeighborList, simbox: &SimulationBox) -> Vec<ForceVec> {
+            let config = self.configuration(positions, pairlist, simbox);
+            let mut forces = vec![ForceVec::zero(); self.atoms.len()];
+            for potential in self.potentials.iter() {
+                potential.forces(&config, &mut forces);
+            }
             forces
         }
+
+        /// The total virial tensor of all terms, `-1/2` the sum of the
+        /// outer products of positions and forces, including the
+        /// dispersion correction. Panics if a term can't calculate its
+        /// virial.
+        pub fn calc_virial(&self, positions: &[PosVec], pairlist: &NeighborList, simbox: &SimulationBox) -> EnergyTensor {
+            let config = self.configuration(positions, pairlist, simbox);
+            self.potentials.iter()
+                .fold(
+                    self.dispersion_virial(simbox.volume()),
+                    |acc, potential| match potential.virial(&config) {
+                        Some(virial) => acc + virial,
+                        None => panic!("{:?} can't calculate its virial!", potential)
+                    }
+                )
+        }
+
+        /// The total force on each atom and the total virial tensor, as
+        /// from `calc_forces` and `calc_virial` but in one pass over each
+        /// term. Panics if a term can't calculate its virial.
+        pub fn calc_forces_and_virial(
+            &self,
+            positions: &[PosVec],
+            pairlist: &NeighborList,
+            simbox: &SimulationBox
+        ) -> (Vec<ForceVec>, EnergyTensor) {
+            let config = self.configuration(positions, pairlist, simbox);
+            let mut forces = vec![ForceVec::zero(); self.atoms.len()];
+            let mut virial = self.dispersion_virial(simbox.volume());
+            for potential in self.potentials.iter() {
+                match potential.forces_and_virial(&config, &mut forces) {
+                    Some(term) => virial += term,
+                    None => panic!("{:?} can't calculate its virial!", potential)
+                }
+            }
+            (forces, virial)
+        }
     }
 
-    #[derive(Debug, Clone)]
+    /// An atom in the topology
+    ///
+    /// Lennard-Jones parameters come from the atom's type, while its mass
+    /// and charge start out as the type's but can be changed for each
+    /// atom.
+    #[derive(Debug, Clone, PartialEq)]
     pub struct Atom {
+        /// The index of the atom's type in the topology's `TypeLibrary`
+        pub atom_type: usize,
+        pub mass: Dalton<f32>,
+        pub charge: ElemCharge<f32>,
+        /// The atom's name in trajectory output, which starts out as its
+        /// type's name
+        pub name: String,
+        /// The symbol of the atom's element, if it has one
+        pub element: Option<String>
+    }
+
+    /// A residue, whose atoms are consecutive in the topology
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Residue {
+        pub name: String,
+        /// The residue number, as in PDB files and GROMACS topologies
+        pub id: i64,
+        pub atoms: Range<usize>
+    }
+
+    /// A chain of consecutive residues
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Chain {
+        pub name: String,
+        /// The indices of the chain's residues in `Top::residues`
+        pub residues: Range<usize>
+    }
+
+    /// `count` consecutive copies of a molecule of `n_atoms` atoms, like a
+    /// line of GROMACS' `[ molecules ]`
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct MoleculeBlock {
+        pub name: String,
+        pub n_atoms: usize,
+        pub count: usize
+    }
+
+    /// The parameters shared by all atoms of a type
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct AtomType {
+        /// The name of the type, used to look up `NbFix` parameters and
+        /// tabulated potentials
+        pub name: String,
         pub mass: Dalton<f32>,
+        /// The default charge of atoms of this type
         pub charge: ElemCharge<f32>,
         pub epsilon: KilojoulePerMole<f32>,
-        pub sigma: Nanometer<f32>,
+        pub sigma: Nanometer<f32>
+    }
+
+    /// A library of atom types, which atoms refer to by index
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use noether::topology::{TypeLibrary, AtomType};
+    /// use noether::units::f32consts::*;
+    ///
+    /// let mut types = TypeLibrary::new();
+    /// let ow = types.add(AtomType {
+    ///     name: "OW".to_string(),
+    ///     mass: 15.9994 * DA,
+    ///     charge: -0.834 * E,
+    ///     epsilon: 0.636 * KJPM,
+    ///     sigma: 0.315 * NM
+    /// });
+    ///
+    /// assert_eq!(types.index("OW"), Some(ow));
+    /// assert_eq!(types.index("HW"), None);
+    /// assert_eq!(types.atom(ow).charge, -0.834 * E);
+    /// ```
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct TypeLibrary {
+        types: Vec<AtomType>
+    }
+
+    impl TypeLibrary {
+        pub fn new() -> TypeLibrary {
+            TypeLibrary { types: vec![] }
+        }
+
+        /// Add a type and return its index. Panics if there is already a
+        /// type with the same name.
+        pub fn add(&mut self, atom_type: AtomType) -> usize {
+            if self.index(&atom_type.name).is_some() {
+                panic!("Atom type {} is already in the library!", atom_type.name);
+            }
+            self.types.push(atom_type);
+            self.types.len() - 1
+        }
+
+        /// The index of the type called `name`
+        pub fn index(&self, name: &str) -> Option<usize> {
+            self.types.iter().position(|t| t.name == name)
+        }
+
+        /// The type with index `index`
+        pub fn get(&self, index: usize) -> &AtomType {
+            &self.types[index]
+        }
+
+        /// Mutable access to the type with index `index`, for building a
+        /// library before giving it to `Top::new`. Use
+        /// `Top::add_atom_type` to change the types of a topology.
+        pub fn get_mut(&mut self, index: usize) -> &mut AtomType {
+            &mut self.types[index]
+        }
+
+        pub fn len(&self) -> usize {
+            self.types.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.types.is_empty()
+        }
+
+        pub fn iter(&self) -> std::slice::Iter<'_, AtomType> {
+            self.types.iter()
+        }
+
+        /// A new atom of type `index`, with the type's mass, charge and name
+        pub fn atom(&self, index: usize) -> Atom {
+            let atom_type = self.get(index);
+            Atom {
+                atom_type: index,
+                mass: atom_type.mass,
+                charge: atom_type.charge,
+                name: atom_type.name.clone(),
+                element: None
+            }
+        }
     }
 }
 
@@ -504,8 +1667,193 @@ pub mod topology {
 
 #[cfg(test)]
 mod tests {
+    use crate::units::f32consts::*;
+    use crate::geom::{PosVec, VelocVec, ForceVec, SimulationBox};
+    use crate::topology::{Top, ForceKernel, DispersionCorrection, LjModifier};
+    use crate::state::tests::state;
+    use crate::potentials::bonded::Bond;
+    use crate::potentials::nonbonded::Coulomb;
+    use crate::pairlist;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use itertools::iproduct;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    /// An LJ fluid on a jittered lattice, so no pairs are too close
+    fn lj_fluid(n_side: usize) -> (Top, Vec<PosVec>, SimulationBox) {
+        let spacing = 0.4;
+        let mut rng = StdRng::seed_from_u64(42);
+        let positions = iproduct!(0..n_side, 0..n_side, 0..n_side)
+            .map(|(x, y, z)| PosVec::from(
+                x as f32 * spacing + rng.gen_range(-0.05, 0.05),
+                y as f32 * spacing + rng.gen_range(-0.05, 0.05),
+                z as f32 * spacing + rng.gen_range(-0.05, 0.05)
+            )).collect::<Vec<_>>();
+        let top = Top::gen_lj_fluid(positions.len(), 40.0 * DA, 1.0 * KJPM, 0.34 * NM);
+        let simbox = SimulationBox::cubic(n_side as f32 * spacing * NM);
+        (top, positions, simbox)
+    }
+
+    fn forces_with(top: &mut Top, positions: &[PosVec], simbox: &SimulationBox, kernel: ForceKernel, full: bool) -> Vec<ForceVec> {
+        let mut list = pairlist::cell_list(positions, simbox, top.lj_cutoff());
+        if full {
+            list = list.to_full();
+        }
+        top.force_kernel = kernel;
+        top.calc_forces(positions, &list, simbox)
+    }
+
+    #[test]
+    fn force_kernels_agree() {
+        let (mut top, positions, simbox) = lj_fluid(8);
+        let serial = forces_with(&mut top, &positions, &simbox, ForceKernel::Serial, false);
+
+        for &(kernel, full) in [
+            (ForceKernel::ThreadBuffers, false),
+            (ForceKernel::ThreadBuffers, true),
+            (ForceKernel::FullList, false),
+            (ForceKernel::FullList, true),
+        ].iter() {
+            let forces = forces_with(&mut top, &positions, &simbox, kernel, full);
+            for (a, b) in serial.iter().zip(forces.iter()) {
+                let diff = (a.clone() - b.clone()).norm().value_unsafe;
+                assert!(diff <= 1e-4 * (1.0 + a.norm().value_unsafe), "{:?} differs by {}", kernel, diff);
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_force_kernels_are_deterministic() {
+        let (mut top, positions, simbox) = lj_fluid(8);
+        for &kernel in [ForceKernel::ThreadBuffers, ForceKernel::FullList].iter() {
+            let first = forces_with(&mut top, &positions, &simbox, kernel, false);
+            for _ in 0..5 {
+                assert_eq!(first, forces_with(&mut top, &positions, &simbox, kernel, false));
+            }
+        }
+    }
+
+    #[test]
+    fn forces_and_virial_match_separate_calculations() {
+        let (mut top, positions, simbox) = lj_fluid(6);
+        top.bonds.push(Bond { atoms: [0, 1], length: 0.35 * NM, force_constant: 1000.0 * KJPM / NM2 });
+        top.dispersion_correction = DispersionCorrection::EnergyPressure;
+        top.add_potential(Coulomb::ReactionField { epsilon_rf: 78.0 }).unwrap();
+        for (i, atom) in top.atoms.iter_mut().enumerate() {
+            atom.charge = if i % 2 == 0 { 0.5 * E } else { -0.5 * E };
+        }
+
+        for &(kernel, full) in [
+            (ForceKernel::Serial, false),
+            (ForceKernel::ThreadBuffers, false),
+            (ForceKernel::ThreadBuffers, true),
+            (ForceKernel::FullList, false),
+            (ForceKernel::FullList, true),
+        ].iter() {
+            let mut list = pairlist::cell_list(&positions, &simbox, top.lj_cutoff());
+            if full {
+                list = list.to_full();
+            }
+            top.force_kernel = kernel;
+            let (forces, virial) = top.calc_forces_and_virial(&positions, &list, &simbox);
+
+            assert_eq!(forces, top.calc_forces(&positions, &list, &simbox));
+            let reference = top.calc_virial(&positions, &list, &simbox);
+            let diff = (virial - reference.clone()).diagonal().norm2().value_unsafe.sqrt();
+            let size = reference.diagonal().norm2().value_unsafe.sqrt();
+            assert!(diff <= 1e-4 * size, "{:?} differs by {}", kernel, diff);
+        }
+    }
+
+    #[test]
+    fn pressure_of_free_atoms_is_kinetic() {
+        let top = Top::gen_lj_fluid(2, 10.0 * DA, 0.0 * KJPM, 0.3 * NM);
+        let state = state(
+            &top,
+            vec![PosVec::from(0.5, 0.5, 0.5), PosVec::from(1.5, 1.5, 1.5)],
+            vec![VelocVec::from(1.0, 0.0, 0.0), VelocVec::from(0.0, 2.0, 0.0)],
+            SimulationBox::cubic(2.0 * NM)
+        );
+
+        // K_xx = 5 and K_yy = 20 kJ/mol, in a volume of 8 nm^3
+        let kinetic = state.kinetic_energy_tensor();
+        assert_eq!(kinetic.trace(), state.kinetic_energy());
+        let pressure = state.pressure_tensor().diagonal();
+        assert!((pressure.x.value_unsafe - 1.25).abs() < 1e-6);
+        assert!((pressure.y.value_unsafe - 5.0).abs() < 1e-6);
+        assert_eq!(pressure.z.value_unsafe, 0.0);
+        assert!((state.pressure() - 6.25 / 3.0 * KJPMNM3).value_unsafe.abs() < 1e-6);
+        // 1 kJ/mol/nm^3 is 16.6 bar
+        assert!((state.pressure_bar() - 6.25 / 3.0 * 16.6054).abs() < 1e-3);
+    }
+
+    #[test]
+    #[should_panic(expected = "Atom 2 has type 1, but there are only 1 atom types")]
+    fn atoms_need_known_types() {
+        let mut top = Top::gen_lj_fluid(3, 40.0 * DA, 1.0 * KJPM, 0.34 * NM);
+        top.atoms[2].atom_type = 1;
+        top.update_lj_table();
+    }
+
+    #[test]
+    #[should_panic(expected = "Residue SOL 2 has atoms 1..3, which must be after 2 and before 4")]
+    fn residues_must_not_overlap() {
+        let mut top = Top::gen_lj_fluid(4, 40.0 * DA, 1.0 * KJPM, 0.34 * NM);
+        top.add_residue("SOL", 1, 0..2);
+        top.add_residue("SOL", 2, 1..3);
+    }
+
+    #[test]
+    fn dispersion_correction_matches_tail_integral() {
+        let (mut top, positions, simbox) = lj_fluid(8);
+        let list = pairlist::cell_list(&positions, &simbox, top.lj_cutoff());
+        let uncorrected = top.calc_energy(&positions, &list, &simbox);
+        let volume = simbox.volume();
+
+        let n_atoms = positions.len() as f32;
+        let density = n_atoms / volume;
+        let rc = top.lj_cutoff();
+        let c6 = top.lj_table().c6_c12(0, 0).0;
+        let energy = -2.0 * PI * n_atoms * density * c6 / (3.0 * rc * rc * rc);
+        let pressure = -4.0 * PI * density * density * c6 / (3.0 * rc * rc * rc);
+
+        top.dispersion_correction = DispersionCorrection::Energy;
+        assert!(((top.dispersion_energy(volume) - energy) / energy).value_unsafe.abs() < 1e-4);
+        assert_eq!(top.dispersion_pressure(volume), 0.0 * KJPMNM3);
+
+        top.dispersion_correction = DispersionCorrection::EnergyPressure;
+        assert!(((top.dispersion_pressure(volume) - pressure) / pressure).value_unsafe.abs() < 1e-4);
+        assert_eq!(top.dispersion_virial(volume).trace(), -1.5 * volume * top.dispersion_pressure(volume));
+
+        let corrected = top.calc_energy(&positions, &list, &simbox);
+        assert!((corrected - uncorrected - energy).value_unsafe.abs() < 1e-2);
+    }
+
+    #[test]
+    fn dispersion_correction_includes_modifiers() {
+        let mut top = Top::gen_lj_fluid(1000, 40.0 * DA, 1.0 * KJPM, 0.34 * NM);
+        top.dispersion_correction = DispersionCorrection::EnergyPressure;
+        let volume = 40.0 * NM3;
+        let plain = (top.dispersion_energy(volume), top.dispersion_pressure(volume));
+
+        // Shifting the potential makes every pair inside the cutoff
+        // miss the same energy again, but leaves the forces alone
+        top.set_lj_modifier(LjModifier::PotentialShift).unwrap();
+        let shifted = (top.dispersion_energy(volume), top.dispersion_pressure(volume));
+        assert!(((shifted.0 - 2.0 * plain.0) / plain.0).value_unsafe.abs() < 1e-4);
+        assert!(((shifted.1 - plain.1) / plain.1).value_unsafe.abs() < 1e-4);
+
+        // Switching weakens the attraction, so more energy is missing
+        for &modifier in [
+            LjModifier::ForceSwitch { rswitch: 0.8 * NM },
+            LjModifier::PotentialSwitch { rswitch: 0.8 * NM }
+        ].iter() {
+            top.set_lj_modifier(modifier).unwrap();
+            assert!(top.dispersion_energy(volume) < plain.0);
+        }
+    }
 }
@@ -0,0 +1,630 @@
+//! Verlet pairlist construction
+//!
+//! Pairlists are built with a linked-cell search that bins atoms into
+//! cells at least as wide as the cutoff, so that only atoms in the 27
+//! neighbouring cells need to be tested. A brute force search over all
+//! pairs is kept as a reference implementation. Both produce a
+//! `NeighborList`, which stores the neighbours of each atom contiguously
+//! in compressed sparse row layout.
+//!
+//! A `VerletList` wraps a `NeighborList` built with a buffer beyond the
+//! interaction cutoff, and rebuilds it whenever any atom has moved far
+//! enough that a pair outside the list could have come within the cutoff.
+//! Excluded pairs, such as bonded neighbours, are removed from the list
+//! every time it is built.
+
+use crate::units::*;
+use crate::units::f32consts::*;
+use crate::geom::{
+    PosVec,
+    SimulationBox
+};
+use itertools::{Itertools, iproduct};
+use rayon::prelude::*;
+use crate::dim::Sqrt;
+
+/// A list of neighbouring atoms in compressed sparse row layout.
+///
+/// The neighbours of atom `i` are stored contiguously and in increasing
+/// order in a single vector, starting at `offsets[i]` and ending just
+/// before `offsets[i + 1]`. A half list stores each pair once, as a
+/// neighbour of the lower index. A full list stores each pair twice,
+/// once for each atom, so that per-atom quantities can be accumulated
+/// without touching any other atom's entry.
+///
+/// # Examples
+///
+/// ```
+/// use noether::pairlist::NeighborList;
+///
+/// let half = NeighborList::from_pairs(4, &[(0, 2), (1, 0), (2, 3)]);
+/// assert_eq!(half.neighbors(0), &[1, 2]);
+/// assert_eq!(half.neighbors(3), &[]);
+/// assert_eq!(half.len(), 3);
+///
+/// let full = half.to_full();
+/// assert_eq!(full.neighbors(3), &[2]);
+/// assert_eq!(full.len(), 3);
+/// assert_eq!(
+///     full.pairs().collect::<Vec<_>>(),
+///     vec![(0, 1), (0, 2), (2, 3)]
+/// );
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct NeighborList {
+    offsets: Vec<usize>,
+    neighbors: Vec<usize>,
+    full: bool
+}
+
+impl NeighborList {
+    /// An empty half list for `n_atoms` atoms.
+    pub fn empty(n_atoms: usize) -> NeighborList {
+        NeighborList {
+            offsets: vec![0; n_atoms + 1],
+            neighbors: vec![],
+            full: false
+        }
+    }
+
+    /// A half list from a list of pairs. Each pair may be given in
+    /// either order, but should only be given once.
+    pub fn from_pairs(n_atoms: usize, pairs: &[(usize, usize)]) -> NeighborList {
+        let mut rows = vec![vec![]; n_atoms];
+        for &(i, j) in pairs.iter() {
+            rows[i.min(j)].push(i.max(j));
+        }
+        NeighborList::from_rows(rows, false)
+    }
+
+    /// Build a list from the neighbours of each atom in turn
+    fn from_rows(rows: Vec<Vec<usize>>, full: bool) -> NeighborList {
+        let mut offsets = Vec::with_capacity(rows.len() + 1);
+        offsets.push(0);
+        let mut neighbors = Vec::with_capacity(rows.iter().map(Vec::len).sum());
+        for mut row in rows.into_iter() {
+            row.sort_unstable();
+            neighbors.extend(row);
+            offsets.push(neighbors.len());
+        }
+
+        NeighborList {
+            offsets,
+            neighbors,
+            full
+        }
+    }
+
+    /// The equivalent full list, with every pair stored for both atoms.
+    pub fn to_full(&self) -> NeighborList {
+        if self.full {
+            return self.clone();
+        }
+
+        let mut rows = vec![vec![]; self.n_atoms()];
+        for (i, j) in self.pairs() {
+            rows[i].push(j);
+            rows[j].push(i);
+        }
+        NeighborList::from_rows(rows, true)
+    }
+
+    /// The same list without the pairs in `exclusions`, which holds the
+    /// sorted excluded partners of each atom. Exclusions must be given
+    /// for both atoms of a pair.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use noether::pairlist::NeighborList;
+    ///
+    /// let list = NeighborList::from_pairs(3, &[(0, 1), (0, 2), (1, 2)]);
+    /// let exclusions = vec![vec![1], vec![0], vec![]];
+    ///
+    /// let excluded = list.exclude(&exclusions);
+    /// assert_eq!(excluded.neighbors(0), &[2]);
+    /// assert_eq!(excluded.len(), 2);
+    /// ```
+    pub fn exclude(&self, exclusions: &[Vec<usize>]) -> NeighborList {
+        let rows = self.iter()
+            .map(|(i, neighbors)| {
+                match exclusions.get(i) {
+                    Some(excluded) if !excluded.is_empty() => neighbors.iter()
+                        .filter(|j| excluded.binary_search(j).is_err())
+                        .cloned()
+                        .collect(),
+                    _ => neighbors.to_vec()
+                }
+            }).collect();
+        NeighborList::from_rows(rows, self.full)
+    }
+
+    /// Whether each pair is stored for both of its atoms.
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
+    /// The number of atoms the list was built for.
+    pub fn n_atoms(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// The number of distinct pairs in the list.
+    pub fn len(&self) -> usize {
+        if self.full {
+            self.neighbors.len() / 2
+        } else {
+            self.neighbors.len()
+        }
+    }
+
+    /// Whether the list contains no pairs.
+    pub fn is_empty(&self) -> bool {
+        self.neighbors.is_empty()
+    }
+
+    /// The neighbours of atom `i`, in increasing order.
+    pub fn neighbors(&self, i: usize) -> &[usize] {
+        &self.neighbors[self.offsets[i]..self.offsets[i + 1]]
+    }
+
+    /// Iterate over each atom and its neighbours.
+    pub fn iter(&self) -> impl Iterator<Item=(usize, &[usize])> {
+        (0..self.n_atoms()).map(move |i| (i, self.neighbors(i)))
+    }
+
+    /// Iterate over each distinct pair `(i, j)` once, with `i < j`.
+    pub fn pairs(&self) -> impl Iterator<Item=(usize, usize)> + '_ {
+        let full = self.full;
+        self.iter()
+            .flat_map(|(i, js)| js.iter().map(move |&j| (i, j)))
+            .filter(move |&(i, j)| !full || i < j)
+    }
+
+    /// Iterate over each distinct pair `(i, j)` once, with `i < j`, in parallel.
+    pub fn par_pairs(&self) -> impl ParallelIterator<Item=(usize, usize)> + '_ {
+        let full = self.full;
+        (0..self.n_atoms())
+            .into_par_iter()
+            .flat_map(move |i| {
+                self.neighbors(i)
+                    .par_iter()
+                    .map(move |&j| (i, j))
+            }).filter(move |&(i, j)| !full || i < j)
+    }
+}
+
+/// A buffered pairlist that knows when it needs rebuilding.
+///
+/// The list includes every pair within `cutoff + buffer` of each other
+/// at the time it was built. As long as no atom has moved more than half
+/// the buffer since then, no pair outside the list can be within the
+/// cutoff, so the list can be reused.
+///
+/// # Examples
+///
+/// ```
+/// use noether::geom::{PosVec, SimulationBox};
+/// use noether::pairlist::VerletList;
+/// use noether::units::f32consts::*;
+///
+/// let simbox = SimulationBox::cubic(4.0 * NM);
+/// let mut positions = vec![
+///     PosVec::from(0.0, 0.0, 0.0),
+///     PosVec::from(1.05, 0.0, 0.0),
+/// ];
+///
+/// let mut verlet = VerletList::new(&positions, &simbox, 1.0 * NM, 0.1 * NM);
+/// assert_eq!(verlet.list().len(), 1);
+///
+/// positions[1].x = 1.09 * NM;
+/// assert!(!verlet.update(&positions, &simbox));
+///
+/// positions[1].x = 1.2 * NM;
+/// assert!(verlet.update(&positions, &simbox));
+/// assert!(verlet.list().is_empty());
+/// ```
+#[derive(Debug, Clone)]
+pub struct VerletList {
+    list: NeighborList,
+    cutoff: Nanometer<f32>,
+    buffer: Nanometer<f32>,
+    reference: Vec<PosVec>,
+    /// How much the box has shrunk, relative to its size, since the list
+    /// was built
+    strain: f32,
+    exclusions: Vec<Vec<usize>>,
+    full: bool
+}
+
+impl VerletList {
+    /// Build a new list for pairs within `cutoff + buffer`.
+    pub fn new(
+        positions: &[PosVec],
+        simbox: &SimulationBox,
+        cutoff: Nanometer<f32>,
+        buffer: Nanometer<f32>
+    ) -> VerletList {
+        let mut verlet = VerletList {
+            list: NeighborList::empty(positions.len()),
+            cutoff,
+            buffer,
+            reference: vec![],
+            strain: 0.0,
+            exclusions: vec![],
+            full: false
+        };
+        verlet.rebuild(positions, simbox);
+        verlet
+    }
+
+    /// The current neighbour list.
+    pub fn list(&self) -> &NeighborList {
+        &self.list
+    }
+
+    /// The interaction cutoff the list is buffered for.
+    pub fn cutoff(&self) -> Nanometer<f32> {
+        self.cutoff
+    }
+
+    /// The buffer added to the cutoff when building the list.
+    pub fn buffer(&self) -> Nanometer<f32> {
+        self.buffer
+    }
+
+    /// The distance within which pairs are included in the list.
+    pub fn rlist(&self) -> Nanometer<f32> {
+        self.cutoff + self.buffer
+    }
+
+    /// Change the buffer and rebuild the list.
+    pub fn set_buffer(
+        &mut self,
+        buffer: Nanometer<f32>,
+        positions: &[PosVec],
+        simbox: &SimulationBox
+    ) {
+        self.buffer = buffer;
+        self.rebuild(positions, simbox);
+    }
+
+    /// Change the pairs left out of the list and rebuild it. See
+    /// `NeighborList::exclude`.
+    pub fn set_exclusions(
+        &mut self,
+        exclusions: Vec<Vec<usize>>,
+        positions: &[PosVec],
+        simbox: &SimulationBox
+    ) {
+        self.exclusions = exclusions;
+        self.rebuild(positions, simbox);
+    }
+
+    /// Choose whether to store each pair for both atoms. See `NeighborList`.
+    pub fn set_full(&mut self, full: bool) {
+        if full != self.full {
+            self.full = full;
+            self.list = if full {
+                self.list.to_full()
+            } else {
+                NeighborList::from_pairs(self.list.n_atoms(), &self.list.pairs().collect::<Vec<_>>())
+            };
+        }
+    }
+
+    /// Rebuild the list from scratch.
+    pub fn rebuild(&mut self, positions: &[PosVec], simbox: &SimulationBox) {
+        self.list = cell_list(positions, simbox, self.rlist());
+        if self.exclusions.iter().any(|excluded| !excluded.is_empty()) {
+            self.list = self.list.exclude(&self.exclusions);
+        }
+        if self.full {
+            self.list = self.list.to_full();
+        }
+        self.reference = positions.to_vec();
+        self.strain = 0.0;
+    }
+
+    /// Follow the box and the atoms as they're scaled by `factors`, as in
+    /// `SimulationBox::scale`, without rebuilding the list.
+    ///
+    /// Shrinking the box brings pairs outside the list closer together,
+    /// so it uses up the buffer as moving atoms does.
+    pub fn scale(&mut self, factors: (f32, f32, f32)) {
+        for pos in self.reference.iter_mut() {
+            *pos = PosVec::new(pos.x * factors.0, pos.y * factors.1, pos.z * factors.2);
+        }
+        let smallest = factors.0.min(factors.1).min(factors.2);
+        self.strain += (1.0 - smallest).max(0.0);
+    }
+
+    /// The largest distance any atom has moved since the list was built.
+    pub fn max_displacement(&self, positions: &[PosVec], simbox: &SimulationBox) -> Nanometer<f32> {
+        positions.par_iter()
+            .zip(self.reference.par_iter())
+            .map(|(pos, reference)| simbox.min_image(pos - reference).norm2())
+            .reduce(
+                || 0.0 * NM2,
+                |a, b| if a >= b { a } else { b }
+            ).sqrt()
+    }
+
+    /// Whether any pair outside the list could have come within the
+    /// cutoff since it was built, because atoms moved by up to half the
+    /// buffer or the box shrank.
+    pub fn needs_rebuild(&self, positions: &[PosVec], simbox: &SimulationBox) -> bool {
+        if positions.len() != self.reference.len() {
+            return true;
+        }
+        let max_displacement = self.max_displacement(positions, simbox);
+        max_displacement.value_unsafe.is_nan()
+            || max_displacement * 2.0 + self.rlist() * self.strain > self.buffer
+    }
+
+    /// Rebuild the list if it needs it. Returns whether it was rebuilt.
+    pub fn update(&mut self, positions: &[PosVec], simbox: &SimulationBox) -> bool {
+        if self.needs_rebuild(positions, simbox) {
+            self.rebuild(positions, simbox);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Generate a pairlist by testing every pair of atoms.
+///
+/// A cutoff of zero includes every pair.
+pub fn brute_force(
+    positions: &[PosVec],
+    simbox: &SimulationBox,
+    cutoff: Nanometer<f32>
+) -> NeighborList {
+    let cutoff2 = cutoff * cutoff;
+
+    let pair_vec:Vec<((_, _),(_, _))> = positions.iter()
+        .enumerate()
+        .tuple_combinations()
+        .collect();
+    let pairs: Vec<(usize, usize)> = pair_vec.par_iter()
+        .filter(|((_, ri), (_, rj))| {
+            cutoff == 0.0 * NM || simbox.min_image(*ri - *rj).norm2() <= cutoff2
+        }).map(|((i, _), (j, _))| (*i, *j))
+        .collect();
+    NeighborList::from_pairs(positions.len(), &pairs)
+}
+
+/// Generate a pairlist with a linked-cell search.
+///
+/// The result is identical to that of `brute_force`. If the box is too
+/// small to fit three cells of width `cutoff` along each box vector, or
+/// the cutoff is zero, this falls back to the brute force search.
+///
+/// # Examples
+///
+/// ```
+/// use noether::geom::{PosVec, SimulationBox};
+/// use noether::pairlist;
+/// use noether::units::f32consts::*;
+///
+/// let simbox = SimulationBox::cubic(4.0 * NM);
+/// let positions = vec![
+///     PosVec::from(0.1, 0.1, 0.1),
+///     PosVec::from(3.9, 0.1, 0.1),
+///     PosVec::from(2.0, 2.0, 2.0),
+/// ];
+///
+/// let pairs = pairlist::cell_list(&positions, &simbox, 1.0 * NM);
+/// assert_eq!(pairs.pairs().collect::<Vec<_>>(), vec![(0, 1)]);
+/// assert_eq!(pairs, pairlist::brute_force(&positions, &simbox, 1.0 * NM));
+/// ```
+pub fn cell_list(
+    positions: &[PosVec],
+    simbox: &SimulationBox,
+    cutoff: Nanometer<f32>
+) -> NeighborList {
+    let grid = match CellGrid::new(positions, simbox, cutoff) {
+        Some(grid) => grid,
+        None => return brute_force(positions, simbox, cutoff)
+    };
+    let cutoff2 = cutoff * cutoff;
+
+    let rows = (0..positions.len())
+        .into_par_iter()
+        .map(|i| {
+            grid.neighbour_cells(grid.cell_of[i])
+                .flat_map(|cell| grid.atoms_in(cell).iter().cloned())
+                .filter(|&j| {
+                    j > i && simbox.min_image(&positions[i] - &positions[j]).norm2() <= cutoff2
+                }).collect()
+        }).collect();
+    NeighborList::from_rows(rows, false)
+}
+
+/// Atoms binned into a grid of cells in fractional coordinates
+struct CellGrid {
+    /// Number of cells along each box vector
+    dims: [usize; 3],
+    /// Cell index of each atom
+    cell_of: Vec<usize>,
+    /// Start of each cell's atoms in `atoms`; has one extra entry at the end
+    cell_start: Vec<usize>,
+    /// Atom indices sorted by cell
+    atoms: Vec<usize>
+}
+
+impl CellGrid {
+    fn new(
+        positions: &[PosVec],
+        simbox: &SimulationBox,
+        cutoff: Nanometer<f32>
+    ) -> Option<CellGrid> {
+        if cutoff <= 0.0 * NM {
+            return None;
+        }
+
+        let (h_a, h_b, h_c) = simbox.heights();
+        let n_cells = |height: Nanometer<f32>| (height / cutoff).value_unsafe.floor() as usize;
+        let dims = [n_cells(h_a), n_cells(h_b), n_cells(h_c)];
+
+        // With fewer than three cells, neighbouring cells alias each other
+        if dims.iter().any(|&n| n < 3) {
+            return None;
+        }
+
+        let cell_of: Vec<usize> = positions.iter()
+            .map(|pos| {
+                let frac = simbox.to_fractional(pos);
+                let bin = |s: f32, n: usize| {
+                    let s = s - s.floor();
+                    ((s * n as f32) as usize).min(n - 1)
+                };
+                let (x, y, z) = (
+                    bin(frac.x.value_unsafe, dims[0]),
+                    bin(frac.y.value_unsafe, dims[1]),
+                    bin(frac.z.value_unsafe, dims[2])
+                );
+                (x * dims[1] + y) * dims[2] + z
+            }).collect();
+
+        let n_total = dims[0] * dims[1] * dims[2];
+        let mut cell_start = vec![0; n_total + 1];
+        for &cell in cell_of.iter() {
+            cell_start[cell + 1] += 1;
+        }
+        for cell in 0..n_total {
+            cell_start[cell + 1] += cell_start[cell];
+        }
+
+        let mut fill = cell_start.clone();
+        let mut atoms = vec![0; positions.len()];
+        for (i, &cell) in cell_of.iter().enumerate() {
+            atoms[fill[cell]] = i;
+            fill[cell] += 1;
+        }
+
+        Some(CellGrid {
+            dims,
+            cell_of,
+            cell_start,
+            atoms
+        })
+    }
+
+    fn atoms_in(&self, cell: usize) -> &[usize] {
+        &self.atoms[self.cell_start[cell]..self.cell_start[cell + 1]]
+    }
+
+    /// The 27 cells surrounding and including `cell`, with periodic wrapping
+    fn neighbour_cells(&self, cell: usize) -> impl Iterator<Item=usize> {
+        let [nx, ny, nz] = self.dims;
+        let (x, y, z) = (cell / (ny * nz), (cell / nz) % ny, cell % nz);
+
+        iproduct!(0..3, 0..3, 0..3).map(move |(dx, dy, dz)| {
+            let wrap = |c: usize, d: usize, n: usize| (c + n + d - 1) % n;
+            (wrap(x, dx, nx) * ny + wrap(y, dy, ny)) * nz + wrap(z, dz, nz)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn random_positions(simbox: &SimulationBox, n: usize) -> Vec<PosVec> {
+        let mut rng = StdRng::seed_from_u64(1729);
+        (0..n).map(|_| {
+            let frac = crate::geom::NodimVec::from(
+                rng.gen_range(-0.5, 1.5),
+                rng.gen_range(-0.5, 1.5),
+                rng.gen_range(-0.5, 1.5)
+            );
+            simbox.from_fractional(&frac)
+        }).collect()
+    }
+
+    #[test]
+    fn cell_list_matches_brute_force_rectangular() {
+        let simbox = SimulationBox::rectangular(3.1 * NM, 4.0 * NM, 5.3 * NM);
+        let positions = random_positions(&simbox, 600);
+
+        let reference = brute_force(&positions, &simbox, 1.0 * NM);
+        assert!(!reference.is_empty());
+        assert_eq!(cell_list(&positions, &simbox, 1.0 * NM), reference);
+    }
+
+    #[test]
+    fn cell_list_matches_brute_force_triclinic() {
+        let simbox = SimulationBox::triclinic(
+            PosVec::from(4.0, 0.0, 0.0),
+            PosVec::from(1.3, 3.7, 0.0),
+            PosVec::from(-1.1, 1.6, 3.5)
+        );
+        let positions = random_positions(&simbox, 600);
+
+        let reference = brute_force(&positions, &simbox, 1.1 * NM);
+        assert!(!reference.is_empty());
+        assert_eq!(cell_list(&positions, &simbox, 1.1 * NM), reference);
+    }
+
+    #[test]
+    fn small_box_falls_back_to_brute_force() {
+        let simbox = SimulationBox::cubic(2.5 * NM);
+        let positions = random_positions(&simbox, 100);
+
+        assert_eq!(
+            cell_list(&positions, &simbox, 1.0 * NM),
+            brute_force(&positions, &simbox, 1.0 * NM)
+        );
+        assert_eq!(
+            cell_list(&positions, &simbox, 0.0 * NM).len(),
+            100 * 99 / 2
+        );
+    }
+
+    #[test]
+    fn full_list_has_same_pairs_as_half_list() {
+        let simbox = SimulationBox::cubic(4.0 * NM);
+        let positions = random_positions(&simbox, 300);
+
+        let half = cell_list(&positions, &simbox, 1.0 * NM);
+        let full = half.to_full();
+        assert!(full.is_full());
+        assert_eq!(full.len(), half.len());
+        assert_eq!(
+            full.pairs().collect::<Vec<_>>(),
+            half.pairs().collect::<Vec<_>>()
+        );
+        for (i, js) in full.iter() {
+            for &j in js.iter() {
+                assert!(full.neighbors(j).contains(&i));
+            }
+        }
+    }
+
+    #[test]
+    fn verlet_list_keeps_exclusions() {
+        let simbox = SimulationBox::cubic(4.0 * NM);
+        let positions = random_positions(&simbox, 300);
+        let mut exclusions = vec![vec![]; positions.len()];
+        for i in (0..positions.len()).step_by(2) {
+            exclusions[i].push(i + 1);
+            exclusions[i + 1].push(i);
+        }
+
+        let mut verlet = VerletList::new(&positions, &simbox, 1.0 * NM, 0.1 * NM);
+        verlet.set_exclusions(exclusions.clone(), &positions, &simbox);
+        verlet.set_full(true);
+        verlet.rebuild(&positions, &simbox);
+
+        let expected = cell_list(&positions, &simbox, 1.1 * NM).exclude(&exclusions);
+        assert!(verlet.list().pairs().all(|(i, j)| !exclusions[i].contains(&j)));
+        assert_eq!(
+            verlet.list().pairs().collect::<Vec<_>>(),
+            expected.pairs().collect::<Vec<_>>()
+        );
+    }
+}
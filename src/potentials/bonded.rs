@@ -0,0 +1,489 @@
+//! Bonded interactions
+//!
+//! The bonds, angles, dihedrals, impropers and 1-4 pairs themselves are
+//! stored in the topology, each with its own parameters. The terms here
+//! evaluate every interaction of their kind in `Configuration::topology`,
+//! so they cost nothing when the corresponding list is empty.
+//!
+//! All distance vectors use the minimum image convention, so molecules
+//! may be split across the periodic boundary as long as no interaction
+//! spans more than half the box.
+//!
+//! Angles are in radians. Dihedrals follow the IUPAC convention, where a
+//! cis conformation has a dihedral angle of zero.
+
+use std::f32::consts::PI;
+
+use crate::dim::Sqrt;
+
+use crate::units::*;
+use crate::units::f32consts::*;
+use crate::geom::{
+    PosVec,
+    ForceVec,
+    EnergyTensor,
+    Tensor3D
+};
+use super::{
+    Potential,
+    Configuration
+};
+use super::nonbonded::lennard_jones;
+use rayon::prelude::*;
+
+/// A harmonic bond `V = ½ k (r - b0)²` between two atoms
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bond {
+    pub atoms: [usize; 2],
+    /// The equilibrium bond length `b0`
+    pub length: Nanometer<f32>,
+    pub force_constant: KilojoulePerMolePerNanometer2<f32>
+}
+
+/// A harmonic angle `V = ½ k (θ - θ0)²` between three atoms, with the
+/// vertex at the middle atom
+#[derive(Debug, Clone, PartialEq)]
+pub struct Angle {
+    pub atoms: [usize; 3],
+    /// The equilibrium angle `θ0` in radians
+    pub angle: f32,
+    /// The force constant `k` per radian squared
+    pub force_constant: KilojoulePerMole<f32>
+}
+
+/// A periodic proper dihedral `V = k (1 + cos(nφ - φs))` between four
+/// bonded atoms
+///
+/// Fourier series dihedrals are written as several `Dihedral`s on the
+/// same atoms with different multiplicities.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dihedral {
+    pub atoms: [usize; 4],
+    /// The phase `φs` in radians
+    pub phase: f32,
+    pub force_constant: KilojoulePerMole<f32>,
+    /// The multiplicity `n`
+    pub multiplicity: u32
+}
+
+/// A harmonic improper dihedral `V = ½ k (ξ - ξ0)²` between four atoms
+///
+/// The improper angle `ξ` is the dihedral angle between the planes of
+/// atoms `i`, `j`, `k` and `j`, `k`, `l`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Improper {
+    pub atoms: [usize; 4],
+    /// The equilibrium improper angle `ξ0` in radians
+    pub angle: f32,
+    /// The force constant `k` per radian squared
+    pub force_constant: KilojoulePerMole<f32>
+}
+
+/// A 1-4 pair, whose Lennard-Jones and Coulomb interactions are scaled by
+/// `Top::fudge_lj` and `Top::fudge_qq`
+///
+/// The pair should also be excluded from the non-bonded pairlist, so that
+/// it is only counted here.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pair {
+    pub atoms: [usize; 2]
+}
+
+/// The energy of one bonded interaction, and for each of its atoms the
+/// index, the position relative to a reference atom of the interaction,
+/// and the force on it
+type Terms<const N: usize> = (KilojoulePerMole<f32>, [(usize, PosVec, ForceVec); N]);
+
+fn bond_terms(config: &Configuration, bond: &Bond) -> Terms<2> {
+    let [i, j] = bond.atoms;
+    let (rij, r2) = config.dist2(i, j);
+    let r = r2.sqrt();
+    let dr = r - bond.length;
+
+    let energy = 0.5 * bond.force_constant * dr * dr;
+    let fi = rij.clone() / r * (-bond.force_constant * dr);
+    (energy, [
+        (i, PosVec::zero(), fi.clone()),
+        (j, -rij, -fi)
+    ])
+}
+
+fn pair_terms(config: &Configuration, pair: &Pair) -> Terms<2> {
+    let [i, j] = pair.atoms;
+    let top = config.topology;
+    let (rij, r2) = config.dist2(i, j);
+
+    let (c6, c12) = top.c6_c12(i, j);
+    let (lj_energy, lj_fscal) = lennard_jones(c6, c12, r2);
+
+    let qq = top.atoms[i].charge * top.atoms[j].charge;
+    let r = r2.sqrt();
+    let coul_energy = KE * qq / r;
+    let coul_fscal = coul_energy / r2;
+
+    let energy = lj_energy * top.fudge_lj + coul_energy * top.fudge_qq;
+    let fi = rij.clone() * (lj_fscal * top.fudge_lj + coul_fscal * top.fudge_qq);
+    (energy, [
+        (i, PosVec::zero(), fi.clone()),
+        (j, -rij, -fi)
+    ])
+}
+
+fn angle_terms(config: &Configuration, angle: &Angle) -> Terms<3> {
+    let [i, j, k] = angle.atoms;
+    let rij = config.displacement(i, j);
+    let rkj = config.displacement(k, j);
+    let nij = rij.norm();
+    let nkj = rkj.norm();
+
+    let cos = ((rij.clone() * rkj.clone()) / (nij * nkj)).value_unsafe.clamp(-1.0, 1.0);
+    let theta = cos.acos();
+    // Keep the force finite for linear angles
+    let sin = theta.sin().max(1e-8);
+    let dtheta = theta - angle.angle;
+
+    let energy = 0.5 * angle.force_constant * dtheta * dtheta;
+    // dV/dθ / sin θ, as -dθ/dr = (1 / sin θ) dcos θ/dr
+    let prefactor = angle.force_constant * dtheta / sin;
+    let fi = (rkj.clone() / (nij * nkj) - rij.clone() * cos / (nij * nij)) * prefactor;
+    let fk = (rij.clone() / (nij * nkj) - rkj.clone() * cos / (nkj * nkj)) * prefactor;
+    let fj = -(fi.clone() + fk.clone());
+    (energy, [
+        (i, rij, fi),
+        (j, PosVec::zero(), fj),
+        (k, rkj, fk)
+    ])
+}
+
+/// The forces on four atoms from a potential of their dihedral angle,
+/// given `dV/dφ`. Uses the formulation of Bekker, also used in GROMACS.
+fn torsion_terms(
+    config: &Configuration,
+    atoms: [usize; 4],
+    potential: impl Fn(f32) -> (KilojoulePerMole<f32>, KilojoulePerMole<f32>)
+) -> Terms<4> {
+    let [i, j, k, l] = atoms;
+    let rij = config.displacement(i, j);
+    let rkj = config.displacement(k, j);
+    let rkl = config.displacement(k, l);
+
+    let m = rij.clone() % rkj.clone();
+    let n = rkj.clone() % rkl.clone();
+    let m2 = m.norm2();
+    let n2 = n.norm2();
+    let rkj2 = rkj.norm2();
+    let nkj = rkj2.sqrt();
+
+    let cos = ((m.clone() * n.clone()) / (m2 * n2).sqrt()).value_unsafe.clamp(-1.0, 1.0);
+    let sign = if (rij.clone() * n.clone()).value_unsafe < 0.0 { -1.0 } else { 1.0 };
+    let phi = sign * cos.acos();
+
+    let (energy, ddphi) = potential(phi);
+
+    let fi = m * (-ddphi * nkj / m2);
+    let fl = n * (ddphi * nkj / n2);
+    let p = (rij.clone() * rkj.clone()) / rkj2;
+    let q = (rkl.clone() * rkj.clone()) / rkj2;
+    let s = fi.clone() * p - fl.clone() * q;
+    let fj = s.clone() - fi.clone();
+    let fk = -(s + fl.clone());
+    (energy, [
+        (i, rij, fi),
+        (j, PosVec::zero(), fj),
+        (k, rkj.clone(), fk),
+        (l, rkj - rkl, fl)
+    ])
+}
+
+fn dihedral_terms(config: &Configuration, dihedral: &Dihedral) -> Terms<4> {
+    torsion_terms(config, dihedral.atoms, |phi| {
+        let n = dihedral.multiplicity as f32;
+        let arg = n * phi - dihedral.phase;
+        let energy = dihedral.force_constant * (1.0 + arg.cos());
+        let ddphi = -dihedral.force_constant * n * arg.sin();
+        (energy, ddphi)
+    })
+}
+
+fn improper_terms(config: &Configuration, improper: &Improper) -> Terms<4> {
+    torsion_terms(config, improper.atoms, |xi| {
+        // Take the difference the short way around the circle
+        let mut dxi = xi - improper.angle;
+        if dxi >= PI {
+            dxi -= 2.0 * PI;
+        } else if dxi < -PI {
+            dxi += 2.0 * PI;
+        }
+        let energy = 0.5 * improper.force_constant * dxi * dxi;
+        let ddxi = improper.force_constant * dxi;
+        (energy, ddxi)
+    })
+}
+
+fn bonded_energy<I: Sync, const N: usize>(
+    interactions: &[I],
+    config: &Configuration,
+    terms: fn(&Configuration, &I) -> Terms<N>
+) -> KilojoulePerMole<f32> {
+    interactions.par_iter()
+        .map(|interaction| terms(config, interaction).0)
+        .reduce(
+            || 0.0 * KJPM,
+            |acc, e| acc + e
+        )
+}
+
+fn bonded_forces<I, const N: usize>(
+    interactions: &[I],
+    config: &Configuration,
+    forces: &mut [ForceVec],
+    terms: fn(&Configuration, &I) -> Terms<N>
+) {
+    for interaction in interactions.iter() {
+        for (i, _, f) in terms(config, interaction).1.iter() {
+            forces[*i] += f.clone();
+        }
+    }
+}
+
+fn bonded_forces_and_virial<I, const N: usize>(
+    interactions: &[I],
+    config: &Configuration,
+    forces: &mut [ForceVec],
+    terms: fn(&Configuration, &I) -> Terms<N>
+) -> EnergyTensor {
+    let mut virial = EnergyTensor::zero();
+    for interaction in interactions.iter() {
+        for (i, r, f) in terms(config, interaction).1.iter() {
+            virial += Tensor3D::outer(r, f) * -0.5;
+            forces[*i] += f.clone();
+        }
+    }
+    virial
+}
+
+fn bonded_virial<I: Sync, const N: usize>(
+    interactions: &[I],
+    config: &Configuration,
+    terms: fn(&Configuration, &I) -> Terms<N>
+) -> EnergyTensor {
+    // The forces of each interaction sum to zero, so the virial can be
+    // taken relative to any of its atoms
+    interactions.par_iter()
+        .map(|interaction| {
+            terms(config, interaction).1.iter()
+                .fold(
+                    EnergyTensor::zero(),
+                    |acc, (_, r, f)| acc + Tensor3D::outer(r, f) * -0.5
+                )
+        }).reduce(
+            EnergyTensor::zero,
+            |acc, w| acc + w
+        )
+}
+
+/// Harmonic bonds from `Top::bonds`
+#[derive(Debug, Clone, Default)]
+pub struct HarmonicBonds;
+
+impl Potential for HarmonicBonds {
+    fn energy(&self, config: &Configuration) -> KilojoulePerMole<f32> {
+        bonded_energy(&config.topology.bonds, config, bond_terms)
+    }
+
+    fn forces(&self, config: &Configuration, forces: &mut [ForceVec]) {
+        bonded_forces(&config.topology.bonds, config, forces, bond_terms)
+    }
+
+    fn virial(&self, config: &Configuration) -> Option<EnergyTensor> {
+        Some(bonded_virial(&config.topology.bonds, config, bond_terms))
+    }
+
+    fn forces_and_virial(&self, config: &Configuration, forces: &mut [ForceVec]) -> Option<EnergyTensor> {
+        Some(bonded_forces_and_virial(&config.topology.bonds, config, forces, bond_terms))
+    }
+}
+
+/// Scaled Lennard-Jones and Coulomb interactions of the 1-4 pairs in
+/// `Top::pairs`, which are not cut off
+#[derive(Debug, Clone, Default)]
+pub struct OneFourPairs;
+
+impl Potential for OneFourPairs {
+    fn energy(&self, config: &Configuration) -> KilojoulePerMole<f32> {
+        bonded_energy(&config.topology.pairs, config, pair_terms)
+    }
+
+    fn forces(&self, config: &Configuration, forces: &mut [ForceVec]) {
+        bonded_forces(&config.topology.pairs, config, forces, pair_terms)
+    }
+
+    fn virial(&self, config: &Configuration) -> Option<EnergyTensor> {
+        Some(bonded_virial(&config.topology.pairs, config, pair_terms))
+    }
+
+    fn forces_and_virial(&self, config: &Configuration, forces: &mut [ForceVec]) -> Option<EnergyTensor> {
+        Some(bonded_forces_and_virial(&config.topology.pairs, config, forces, pair_terms))
+    }
+}
+
+/// Harmonic angles from `Top::angles`
+#[derive(Debug, Clone, Default)]
+pub struct HarmonicAngles;
+
+impl Potential for HarmonicAngles {
+    fn energy(&self, config: &Configuration) -> KilojoulePerMole<f32> {
+        bonded_energy(&config.topology.angles, config, angle_terms)
+    }
+
+    fn forces(&self, config: &Configuration, forces: &mut [ForceVec]) {
+        bonded_forces(&config.topology.angles, config, forces, angle_terms)
+    }
+
+    fn virial(&self, config: &Configuration) -> Option<EnergyTensor> {
+        Some(bonded_virial(&config.topology.angles, config, angle_terms))
+    }
+
+    fn forces_and_virial(&self, config: &Configuration, forces: &mut [ForceVec]) -> Option<EnergyTensor> {
+        Some(bonded_forces_and_virial(&config.topology.angles, config, forces, angle_terms))
+    }
+}
+
+/// Periodic proper dihedrals from `Top::dihedrals`
+#[derive(Debug, Clone, Default)]
+pub struct PeriodicDihedrals;
+
+impl Potential for PeriodicDihedrals {
+    fn energy(&self, config: &Configuration) -> KilojoulePerMole<f32> {
+        bonded_energy(&config.topology.dihedrals, config, dihedral_terms)
+    }
+
+    fn forces(&self, config: &Configuration, forces: &mut [ForceVec]) {
+        bonded_forces(&config.topology.dihedrals, config, forces, dihedral_terms)
+    }
+
+    fn virial(&self, config: &Configuration) -> Option<EnergyTensor> {
+        Some(bonded_virial(&config.topology.dihedrals, config, dihedral_terms))
+    }
+
+    fn forces_and_virial(&self, config: &Configuration, forces: &mut [ForceVec]) -> Option<EnergyTensor> {
+        Some(bonded_forces_and_virial(&config.topology.dihedrals, config, forces, dihedral_terms))
+    }
+}
+
+/// Harmonic improper dihedrals from `Top::impropers`
+#[derive(Debug, Clone, Default)]
+pub struct HarmonicImpropers;
+
+impl Potential for HarmonicImpropers {
+    fn energy(&self, config: &Configuration) -> KilojoulePerMole<f32> {
+        bonded_energy(&config.topology.impropers, config, improper_terms)
+    }
+
+    fn forces(&self, config: &Configuration, forces: &mut [ForceVec]) {
+        bonded_forces(&config.topology.impropers, config, forces, improper_terms)
+    }
+
+    fn virial(&self, config: &Configuration) -> Option<EnergyTensor> {
+        Some(bonded_virial(&config.topology.impropers, config, improper_terms))
+    }
+
+    fn forces_and_virial(&self, config: &Configuration, forces: &mut [ForceVec]) -> Option<EnergyTensor> {
+        Some(bonded_forces_and_virial(&config.topology.impropers, config, forces, improper_terms))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::SimulationBox;
+    use crate::pairlist::NeighborList;
+    use crate::topology::{Top, AtomType};
+    use crate::potentials::tests::assert_forces_match_energy;
+
+    /// A butane-like chain that crosses the periodic boundary
+    fn chain() -> (Top, Vec<PosVec>, SimulationBox) {
+        let mut top = Top::gen_lj_fluid(4, 12.0 * DA, 0.0 * KJPM, 0.3 * NM);
+        top.bonds = (0..3).map(|i| Bond {
+            atoms: [i, i + 1],
+            length: 0.153 * NM,
+            force_constant: 2.5e5 * KJPM / NM2
+        }).collect();
+        top.angles = (0..2).map(|i| Angle {
+            atoms: [i, i + 1, i + 2],
+            angle: 1.95,
+            force_constant: 400.0 * KJPM
+        }).collect();
+        top.dihedrals = vec![
+            Dihedral { atoms: [0, 1, 2, 3], phase: 0.0, force_constant: 5.0 * KJPM, multiplicity: 3 },
+            Dihedral { atoms: [0, 1, 2, 3], phase: PI, force_constant: 2.0 * KJPM, multiplicity: 1 },
+        ];
+        top.impropers = vec![
+            Improper { atoms: [0, 1, 2, 3], angle: 0.5, force_constant: 40.0 * KJPM }
+        ];
+        let positions = vec![
+            PosVec::from(1.95, 1.0, 1.0),
+            PosVec::from(0.06, 1.08, 1.02),
+            PosVec::from(0.18, 0.99, 1.09),
+            PosVec::from(0.27, 1.10, 1.15),
+        ];
+        (top, positions, SimulationBox::cubic(2.0 * NM))
+    }
+
+    #[test]
+    fn bonded_forces_match_energy() {
+        let (top, positions, simbox) = chain();
+        let list = NeighborList::empty(positions.len());
+        let config = top.configuration(&positions, &list, &simbox);
+
+        assert_forces_match_energy(&HarmonicBonds, &config, 2e-2);
+        assert_forces_match_energy(&HarmonicAngles, &config, 2e-2);
+        assert_forces_match_energy(&PeriodicDihedrals, &config, 2e-2);
+        assert_forces_match_energy(&HarmonicImpropers, &config, 2e-2);
+    }
+
+    #[test]
+    fn one_four_forces_match_energy() {
+        let (mut top, positions, simbox) = chain();
+        let end = AtomType { name: "END".to_string(), epsilon: 0.5 * KJPM, ..top.atom_types().get(0).clone() };
+        let end = top.add_atom_type(end);
+        top.atoms[0].atom_type = end;
+        top.atoms[3].atom_type = end;
+        top.atoms[0].charge = 0.4 * E;
+        top.atoms[3].charge = -0.3 * E;
+        top.fudge_lj = 0.5;
+        top.fudge_qq = 0.8333;
+        top.pairs = vec![Pair { atoms: [0, 3] }];
+        let list = NeighborList::empty(positions.len());
+        let config = top.configuration(&positions, &list, &simbox);
+
+        assert_forces_match_energy(&OneFourPairs, &config, 2e-2);
+    }
+
+    #[test]
+    fn dihedral_follows_iupac_convention() {
+        let (mut top, _, simbox) = chain();
+        top.dihedrals = vec![
+            Dihedral { atoms: [0, 1, 2, 3], phase: 0.0, force_constant: 1.0 * KJPM, multiplicity: 1 }
+        ];
+        let list = NeighborList::empty(4);
+
+        let cis = vec![
+            PosVec::from(0.5, 0.6, 0.5),
+            PosVec::from(0.5, 0.5, 0.5),
+            PosVec::from(0.6, 0.5, 0.5),
+            PosVec::from(0.6, 0.6, 0.5),
+        ];
+        let energy = PeriodicDihedrals.energy(&top.configuration(&cis, &list, &simbox));
+        assert!((energy - 2.0 * KJPM).value_unsafe.abs() < 1e-5);
+
+        let trans = vec![
+            PosVec::from(0.5, 0.6, 0.5),
+            PosVec::from(0.5, 0.5, 0.5),
+            PosVec::from(0.6, 0.5, 0.5),
+            PosVec::from(0.6, 0.4, 0.5),
+        ];
+        let energy = PeriodicDihedrals.energy(&top.configuration(&trans, &list, &simbox));
+        assert!(energy.value_unsafe.abs() < 1e-5);
+    }
+}
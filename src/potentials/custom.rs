@@ -0,0 +1,639 @@
+//! Custom pair potentials from algebraic expressions
+//!
+//! `CustomPair` is a non-bonded pair potential whose energy is given as a
+//! string, like OpenMM's `CustomNonbondedForce`. The expression is parsed
+//! once into an `Expression`, and forces come from differentiating it
+//! with forward mode automatic differentiation, so they are always
+//! consistent with the energy. It is summed over the same pairlist and
+//! cutoff as `LennardJones`.
+
+use std::fmt;
+use std::error;
+use std::collections::HashMap;
+
+use crate::units::*;
+use crate::units::f32consts::*;
+use crate::geom::{
+    ForceVec,
+    EnergyTensor
+};
+use crate::special;
+use crate::topology::Top;
+use super::{
+    Potential,
+    Configuration
+};
+use super::nonbonded::{
+    PairInteraction,
+    pair_energy,
+    pair_forces,
+    pair_virial,
+    pair_forces_and_virial
+};
+
+/// An error in the syntax of an expression, or a name it doesn't know
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpressionError {
+    /// The expression at fault
+    pub expression: String,
+    /// The character offset of the problem in the expression
+    pub position: usize,
+    pub message: String
+}
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at position {} of \"{}\"", self.message, self.position, self.expression)
+    }
+}
+
+impl error::Error for ExpressionError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    Sqrt,
+    Exp,
+    Log,
+    Sin,
+    Cos,
+    Tanh,
+    Abs,
+    Erf,
+    Erfc
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Function> {
+        Some(match name {
+            "sqrt" => Function::Sqrt,
+            "exp" => Function::Exp,
+            "log" => Function::Log,
+            "sin" => Function::Sin,
+            "cos" => Function::Cos,
+            "tanh" => Function::Tanh,
+            "abs" => Function::Abs,
+            "erf" => Function::Erf,
+            "erfc" => Function::Erfc,
+            _ => return None
+        })
+    }
+
+    /// The function and its derivative at `x`
+    fn apply(self, x: f64) -> (f64, f64) {
+        let gaussian = || 2.0 / std::f64::consts::PI.sqrt() * (-x * x).exp();
+        match self {
+            Function::Sqrt => (x.sqrt(), 0.5 / x.sqrt()),
+            Function::Exp => (x.exp(), x.exp()),
+            Function::Log => (x.ln(), 1.0 / x),
+            Function::Sin => (x.sin(), x.cos()),
+            Function::Cos => (x.cos(), -x.sin()),
+            Function::Tanh => (x.tanh(), 1.0 - x.tanh() * x.tanh()),
+            Function::Abs => (x.abs(), x.signum()),
+            Function::Erf => (1.0 - special::erfc(x), gaussian()),
+            Function::Erfc => (special::erfc(x), -gaussian())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Constant(f64),
+    Variable(usize),
+    Neg(Box<Node>),
+    Add(Box<Node>, Box<Node>),
+    Sub(Box<Node>, Box<Node>),
+    Mul(Box<Node>, Box<Node>),
+    Div(Box<Node>, Box<Node>),
+    Pow(Box<Node>, Box<Node>),
+    Call(Function, Box<Node>)
+}
+
+impl Node {
+    /// The value of the node and its derivative with respect to the
+    /// variable `wrt`
+    fn eval(&self, values: &[f64], wrt: Option<usize>) -> (f64, f64) {
+        match self {
+            Node::Constant(c) => (*c, 0.0),
+            Node::Variable(v) => (values[*v], if Some(*v) == wrt { 1.0 } else { 0.0 }),
+            Node::Neg(a) => {
+                let (a, da) = a.eval(values, wrt);
+                (-a, -da)
+            },
+            Node::Add(a, b) => {
+                let ((a, da), (b, db)) = (a.eval(values, wrt), b.eval(values, wrt));
+                (a + b, da + db)
+            },
+            Node::Sub(a, b) => {
+                let ((a, da), (b, db)) = (a.eval(values, wrt), b.eval(values, wrt));
+                (a - b, da - db)
+            },
+            Node::Mul(a, b) => {
+                let ((a, da), (b, db)) = (a.eval(values, wrt), b.eval(values, wrt));
+                (a * b, da * b + a * db)
+            },
+            Node::Div(a, b) => {
+                let ((a, da), (b, db)) = (a.eval(values, wrt), b.eval(values, wrt));
+                (a / b, (da * b - a * db) / (b * b))
+            },
+            Node::Pow(a, b) => {
+                let (a, da) = a.eval(values, wrt);
+                match **b {
+                    // Integer powers are common and work for negative bases
+                    Node::Constant(n) if n.fract() == 0.0 && n.abs() < 64.0 => {
+                        let n = n as i32;
+                        (a.powi(n), f64::from(n) * a.powi(n - 1) * da)
+                    },
+                    _ => {
+                        let (b, db) = b.eval(values, wrt);
+                        let value = a.powf(b);
+                        let derivative = if db == 0.0 {
+                            b * a.powf(b - 1.0) * da
+                        } else {
+                            value * (db * a.ln() + b * da / a)
+                        };
+                        (value, derivative)
+                    }
+                }
+            },
+            Node::Call(function, a) => {
+                let (a, da) = a.eval(values, wrt);
+                let (value, derivative) = function.apply(a);
+                (value, derivative * da)
+            }
+        }
+    }
+}
+
+/// A parsed algebraic expression
+///
+/// Expressions use numbers, named variables, `+`, `-`, `*`, `/`, `^` for
+/// powers, parentheses, the constant `pi` and the functions `sqrt`, `exp`,
+/// `log`, `sin`, `cos`, `tanh`, `abs`, `erf` and `erfc`. Powers bind
+/// tighter than negation and associate to the right, so `-2^2` is `-4`
+/// and `2^3^2` is `512`.
+///
+/// # Examples
+///
+/// ```
+/// use noether::potentials::custom::Expression;
+///
+/// let expr = Expression::parse("4*eps*((sig/r)^12 - (sig/r)^6)", &["r", "eps", "sig"]).unwrap();
+/// let (energy, derivative) = expr.eval_derivative(&[2f64.powf(1.0 / 6.0), 1.0, 1.0], 0);
+/// assert!((energy + 1.0).abs() < 1e-12);
+/// assert!(derivative.abs() < 1e-12);
+///
+/// let error = Expression::parse("2 * (r + x", &["r"]).unwrap_err();
+/// assert_eq!(error.to_string(), "unknown variable \"x\" at position 9 of \"2 * (r + x\"");
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    root: Node,
+    n_variables: usize
+}
+
+impl Expression {
+    /// Parse `text`, which may use the variables in `variables`. When the
+    /// expression is evaluated, the values of the variables are given in
+    /// the same order.
+    pub fn parse(text: &str, variables: &[&str]) -> Result<Expression, ExpressionError> {
+        let mut parser = Parser {
+            text,
+            chars: text.chars().collect(),
+            pos: 0,
+            variables
+        };
+        let root = parser.sum()?;
+        parser.skip_whitespace();
+        if parser.pos < parser.chars.len() {
+            return Err(parser.error(parser.pos, "unexpected character"));
+        }
+        Ok(Expression { root, n_variables: variables.len() })
+    }
+
+    /// The value of the expression
+    pub fn eval(&self, values: &[f64]) -> f64 {
+        assert_eq!(values.len(), self.n_variables);
+        self.root.eval(values, None).0
+    }
+
+    /// The value of the expression and its derivative with respect to
+    /// the variable with index `wrt`
+    pub fn eval_derivative(&self, values: &[f64], wrt: usize) -> (f64, f64) {
+        assert_eq!(values.len(), self.n_variables);
+        self.root.eval(values, Some(wrt))
+    }
+}
+
+/// A recursive descent parser with one function per precedence level
+struct Parser<'a> {
+    text: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+    variables: &'a [&'a str]
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, position: usize, message: &str) -> ExpressionError {
+        ExpressionError {
+            expression: self.text.to_string(),
+            position,
+            message: message.to_string()
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    /// Consume `c` if it is the next non-whitespace character
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.chars.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn sum(&mut self) -> Result<Node, ExpressionError> {
+        let mut node = self.product()?;
+        loop {
+            if self.eat('+') {
+                node = Node::Add(Box::new(node), Box::new(self.product()?));
+            } else if self.eat('-') {
+                node = Node::Sub(Box::new(node), Box::new(self.product()?));
+            } else {
+                return Ok(node);
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<Node, ExpressionError> {
+        let mut node = self.unary()?;
+        loop {
+            if self.eat('*') {
+                node = Node::Mul(Box::new(node), Box::new(self.unary()?));
+            } else if self.eat('/') {
+                node = Node::Div(Box::new(node), Box::new(self.unary()?));
+            } else {
+                return Ok(node);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, ExpressionError> {
+        if self.eat('-') {
+            Ok(Node::Neg(Box::new(self.unary()?)))
+        } else if self.eat('+') {
+            self.unary()
+        } else {
+            self.power()
+        }
+    }
+
+    fn power(&mut self) -> Result<Node, ExpressionError> {
+        let base = self.atom()?;
+        if self.eat('^') {
+            Ok(Node::Pow(Box::new(base), Box::new(self.unary()?)))
+        } else {
+            Ok(base)
+        }
+    }
+
+    fn atom(&mut self) -> Result<Node, ExpressionError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        match self.chars.get(self.pos) {
+            Some('(') => {
+                self.pos += 1;
+                let node = self.sum()?;
+                if !self.eat(')') {
+                    return Err(self.error(self.pos, "expected \")\""));
+                }
+                Ok(node)
+            },
+            Some(c) if c.is_ascii_digit() || *c == '.' => {
+                while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_digit() || *c == '.') {
+                    self.pos += 1;
+                }
+                // An exponent, which may have a sign
+                if self.chars.get(self.pos).is_some_and(|c| *c == 'e' || *c == 'E') {
+                    let mut end = self.pos + 1;
+                    if self.chars.get(end).is_some_and(|c| *c == '+' || *c == '-') {
+                        end += 1;
+                    }
+                    if self.chars.get(end).is_some_and(|c| c.is_ascii_digit()) {
+                        self.pos = end;
+                        while self.chars.get(self.pos).is_some_and(|c| c.is_ascii_digit()) {
+                            self.pos += 1;
+                        }
+                    }
+                }
+                let number: String = self.chars[start..self.pos].iter().collect();
+                number.parse()
+                    .map(Node::Constant)
+                    .map_err(|_| self.error(start, "invalid number"))
+            },
+            Some(c) if c.is_alphabetic() || *c == '_' => {
+                while self.chars.get(self.pos).is_some_and(|c| c.is_alphanumeric() || *c == '_') {
+                    self.pos += 1;
+                }
+                let name: String = self.chars[start..self.pos].iter().collect();
+
+                if let Some(index) = self.variables.iter().position(|v| *v == name) {
+                    Ok(Node::Variable(index))
+                } else if let Some(function) = Function::from_name(&name) {
+                    if !self.eat('(') {
+                        return Err(self.error(self.pos, "expected \"(\""));
+                    }
+                    let argument = self.sum()?;
+                    if !self.eat(')') {
+                        return Err(self.error(self.pos, "expected \")\""));
+                    }
+                    Ok(Node::Call(function, Box::new(argument)))
+                } else if name == "pi" {
+                    Ok(Node::Constant(std::f64::consts::PI))
+                } else {
+                    Err(self.error(start, &format!("unknown variable \"{}\"", name)))
+                }
+            },
+            Some(_) => Err(self.error(start, "unexpected character")),
+            None => Err(self.error(start, "unexpected end of expression"))
+        }
+    }
+}
+
+/// A pair potential given by an expression in the distance `r`
+///
+/// Each atom has a value for every per-atom parameter. The energy
+/// expression can use a parameter `p` of the two atoms as `p1` and `p2`,
+/// and can also use combined parameters, which are defined by their own
+/// expressions in the per-atom parameters of the two atoms. Distances are
+/// in nm and energies in kJ/mol, and the parameters must be in units
+/// consistent with them.
+///
+/// Like `LennardJones`, the potential is summed over the pairlist and
+/// truncated at `Top::lj_cutoff`, but it is not affected by
+/// `Top::lj_modifier`; any shift must be written into the expression.
+///
+/// # Examples
+///
+/// ```
+/// use noether::potentials::custom::CustomPair;
+/// use noether::topology::Top;
+/// use noether::units::f32consts::*;
+///
+/// let lj = CustomPair::new(
+///     "4*eps*((sig/r)^12 - (sig/r)^6)",
+///     &[("eps", "sqrt(eps1*eps2)"), ("sig", "(sig1 + sig2)/2")],
+///     vec![("eps", vec![1.0, 0.5]), ("sig", vec![0.3, 0.4])]
+/// ).unwrap();
+/// assert_eq!(lj.n_atoms(), 2);
+///
+/// // The topology must have an atom for every value
+/// let mut top = Top::gen_lj_fluid(3, 40.0 * DA, 1.0 * KJPM, 0.34 * NM);
+/// let error = top.add_potential(lj).unwrap_err();
+/// assert_eq!(error, "CustomPair has parameters for 2 atoms, but the topology has 3");
+///
+/// // Unknown names are reported when the potential is created
+/// let error = CustomPair::new("eps/r", &[], vec![]).unwrap_err();
+/// assert_eq!(error.message, "unknown variable \"eps\"");
+/// ```
+#[derive(Debug, Clone)]
+pub struct CustomPair {
+    /// In terms of `r`, then the combined parameters, then the per-atom
+    /// parameters of each atom
+    energy: Expression,
+    /// The number of variables of `energy`
+    n_variables: usize,
+    /// Which distinct set of per-atom parameters each atom has
+    classes: Vec<usize>,
+    n_classes: usize,
+    /// The variables of `energy` for each pair of classes, `n_variables`
+    /// at a time, with zero in place of `r`
+    pair_values: Vec<f64>
+}
+
+/// The most variables the energy expression of a `CustomPair` can have,
+/// so they fit in a buffer on the stack
+const MAX_VARIABLES: usize = 32;
+
+impl CustomPair {
+    /// A pair potential with `energy` as a function of `r`, the
+    /// `combining` parameters, given as pairs of name and expression, and
+    /// the per-atom `parameters`, given as pairs of name and values.
+    ///
+    /// The combined parameters are calculated here, once for each pair of
+    /// atoms with distinct per-atom parameters. Panics if the per-atom
+    /// parameters have different numbers of values.
+    pub fn new(
+        energy: &str,
+        combining: &[(&str, &str)],
+        parameters: Vec<(&str, Vec<f32>)>
+    ) -> Result<CustomPair, ExpressionError> {
+        let n_atoms = parameters.first().map_or(0, |(_, values)| values.len());
+        if parameters.iter().any(|(_, values)| values.len() != n_atoms) {
+            panic!("Every per-atom parameter needs a value for every atom!");
+        }
+
+        let per_atom: Vec<String> = [1, 2].iter()
+            .flat_map(|n| parameters.iter().map(move |(name, _)| format!("{}{}", name, n)))
+            .collect();
+        let per_atom: Vec<&str> = per_atom.iter().map(String::as_str).collect();
+
+        let variables: Vec<&str> = std::iter::once("r")
+            .chain(combining.iter().map(|&(name, _)| name))
+            .chain(per_atom.iter().cloned())
+            .collect();
+        if variables.len() > MAX_VARIABLES {
+            return Err(ExpressionError {
+                expression: energy.to_string(),
+                position: 0,
+                message: format!("more than {} variables", MAX_VARIABLES)
+            });
+        }
+
+        let energy = Expression::parse(energy, &variables)?;
+        let combining: Vec<Expression> = combining.iter()
+            .map(|&(_, expr)| Expression::parse(expr, &per_atom))
+            .collect::<Result<_, _>>()?;
+
+        // Atoms with the same parameters share a class
+        let mut class_parameters: Vec<Vec<f64>> = vec![];
+        let mut lookup: HashMap<Vec<u32>, usize> = HashMap::new();
+        let classes = (0..n_atoms)
+            .map(|i| {
+                let values: Vec<f32> = parameters.iter().map(|(_, values)| values[i]).collect();
+                let key = values.iter().map(|v| v.to_bits()).collect();
+                *lookup.entry(key).or_insert_with(|| {
+                    class_parameters.push(values.into_iter().map(f64::from).collect());
+                    class_parameters.len() - 1
+                })
+            }).collect();
+
+        let n_classes = class_parameters.len();
+        let mut pair_values = Vec::with_capacity(n_classes * n_classes * variables.len());
+        for a in class_parameters.iter() {
+            for b in class_parameters.iter() {
+                let per_atom: Vec<f64> = a.iter().chain(b.iter()).cloned().collect();
+                pair_values.push(0.0);
+                pair_values.extend(combining.iter().map(|expr| expr.eval(&per_atom)));
+                pair_values.extend(per_atom);
+            }
+        }
+
+        Ok(CustomPair {
+            energy,
+            n_variables: variables.len(),
+            classes,
+            n_classes,
+            pair_values
+        })
+    }
+
+    /// The number of atoms with per-atom parameters
+    pub fn n_atoms(&self) -> usize {
+        self.classes.len()
+    }
+}
+
+impl PairInteraction for CustomPair {
+    fn interaction(
+        &self,
+        _config: &Configuration,
+        i: usize,
+        j: usize,
+        r2: Nanometer2<f32>
+    ) -> (KilojoulePerMole<f32>, KilojoulePerMolePerNanometer2<f32>) {
+        let n = self.n_variables;
+        let pair = self.classes[i] * self.n_classes + self.classes[j];
+        let mut values = [0.0; MAX_VARIABLES];
+        values[..n].copy_from_slice(&self.pair_values[pair * n..(pair + 1) * n]);
+
+        let r = f64::from(r2.value_unsafe).sqrt();
+        values[0] = r;
+
+        let (energy, derivative) = self.energy.eval_derivative(&values[..n], 0);
+        (energy as f32 * KJPM, (-derivative / r) as f32 * KJPMNM2)
+    }
+}
+
+impl Potential for CustomPair {
+    fn check(&self, topology: &Top) -> Result<(), String> {
+        if self.n_atoms() != topology.atoms.len() {
+            return Err(format!(
+                "CustomPair has parameters for {} atoms, but the topology has {}",
+                self.n_atoms(), topology.atoms.len()
+            ));
+        }
+        Ok(())
+    }
+
+    fn energy(&self, config: &Configuration) -> KilojoulePerMole<f32> {
+        pair_energy(self, config)
+    }
+
+    fn forces(&self, config: &Configuration, forces: &mut [ForceVec]) {
+        pair_forces(self, config, forces)
+    }
+
+    fn virial(&self, config: &Configuration) -> Option<EnergyTensor> {
+        Some(pair_virial(self, config))
+    }
+
+    fn forces_and_virial(&self, config: &Configuration, forces: &mut [ForceVec]) -> Option<EnergyTensor> {
+        Some(pair_forces_and_virial(self, config, forces))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::topology::AtomType;
+    use crate::potentials::nonbonded::LennardJones;
+    use crate::potentials::tests::{assert_forces_match_energy, four_atoms};
+
+    #[test]
+    fn expressions_follow_precedence() {
+        let eval = |text| Expression::parse(text, &["x"]).unwrap().eval(&[3.0]);
+        assert_eq!(eval("1 + 2 * x"), 7.0);
+        assert_eq!(eval("-2^2"), -4.0);
+        assert_eq!(eval("2^3^2"), 512.0);
+        assert_eq!(eval("(1 + 2) * x / 9"), 1.0);
+        assert_eq!(eval("x - 1 - 1"), 1.0);
+        assert_eq!(eval("2.5e-1 * 4E1"), 10.0);
+        assert_eq!(eval("abs(-x) * cos(0)"), 3.0);
+        assert!((eval("erf(x) + erfc(x)") - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn derivatives_match_finite_differences() {
+        let exprs = [
+            "x^3 - 2*x",
+            "sqrt(x) * exp(-x/2)",
+            "log(x) / (1 + x^2)",
+            "sin(x)^2 + tanh(x)",
+            "erfc(0.8*x) / x",
+            "x^x",
+            "2^(x/3)"
+        ];
+        for text in exprs.iter() {
+            let expr = Expression::parse(text, &["x"]).unwrap();
+            for &x in [0.3, 1.1, 2.7].iter() {
+                let h = 1e-6;
+                let numeric = (expr.eval(&[x + h]) - expr.eval(&[x - h])) / (2.0 * h);
+                let (_, analytic) = expr.eval_derivative(&[x], 0);
+                assert!((numeric - analytic).abs() < 1e-6 * (1.0 + analytic.abs()), "{} at {}", text, x);
+            }
+        }
+    }
+
+    #[test]
+    fn parse_errors_give_position() {
+        let error = |text| Expression::parse(text, &["r"]).unwrap_err();
+        assert_eq!(error("r +").position, 3);
+        assert_eq!(error("r +").message, "unexpected end of expression");
+        assert_eq!(error("(r * 2").message, "expected \")\"");
+        assert_eq!(error("r r").position, 2);
+        assert_eq!(error("sqrt r").message, "expected \"(\"");
+        assert_eq!(error("2 * $").position, 4);
+    }
+
+    #[test]
+    fn custom_lj_matches_built_in() {
+        let (mut top, positions, simbox, list) = four_atoms();
+        let lj = top.atom_types().get(0).clone();
+        let b = top.add_atom_type(AtomType { name: "B".to_string(), epsilon: 0.4 * KJPM, sigma: 0.3 * NM, ..lj.clone() });
+        let c = top.add_atom_type(AtomType { name: "C".to_string(), sigma: 0.38 * NM, ..lj });
+        top.atoms[1].atom_type = b;
+        top.atoms[2].atom_type = c;
+        let config = top.configuration(&positions, &list, &simbox);
+
+        let lj = CustomPair::new(
+            "4*eps*((sig/r)^12 - (sig/r)^6)",
+            &[("eps", "sqrt(eps1*eps2)"), ("sig", "(sig1 + sig2)/2")],
+            vec![
+                ("eps", top.atoms.iter().map(|a| top.atom_types().get(a.atom_type).epsilon.value_unsafe).collect()),
+                ("sig", top.atoms.iter().map(|a| top.atom_types().get(a.atom_type).sigma.value_unsafe).collect())
+            ]
+        ).unwrap();
+
+        let expected = LennardJones.energy(&config);
+        let energy = lj.energy(&config);
+        assert!(((energy - expected) / expected).value_unsafe.abs() < 1e-4);
+
+        let mut expected = vec![ForceVec::zero(); 4];
+        LennardJones.forces(&config, &mut expected);
+        let mut forces = vec![ForceVec::zero(); 4];
+        lj.forces(&config, &mut forces);
+        for (a, b) in forces.iter().zip(expected.iter()) {
+            let diff = (a.clone() - b.clone()).norm().value_unsafe;
+            assert!(diff < 1e-3 * (1.0 + b.norm().value_unsafe));
+        }
+
+        assert_forces_match_energy(&lj, &config, 1e-2);
+    }
+}
@@ -0,0 +1,754 @@
+//! Long-range electrostatics with Ewald summation
+//!
+//! The Coulomb interaction is split into a short-ranged real-space part,
+//! `erfc(αr)/r`, which is summed over the pairlist within the cutoff, and
+//! a smooth long-ranged part that is summed in reciprocal space. `Ewald`
+//! sums the reciprocal part directly over wave vectors, and is slow but
+//! simple enough to serve as a reference. `Pme` uses smooth particle-mesh
+//! Ewald (Essmann et al. 1995), spreading the charges onto a grid with
+//! B-splines and convolving with FFTs.
+//!
+//! Both include the self energy, a correction that removes the reciprocal
+//! space interaction of excluded pairs, and the uniform background that
+//! neutralises any net charge.
+
+use std::f64::consts::PI;
+
+use crate::dim::Dimensioned;
+
+use crate::units::*;
+use crate::units::f32consts::*;
+use crate::geom::{
+    Vec3D,
+    ForceVec,
+    EnergyTensor,
+    SimulationBox
+};
+use crate::special;
+use crate::fft::{
+    self,
+    Complex
+};
+use super::{
+    Potential,
+    Configuration
+};
+use super::nonbonded::{
+    PairInteraction,
+    pair_energy,
+    pair_forces,
+    pair_virial,
+    pair_forces_and_virial
+};
+use itertools::iproduct;
+use rayon::prelude::*;
+
+type Matrix = [[f64; 3]; 3];
+
+/// B-spline weights and their derivatives along one dimension
+type Spline = (Vec<f64>, Vec<f64>);
+
+/// The Ewald splitting parameter for which the real-space interaction at
+/// `cutoff` is `tolerance` times the plain Coulomb interaction, like
+/// GROMACS' `ewald-rtol`
+///
+/// # Examples
+///
+/// ```
+/// use noether::potentials::ewald::alpha_from_tolerance;
+/// use noether::units::f32consts::*;
+///
+/// let alpha = alpha_from_tolerance(1.0 * NM, 1e-5);
+/// assert!((alpha * NM).value_unsafe > 3.1 && (alpha * NM).value_unsafe < 3.2);
+/// ```
+pub fn alpha_from_tolerance(cutoff: Nanometer<f32>, tolerance: f32) -> PerNanometer<f32> {
+    let rc = cutoff.value_unsafe as f64;
+    let tolerance = tolerance as f64;
+    let (mut lower, mut upper) = (0.0, 1.0);
+    while special::erfc(upper * rc) > tolerance {
+        upper *= 2.0;
+    }
+    for _ in 0..60 {
+        let mid = (lower + upper) / 2.0;
+        if special::erfc(mid * rc) > tolerance {
+            lower = mid;
+        } else {
+            upper = mid;
+        }
+    }
+    upper as f32 / NM
+}
+
+/// The real-space part of the Ewald sum
+struct RealSpace {
+    alpha: f64
+}
+
+impl PairInteraction for RealSpace {
+    fn interaction(
+        &self,
+        config: &Configuration,
+        i: usize,
+        j: usize,
+        r2: Nanometer2<f32>
+    ) -> (KilojoulePerMole<f32>, KilojoulePerMolePerNanometer2<f32>) {
+        let atoms = &config.topology.atoms;
+        let qq = (KE * atoms[i].charge * atoms[j].charge).value_unsafe as f64;
+        let r2 = r2.value_unsafe as f64;
+        let r = r2.sqrt();
+        let erfc = special::erfc(self.alpha * r);
+        let gaussian = 2.0 * self.alpha / PI.sqrt() * (-self.alpha * self.alpha * r2).exp();
+
+        let energy = qq * erfc / r;
+        let fscal = qq * (erfc / r + gaussian) / r2;
+        (energy as f32 * KJPM, fscal as f32 * KJPM / NM2)
+    }
+}
+
+/// A way of summing the reciprocal space part of the Ewald sum
+trait Reciprocal {
+    fn alpha(&self) -> PerNanometer<f32>;
+
+    /// The reciprocal space energy and virial, also adding the forces to
+    /// `forces` if they are given
+    fn reciprocal(&self, config: &Configuration, forces: Option<&mut [ForceVec]>) -> (f64, Matrix);
+}
+
+fn to_array<Q: Copy + Dimensioned<Value=f32>>(v: &Vec3D<Q>) -> [f64; 3] {
+    [*v.x.value_unsafe() as f64, *v.y.value_unsafe() as f64, *v.z.value_unsafe() as f64]
+}
+
+fn reciprocal_matrix(simbox: &SimulationBox) -> Matrix {
+    let (a, b, c) = simbox.reciprocal_vectors();
+    [to_array(&a), to_array(&b), to_array(&c)]
+}
+
+fn charges(config: &Configuration) -> Vec<f64> {
+    config.topology.atoms.iter()
+        .map(|atom| atom.charge.value_unsafe as f64)
+        .collect()
+}
+
+fn to_tensor(m: Matrix) -> EnergyTensor {
+    let row = |r: [f64; 3]| Vec3D::from(r[0] as f32, r[1] as f32, r[2] as f32);
+    EnergyTensor::new(row(m[0]), row(m[1]), row(m[2]))
+}
+
+/// The virial of one reciprocal space term with energy `energy` at wave
+/// vector `k`, whose Gaussian damping is `exp(-k² / 4α²)`
+fn wave_virial(virial: &mut Matrix, energy: f64, k: [f64; 3], alpha: f64) {
+    let k2 = k[0] * k[0] + k[1] * k[1] + k[2] * k[2];
+    let factor = 2.0 * (1.0 + k2 / (4.0 * alpha * alpha)) / k2;
+    for (a, row) in virial.iter_mut().enumerate() {
+        for (b, element) in row.iter_mut().enumerate() {
+            let delta = if a == b { 1.0 } else { 0.0 };
+            *element -= 0.5 * energy * (delta - factor * k[a] * k[b]);
+        }
+    }
+}
+
+/// The self energy, the net charge correction and the reciprocal space
+/// interactions of excluded pairs, which must all be removed from the sum
+fn corrections(config: &Configuration, alpha: f64, forces: Option<&mut [ForceVec]>) -> (f64, Matrix) {
+    let ke = KE.value_unsafe as f64;
+    let q = charges(config);
+    let volume = config.simbox.volume().value_unsafe as f64;
+    let mut virial = [[0.0; 3]; 3];
+
+    let self_energy = -ke * alpha / PI.sqrt() * q.iter().map(|q| q * q).sum::<f64>();
+
+    // The energy of a uniform background charge scales with 1/V, so its
+    // virial is isotropic
+    let net_charge: f64 = q.iter().sum();
+    let background = -ke * PI * net_charge * net_charge / (2.0 * volume * alpha * alpha);
+    for (a, row) in virial.iter_mut().enumerate() {
+        row[a] -= 0.5 * background;
+    }
+
+    let mut exclusion_energy = 0.0;
+    let mut exclusion_forces = vec![];
+    for (i, excluded) in config.topology.exclusions.iter().enumerate() {
+        for &j in excluded.iter().filter(|&&j| i < j) {
+            let qq = ke * q[i] * q[j];
+            if qq == 0.0 {
+                continue;
+            }
+            let r = to_array(&config.displacement(i, j));
+            let r2 = r[0] * r[0] + r[1] * r[1] + r[2] * r[2];
+            let dist = r2.sqrt();
+            let erf = 1.0 - special::erfc(alpha * dist);
+            let gaussian = 2.0 * alpha / PI.sqrt() * (-alpha * alpha * r2).exp();
+
+            exclusion_energy -= qq * erf / dist;
+            let fscal = qq * (gaussian / dist - erf / r2) / dist;
+            let f = [r[0] * fscal, r[1] * fscal, r[2] * fscal];
+            for (a, row) in virial.iter_mut().enumerate() {
+                for (b, element) in row.iter_mut().enumerate() {
+                    *element -= 0.5 * r[a] * f[b];
+                }
+            }
+            exclusion_forces.push((i, j, f));
+        }
+    }
+    if let Some(forces) = forces {
+        for (i, j, f) in exclusion_forces.into_iter() {
+            let f = ForceVec::from(f[0] as f32, f[1] as f32, f[2] as f32);
+            forces[i] += f.clone();
+            forces[j] -= f;
+        }
+    }
+
+    (self_energy + background + exclusion_energy, virial)
+}
+
+fn ewald_energy<R: Reciprocal>(method: &R, config: &Configuration) -> KilojoulePerMole<f32> {
+    let alpha = (method.alpha() * NM).value_unsafe as f64;
+    let real = pair_energy(&RealSpace { alpha }, config);
+    let (reciprocal, _) = method.reciprocal(config, None);
+    let (correction, _) = corrections(config, alpha, None);
+    real + (reciprocal + correction) as f32 * KJPM
+}
+
+fn ewald_forces<R: Reciprocal>(method: &R, config: &Configuration, forces: &mut [ForceVec]) {
+    let alpha = (method.alpha() * NM).value_unsafe as f64;
+    pair_forces(&RealSpace { alpha }, config, forces);
+    method.reciprocal(config, Some(forces));
+    corrections(config, alpha, Some(forces));
+}
+
+fn ewald_virial<R: Reciprocal>(method: &R, config: &Configuration) -> EnergyTensor {
+    let alpha = (method.alpha() * NM).value_unsafe as f64;
+    let real = pair_virial(&RealSpace { alpha }, config);
+    let (_, reciprocal) = method.reciprocal(config, None);
+    let (_, correction) = corrections(config, alpha, None);
+    real + to_tensor(reciprocal) + to_tensor(correction)
+}
+
+fn ewald_forces_and_virial<R: Reciprocal>(method: &R, config: &Configuration, forces: &mut [ForceVec]) -> EnergyTensor {
+    let alpha = (method.alpha() * NM).value_unsafe as f64;
+    let real = pair_forces_and_virial(&RealSpace { alpha }, config, forces);
+    let (_, reciprocal) = method.reciprocal(config, Some(forces));
+    let (_, correction) = corrections(config, alpha, Some(forces));
+    real + to_tensor(reciprocal) + to_tensor(correction)
+}
+
+/// Coulomb interactions by Ewald summation, with the reciprocal space sum
+/// taken directly over all wave vectors `2π (m1 a* + m2 b* + m3 c*)` with
+/// `|m_i| <= kmax[i]`
+///
+/// The cost grows with the number of atoms times the number of wave
+/// vectors, so this is mainly useful as a reference for `Pme`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ewald {
+    pub alpha: PerNanometer<f32>,
+    pub kmax: [usize; 3]
+}
+
+impl Reciprocal for Ewald {
+    fn alpha(&self) -> PerNanometer<f32> {
+        self.alpha
+    }
+
+    fn reciprocal(&self, config: &Configuration, forces: Option<&mut [ForceVec]>) -> (f64, Matrix) {
+        let alpha = (self.alpha * NM).value_unsafe as f64;
+        let ke = KE.value_unsafe as f64;
+        let volume = config.simbox.volume().value_unsafe as f64;
+        let recip = reciprocal_matrix(config.simbox);
+        let q = charges(config);
+        let frac: Vec<[f64; 3]> = config.positions.iter()
+            .map(|pos| to_array(&config.simbox.to_fractional(pos)))
+            .collect();
+        let [k1, k2, k3] = self.kmax;
+        let (k1, k2, k3) = (k1 as i64, k2 as i64, k3 as i64);
+
+        // Only one of each pair of opposite wave vectors, counted twice
+        let waves: Vec<[i64; 3]> = iproduct!(0..=k1, -k2..=k2, -k3..=k3)
+            .map(|(m1, m2, m3)| [m1, m2, m3])
+            .filter(|&m| m > [0, 0, 0])
+            .collect();
+        let terms: Vec<([f64; 3], f64, Complex)> = waves.par_iter()
+            .map(|m| {
+                let mut k = [0.0; 3];
+                for (d, k) in k.iter_mut().enumerate() {
+                    *k = 2.0 * PI * (0..3).map(|a| m[a] as f64 * recip[a][d]).sum::<f64>();
+                }
+                let k2 = k[0] * k[0] + k[1] * k[1] + k[2] * k[2];
+                let damping = (-k2 / (4.0 * alpha * alpha)).exp() / k2;
+                let structure = frac.iter()
+                    .zip(q.iter())
+                    .fold(Complex::default(), |acc, (s, &q)| {
+                        let phase = 2.0 * PI * (m[0] as f64 * s[0] + m[1] as f64 * s[1] + m[2] as f64 * s[2]);
+                        acc + Complex::cis(phase) * q
+                    });
+                (k, damping, structure)
+            }).collect();
+
+        let prefactor = 2.0 * PI * ke / volume;
+        let mut energy = 0.0;
+        let mut virial = [[0.0; 3]; 3];
+        for (k, damping, structure) in terms.iter() {
+            let e = 2.0 * prefactor * damping * structure.norm2();
+            energy += e;
+            wave_virial(&mut virial, e, *k, alpha);
+        }
+
+        if let Some(forces) = forces {
+            forces.par_iter_mut()
+                .enumerate()
+                .for_each(|(i, force)| {
+                    let mut f = [0.0; 3];
+                    for (m, (k, damping, structure)) in waves.iter().zip(terms.iter()) {
+                        let phase = 2.0 * PI * (0..3).map(|a| m[a] as f64 * frac[i][a]).sum::<f64>();
+                        let scale = 4.0 * prefactor * damping * q[i]
+                            * (structure.re * phase.sin() - structure.im * phase.cos());
+                        for d in 0..3 {
+                            f[d] += scale * k[d];
+                        }
+                    }
+                    *force += ForceVec::from(f[0] as f32, f[1] as f32, f[2] as f32);
+                });
+        }
+
+        (energy, virial)
+    }
+}
+
+impl Potential for Ewald {
+    fn energy(&self, config: &Configuration) -> KilojoulePerMole<f32> {
+        ewald_energy(self, config)
+    }
+
+    fn forces(&self, config: &Configuration, forces: &mut [ForceVec]) {
+        ewald_forces(self, config, forces)
+    }
+
+    fn virial(&self, config: &Configuration) -> Option<EnergyTensor> {
+        Some(ewald_virial(self, config))
+    }
+
+    fn forces_and_virial(&self, config: &Configuration, forces: &mut [ForceVec]) -> Option<EnergyTensor> {
+        Some(ewald_forces_and_virial(self, config, forces))
+    }
+}
+
+/// Coulomb interactions by smooth particle-mesh Ewald
+///
+/// Charges are spread onto a `grid` with cardinal B-splines of `order`
+/// points in each dimension, so `order` 4 is cubic interpolation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pme {
+    pub alpha: PerNanometer<f32>,
+    pub grid: [usize; 3],
+    pub order: usize
+}
+
+/// The smallest number at least `n` with no prime factors above 5,
+/// which the FFT handles quickly
+fn fft_friendly(n: usize) -> usize {
+    (n.max(1)..)
+        .find(|&size| {
+            let mut rest = size;
+            for p in [2, 3, 5].iter() {
+                while rest % p == 0 {
+                    rest /= p;
+                }
+            }
+            rest == 1
+        }).unwrap()
+}
+
+/// The cardinal B-spline of `order` at `w + j` for `j` in `0..order`, and
+/// its derivatives, for `w` in `[0, 1)`
+fn bspline(w: f64, order: usize) -> Spline {
+    // Start from the linear spline, M2(w) = w and M2(w + 1) = 1 - w
+    let mut m = vec![0.0; order];
+    m[0] = w;
+    m[1] = 1.0 - w;
+    let mut dm = vec![0.0; order];
+    for n in 3..=order {
+        if n == order {
+            dm[0] = m[0];
+            for j in 1..order {
+                dm[j] = m[j] - m[j - 1];
+            }
+        }
+        let mut next = vec![0.0; order];
+        for (j, next) in next.iter_mut().enumerate().take(n) {
+            let x = w + j as f64;
+            let previous = if j > 0 { m[j - 1] } else { 0.0 };
+            *next = (x * m[j] + (n as f64 - x) * previous) / (n - 1) as f64;
+        }
+        m = next;
+    }
+    if order == 2 {
+        dm[0] = 1.0;
+        dm[1] = -1.0;
+    }
+    (m, dm)
+}
+
+/// `|b(m)|²` from Essmann et al. for every `m` along a grid dimension
+fn bspline_moduli(size: usize, order: usize) -> Vec<f64> {
+    let (m, _) = bspline(0.0, order);
+    let mut moduli: Vec<f64> = (0..size)
+        .map(|k| {
+            let sum = (0..order - 1).fold(Complex::default(), |acc, j| {
+                acc + Complex::cis(2.0 * PI * (k * j) as f64 / size as f64) * m[j + 1]
+            });
+            let norm2 = sum.norm2();
+            if norm2 > 1e-10 { 1.0 / norm2 } else { 0.0 }
+        }).collect();
+    // Odd orders have zeros at the Nyquist frequency; interpolate over them
+    for k in 0..size {
+        if moduli[k] == 0.0 {
+            moduli[k] = (moduli[(k + size - 1) % size] + moduli[(k + 1) % size]) / 2.0;
+        }
+    }
+    moduli
+}
+
+impl Pme {
+    /// Choose a grid for `simbox` with points no further than `spacing`
+    /// apart along each box vector
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use noether::geom::SimulationBox;
+    /// use noether::potentials::ewald::{Pme, alpha_from_tolerance};
+    /// use noether::units::f32consts::*;
+    ///
+    /// let simbox = SimulationBox::cubic(3.0 * NM);
+    /// let alpha = alpha_from_tolerance(1.0 * NM, 1e-5);
+    /// let pme = Pme::new(alpha, &simbox, 0.12 * NM, 4);
+    ///
+    /// assert_eq!(pme.grid, [25, 25, 25]);
+    /// ```
+    pub fn new(alpha: PerNanometer<f32>, simbox: &SimulationBox, spacing: Nanometer<f32>, order: usize) -> Pme {
+        if order < 2 {
+            panic!("PME interpolation order must be at least 2");
+        }
+        let (a, b, c) = simbox.lengths();
+        let size = |length: Nanometer<f32>| {
+            fft_friendly(((length / spacing).value_unsafe.ceil() as usize).max(order))
+        };
+        Pme {
+            alpha,
+            grid: [size(a), size(b), size(c)],
+            order
+        }
+    }
+}
+
+impl Reciprocal for Pme {
+    fn alpha(&self) -> PerNanometer<f32> {
+        self.alpha
+    }
+
+    fn reciprocal(&self, config: &Configuration, forces: Option<&mut [ForceVec]>) -> (f64, Matrix) {
+        let alpha = (self.alpha * NM).value_unsafe as f64;
+        let ke = KE.value_unsafe as f64;
+        let volume = config.simbox.volume().value_unsafe as f64;
+        let recip = reciprocal_matrix(config.simbox);
+        let q = charges(config);
+        let order = self.order;
+        let grid = self.grid;
+        let [n1, n2, n3] = grid;
+        let index = |g: [usize; 3]| (g[0] * n2 + g[1]) * n3 + g[2];
+
+        // The B-spline weights and their derivatives for each atom along
+        // each dimension, and the grid point of the first weight
+        let splines: Vec<([usize; 3], [Spline; 3])> = config.positions.par_iter()
+            .map(|pos| {
+                let s = to_array(&config.simbox.to_fractional(pos));
+                let mut base = [0; 3];
+                let mut weights = [(vec![], vec![]), (vec![], vec![]), (vec![], vec![])];
+                for d in 0..3 {
+                    let u = (s[d] - s[d].floor()) * grid[d] as f64;
+                    let floor = u.floor();
+                    base[d] = floor as usize % grid[d];
+                    weights[d] = bspline(u - floor, order);
+                }
+                (base, weights)
+            }).collect();
+        // Grid points are counted down from the base
+        let point = |base: usize, j: usize, size: usize| (base + size * order - j) % size;
+
+        let mut mesh = vec![Complex::default(); n1 * n2 * n3];
+        for ((base, [w1, w2, w3]), &q) in splines.iter().zip(q.iter()) {
+            if q == 0.0 {
+                continue;
+            }
+            for j1 in 0..order {
+                let g1 = point(base[0], j1, n1);
+                for j2 in 0..order {
+                    let g2 = point(base[1], j2, n2);
+                    let w12 = q * w1.0[j1] * w2.0[j2];
+                    for j3 in 0..order {
+                        let g3 = point(base[2], j3, n3);
+                        mesh[index([g1, g2, g3])].re += w12 * w3.0[j3];
+                    }
+                }
+            }
+        }
+
+        fft::fft3d(&mut mesh, grid, false);
+
+        let moduli = [
+            bspline_moduli(n1, order),
+            bspline_moduli(n2, order),
+            bspline_moduli(n3, order)
+        ];
+        let signed = |m: usize, size: usize| if m > size / 2 { m as f64 - size as f64 } else { m as f64 };
+        let prefactor = ke / (2.0 * PI * volume);
+        let mut energy = 0.0;
+        let mut virial = [[0.0; 3]; 3];
+        for (m1, m2, m3) in iproduct!(0..n1, 0..n2, 0..n3) {
+            let i = index([m1, m2, m3]);
+            if i == 0 {
+                mesh[i] = Complex::default();
+                continue;
+            }
+            let m = [signed(m1, n1), signed(m2, n2), signed(m3, n3)];
+            let mut mvec = [0.0; 3];
+            for (d, mvec) in mvec.iter_mut().enumerate() {
+                *mvec = (0..3).map(|a| m[a] * recip[a][d]).sum::<f64>();
+            }
+            let msq = mvec[0] * mvec[0] + mvec[1] * mvec[1] + mvec[2] * mvec[2];
+            let eta = (-PI * PI * msq / (alpha * alpha)).exp() / msq
+                * moduli[0][m1] * moduli[1][m2] * moduli[2][m3];
+
+            let e = prefactor * eta * mesh[i].norm2();
+            energy += e;
+            wave_virial(&mut virial, e, [2.0 * PI * mvec[0], 2.0 * PI * mvec[1], 2.0 * PI * mvec[2]], alpha);
+            mesh[i] = mesh[i] * eta;
+        }
+
+        if let Some(forces) = forces {
+            // The derivative of the energy with respect to the charge at
+            // each grid point
+            fft::fft3d(&mut mesh, grid, true);
+            let potential: Vec<f64> = mesh.iter().map(|x| 2.0 * prefactor * x.re).collect();
+
+            forces.par_iter_mut()
+                .zip(splines.par_iter())
+                .zip(q.par_iter())
+                .for_each(|((force, (base, [w1, w2, w3])), &q)| {
+                    if q == 0.0 {
+                        return;
+                    }
+                    // Gradient with respect to the scaled fractional coordinates
+                    let mut grad = [0.0; 3];
+                    for j1 in 0..order {
+                        let g1 = point(base[0], j1, n1);
+                        for j2 in 0..order {
+                            let g2 = point(base[1], j2, n2);
+                            for j3 in 0..order {
+                                let g3 = point(base[2], j3, n3);
+                                let phi = potential[index([g1, g2, g3])];
+                                grad[0] += phi * w1.1[j1] * w2.0[j2] * w3.0[j3];
+                                grad[1] += phi * w1.0[j1] * w2.1[j2] * w3.0[j3];
+                                grad[2] += phi * w1.0[j1] * w2.0[j2] * w3.1[j3];
+                            }
+                        }
+                    }
+                    let mut f = [0.0; 3];
+                    for (d, f) in f.iter_mut().enumerate() {
+                        *f = -q * (0..3).map(|a| grad[a] * grid[a] as f64 * recip[a][d]).sum::<f64>();
+                    }
+                    *force += ForceVec::from(f[0] as f32, f[1] as f32, f[2] as f32);
+                });
+        }
+
+        (energy, virial)
+    }
+}
+
+impl Potential for Pme {
+    fn energy(&self, config: &Configuration) -> KilojoulePerMole<f32> {
+        ewald_energy(self, config)
+    }
+
+    fn forces(&self, config: &Configuration, forces: &mut [ForceVec]) {
+        ewald_forces(self, config, forces)
+    }
+
+    fn virial(&self, config: &Configuration) -> Option<EnergyTensor> {
+        Some(ewald_virial(self, config))
+    }
+
+    fn forces_and_virial(&self, config: &Configuration, forces: &mut [ForceVec]) -> Option<EnergyTensor> {
+        Some(ewald_forces_and_virial(self, config, forces))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::PosVec;
+    use crate::pairlist::{self, NeighborList};
+    use crate::topology::Top;
+    use crate::potentials::tests::assert_forces_match_energy;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    /// A 2x2x2 supercell of the rock salt conventional cell
+    fn rock_salt() -> (Top, Vec<PosVec>, SimulationBox) {
+        let a = 0.564;
+        let mut top = Top::gen_lj_fluid(64, 23.0 * DA, 0.0 * KJPM, 0.3 * NM);
+        top.set_lj_cutoff(0.5 * NM).unwrap();
+        let mut positions = vec![];
+        for (i, (x, y, z)) in iproduct!(0..4, 0..4, 0..4).enumerate() {
+            top.atoms[i].charge = if (x + y + z) % 2 == 0 { 1.0 * E } else { -1.0 * E };
+            positions.push(PosVec::from(x as f32, y as f32, z as f32) * (a / 2.0));
+        }
+        (top, positions, SimulationBox::cubic(2.0 * a * NM))
+    }
+
+    /// Random charges in a triclinic box, with a net charge and some
+    /// excluded pairs
+    fn random_charges(n: usize) -> (Top, Vec<PosVec>, SimulationBox) {
+        let mut rng = StdRng::seed_from_u64(11);
+        let simbox = SimulationBox::triclinic(
+            PosVec::from(2.0, 0.0, 0.0),
+            PosVec::from(0.4, 1.9, 0.0),
+            PosVec::from(-0.3, 0.6, 2.1)
+        );
+        let mut top = Top::gen_lj_fluid(n, 23.0 * DA, 0.0 * KJPM, 0.3 * NM);
+        top.set_lj_cutoff(0.9 * NM).unwrap();
+        let positions: Vec<PosVec> = (0..n)
+            .map(|i| {
+                top.atoms[i].charge = rng.gen_range(-1.0, 1.0) * E;
+                let frac = crate::geom::NodimVec::from(rng.gen(), rng.gen(), rng.gen());
+                simbox.from_fractional(&frac)
+            }).collect();
+        for i in (0..n - 1).step_by(3) {
+            top.add_exclusion(i, i + 1);
+        }
+        (top, positions, simbox)
+    }
+
+    fn pairlist(top: &Top, positions: &[PosVec], simbox: &SimulationBox) -> NeighborList {
+        pairlist::brute_force(positions, simbox, top.lj_cutoff()).exclude(&top.exclusions)
+    }
+
+    fn forces(potential: &dyn Potential, config: &Configuration) -> Vec<ForceVec> {
+        let mut forces = vec![ForceVec::zero(); config.positions.len()];
+        potential.forces(config, &mut forces);
+        forces
+    }
+
+    #[test]
+    fn ewald_gives_madelung_energy() {
+        let (top, positions, simbox) = rock_salt();
+        let list = pairlist(&top, &positions, &simbox);
+        let config = top.configuration(&positions, &list, &simbox);
+        let alpha = alpha_from_tolerance(top.lj_cutoff(), 1e-6);
+
+        // 32 ion pairs with the Madelung constant of rock salt
+        let expected = -32.0 * 1.747_565 * KE.value_unsafe / 0.282;
+
+        let ewald = Ewald { alpha, kmax: [10, 10, 10] };
+        let energy = ewald.energy(&config).value_unsafe;
+        assert!((energy - expected).abs() < 1e-4 * expected.abs(), "{} != {}", energy, expected);
+
+        let pme = Pme::new(alpha, &simbox, 0.05 * NM, 6);
+        let energy = pme.energy(&config).value_unsafe;
+        assert!((energy - expected).abs() < 1e-3 * expected.abs(), "{} != {}", energy, expected);
+    }
+
+    #[test]
+    fn pme_matches_ewald() {
+        let (top, positions, simbox) = random_charges(40);
+        let list = pairlist(&top, &positions, &simbox);
+        let config = top.configuration(&positions, &list, &simbox);
+        let alpha = alpha_from_tolerance(top.lj_cutoff(), 1e-5);
+
+        let ewald = Ewald { alpha, kmax: [12, 12, 12] };
+        let pme = Pme::new(alpha, &simbox, 0.1 * NM, 6);
+
+        let reference = ewald.energy(&config).value_unsafe;
+        let energy = pme.energy(&config).value_unsafe;
+        assert!((energy - reference).abs() < 1e-3 * reference.abs(), "{} != {}", energy, reference);
+
+        let reference = forces(&ewald, &config);
+        let pme_forces = forces(&pme, &config);
+        let rms = |fs: &mut dyn Iterator<Item=ForceVec>| {
+            (fs.map(|f| f.norm2().value_unsafe).sum::<f32>() / positions.len() as f32).sqrt()
+        };
+        let error = rms(&mut pme_forces.iter().zip(reference.iter()).map(|(a, b)| a.clone() - b.clone()));
+        let scale = rms(&mut reference.iter().cloned());
+        assert!(error < 1e-3 * scale, "RMS force error {} of {}", error, scale);
+
+        let reference = ewald.virial(&config).unwrap();
+        let virial = pme.virial(&config).unwrap();
+        let elements = |t: &EnergyTensor| {
+            [&t.x, &t.y, &t.z].iter()
+                .flat_map(|row| vec![row.x, row.y, row.z])
+                .map(|e| e.value_unsafe)
+                .collect::<Vec<f32>>()
+        };
+        let scale = elements(&reference).iter().fold(0.0f32, |acc, e| acc.max(e.abs()));
+        for (a, b) in elements(&virial).iter().zip(elements(&reference).iter()) {
+            assert!((a - b).abs() < 1e-3 * scale, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn ewald_forces_match_energy() {
+        let (top, positions, simbox) = random_charges(9);
+        let list = pairlist(&top, &positions, &simbox);
+        let config = top.configuration(&positions, &list, &simbox);
+        let alpha = alpha_from_tolerance(top.lj_cutoff(), 1e-5);
+
+        assert_forces_match_energy(&Ewald { alpha, kmax: [8, 8, 8] }, &config, 1e-2);
+        assert_forces_match_energy(&Pme::new(alpha, &simbox, 0.1 * NM, 4), &config, 1e-2);
+    }
+
+    #[test]
+    fn ewald_virial_matches_strain_derivative() {
+        let (top, positions, simbox) = random_charges(9);
+        let alpha = alpha_from_tolerance(top.lj_cutoff(), 1e-5);
+        let ewald = Ewald { alpha, kmax: [8, 8, 8] };
+
+        // Stretch the box and positions along x by a factor of 1 + h
+        let energy = |h: f32| {
+            let stretch = |v: &PosVec| PosVec::new(v.x * (1.0 + h), v.y, v.z);
+            let (a, b, c) = simbox.vectors();
+            let simbox = SimulationBox::triclinic(stretch(a), stretch(b), stretch(c));
+            let positions: Vec<PosVec> = positions.iter().map(stretch).collect();
+            let list = pairlist(&top, &positions, &simbox);
+            ewald.energy(&top.configuration(&positions, &list, &simbox)).value_unsafe
+        };
+        let h = 1e-3;
+        let derivative = (energy(h) - energy(-h)) / (2.0 * h);
+
+        let list = pairlist(&top, &positions, &simbox);
+        let virial = ewald.virial(&top.configuration(&positions, &list, &simbox)).unwrap();
+        let xx = virial.x.x.value_unsafe;
+        assert!((0.5 * derivative - xx).abs() < 1e-2 * (1.0 + xx.abs()), "{} != {}", 0.5 * derivative, xx);
+    }
+
+    #[test]
+    fn ewald_forces_and_virial_match_separate_calculations() {
+        let (top, positions, simbox) = random_charges(9);
+        let list = pairlist(&top, &positions, &simbox);
+        let config = top.configuration(&positions, &list, &simbox);
+        let alpha = alpha_from_tolerance(top.lj_cutoff(), 1e-5);
+
+        let methods: Vec<Box<dyn Potential>> = vec![
+            Box::new(Ewald { alpha, kmax: [8, 8, 8] }),
+            Box::new(Pme::new(alpha, &simbox, 0.1 * NM, 4))
+        ];
+        for method in methods.iter() {
+            let mut combined = vec![ForceVec::zero(); positions.len()];
+            let virial = method.forces_and_virial(&config, &mut combined).unwrap();
+            assert!(combined == forces(method.as_ref(), &config));
+
+            // The pair virial is summed in a different order
+            let reference = method.virial(&config).unwrap();
+            let diff = (virial - reference.clone()).diagonal().norm2().value_unsafe.sqrt();
+            assert!(diff <= 1e-5 * reference.diagonal().norm2().value_unsafe.sqrt());
+        }
+    }
+}
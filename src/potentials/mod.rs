@@ -0,0 +1,143 @@
+//! Terms of the potential energy function
+//!
+//! Every interaction implements the `Potential` trait, and a `Top` holds
+//! a list of them that it sums over to get energies and forces. New
+//! interactions, including user-defined ones, can be added to a topology
+//! with `Top::add_potential`.
+
+use std::fmt;
+
+use crate::units::*;
+use crate::geom::{
+    PosVec,
+    ForceVec,
+    EnergyTensor,
+    SimulationBox
+};
+use crate::pairlist::NeighborList;
+use crate::topology::Top;
+
+pub mod bonded;
+pub mod nonbonded;
+pub mod ewald;
+pub mod tabulated;
+pub mod custom;
+
+/// A term in the potential energy function.
+///
+/// The virial is optional, so that terms that don't need pressure
+/// coupling can be written without it. It uses the GROMACS convention
+/// `Ξ = -½ Σ r_ij ⊗ F_ij`, summed over pairs with `r_ij = r_i - r_j`
+/// and `F_ij` the force on `i` due to `j`.
+pub trait Potential: fmt::Debug + Send + Sync {
+    /// The potential energy of this term.
+    fn energy(&self, config: &Configuration) -> KilojoulePerMole<f32>;
+
+    /// Add the forces from this term on each atom to `forces`.
+    fn forces(&self, config: &Configuration, forces: &mut [ForceVec]);
+
+    /// The virial tensor of this term, if it can calculate it.
+    fn virial(&self, _config: &Configuration) -> Option<EnergyTensor> {
+        None
+    }
+
+    /// Check that this term can be evaluated for the atoms of
+    /// `topology`, which `Top::add_potential` does before adding it.
+    fn check(&self, _topology: &Top) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Add the forces from this term to `forces` and return its virial,
+    /// if it can calculate it. Terms that get the virial cheaply from the
+    /// forces should override this to do both in one pass.
+    fn forces_and_virial(&self, config: &Configuration, forces: &mut [ForceVec]) -> Option<EnergyTensor> {
+        self.forces(config, forces);
+        self.virial(config)
+    }
+}
+
+/// Everything a `Potential` needs to evaluate itself
+#[derive(Clone, Copy)]
+pub struct Configuration<'a> {
+    pub topology: &'a Top,
+    pub positions: &'a [PosVec],
+    pub simbox: &'a SimulationBox,
+    pub pairlist: &'a NeighborList
+}
+
+impl<'a> Configuration<'a> {
+    /// Get the minimum image convention distance vector from `j` to `i`
+    pub fn displacement(&self, i: usize, j: usize) -> PosVec {
+        self.simbox.min_image(&self.positions[i] - &self.positions[j])
+    }
+
+    /// Get the minimum image convention distance between two atoms,
+    /// and the corresponding distance vector from `j` to `i`
+    pub fn dist2(&self, i: usize, j: usize) -> (PosVec, Nanometer2<f32>) {
+        let diff = self.displacement(i, j);
+        let diff2 = diff.norm2();
+        (diff, diff2)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::units::f32consts::*;
+    use crate::topology::ForceKernel;
+    use crate::pairlist;
+
+    /// Four Lennard-Jones atoms in a 3 nm box, some interacting across
+    /// the boundaries, with the serial force kernel and every pair in the
+    /// pairlist
+    pub fn four_atoms() -> (Top, Vec<PosVec>, SimulationBox, NeighborList) {
+        let mut top = Top::gen_lj_fluid(4, 40.0 * DA, 1.0 * KJPM, 0.34 * NM);
+        top.force_kernel = ForceKernel::Serial;
+        let simbox = SimulationBox::cubic(3.0 * NM);
+        let positions = vec![
+            PosVec::from(0.1, 0.1, 0.1),
+            PosVec::from(0.45, 0.2, 0.1),
+            PosVec::from(2.9, 0.3, 0.2),
+            PosVec::from(0.3, 0.5, 2.8),
+        ];
+        let list = pairlist::brute_force(&positions, &simbox, 0.0 * NM);
+        (top, positions, simbox, list)
+    }
+
+    /// Check that the forces from a potential are the negative gradient
+    /// of its energy, by central finite differences
+    pub fn assert_forces_match_energy(potential: &dyn Potential, config: &Configuration, tolerance: f32) {
+        let mut forces = vec![ForceVec::zero(); config.positions.len()];
+        potential.forces(config, &mut forces);
+
+        let h = 1e-3;
+        for i in 0..config.positions.len() {
+            for dim in 0..3 {
+                let displaced = |delta: f32| {
+                    let mut positions = config.positions.to_vec();
+                    match dim {
+                        0 => positions[i].x += delta * NM,
+                        1 => positions[i].y += delta * NM,
+                        _ => positions[i].z += delta * NM
+                    }
+                    let config = Configuration {
+                        positions: &positions,
+                        ..*config
+                    };
+                    potential.energy(&config).value_unsafe
+                };
+                let numerical = -(displaced(h) - displaced(-h)) / (2.0 * h);
+                let analytical = match dim {
+                    0 => forces[i].x,
+                    1 => forces[i].y,
+                    _ => forces[i].z
+                }.value_unsafe;
+                assert!(
+                    (numerical - analytical).abs() <= tolerance * (1.0 + analytical.abs()),
+                    "Force on atom {} along {} is {}, but energy gradient gives {}",
+                    i, dim, analytical, numerical
+                );
+            }
+        }
+    }
+}
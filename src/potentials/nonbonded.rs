@@ -0,0 +1,785 @@
+//! Non-bonded pair interactions
+//!
+//! Pair potentials implement `PairInteraction`, which gives the energy
+//! and force for a single pair, and use `pair_energy`, `pair_forces` and
+//! `pair_virial` to sum it over the pairlist within the cutoff, or
+//! `pair_forces_and_virial` to sum the forces and virial together.
+//!
+//! Lennard-Jones parameters for every pair of atoms are looked up in an
+//! `LjTable`, which the topology builds once from its combination rule
+//! and NBFIX overrides.
+
+use crate::units::*;
+use crate::units::f32consts::*;
+use crate::geom::{
+    PosVec,
+    ForceVec,
+    EnergyTensor,
+    Tensor3D
+};
+use crate::topology::{
+    Atom,
+    TypeLibrary,
+    ForceKernel,
+    CombinationRule,
+    LjModifier,
+    NbFix
+};
+use super::{
+    Potential,
+    Configuration
+};
+use rayon::prelude::*;
+use crate::dim::Sqrt;
+
+/// An interaction between pairs of atoms that depends only on
+/// their distance
+pub trait PairInteraction: Sync {
+    /// The energy of the pair `i`, `j` at squared distance `r2`, and
+    /// the scalar force `-dV/dr / r`. Multiplying the scalar force by
+    /// the distance vector from `j` to `i` gives the force on `i`.
+    fn interaction(
+        &self,
+        config: &Configuration,
+        i: usize,
+        j: usize,
+        r2: Nanometer2<f32>
+    ) -> (KilojoulePerMole<f32>, KilojoulePerMolePerNanometer2<f32>);
+}
+
+/// Sum the energy of a pair interaction over the pairlist
+pub fn pair_energy<P: PairInteraction>(interaction: &P, config: &Configuration) -> KilojoulePerMole<f32> {
+    let cutoff = config.topology.lj_cutoff();
+    let cutoff_squared = cutoff * cutoff;
+
+    config.pairlist
+        .par_pairs()
+        .map(|(i, j)| {
+            let (_, r2) = config.dist2(i, j);
+            (i, j, r2)
+        }).filter(|(_, _, r2)| r2 <= &cutoff_squared)
+        .map(|(i, j, r2)| interaction.interaction(config, i, j, r2).0)
+        .reduce(
+            || 0.0 * KJPM,
+            |acc, e| acc + e
+        )
+}
+
+/// Add the forces of a pair interaction over the pairlist to `forces`,
+/// using the topology's `ForceKernel`
+pub fn pair_forces<P: PairInteraction>(interaction: &P, config: &Configuration, forces: &mut [ForceVec]) {
+    pair_kernel(interaction, config, forces, false);
+}
+
+/// Add the forces of a pair interaction over the pairlist to `forces`,
+/// as `pair_forces` does, and sum its virial in the same pass
+pub fn pair_forces_and_virial<P: PairInteraction>(
+    interaction: &P,
+    config: &Configuration,
+    forces: &mut [ForceVec]
+) -> EnergyTensor {
+    pair_kernel(interaction, config, forces, true)
+}
+
+/// The force kernels behind `pair_forces`, which also sum the virial if
+/// `with_virial` is set, and return zero otherwise
+fn pair_kernel<P: PairInteraction>(
+    interaction: &P,
+    config: &Configuration,
+    forces: &mut [ForceVec],
+    with_virial: bool
+) -> EnergyTensor {
+    let cutoff = config.topology.lj_cutoff();
+    let cutoff_squared = cutoff * cutoff;
+    let n_atoms = config.positions.len();
+
+    // Distance vector from atom j to atom i, and the force on i from j
+    let pair_force = |i: usize, j: usize| {
+        let (r, r2) = config.dist2(i, j);
+        if r2 <= cutoff_squared {
+            let (_, fscal) = interaction.interaction(config, i, j, r2);
+            let f = r.clone() * fscal;
+            Some((r, f))
+        } else {
+            None
+        }
+    };
+    // The virial of a pair, weighted by `weight`, or nothing
+    let pair_virial = |r: &PosVec, f: &ForceVec, weight: f32| {
+        if with_virial {
+            Tensor3D::outer(r, f) * weight
+        } else {
+            EnergyTensor::zero()
+        }
+    };
+
+    match config.topology.force_kernel {
+        ForceKernel::Serial => {
+            let mut virial = EnergyTensor::zero();
+            for (i, j) in config.pairlist.pairs() {
+                if let Some((r, f)) = pair_force(i, j) {
+                    virial += pair_virial(&r, &f, -0.5);
+                    forces[i] += f.clone();
+                    forces[j] -= f;
+                }
+            }
+            virial
+        },
+        ForceKernel::ThreadBuffers => {
+            let n_chunks = rayon::current_num_threads().max(1);
+            let pairlist = config.pairlist;
+            let full = pairlist.is_full();
+
+            let buffers: Vec<(Vec<ForceVec>, EnergyTensor)> = (0..n_chunks)
+                .into_par_iter()
+                .map(|chunk| {
+                    let mut forces = vec![ForceVec::zero(); n_atoms];
+                    let mut virial = EnergyTensor::zero();
+                    // A half list gives low-index atoms most of the
+                    // pairs, so interleave the atoms to even out the work
+                    for i in (chunk..n_atoms).step_by(n_chunks) {
+                        for &j in pairlist.neighbors(i).iter().filter(|&&j| !full || i < j) {
+                            if let Some((r, f)) = pair_force(i, j) {
+                                virial += pair_virial(&r, &f, -0.5);
+                                forces[i] += f.clone();
+                                forces[j] -= f;
+                            }
+                        }
+                    }
+                    (forces, virial)
+                }).collect();
+
+            forces.par_iter_mut()
+                .enumerate()
+                .for_each(|(i, force)| {
+                    for (buffer, _) in buffers.iter() {
+                        *force += buffer[i].clone();
+                    }
+                });
+            buffers.into_iter()
+                .fold(EnergyTensor::zero(), |acc, (_, virial)| acc + virial)
+        },
+        ForceKernel::FullList => {
+            let full_list;
+            let pairlist = if config.pairlist.is_full() {
+                config.pairlist
+            } else {
+                full_list = config.pairlist.to_full();
+                &full_list
+            };
+
+            // Every pair is seen from both of its atoms, with the same
+            // outer product, so each sighting counts for half
+            forces.par_iter_mut()
+                .enumerate()
+                .map(|(i, force)| {
+                    let mut virial = EnergyTensor::zero();
+                    for (r, f) in pairlist.neighbors(i).iter().filter_map(|&j| pair_force(i, j)) {
+                        virial += pair_virial(&r, &f, -0.25);
+                        *force += f;
+                    }
+                    virial
+                }).reduce(
+                    EnergyTensor::zero,
+                    |acc, w| acc + w
+                )
+        }
+    }
+}
+
+/// Sum the virial of a pair interaction over the pairlist
+pub fn pair_virial<P: PairInteraction>(interaction: &P, config: &Configuration) -> EnergyTensor {
+    let cutoff = config.topology.lj_cutoff();
+    let cutoff_squared = cutoff * cutoff;
+
+    config.pairlist
+        .par_pairs()
+        .filter_map(|(i, j)| {
+            let (r, r2) = config.dist2(i, j);
+            if r2 <= cutoff_squared {
+                let (_, fscal) = interaction.interaction(config, i, j, r2);
+                Some(Tensor3D::outer(&r, &r) * fscal * -0.5)
+            } else {
+                None
+            }
+        }).reduce(
+            EnergyTensor::zero,
+            |acc, w| acc + w
+        )
+}
+
+/// Lennard-Jones parameters for every pair of atom types
+///
+/// The parameters for each pair of types are combined once, when the
+/// table is built, so the non-bonded kernels only need to look them up.
+///
+/// # Examples
+///
+/// ```
+/// use noether::topology::{TypeLibrary, AtomType, CombinationRule, NbFix};
+/// use noether::potentials::nonbonded::LjTable;
+/// use noether::units::f32consts::*;
+///
+/// let atom_type = |name: &str, epsilon, sigma| AtomType {
+///     name: name.to_string(),
+///     mass: 12.0 * DA,
+///     charge: 0.0 * E,
+///     epsilon,
+///     sigma
+/// };
+/// let mut types = TypeLibrary::new();
+/// let c = types.add(atom_type("C", 0.4 * KJPM, 0.34 * NM));
+/// let o = types.add(atom_type("O", 0.9 * KJPM, 0.30 * NM));
+///
+/// let table = LjTable::new(&types, CombinationRule::LorentzBerthelot, &[]);
+/// assert_eq!(table.n_types(), 2);
+/// let (eps, sig) = table.parameters(c, o);
+/// assert!((eps - 0.6 * KJPM).value_unsafe.abs() < 1e-6);
+/// assert!((sig - 0.32 * NM).value_unsafe.abs() < 1e-6);
+///
+/// let nbfix = NbFix {
+///     types: ["C".to_string(), "O".to_string()],
+///     epsilon: 0.1 * KJPM,
+///     sigma: 0.35 * NM
+/// };
+/// let table = LjTable::new(&types, CombinationRule::LorentzBerthelot, &[nbfix]);
+/// assert_eq!(table.parameters(o, c), (0.1 * KJPM, 0.35 * NM));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct LjTable {
+    n_types: usize,
+    /// Each of the following for each pair of types, row by row
+    epsilon: Vec<KilojoulePerMole<f32>>,
+    sigma: Vec<Nanometer<f32>>,
+    c6: Vec<KilojouleNanometer6PerMole<f32>>,
+    c12: Vec<KilojouleNanometer12PerMole<f32>>
+}
+
+impl LjTable {
+    /// Build the table for the types in `types`, with `nbfix` taking
+    /// precedence over the combination rule
+    pub fn new(types: &TypeLibrary, rule: CombinationRule, nbfix: &[NbFix]) -> LjTable {
+        let n_types = types.len();
+        let mut table = LjTable {
+            n_types,
+            epsilon: Vec::with_capacity(n_types * n_types),
+            sigma: Vec::with_capacity(n_types * n_types),
+            c6: Vec::with_capacity(n_types * n_types),
+            c12: Vec::with_capacity(n_types * n_types)
+        };
+        for a in types.iter() {
+            for b in types.iter() {
+                let fix = nbfix.iter().find(|fix| {
+                    let [ref x, ref y] = fix.types;
+                    (x == &a.name && y == &b.name) || (x == &b.name && y == &a.name)
+                });
+                let (eps, sig) = match fix {
+                    Some(fix) => (fix.epsilon, fix.sigma),
+                    None => rule.combine((a.epsilon, a.sigma), (b.epsilon, b.sigma))
+                };
+                let sig6 = sig * sig * sig * sig * sig * sig;
+                table.epsilon.push(eps);
+                table.sigma.push(sig);
+                table.c6.push(4.0 * eps * sig6);
+                table.c12.push(4.0 * eps * sig6 * sig6);
+            }
+        }
+        table
+    }
+
+    /// The number of atom types
+    pub fn n_types(&self) -> usize {
+        self.n_types
+    }
+
+    fn index(&self, a: usize, b: usize) -> usize {
+        a * self.n_types + b
+    }
+
+    /// The `epsilon` and `sigma` between types `a` and `b`
+    pub fn parameters(&self, a: usize, b: usize) -> (KilojoulePerMole<f32>, Nanometer<f32>) {
+        let index = self.index(a, b);
+        (self.epsilon[index], self.sigma[index])
+    }
+
+    /// The `C6 = 4 ε σ⁶` and `C12 = 4 ε σ¹²` between types `a` and `b`
+    pub fn c6_c12(&self, a: usize, b: usize) -> (KilojouleNanometer6PerMole<f32>, KilojouleNanometer12PerMole<f32>) {
+        let index = self.index(a, b);
+        (self.c6[index], self.c12[index])
+    }
+
+    /// The `C6` averaged over all distinct pairs of `atoms` that aren't
+    /// in `exclusions`, the sorted excluded partners of each atom, as
+    /// GROMACS averages it for the dispersion correction
+    pub fn mean_c6(&self, atoms: &[Atom], exclusions: &[Vec<usize>]) -> KilojouleNanometer6PerMole<f32> {
+        let mut counts = vec![0.0f64; self.n_types];
+        for atom in atoms.iter() {
+            counts[atom.atom_type] += 1.0;
+        }
+
+        // Every ordered pair of atoms, less each atom with itself
+        let c6 = |a: usize, b: usize| self.c6[self.index(a, b)].value_unsafe as f64;
+        let mut sum = 0.0;
+        for (a, na) in counts.iter().enumerate() {
+            for (b, nb) in counts.iter().enumerate() {
+                sum += na * nb * c6(a, b);
+            }
+            sum -= na * c6(a, a);
+        }
+        let mut n_pairs = atoms.len() as f64 * (atoms.len() as f64 - 1.0);
+
+        for (i, excluded) in exclusions.iter().enumerate() {
+            for &j in excluded.iter() {
+                sum -= c6(atoms[i].atom_type, atoms[j].atom_type);
+                n_pairs -= 1.0;
+            }
+        }
+        if n_pairs <= 0.0 {
+            return 0.0 * KJNM6PM;
+        }
+        (sum / n_pairs) as f32 * KJNM6PM
+    }
+}
+
+/// The 12-6 Lennard-Jones potential
+///
+/// Parameters for each pair of atom types come from the topology's
+/// `LjTable`, and the potential is modified near the cutoff according to
+/// `Top::lj_modifier`. Pairs of atom types with a tabulated potential from
+/// `Top::add_pair_table` use the table instead.
+#[derive(Debug, Clone, Default)]
+pub struct LennardJones;
+
+impl PairInteraction for LennardJones {
+    fn interaction(
+        &self,
+        config: &Configuration,
+        i: usize,
+        j: usize,
+        r2: Nanometer2<f32>
+    ) -> (KilojoulePerMole<f32>, KilojoulePerMolePerNanometer2<f32>) {
+        let top = config.topology;
+        if let Some(table) = top.pair_table(i, j) {
+            return table.interaction(r2);
+        }
+        let (c6, c12) = top.c6_c12(i, j);
+        match top.lj_modifier() {
+            LjModifier::None => lennard_jones(c6, c12, r2),
+            modifier => modified_lennard_jones(modifier, top.lj_cutoff(), c6, c12, r2)
+        }
+    }
+}
+
+/// Integrals over all space of the dispersion energy and virial missing
+/// from a unit `C6` with `modifier` and `cutoff`, assuming a uniform pair
+/// distribution.
+///
+/// Returns `∫ 4π r² ΔV dr` and `∫ 4π r³ ΔV' dr`, where `ΔV` is the
+/// difference between `-1/r⁶` and what the modified potential gives. Any
+/// constant shift of the potential inside the cutoff is included.
+pub(crate) fn dispersion_integrals(modifier: LjModifier, cutoff: Nanometer<f32>) -> (f64, f64) {
+    use std::f64::consts::PI;
+
+    let rc = cutoff.value_unsafe as f64;
+    // The modified potential and its derivative for a unit C6
+    let modified = |r: f64| -> (f64, f64) {
+        let r2 = (r * r) as f32 * NM2;
+        let (energy, fscal) = modified_lennard_jones(modifier, cutoff, 1.0 * KJNM6PM, 0.0 * KJNM12PM, r2);
+        (energy.value_unsafe as f64, -(fscal.value_unsafe as f64) * r)
+    };
+    let delta = |r: f64| -> (f64, f64) {
+        let (v, dv) = modified(r);
+        (-r.powi(-6) - v, 6.0 * r.powi(-7) - dv)
+    };
+
+    // Beyond the cutoff the whole potential is missing
+    let mut energy = -4.0 * PI / (3.0 * rc.powi(3));
+    let mut virial = 8.0 * PI / rc.powi(3);
+
+    // Inside the switching region the forces are exact, but the potential
+    // may be shifted by a constant
+    let r_inner = match modifier {
+        LjModifier::None | LjModifier::PotentialShift => rc,
+        LjModifier::ForceSwitch { rswitch } | LjModifier::PotentialSwitch { rswitch } => {
+            rswitch.value_unsafe as f64
+        }
+    };
+    let (shift, _) = delta(r_inner);
+    energy += 4.0 * PI / 3.0 * r_inner.powi(3) * shift;
+
+    // Integrate the switching region with Simpson's rule
+    if r_inner < rc {
+        let n = 200;
+        let h = (rc - r_inner) / n as f64;
+        for k in 0..=n {
+            let weight = match k {
+                0 => 1.0,
+                k if k == n => 1.0,
+                k if k % 2 == 1 => 4.0,
+                _ => 2.0
+            } * h / 3.0;
+            let r = r_inner + k as f64 * h;
+            let (dv, ddv) = delta(r);
+            energy += weight * 4.0 * PI * r * r * dv;
+            virial += weight * 4.0 * PI * r * r * r * ddv;
+        }
+    }
+    (energy, virial)
+}
+
+/// `r^-n` and its force `n r^-(n+1)` with the GROMACS force switch between
+/// `r1` and `rc`, both shifted so the potential is zero at `rc`
+fn force_switched_power(n: i32, r: f32, r1: f32, rc: f32) -> (f32, f32) {
+    let alpha = n as f32;
+    let a = -alpha * ((alpha + 4.0) * rc - (alpha + 1.0) * r1)
+        / (rc.powi(n + 2) * (rc - r1).powi(2));
+    let b = alpha * ((alpha + 3.0) * rc - (alpha + 1.0) * r1)
+        / (rc.powi(n + 2) * (rc - r1).powi(3));
+    let shift = rc.powi(-n) - a / 3.0 * (rc - r1).powi(3) - b / 4.0 * (rc - r1).powi(4);
+
+    let mut potential = r.powi(-n) - shift;
+    let mut force = alpha * r.powi(-n - 1);
+    if r > r1 {
+        let dr = r - r1;
+        potential -= a / 3.0 * dr.powi(3) + b / 4.0 * dr.powi(4);
+        force += a * dr.powi(2) + b * dr.powi(3);
+    }
+    (potential, force)
+}
+
+/// The Lennard-Jones energy and scalar force with a cutoff modifier
+fn modified_lennard_jones(
+    modifier: LjModifier,
+    cutoff: Nanometer<f32>,
+    c6: KilojouleNanometer6PerMole<f32>,
+    c12: KilojouleNanometer12PerMole<f32>,
+    r2: Nanometer2<f32>
+) -> (KilojoulePerMole<f32>, KilojoulePerMolePerNanometer2<f32>) {
+    let rc = cutoff.value_unsafe;
+
+    match modifier {
+        LjModifier::None => lennard_jones(c6, c12, r2),
+        LjModifier::PotentialShift => {
+            let (energy, fscal) = lennard_jones(c6, c12, r2);
+            let (shift, _) = lennard_jones(c6, c12, cutoff * cutoff);
+            (energy - shift, fscal)
+        },
+        LjModifier::ForceSwitch { rswitch } => {
+            let r1 = rswitch.value_unsafe;
+            let r = r2.value_unsafe.sqrt();
+            let (v6, f6) = force_switched_power(6, r, r1, rc);
+            let (v12, f12) = force_switched_power(12, r, r1, rc);
+            let (c6, c12) = (c6.value_unsafe, c12.value_unsafe);
+
+            let energy = c12 * v12 - c6 * v6;
+            let fscal = (c12 * f12 - c6 * f6) / r;
+            (energy * KJPM, fscal * KJPM / NM2)
+        },
+        LjModifier::PotentialSwitch { rswitch } => {
+            let r1 = rswitch.value_unsafe;
+            let (energy, fscal) = lennard_jones(c6, c12, r2);
+            let r = r2.value_unsafe.sqrt();
+            if r <= r1 {
+                return (energy, fscal);
+            }
+            let width = rc - r1;
+            let t = (r - r1) / width;
+            let switch = 1.0 - t.powi(3) * (10.0 - 15.0 * t + 6.0 * t * t);
+            let dswitch = -30.0 * t * t * (1.0 - t).powi(2) / width;
+
+            // -d(V S)/dr / r = S fscal - V dS/dr / r
+            let fscal = fscal * switch - energy * dswitch / r / NM2;
+            (energy * switch, fscal)
+        }
+    }
+}
+
+/// The Lennard-Jones energy and scalar force at squared distance `r2`
+pub(crate) fn lennard_jones(
+    c6: KilojouleNanometer6PerMole<f32>,
+    c12: KilojouleNanometer12PerMole<f32>,
+    r2: Nanometer2<f32>
+) -> (KilojoulePerMole<f32>, KilojoulePerMolePerNanometer2<f32>) {
+    let r6 = r2 * r2 * r2;
+    let dispersion = c6 / r6;
+    let repulsion = c12 / (r6 * r6);
+
+    let energy = repulsion - dispersion;
+    let fscal = (12.0 * repulsion - 6.0 * dispersion) / r2;
+    (energy, fscal)
+}
+
+impl Potential for LennardJones {
+    fn energy(&self, config: &Configuration) -> KilojoulePerMole<f32> {
+        pair_energy(self, config)
+    }
+
+    fn forces(&self, config: &Configuration, forces: &mut [ForceVec]) {
+        pair_forces(self, config, forces)
+    }
+
+    fn virial(&self, config: &Configuration) -> Option<EnergyTensor> {
+        Some(pair_virial(self, config))
+    }
+
+    fn forces_and_virial(&self, config: &Configuration, forces: &mut [ForceVec]) -> Option<EnergyTensor> {
+        Some(pair_forces_and_virial(self, config, forces))
+    }
+}
+
+/// Coulomb interactions between charges within the cutoff
+///
+/// Excluded pairs are left out entirely, and 1-4 pairs are handled by
+/// `bonded::OneFourPairs`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Coulomb {
+    /// The plain Coulomb potential, truncated at the cutoff
+    Cutoff,
+    /// The reaction-field potential for charges surrounded by a continuum
+    /// with relative permittivity `epsilon_rf` beyond the cutoff. Use
+    /// `f32::INFINITY` for a conducting continuum.
+    ReactionField { epsilon_rf: f32 },
+    /// The Coulomb potential with both the energy and force shifted to
+    /// zero at the cutoff
+    ShiftedForce
+}
+
+impl PairInteraction for Coulomb {
+    fn interaction(
+        &self,
+        config: &Configuration,
+        i: usize,
+        j: usize,
+        r2: Nanometer2<f32>
+    ) -> (KilojoulePerMole<f32>, KilojoulePerMolePerNanometer2<f32>) {
+        let atoms = &config.topology.atoms;
+        let qq = KE * atoms[i].charge * atoms[j].charge;
+        let r = r2.sqrt();
+        let rc = config.topology.lj_cutoff();
+
+        match *self {
+            Coulomb::Cutoff => {
+                let energy = qq / r;
+                (energy, energy / r2)
+            },
+            Coulomb::ReactionField { epsilon_rf } => {
+                let rc3 = rc * rc * rc;
+                let k_rf = if epsilon_rf.is_infinite() {
+                    0.5 / rc3
+                } else {
+                    (epsilon_rf - 1.0) / (2.0 * epsilon_rf + 1.0) / rc3
+                };
+                let c_rf = 1.0 / rc + k_rf * rc * rc;
+                let energy = qq * (1.0 / r + k_rf * r2 - c_rf);
+                let fscal = qq * (1.0 / (r * r2) - 2.0 * k_rf);
+                (energy, fscal)
+            },
+            Coulomb::ShiftedForce => {
+                let energy = qq * (1.0 / r - 1.0 / rc + (r - rc) / (rc * rc));
+                let fscal = qq * (1.0 / r2 - 1.0 / (rc * rc)) / r;
+                (energy, fscal)
+            }
+        }
+    }
+}
+
+impl Potential for Coulomb {
+    fn energy(&self, config: &Configuration) -> KilojoulePerMole<f32> {
+        pair_energy(self, config)
+    }
+
+    fn forces(&self, config: &Configuration, forces: &mut [ForceVec]) {
+        pair_forces(self, config, forces)
+    }
+
+    fn virial(&self, config: &Configuration) -> Option<EnergyTensor> {
+        Some(pair_virial(self, config))
+    }
+
+    fn forces_and_virial(&self, config: &Configuration, forces: &mut [ForceVec]) -> Option<EnergyTensor> {
+        Some(pair_forces_and_virial(self, config, forces))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::{PosVec, SimulationBox};
+    use crate::pairlist;
+    use crate::topology::{Top, AtomType};
+    use crate::potentials::tests::{assert_forces_match_energy, four_atoms};
+    use crate::potentials::tabulated::PairTable;
+
+    #[test]
+    fn lj_forces_match_energy() {
+        let (top, positions, simbox, list) = four_atoms();
+        let config = top.configuration(&positions, &list, &simbox);
+
+        assert_forces_match_energy(&LennardJones, &config, 1e-2);
+    }
+
+    #[test]
+    fn lj_forces_match_energy_with_mixed_types() {
+        let (mut top, positions, simbox, list) = four_atoms();
+        let lj = top.atom_types().get(0).clone();
+        let b = top.add_atom_type(AtomType { name: "B".to_string(), epsilon: 0.4 * KJPM, sigma: 0.3 * NM, ..lj.clone() });
+        let c = top.add_atom_type(AtomType { name: "C".to_string(), sigma: 0.38 * NM, ..lj });
+        top.atoms[1].atom_type = b;
+        top.atoms[2].atom_type = c;
+        top.set_combination_rule(CombinationRule::WaldmanHagler);
+        top.add_nbfix("LJ", "B", 2.0 * KJPM, 0.33 * NM);
+        let config = top.configuration(&positions, &list, &simbox);
+
+        assert_eq!(top.lj_table().parameters(0, b), (2.0 * KJPM, 0.33 * NM));
+        assert_forces_match_energy(&LennardJones, &config, 1e-2);
+    }
+
+    #[test]
+    fn mean_c6_leaves_out_self_and_excluded_pairs() {
+        let mut top = Top::gen_lj_fluid(4, 40.0 * DA, 1.0 * KJPM, 0.34 * NM);
+        let lj = top.atom_types().get(0).clone();
+        let b = top.add_atom_type(AtomType { name: "B".to_string(), epsilon: 0.4 * KJPM, ..lj });
+        top.atoms[1].atom_type = b;
+        top.atoms[3].atom_type = b;
+        top.add_exclusion(0, 1);
+
+        let table = top.lj_table();
+        let c6 = |i: usize, j: usize| {
+            table.c6_c12(top.atoms[i].atom_type, top.atoms[j].atom_type).0.value_unsafe
+        };
+        let pairs = [(0, 2), (0, 3), (1, 2), (1, 3), (2, 3)];
+        let expected = pairs.iter().map(|&(i, j)| c6(i, j)).sum::<f32>() / pairs.len() as f32;
+        let mean = table.mean_c6(&top.atoms, &top.exclusions).value_unsafe;
+        assert!((mean - expected).abs() < 1e-6 * expected, "{} != {}", mean, expected);
+    }
+
+    #[test]
+    fn coulomb_forces_match_energy() {
+        let (mut top, positions, simbox, list) = four_atoms();
+        for (atom, &q) in top.atoms.iter_mut().zip([0.5, -0.8, 0.4, -0.1].iter()) {
+            atom.charge = q * E;
+        }
+        let config = top.configuration(&positions, &list, &simbox);
+
+        assert_forces_match_energy(&Coulomb::Cutoff, &config, 1e-2);
+        assert_forces_match_energy(&Coulomb::ReactionField { epsilon_rf: 78.0 }, &config, 1e-2);
+        assert_forces_match_energy(&Coulomb::ReactionField { epsilon_rf: f32::INFINITY }, &config, 1e-2);
+        assert_forces_match_energy(&Coulomb::ShiftedForce, &config, 1e-2);
+    }
+
+    #[test]
+    fn shifted_coulomb_vanishes_at_cutoff() {
+        let mut top = Top::gen_lj_fluid(2, 40.0 * DA, 1.0 * KJPM, 0.34 * NM);
+        top.atoms[0].charge = 1.0 * E;
+        top.atoms[1].charge = -1.0 * E;
+        let simbox = SimulationBox::cubic(3.0 * NM);
+        let positions = vec![PosVec::zero(); 2];
+        let list = pairlist::brute_force(&positions, &simbox, 0.0 * NM);
+        let config = top.configuration(&positions, &list, &simbox);
+        let rc2 = top.lj_cutoff() * top.lj_cutoff();
+
+        for coulomb in [Coulomb::ShiftedForce, Coulomb::ReactionField { epsilon_rf: 1.0 }].iter() {
+            let (energy, _) = coulomb.interaction(&config, 0, 1, rc2);
+            assert!(energy.value_unsafe.abs() < 1e-4);
+        }
+        let (_, fscal) = Coulomb::ShiftedForce.interaction(&config, 0, 1, rc2);
+        assert!(fscal.value_unsafe.abs() < 1e-4);
+    }
+
+    fn lj_modifiers() -> Vec<LjModifier> {
+        vec![
+            LjModifier::PotentialShift,
+            LjModifier::ForceSwitch { rswitch: 0.6 * NM },
+            LjModifier::PotentialSwitch { rswitch: 0.6 * NM },
+        ]
+    }
+
+    #[test]
+    fn modified_lj_forces_match_energy() {
+        let mut top = Top::gen_lj_fluid(4, 40.0 * DA, 1.0 * KJPM, 0.34 * NM);
+        top.force_kernel = ForceKernel::Serial;
+        let simbox = SimulationBox::cubic(3.0 * NM);
+        // Pairs inside, within and beyond the switching region
+        let positions = vec![
+            PosVec::from(0.1, 0.1, 0.1),
+            PosVec::from(0.45, 0.15, 0.1),
+            PosVec::from(0.95, 0.2, 0.15),
+            PosVec::from(1.7, 0.1, 0.2),
+        ];
+        let list = pairlist::brute_force(&positions, &simbox, 0.0 * NM);
+
+        for &modifier in lj_modifiers().iter() {
+            top.set_lj_modifier(modifier).unwrap();
+            let config = top.configuration(&positions, &list, &simbox);
+            assert_forces_match_energy(&LennardJones, &config, 1e-2);
+        }
+    }
+
+    #[test]
+    fn modified_lj_vanishes_at_cutoff() {
+        let mut top = Top::gen_lj_fluid(2, 40.0 * DA, 1.0 * KJPM, 0.34 * NM);
+        let simbox = SimulationBox::cubic(3.0 * NM);
+        let positions = vec![PosVec::zero(); 2];
+        let list = pairlist::brute_force(&positions, &simbox, 0.0 * NM);
+        let rc2 = top.lj_cutoff() * top.lj_cutoff();
+        let inner2 = 0.5 * NM * 0.5 * NM;
+
+        let (_, unmodified) = {
+            let config = top.configuration(&positions, &list, &simbox);
+            LennardJones.interaction(&config, 0, 1, inner2)
+        };
+        for &modifier in lj_modifiers().iter() {
+            top.set_lj_modifier(modifier).unwrap();
+            let config = top.configuration(&positions, &list, &simbox);
+            let (energy, fscal) = LennardJones.interaction(&config, 0, 1, rc2);
+            assert!(energy.value_unsafe.abs() < 1e-6);
+
+            // Forces are untouched inside the switching region, and the
+            // switches also take them to zero at the cutoff
+            let (_, inner) = LennardJones.interaction(&config, 0, 1, inner2);
+            assert!((inner - unmodified).value_unsafe.abs() < 1e-5);
+            if modifier != LjModifier::PotentialShift {
+                assert!(fscal.value_unsafe.abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn lj_switch_beyond_cutoff_is_an_error() {
+        let mut top = Top::gen_lj_fluid(2, 40.0 * DA, 1.0 * KJPM, 0.34 * NM);
+        let modifier = LjModifier::PotentialSwitch { rswitch: 1.2 * NM };
+        let error = top.set_lj_modifier(modifier).unwrap_err();
+        assert_eq!(error.to_string(), "rswitch 1.2 nm must be less than the cutoff 1 nm");
+        assert_eq!(top.lj_modifier(), LjModifier::None);
+
+        // Nor can the cutoff come down to meet the switch afterwards
+        top.set_lj_modifier(LjModifier::ForceSwitch { rswitch: 0.8 * NM }).unwrap();
+        let error = top.set_lj_cutoff(0.8 * NM).unwrap_err();
+        assert_eq!(error.to_string(), "rswitch 0.8 nm must be less than the cutoff 0.8 nm");
+        assert!(top.lj_cutoff() == 1.0 * NM);
+    }
+
+    #[test]
+    fn tabulated_pairs_replace_lj() {
+        let (mut top, positions, simbox, list) = four_atoms();
+        let cg = AtomType { name: "CG".to_string(), ..top.atom_types().get(0).clone() };
+        top.atoms[3].atom_type = top.add_atom_type(cg);
+
+        // A soft repulsion for pairs involving the CG atom
+        let r: Vec<f32> = (0..=60).map(|k| 0.02 * k as f32).collect();
+        let v = r.iter().map(|&r| 5.0 * (1.0 - r / 1.2).powi(2)).collect();
+        top.add_pair_table("LJ", "CG", PairTable::new(r, v, None));
+
+        let config = top.configuration(&positions, &list, &simbox);
+        let r2 = config.dist2(0, 3).1;
+        let (energy, _) = LennardJones.interaction(&config, 3, 0, r2);
+        let expected = 5.0 * (1.0 - r2.value_unsafe.sqrt() / 1.2).powi(2);
+        assert!((energy.value_unsafe - expected).abs() < 1e-4);
+
+        let (c6, c12) = top.c6_c12(0, 1);
+        let r2 = config.dist2(0, 1).1;
+        assert_eq!(LennardJones.interaction(&config, 0, 1, r2), lennard_jones(c6, c12, r2));
+
+        assert_forces_match_energy(&LennardJones, &config, 1e-2);
+    }
+}
@@ -0,0 +1,273 @@
+//! Tabulated pair potentials
+//!
+//! A `PairTable` interpolates a pair potential given as a table of
+//! distances and energies, and optionally forces, with a cubic spline. A
+//! topology can use a table in place of Lennard-Jones for any pair of atom
+//! types with `Top::add_pair_table`, which is how potentials from iterative
+//! Boltzmann inversion and other coarse-graining methods are run.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::units::*;
+use crate::units::f32consts::*;
+
+/// A pair potential interpolated from a table with a cubic spline
+///
+/// When the table has forces, the spline is the cubic Hermite spline that
+/// matches both the energies and the forces at every point. Otherwise it is
+/// the natural cubic spline through the energies, and the forces are its
+/// derivative. Either way the forces are exactly consistent with the
+/// energy.
+///
+/// Distances beyond the end of the table have zero energy and force, and
+/// distances before its start extrapolate the first segment.
+///
+/// # Examples
+///
+/// ```
+/// use noether::potentials::tabulated::PairTable;
+/// use noether::units::f32consts::*;
+///
+/// let text = "# r V
+///     0.5  3.0
+///     1.0  1.0
+///     1.5  0.0
+/// ";
+/// let table = PairTable::parse(text, "harmonic.xvg").unwrap();
+/// let (energy, _) = table.interaction(1.0 * NM2);
+/// assert!((energy - 1.0 * KJPM).value_unsafe.abs() < 1e-6);
+///
+/// let error = PairTable::parse("0.5 1.0\n0.4 2.0\n", "bad.xvg").unwrap_err();
+/// assert_eq!(error.to_string(), "bad.xvg:2: distances must increase");
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct PairTable {
+    /// The distance at the start of each segment, and at the end of the
+    /// last
+    r: Vec<f64>,
+    /// The polynomial coefficients of each segment, in powers of the
+    /// distance from its start
+    coeffs: Vec<[f64; 4]>
+}
+
+impl PairTable {
+    /// Build a table from distances `r` in nm, energies `v` in kJ/mol and
+    /// optionally forces `f = -dV/dr` in kJ/mol/nm.
+    ///
+    /// Panics if there are fewer than two points, if the columns have
+    /// different lengths or if the distances don't increase.
+    pub fn new(r: Vec<f32>, v: Vec<f32>, f: Option<Vec<f32>>) -> PairTable {
+        let n = r.len();
+        if n < 2 {
+            panic!("A pair table needs at least two points!");
+        }
+        if v.len() != n || f.as_ref().is_some_and(|f| f.len() != n) {
+            panic!("Pair table columns must all be the same length!");
+        }
+        if r.windows(2).any(|w| w[1] <= w[0]) {
+            panic!("Pair table distances must increase!");
+        }
+
+        let r: Vec<f64> = r.into_iter().map(f64::from).collect();
+        let v: Vec<f64> = v.into_iter().map(f64::from).collect();
+        let h: Vec<f64> = r.windows(2).map(|w| w[1] - w[0]).collect();
+
+        let coeffs = match f {
+            Some(f) => {
+                let dv: Vec<f64> = f.into_iter().map(|f| -f64::from(f)).collect();
+                (0..n - 1).map(|k| {
+                    let slope = (v[k + 1] - v[k]) / h[k];
+                    [
+                        v[k],
+                        dv[k],
+                        (3.0 * slope - 2.0 * dv[k] - dv[k + 1]) / h[k],
+                        (dv[k] + dv[k + 1] - 2.0 * slope) / (h[k] * h[k])
+                    ]
+                }).collect()
+            },
+            None => {
+                let m = natural_spline_curvatures(&h, &v);
+                (0..n - 1).map(|k| {
+                    [
+                        v[k],
+                        (v[k + 1] - v[k]) / h[k] - h[k] * (2.0 * m[k] + m[k + 1]) / 6.0,
+                        m[k] / 2.0,
+                        (m[k + 1] - m[k]) / (6.0 * h[k])
+                    ]
+                }).collect()
+            }
+        };
+
+        PairTable { r, coeffs }
+    }
+
+    /// Parse a table with two or three whitespace separated columns of
+    /// distance, energy and optionally force. Blank lines, and lines
+    /// starting with `#` or `@` as in `.xvg` files, are ignored.
+    ///
+    /// `name` identifies the table in error messages, which give the line
+    /// at fault.
+    pub fn parse(text: &str, name: &str) -> io::Result<PairTable> {
+        let error = |line: usize, msg: &str| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {}", name, line, msg))
+        };
+
+        let (mut r, mut v, mut f) = (vec![], vec![], vec![]);
+        let mut n_columns = None;
+        let mut last_line = 0;
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with('@') {
+                continue;
+            }
+            last_line = line_number;
+
+            let values = line.split_whitespace()
+                .map(|word| word.parse::<f32>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| error(line_number, &e.to_string()))?;
+            match (values.len(), n_columns) {
+                (2, None) | (3, None) => n_columns = Some(values.len()),
+                (len, Some(n)) if len == n => {},
+                (_, None) => return Err(error(line_number, "expected 2 or 3 columns")),
+                (_, Some(n)) => return Err(error(line_number, &format!("expected {} columns", n)))
+            }
+            if r.last().is_some_and(|&last| values[0] <= last) {
+                return Err(error(line_number, "distances must increase"));
+            }
+
+            r.push(values[0]);
+            v.push(values[1]);
+            if let Some(&force) = values.get(2) {
+                f.push(force);
+            }
+        }
+
+        if r.len() < 2 {
+            return Err(error(last_line, "a pair table needs at least two points"));
+        }
+        let f = if n_columns == Some(3) { Some(f) } else { None };
+        Ok(PairTable::new(r, v, f))
+    }
+
+    /// Read a table file in the format of `parse`
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<PairTable> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        PairTable::parse(&text, &path.display().to_string())
+    }
+
+    /// The last distance in the table, beyond which the potential is zero
+    pub fn max_distance(&self) -> Nanometer<f32> {
+        self.r[self.r.len() - 1] as f32 * NM
+    }
+
+    /// The energy at squared distance `r2`, and the scalar force
+    /// `-dV/dr / r`
+    pub fn interaction(&self, r2: Nanometer2<f32>) -> (KilojoulePerMole<f32>, KilojoulePerMolePerNanometer2<f32>) {
+        let r = f64::from(r2.value_unsafe).sqrt();
+        if r > self.r[self.r.len() - 1] {
+            return (0.0 * KJPM, 0.0 * KJPMNM2);
+        }
+
+        // The segment containing r, or the first one before the table
+        let k = self.r.partition_point(|&start| start <= r)
+            .saturating_sub(1)
+            .min(self.coeffs.len() - 1);
+        let [a, b, c, d] = self.coeffs[k];
+        let t = r - self.r[k];
+
+        let energy = a + t * (b + t * (c + t * d));
+        let dv = b + t * (2.0 * c + t * 3.0 * d);
+        (energy as f32 * KJPM, (-dv / r) as f32 * KJPMNM2)
+    }
+}
+
+/// The second derivatives at each point of the natural cubic spline through
+/// `v`, with intervals `h`
+fn natural_spline_curvatures(h: &[f64], v: &[f64]) -> Vec<f64> {
+    let n = v.len();
+    let mut m = vec![0.0; n];
+    if n < 3 {
+        return m;
+    }
+
+    // Solve the tridiagonal system for the interior points with the
+    // Thomas algorithm
+    let mut diag = vec![0.0; n];
+    let mut rhs = vec![0.0; n];
+    for k in 1..n - 1 {
+        diag[k] = 2.0 * (h[k - 1] + h[k]);
+        rhs[k] = 6.0 * ((v[k + 1] - v[k]) / h[k] - (v[k] - v[k - 1]) / h[k - 1]);
+    }
+    for k in 2..n - 1 {
+        let w = h[k - 1] / diag[k - 1];
+        diag[k] -= w * h[k - 1];
+        rhs[k] -= w * rhs[k - 1];
+    }
+    for k in (1..n - 1).rev() {
+        m[k] = (rhs[k] - h[k] * m[k + 1]) / diag[k];
+    }
+    m
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spline_reproduces_points_and_forces() {
+        let r: Vec<f32> = (0..40).map(|k| 0.3 + 0.025 * k as f32).collect();
+        let lj = |r: f32| 4.0 * ((0.3 / r).powi(12) - (0.3 / r).powi(6));
+        let dlj = |r: f32| 4.0 * (-12.0 * (0.3 / r).powi(12) + 6.0 * (0.3 / r).powi(6)) / r;
+        let v: Vec<f32> = r.iter().map(|&r| lj(r)).collect();
+        let f: Vec<f32> = r.iter().map(|&r| -dlj(r)).collect();
+
+        let natural = PairTable::new(r.clone(), v.clone(), None);
+        let hermite = PairTable::new(r.clone(), v.clone(), Some(f.clone()));
+        // Leaving out the last point, which may round to beyond the table
+        for (&r, &v) in r.iter().zip(v.iter()).take(39) {
+            let (e, _) = natural.interaction(r * r * NM2);
+            assert!((e.value_unsafe - v).abs() < 1e-3);
+            let (e, fscal) = hermite.interaction(r * r * NM2);
+            assert!((e.value_unsafe - v).abs() < 1e-3);
+            assert!((fscal.value_unsafe * r + dlj(r)).abs() < 1e-3 * (1.0 + dlj(r).abs()));
+        }
+
+        // Between points, the force is the derivative of the energy
+        for table in [natural, hermite].iter() {
+            for k in 0..200 {
+                let r = 0.31 + 0.0047 * k as f32;
+                let delta = 1e-3;
+                let (plus, _) = table.interaction((r + delta) * (r + delta) * NM2);
+                let (minus, _) = table.interaction((r - delta) * (r - delta) * NM2);
+                let (_, fscal) = table.interaction(r * r * NM2);
+                let numeric = -(plus - minus).value_unsafe / (2.0 * delta);
+                let analytic = fscal.value_unsafe * r;
+                assert!((numeric - analytic).abs() < 1e-2 * (1.0 + analytic.abs()));
+            }
+        }
+    }
+
+    #[test]
+    fn table_is_zero_beyond_its_end() {
+        let table = PairTable::new(vec![0.1, 0.2, 0.3], vec![2.0, 1.0, 0.5], None);
+        assert_eq!(table.max_distance(), 0.3 * NM);
+        assert_eq!(table.interaction(0.1 * NM2), (0.0 * KJPM, 0.0 * KJPMNM2));
+    }
+
+    #[test]
+    fn parse_reports_bad_lines() {
+        let error = |text| PairTable::parse(text, "table.xvg").unwrap_err().to_string();
+        assert_eq!(error("@ title\n0.1 1.0\n0.2 1.0 3.0\n"), "table.xvg:3: expected 2 columns");
+        assert_eq!(error("0.1 1.0 2.0 3.0\n"), "table.xvg:1: expected 2 or 3 columns");
+        assert!(error("0.1 one\n").starts_with("table.xvg:1: invalid float"));
+        assert_eq!(error("# empty\n0.1 1.0\n"), "table.xvg:2: a pair table needs at least two points");
+
+        let table = PairTable::parse("0.1 2.0 -1.0\n\n0.2 1.0 -1.0\n", "table.xvg").unwrap();
+        assert_eq!(table, PairTable::new(vec![0.1, 0.2], vec![2.0, 1.0], Some(vec![-1.0, -1.0])));
+    }
+}
@@ -0,0 +1,588 @@
+//! AMBER `prmtop` topologies
+//!
+//! A `prmtop` file is a list of `%FLAG` sections, each with a Fortran
+//! `%FORMAT` that gives the width of its fields. The reader takes the
+//! atoms, charges, masses, Lennard-Jones tables, bonds, angles, dihedrals,
+//! exclusions and 1-4 scaling factors from them, and converts from
+//! AMBER's kcal/mol, Ångström and scaled charge units. Atom names,
+//! residues, and elements from `ATOMIC_NUMBER` are kept too, and
+//! consecutive molecules from `ATOMS_PER_MOLECULE` with the same size and
+//! first residue become one block of molecules named after the residue.
+//!
+//! AMBER gives Lennard-Jones parameters as a table of `A = C12` and
+//! `B = C6` for every pair of types. Each type gets an `AtomType` with the
+//! parameters of its own diagonal entry and the Lorentz-Berthelot rule,
+//! and off-diagonal entries that the rule doesn't reproduce become
+//! `NbFix` overrides, so the topology's table matches the file.
+//!
+//! AMBER force constants for bonds and angles are for `V = k (x - x0)²`,
+//! so they are doubled for noether's `V = ½ k (x - x0)²`. Impropers are
+//! periodic, so they become `Dihedral`s like the proper torsions.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::units::f32consts::*;
+use crate::elements;
+use crate::topology::{
+    Top,
+    AtomType,
+    TypeLibrary,
+    CombinationRule
+};
+use crate::potentials::bonded::{
+    Bond,
+    Angle,
+    Dihedral,
+    Pair
+};
+use super::parse_error;
+
+/// Multiply AMBER charges by this to get elementary charges
+const CHARGE_SCALE: f32 = 1.0 / 18.2223;
+/// Kilojoules per kilocalorie
+const KJ_PER_KCAL: f32 = 4.184;
+/// Nanometers per Ångström
+const NM_PER_A: f32 = 0.1;
+/// The relative difference between a table entry and the combination
+/// rule beyond which the entry becomes an `NbFix`
+const NBFIX_TOLERANCE: f32 = 1e-4;
+
+/// Read the AMBER `prmtop` file at `path`
+///
+/// # Examples
+///
+/// ```no_run
+/// use noether::readers::amber;
+///
+/// let top = amber::read_prmtop("complex.prmtop").unwrap();
+/// println!("Read {} atoms", top.atoms.len());
+/// ```
+pub fn read_prmtop<P: AsRef<Path>>(path: P) -> io::Result<Top> {
+    let path = path.as_ref();
+    let text = fs::read_to_string(path)?;
+    parse_prmtop(&text, path)
+}
+
+/// The values of a section, each with the line it's on
+struct Section {
+    line: usize,
+    values: Vec<(usize, String)>
+}
+
+struct Prmtop<'a> {
+    path: &'a Path,
+    sections: HashMap<String, Section>
+}
+
+impl<'a> Prmtop<'a> {
+    fn section(&self, flag: &str) -> io::Result<&Section> {
+        self.sections.get(flag).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: missing %FLAG {}", self.path.display(), flag)
+            )
+        })
+    }
+
+    /// Parse every value of a section
+    fn values<T: FromStr>(&self, flag: &str) -> io::Result<Vec<T>> {
+        self.section(flag)?.values.iter()
+            .map(|(line, text)| {
+                text.parse().map_err(|_| {
+                    parse_error(self.path, *line, format!("invalid value \"{}\" in {}", text, flag))
+                })
+            }).collect()
+    }
+
+    /// Parse a section, which must have `len` values
+    fn values_len<T: FromStr>(&self, flag: &str, len: usize) -> io::Result<Vec<T>> {
+        let values = self.values(flag)?;
+        if values.len() != len {
+            let line = self.section(flag)?.line;
+            return Err(parse_error(
+                self.path,
+                line,
+                format!("expected {} values in {}, found {}", len, flag, values.len())
+            ));
+        }
+        Ok(values)
+    }
+
+    /// Parse a section if it's there
+    fn optional<T: FromStr>(&self, flag: &str) -> io::Result<Option<Vec<T>>> {
+        if self.sections.contains_key(flag) {
+            self.values(flag).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn error<M: std::fmt::Display>(&self, flag: &str, message: M) -> io::Error {
+        let line = self.sections.get(flag).map_or(0, |section| section.line);
+        parse_error(self.path, line, message)
+    }
+}
+
+/// Split a `prmtop` into sections of fixed width values
+fn split_sections<'a>(text: &str, path: &'a Path) -> io::Result<Prmtop<'a>> {
+    let mut sections: HashMap<String, Section> = HashMap::new();
+    let mut current: Option<(String, usize)> = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        if let Some(flag) = line.strip_prefix("%FLAG") {
+            let flag = flag.trim().to_string();
+            sections.insert(flag.clone(), Section { line: number, values: vec![] });
+            current = Some((flag, 0));
+        } else if let Some(format) = line.strip_prefix("%FORMAT") {
+            let (flag, width) = current.as_mut()
+                .ok_or_else(|| parse_error(path, number, "%FORMAT without %FLAG"))?;
+            *width = format_width(format)
+                .ok_or_else(|| parse_error(path, number, format!("unsupported format {}", format.trim())))?;
+            sections.get_mut(flag.as_str()).unwrap().line = number;
+        } else if line.starts_with('%') {
+            // %VERSION and %COMMENT
+            continue;
+        } else if let Some((flag, width)) = current.as_ref() {
+            if *width == 0 {
+                return Err(parse_error(path, number, format!("missing %FORMAT for {}", flag)));
+            }
+            let values = &mut sections.get_mut(flag.as_str()).unwrap().values;
+            let chars: Vec<char> = line.chars().collect();
+            for chunk in chars.chunks(*width) {
+                let value: String = chunk.iter().collect();
+                let value = value.trim();
+                if !value.is_empty() {
+                    values.push((number, value.to_string()));
+                }
+            }
+        }
+    }
+    Ok(Prmtop { path, sections })
+}
+
+/// The field width of a Fortran format like `(10I8)` or `(5E16.8)`
+fn format_width(format: &str) -> Option<usize> {
+    let format = format.trim().trim_start_matches('(').trim_end_matches(')');
+    let start = format.find(|c: char| c.is_ascii_alphabetic())?;
+    let width = &format[start + 1..];
+    let width = width.split('.').next()?;
+    width.parse().ok()
+}
+
+/// Parse the contents of a `prmtop` file, naming it `path` in errors
+fn parse_prmtop(text: &str, path: &Path) -> io::Result<Top> {
+    let prmtop = split_sections(text, path)?;
+
+    let pointers: Vec<usize> = prmtop.values("POINTERS")?;
+    if pointers.len() < 20 {
+        return Err(prmtop.error("POINTERS", "too few values in POINTERS"));
+    }
+    let (n_atoms, n_types) = (pointers[0], pointers[1]);
+    let n_excluded = pointers[10];
+    let (n_bond_types, n_angle_types, n_dihedral_types) = (pointers[15], pointers[16], pointers[17]);
+
+    let n_residues = pointers[11];
+    let names: Vec<String> = prmtop.values_len("ATOM_NAME", n_atoms)?;
+    let atomic_numbers = prmtop.optional::<usize>("ATOMIC_NUMBER")?;
+    let charges: Vec<f32> = prmtop.values_len("CHARGE", n_atoms)?;
+    let masses: Vec<f32> = prmtop.values_len("MASS", n_atoms)?;
+    let type_index: Vec<usize> = prmtop.values_len("ATOM_TYPE_INDEX", n_atoms)?;
+    let type_names: Vec<String> = prmtop.values_len("AMBER_ATOM_TYPE", n_atoms)?;
+    let parm_index: Vec<i64> = prmtop.values_len("NONBONDED_PARM_INDEX", n_types * n_types)?;
+    let acoef: Vec<f32> = prmtop.values("LENNARD_JONES_ACOEF")?;
+    let bcoef: Vec<f32> = prmtop.values("LENNARD_JONES_BCOEF")?;
+
+    // The C12 and C6 of each pair of types, in kcal/mol and Ångström
+    let lj_pair = |a: usize, b: usize| -> io::Result<(f32, f32)> {
+        match parm_index[a * n_types + b] {
+            index if index > 0 => {
+                let index = index as usize - 1;
+                match (acoef.get(index), bcoef.get(index)) {
+                    (Some(&c12), Some(&c6)) => Ok((c12, c6)),
+                    _ => Err(prmtop.error("NONBONDED_PARM_INDEX", "Lennard-Jones index out of range"))
+                }
+            },
+            _ => Err(prmtop.error("NONBONDED_PARM_INDEX", "10-12 hydrogen bond terms are not supported"))
+        }
+    };
+    // Epsilon and sigma in kJ/mol and nm
+    let epsilon_sigma = |(c12, c6): (f32, f32)| -> Option<(f32, f32)> {
+        if c12 == 0.0 && c6 == 0.0 {
+            Some((0.0, 0.0))
+        } else if c12 > 0.0 && c6 > 0.0 {
+            Some((c6 * c6 / (4.0 * c12) * KJ_PER_KCAL, (c12 / c6).powf(1.0 / 6.0) * NM_PER_A))
+        } else {
+            None
+        }
+    };
+
+    // One atom type for each Lennard-Jones type, named after the first
+    // atom with it
+    let mut atom_types = TypeLibrary::new();
+    let mut lj_types = vec![];
+    for t in 0..n_types {
+        let first = match type_index.iter().position(|&index| index == t + 1) {
+            Some(first) => first,
+            None => {
+                // Unused, but keep the numbering
+                lj_types.push(None);
+                continue;
+            }
+        };
+        let (epsilon, sigma) = epsilon_sigma(lj_pair(t, t)?)
+            .ok_or_else(|| prmtop.error("LENNARD_JONES_ACOEF", format!("type {} has only one of A and B", t + 1)))?;
+        let mut name = type_names[first].clone();
+        if atom_types.index(&name).is_some() {
+            name = format!("{}_{}", name, t + 1);
+        }
+        let index = atom_types.add(AtomType {
+            name,
+            mass: masses[first] * DA,
+            charge: 0.0 * E,
+            epsilon: epsilon * KJPM,
+            sigma: sigma * NM
+        });
+        lj_types.push(Some(index));
+    }
+
+    let atoms = (0..n_atoms).map(|i| {
+        let t = type_index[i];
+        let atom_type = t.checked_sub(1)
+            .and_then(|t| lj_types.get(t).cloned().flatten())
+            .ok_or_else(|| prmtop.error("ATOM_TYPE_INDEX", format!("atom {} has invalid type {}", i + 1, t)))?;
+        let mut atom = atom_types.atom(atom_type);
+        atom.charge = charges[i] * CHARGE_SCALE * E;
+        atom.mass = masses[i] * DA;
+        atom.name = names[i].clone();
+        atom.element = atomic_numbers.as_ref()
+            .and_then(|numbers| numbers.get(i).cloned())
+            .and_then(elements::symbol)
+            .map(str::to_string);
+        Ok(atom)
+    }).collect::<io::Result<Vec<_>>>()?;
+
+    let mut top = Top::new(atom_types, atoms);
+    top.set_combination_rule(CombinationRule::LorentzBerthelot);
+
+    // Pairs of types that don't follow the combination rule
+    let used: Vec<(usize, usize)> = lj_types.iter().enumerate()
+        .filter_map(|(t, index)| index.map(|index| (t, index)))
+        .collect();
+    for (k, &(a, type_a)) in used.iter().enumerate() {
+        for &(b, type_b) in used[k + 1..].iter() {
+            let (epsilon, sigma) = epsilon_sigma(lj_pair(a, b)?)
+                .ok_or_else(|| prmtop.error("LENNARD_JONES_ACOEF", format!("types {} and {} have only one of A and B", a + 1, b + 1)))?;
+            let (c6, c12) = top.lj_table().c6_c12(type_a, type_b);
+            let sig6 = (sigma * sigma * sigma).powi(2);
+            let (file_c6, file_c12) = (4.0 * epsilon * sig6, 4.0 * epsilon * sig6 * sig6);
+            let differs = |x: f32, y: f32| (x - y).abs() > NBFIX_TOLERANCE * x.abs().max(y.abs());
+            if differs(file_c6, c6.value_unsafe) || differs(file_c12, c12.value_unsafe) {
+                let name_a = top.atom_types().get(type_a).name.clone();
+                let name_b = top.atom_types().get(type_b).name.clone();
+                top.add_nbfix(&name_a, &name_b, epsilon * KJPM, sigma * NM);
+            }
+        }
+    }
+
+    // Bonded parameters, converted to noether's units
+    let bond_k: Vec<f32> = prmtop.values_len("BOND_FORCE_CONSTANT", n_bond_types)?;
+    let bond_r: Vec<f32> = prmtop.values_len("BOND_EQUIL_VALUE", n_bond_types)?;
+    let angle_k: Vec<f32> = prmtop.values_len("ANGLE_FORCE_CONSTANT", n_angle_types)?;
+    let angle_theta: Vec<f32> = prmtop.values_len("ANGLE_EQUIL_VALUE", n_angle_types)?;
+    let dihedral_k: Vec<f32> = prmtop.values_len("DIHEDRAL_FORCE_CONSTANT", n_dihedral_types)?;
+    let dihedral_n: Vec<f32> = prmtop.values_len("DIHEDRAL_PERIODICITY", n_dihedral_types)?;
+    let dihedral_phase: Vec<f32> = prmtop.values_len("DIHEDRAL_PHASE", n_dihedral_types)?;
+    let scee = prmtop.optional::<f32>("SCEE_SCALE_FACTOR")?;
+    let scnb = prmtop.optional::<f32>("SCNB_SCALE_FACTOR")?;
+
+    // Interactions are lists of atoms, given as indices into the
+    // coordinate array, followed by a 1-based parameter index
+    let interactions = |flags: [&str; 2], n: usize, n_params: usize| -> io::Result<Vec<(Vec<i64>, usize)>> {
+        let mut result = vec![];
+        for &flag in flags.iter() {
+            let values: Vec<i64> = prmtop.values(flag)?;
+            if !values.len().is_multiple_of(n + 1) {
+                return Err(prmtop.error(flag, format!("{} doesn't have {} values per entry", flag, n + 1)));
+            }
+            for entry in values.chunks(n + 1) {
+                let atoms: Vec<i64> = entry[..n].iter().map(|&x| x / 3).collect();
+                if atoms.iter().any(|x| x.unsigned_abs() as usize >= n_atoms) {
+                    return Err(prmtop.error(flag, format!("atom out of range in {}", flag)));
+                }
+                let param = entry[n];
+                if param < 1 || param as usize > n_params {
+                    return Err(prmtop.error(flag, format!("parameter index {} out of range in {}", param, flag)));
+                }
+                result.push((atoms, param as usize - 1));
+            }
+        }
+        Ok(result)
+    };
+
+    let bond_flags = ["BONDS_INC_HYDROGEN", "BONDS_WITHOUT_HYDROGEN"];
+    for (atoms, t) in interactions(bond_flags, 2, n_bond_types)? {
+        top.bonds.push(Bond {
+            atoms: [atoms[0] as usize, atoms[1] as usize],
+            length: bond_r[t] * NM_PER_A * NM,
+            force_constant: 2.0 * bond_k[t] * KJ_PER_KCAL / (NM_PER_A * NM_PER_A) * KJPM / NM2
+        });
+    }
+
+    let angle_flags = ["ANGLES_INC_HYDROGEN", "ANGLES_WITHOUT_HYDROGEN"];
+    for (atoms, t) in interactions(angle_flags, 3, n_angle_types)? {
+        top.angles.push(Angle {
+            atoms: [atoms[0] as usize, atoms[1] as usize, atoms[2] as usize],
+            angle: angle_theta[t],
+            force_constant: 2.0 * angle_k[t] * KJ_PER_KCAL * KJPM
+        });
+    }
+
+    // A negative third atom means the 1-4 pair is counted by another
+    // dihedral, and a negative fourth atom marks an improper
+    let dihedral_flags = ["DIHEDRALS_INC_HYDROGEN", "DIHEDRALS_WITHOUT_HYDROGEN"];
+    let mut scale_factors: Option<(f32, f32)> = None;
+    for (atoms, t) in interactions(dihedral_flags, 4, n_dihedral_types)? {
+        let [i, j, k, l] = [atoms[0], atoms[1], atoms[2], atoms[3]];
+        let unsigned = [i, j, k, l].map(|x| x.unsigned_abs() as usize);
+        top.dihedrals.push(Dihedral {
+            atoms: unsigned,
+            phase: dihedral_phase[t],
+            force_constant: dihedral_k[t] * KJ_PER_KCAL * KJPM,
+            multiplicity: dihedral_n[t].abs().round() as u32
+        });
+
+        if k >= 0 && l >= 0 {
+            top.pairs.push(Pair { atoms: [unsigned[0], unsigned[3]] });
+
+            let factors = (
+                scee.as_ref().map_or(1.2, |scee| scee[t]),
+                scnb.as_ref().map_or(2.0, |scnb| scnb[t])
+            );
+            match scale_factors {
+                None => scale_factors = Some(factors),
+                Some(existing) if existing != factors => {
+                    return Err(prmtop.error("SCEE_SCALE_FACTOR", "1-4 scale factors that differ between dihedrals are not supported"));
+                },
+                Some(_) => {}
+            }
+        }
+    }
+    let (scee, scnb) = scale_factors.unwrap_or((1.2, 2.0));
+    top.fudge_qq = if scee == 0.0 { 0.0 } else { 1.0 / scee };
+    top.fudge_lj = if scnb == 0.0 { 0.0 } else { 1.0 / scnb };
+
+    // Each atom lists the higher numbered atoms it excludes, or a single
+    // zero if there are none
+    let n_excluded_atoms: Vec<usize> = prmtop.values_len("NUMBER_EXCLUDED_ATOMS", n_atoms)?;
+    let excluded: Vec<usize> = prmtop.values_len("EXCLUDED_ATOMS_LIST", n_excluded)?;
+    let mut start = 0;
+    for (i, &count) in n_excluded_atoms.iter().enumerate() {
+        let end = start + count;
+        let list = excluded.get(start..end)
+            .ok_or_else(|| prmtop.error("EXCLUDED_ATOMS_LIST", "too few excluded atoms"))?;
+        for &j in list.iter().filter(|&&j| j > 0) {
+            if j > n_atoms {
+                return Err(prmtop.error("EXCLUDED_ATOMS_LIST", format!("excluded atom {} out of range", j)));
+            }
+            top.add_exclusion(i, j - 1);
+        }
+        start = end;
+    }
+
+    // Residues are given by the first atom of each, numbered from one
+    let labels: Vec<String> = prmtop.values_len("RESIDUE_LABEL", n_residues)?;
+    let first_atoms: Vec<usize> = prmtop.values_len("RESIDUE_POINTER", n_residues)?;
+    for (r, label) in labels.iter().enumerate() {
+        let no_atoms = || prmtop.error("RESIDUE_POINTER", format!("residue {} has no atoms", r + 1));
+        let start = first_atoms[r].checked_sub(1).ok_or_else(no_atoms)?;
+        let end = match first_atoms.get(r + 1) {
+            Some(next) => next.checked_sub(1).ok_or_else(no_atoms)?,
+            None => n_atoms
+        };
+        if start >= end || end > n_atoms {
+            return Err(no_atoms());
+        }
+        top.add_residue(label, r as i64 + 1, start..end);
+    }
+
+    if let Some(sizes) = prmtop.optional::<usize>("ATOMS_PER_MOLECULE")? {
+        if sizes.iter().sum::<usize>() != n_atoms || sizes.contains(&0) {
+            return Err(prmtop.error("ATOMS_PER_MOLECULE", "molecule sizes don't add up to the number of atoms"));
+        }
+        let mut start = 0;
+        let mut blocks: Vec<(String, usize, usize)> = vec![];
+        for &size in sizes.iter() {
+            let name = top.residue_of(start).map_or("MOL", |r| top.residues()[r].name.as_str()).to_string();
+            match blocks.last_mut() {
+                Some((last, n_atoms, count)) if *last == name && *n_atoms == size => *count += 1,
+                _ => blocks.push((name, size, 1))
+            }
+            start += size;
+        }
+        for (name, n_atoms, count) in blocks {
+            top.add_molecules(&name, n_atoms, count);
+        }
+    }
+
+    Ok(top)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sections of floats, integers and strings in the usual formats
+    fn section(flag: &str, values: &[f64]) -> String {
+        let mut text = format!("%FLAG {}\n%FORMAT(5E16.8)\n", flag);
+        for chunk in values.chunks(5) {
+            for x in chunk {
+                text.push_str(&format!("{:>16.8E}", x));
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    fn int_section(flag: &str, values: &[i64]) -> String {
+        let mut text = format!("%FLAG {}\n%FORMAT(10I8)\n", flag);
+        for chunk in values.chunks(10) {
+            for x in chunk {
+                text.push_str(&format!("{:>8}", x));
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    fn string_section(flag: &str, values: &[&str]) -> String {
+        let mut text = format!("%FLAG {}\n%FORMAT(20a4)\n", flag);
+        for chunk in values.chunks(20) {
+            for x in chunk {
+                text.push_str(&format!("{:<4}", x));
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    /// A four atom chain C-C-C-H with two Lennard-Jones types, where the
+    /// mixed pair doesn't follow Lorentz-Berthelot
+    fn chain() -> String {
+        let lj = |epsilon: f64, sigma: f64| {
+            let sig6 = sigma.powi(6);
+            (4.0 * epsilon * sig6 * sig6, 4.0 * epsilon * sig6)
+        };
+        let (a11, b11) = lj(0.1094, 3.39967);
+        let (a12, b12) = lj(0.05, 3.0);
+        let (a22, b22) = lj(0.0157, 2.64953);
+
+        let mut pointers = vec![0; 31];
+        pointers[0] = 4;
+        pointers[1] = 2;
+        pointers[10] = 7;
+        pointers[15] = 2;
+        pointers[16] = 1;
+        pointers[11] = 2;
+        pointers[17] = 2;
+
+        [
+            "%VERSION  VERSION_STAMP = V0001.000  DATE = 01/01/20  00:00:00\n".to_string(),
+            string_section("TITLE", &["chai"]),
+            int_section("POINTERS", &pointers),
+            string_section("ATOM_NAME", &["C1", "C2", "C3", "H4"]),
+            section("CHARGE", &[-0.1 * 18.2223, 0.0, -0.05 * 18.2223, 0.15 * 18.2223]),
+            section("MASS", &[12.01, 12.01, 12.01, 1.008]),
+            int_section("ATOM_TYPE_INDEX", &[1, 1, 1, 2]),
+            int_section("NUMBER_EXCLUDED_ATOMS", &[3, 2, 1, 1]),
+            int_section("NONBONDED_PARM_INDEX", &[1, 2, 2, 3]),
+            section("LENNARD_JONES_ACOEF", &[a11, a12, a22]),
+            section("LENNARD_JONES_BCOEF", &[b11, b12, b22]),
+            section("BOND_FORCE_CONSTANT", &[310.0, 340.0]),
+            section("BOND_EQUIL_VALUE", &[1.526, 1.09]),
+            section("ANGLE_FORCE_CONSTANT", &[40.0]),
+            section("ANGLE_EQUIL_VALUE", &[1.9111]),
+            section("DIHEDRAL_FORCE_CONSTANT", &[0.15, 0.25]),
+            section("DIHEDRAL_PERIODICITY", &[3.0, 1.0]),
+            section("DIHEDRAL_PHASE", &[0.0, 3.14159400]),
+            section("SCEE_SCALE_FACTOR", &[1.2, 1.2]),
+            section("SCNB_SCALE_FACTOR", &[2.0, 2.0]),
+            int_section("BONDS_INC_HYDROGEN", &[6, 9, 2]),
+            int_section("BONDS_WITHOUT_HYDROGEN", &[0, 3, 1, 3, 6, 1]),
+            int_section("ANGLES_INC_HYDROGEN", &[3, 6, 9, 1]),
+            int_section("ANGLES_WITHOUT_HYDROGEN", &[0, 3, 6, 1]),
+            int_section("DIHEDRALS_INC_HYDROGEN", &[0, 3, 6, 9, 1, 0, 3, -6, 9, 2]),
+            int_section("DIHEDRALS_WITHOUT_HYDROGEN", &[]),
+            int_section("EXCLUDED_ATOMS_LIST", &[2, 3, 4, 3, 4, 4, 0]),
+            string_section("AMBER_ATOM_TYPE", &["CT", "CT", "CT", "HC"]),
+            string_section("RESIDUE_LABEL", &["PRP", "HYD"]),
+            int_section("RESIDUE_POINTER", &[1, 4]),
+            int_section("ATOMIC_NUMBER", &[6, 6, 6, 1]),
+            int_section("ATOMS_PER_MOLECULE", &[4]),
+        ].concat()
+    }
+
+    #[test]
+    fn reads_atoms_and_parameters() {
+        let top = parse_prmtop(&chain(), Path::new("chain.prmtop")).unwrap();
+
+        assert_eq!(top.atoms.len(), 4);
+        assert_eq!(top.atom_types().len(), 2);
+        assert_eq!(top.atom_types().get(1).name, "HC");
+        assert!((top.atoms[0].charge - -0.1 * E).value_unsafe.abs() < 1e-6);
+        assert!((top.atoms[3].charge - 0.15 * E).value_unsafe.abs() < 1e-6);
+        assert_eq!(top.atoms[3].mass, 1.008 * DA);
+        assert_eq!(top.atoms[3].name, "H4");
+        assert_eq!(top.atoms[3].element.as_deref(), Some("H"));
+        assert_eq!(top.residues()[1].atoms, 3..4);
+        assert_eq!(top.residue_of(2), Some(0));
+        assert_eq!(top.molecule_of(3), Some((0, 0)));
+        assert_eq!(top.molecules()[0].name, "PRP");
+
+        let ct = top.atom_types().get(0);
+        assert!((ct.epsilon - 0.1094 * 4.184 * KJPM).value_unsafe.abs() < 1e-5);
+        assert!((ct.sigma - 0.339967 * NM).value_unsafe.abs() < 1e-6);
+        assert_eq!(top.nbfix().len(), 1);
+        let (epsilon, sigma) = top.lj_table().parameters(0, 1);
+        assert!((epsilon - 0.05 * 4.184 * KJPM).value_unsafe.abs() < 1e-5);
+        assert!((sigma - 0.3 * NM).value_unsafe.abs() < 1e-6);
+
+        assert_eq!(top.bonds.len(), 3);
+        assert_eq!(top.bonds[0].atoms, [2, 3]);
+        assert!((top.bonds[0].length - 0.109 * NM).value_unsafe.abs() < 1e-6);
+        assert!((top.bonds[0].force_constant - 284512.0 * KJPM / NM2).value_unsafe.abs() < 0.1);
+        assert!((top.angles[0].force_constant - 334.72 * KJPM).value_unsafe.abs() < 1e-3);
+
+        // The second dihedral term shares its 1-4 pair with the first
+        assert_eq!(top.dihedrals.len(), 2);
+        assert_eq!(top.dihedrals[1].atoms, [0, 1, 2, 3]);
+        assert_eq!(top.dihedrals[1].multiplicity, 1);
+        assert_eq!(top.pairs, vec![Pair { atoms: [0, 3] }]);
+        assert!((top.fudge_qq - 1.0 / 1.2).abs() < 1e-6);
+        assert_eq!(top.fudge_lj, 0.5);
+
+        assert_eq!(top.exclusions[0], vec![1, 2, 3]);
+        assert_eq!(top.exclusions[3], vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn errors_name_file_and_line() {
+        let text = chain().replace("%FLAG MASS", "%FLAG MASSES");
+        let error = parse_prmtop(&text, Path::new("chain.prmtop")).unwrap_err();
+        assert_eq!(error.to_string(), "chain.prmtop: missing %FLAG MASS");
+
+        let text = chain().replace("       2       2       3", "       2       x       3");
+        let error = parse_prmtop(&text, Path::new("chain.prmtop")).unwrap_err();
+        assert_eq!(error.to_string(), "chain.prmtop:28: invalid value \"x\" in NONBONDED_PARM_INDEX");
+
+        for pointers in [[0, 4], [1, 0]].iter() {
+            let text = chain().replace(&int_section("RESIDUE_POINTER", &[1, 4]), &int_section("RESIDUE_POINTER", pointers));
+            let error = parse_prmtop(&text, Path::new("chain.prmtop")).unwrap_err();
+            assert_eq!(error.to_string(), "chain.prmtop:89: residue 1 has no atoms");
+        }
+    }
+}
@@ -0,0 +1,1071 @@
+//! GROMACS `.top` and `.itp` topologies
+//!
+//! Topologies are run through a preprocessor that handles `#include`,
+//! `#define`, `#undef`, `#ifdef`, `#ifndef`, `#else` and `#endif`, and
+//! substitutes defined macros into data lines, before the directives are
+//! read. The supported directives are `[ defaults ]`, `[ atomtypes ]`,
+//! `[ nonbond_params ]`, `[ bondtypes ]`, `[ angletypes ]`,
+//! `[ dihedraltypes ]`, `[ moleculetype ]`, `[ atoms ]`, `[ bonds ]`,
+//! `[ pairs ]`, `[ angles ]`, `[ dihedrals ]`, `[ exclusions ]`,
+//! `[ system ]` and `[ molecules ]`, and `[ constrainttypes ]` is skipped.
+//! Anything else is an error naming the file and line, as is any
+//! interaction whose function type has no equivalent here. That includes
+//! `[ settles ]`, `[ constraints ]`, `[ pairtypes ]` and `[ cmaptypes ]`,
+//! so rigid water models need `#define FLEXIBLE`.
+//!
+//! Bonded interactions without parameters of their own take them from
+//! the matching `[ *types ]` entry with the fewest `X` wildcards, by the
+//! bonded types of their atoms. Supported function types are harmonic
+//! bonds (1) and connections (5), harmonic angles (1), periodic proper and
+//! improper dihedrals (1, 4 and 9) and harmonic impropers (2). 1-4 pairs
+//! use the atom types' Lennard-Jones parameters scaled by `fudgeLJ`, and
+//! must not have parameters of their own, so `gen-pairs` must be `yes`.
+//!
+//! Atom names, residues and molecules are kept in the topology. Residue
+//! numbers continue from the previous molecule where they would otherwise
+//! repeat, as they do for every copy of a water, and elements come from
+//! the atomic numbers of the atom types that have them.
+//!
+//! GROMACS units are the same as noether's, except that angles are in
+//! degrees.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::units::f32consts::*;
+use crate::elements;
+use crate::topology::{
+    Top,
+    Atom,
+    AtomType,
+    TypeLibrary,
+    CombinationRule
+};
+use crate::potentials::bonded::{
+    Bond,
+    Angle,
+    Dihedral,
+    Improper,
+    Pair
+};
+use super::parse_error;
+
+/// Includes nested deeper than this are assumed to be recursive
+const MAX_INCLUDE_DEPTH: usize = 64;
+
+/// Read the GROMACS topology at `path`
+///
+/// Included files are looked for relative to the including file, and
+/// then in each of `include_dirs`, which would usually contain the
+/// GROMACS force field directory (`$GMXLIB`).
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::PathBuf;
+/// use noether::readers::gromacs;
+///
+/// let top = gromacs::read_top("topol.top", &[PathBuf::from("/usr/share/gromacs/top")]).unwrap();
+/// println!("Read {} atoms", top.atoms.len());
+/// ```
+pub fn read_top<P: AsRef<Path>>(path: P, include_dirs: &[PathBuf]) -> io::Result<Top> {
+    read_top_with(path.as_ref(), include_dirs, &|path| fs::read_to_string(path))
+}
+
+/// Read a topology, using `read` to get the contents of each file
+fn read_top_with(
+    path: &Path,
+    include_dirs: &[PathBuf],
+    read: &dyn Fn(&Path) -> io::Result<String>
+) -> io::Result<Top> {
+    let mut preprocessor = Preprocessor {
+        include_dirs,
+        read,
+        defines: HashMap::new(),
+        lines: vec![]
+    };
+    preprocessor.process(path, 0)?;
+
+    let mut reader = TopReader::default();
+    for line in preprocessor.lines.iter() {
+        reader.read_line(&line.text)
+            .map_err(|message| parse_error(&line.file, line.number, message))?;
+    }
+    Ok(reader.build())
+}
+
+/// A line of a topology after preprocessing, and where it came from
+struct Line {
+    file: PathBuf,
+    number: usize,
+    text: String
+}
+
+struct Preprocessor<'a> {
+    include_dirs: &'a [PathBuf],
+    read: &'a dyn Fn(&Path) -> io::Result<String>,
+    defines: HashMap<String, String>,
+    lines: Vec<Line>
+}
+
+impl<'a> Preprocessor<'a> {
+    fn process(&mut self, path: &Path, depth: usize) -> io::Result<()> {
+        let text = (self.read)(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        self.process_text(path, &text, depth)
+    }
+
+    /// Preprocess `text`, the contents of the file at `path`
+    fn process_text(&mut self, path: &Path, text: &str, depth: usize) -> io::Result<()> {
+        let error = |number: usize, message: &str| parse_error(path, number, message);
+
+        // Whether each enclosing #ifdef is active, and whether its #else
+        // has been seen
+        let mut conditions: Vec<(bool, bool)> = vec![];
+        let mut last_number = 0;
+        let mut raw_lines = text.lines().enumerate();
+        while let Some((index, raw)) = raw_lines.next() {
+            let number = index + 1;
+            last_number = number;
+
+            // Join continuation lines
+            let mut line = raw.to_string();
+            while line.ends_with('\\') {
+                line.pop();
+                match raw_lines.next() {
+                    Some((_, next)) => {
+                        line.push(' ');
+                        line.push_str(next);
+                    },
+                    None => break
+                }
+            }
+
+            let line = match line.find(';') {
+                Some(comment) => &line[..comment],
+                None => &line[..]
+            }.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let active = conditions.iter().all(|&(active, _)| active);
+
+            if let Some(directive) = line.strip_prefix('#') {
+                let mut words = directive.split_whitespace();
+                let keyword = words.next().unwrap_or("");
+                let argument = words.next();
+                match keyword {
+                    "ifdef" | "ifndef" => {
+                        let name = argument.ok_or_else(|| error(number, "missing macro name"))?;
+                        let defined = self.defines.contains_key(name);
+                        conditions.push((defined == (keyword == "ifdef"), false));
+                    },
+                    "else" => match conditions.last_mut() {
+                        Some((active, seen_else)) if !*seen_else => {
+                            *active = !*active;
+                            *seen_else = true;
+                        },
+                        _ => return Err(error(number, "#else without #ifdef"))
+                    },
+                    "endif" => {
+                        if conditions.pop().is_none() {
+                            return Err(error(number, "#endif without #ifdef"));
+                        }
+                    },
+                    _ if !active => {},
+                    "define" => {
+                        let name = argument.ok_or_else(|| error(number, "missing macro name"))?;
+                        let value = words.collect::<Vec<_>>().join(" ");
+                        self.defines.insert(name.to_string(), value);
+                    },
+                    "undef" => {
+                        let name = argument.ok_or_else(|| error(number, "missing macro name"))?;
+                        self.defines.remove(name);
+                    },
+                    "include" => {
+                        let name = directive["include".len()..].trim()
+                            .trim_matches(|c| c == '"' || c == '<' || c == '>');
+                        if depth >= MAX_INCLUDE_DEPTH {
+                            return Err(error(number, "too many nested includes"));
+                        }
+                        let (included, text) = self.find_include(path, name)
+                            .ok_or_else(|| error(number, &format!("could not find included file {}", name)))?;
+                        self.process_text(&included, &text, depth + 1)?;
+                    },
+                    _ => {
+                        return Err(error(number, &format!("unsupported preprocessor directive #{}", keyword)));
+                    }
+                }
+                continue;
+            }
+
+            if active {
+                let text = line.split_whitespace()
+                    .map(|word| self.defines.get(word).map_or(word, String::as_str))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                self.lines.push(Line { file: path.to_path_buf(), number, text });
+            }
+        }
+
+        if !conditions.is_empty() {
+            return Err(error(last_number, "missing #endif"));
+        }
+        Ok(())
+    }
+
+    /// The path to an included file, relative to the including file or
+    /// one of the include directories, and its contents
+    fn find_include(&self, from: &Path, name: &str) -> Option<(PathBuf, String)> {
+        let local = from.parent().map(|dir| dir.join(name));
+        local.into_iter()
+            .chain(self.include_dirs.iter().map(|dir| dir.join(name)))
+            .find_map(|candidate| (self.read)(&candidate).ok().map(|text| (candidate, text)))
+    }
+}
+
+/// The directive that the following lines belong to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Directive {
+    None,
+    Defaults,
+    AtomTypes,
+    NonbondParams,
+    BondTypes,
+    ConstraintTypes,
+    AngleTypes,
+    DihedralTypes,
+    MoleculeType,
+    Atoms,
+    Bonds,
+    Pairs,
+    Angles,
+    Dihedrals,
+    Exclusions,
+    System,
+    Molecules
+}
+
+/// The contents of `[ defaults ]`
+#[derive(Debug, Clone, Copy)]
+struct Defaults {
+    comb_rule: u32,
+    fudge_lj: f32,
+    fudge_qq: f32
+}
+
+impl Default for Defaults {
+    fn default() -> Defaults {
+        Defaults { comb_rule: 1, fudge_lj: 1.0, fudge_qq: 1.0 }
+    }
+}
+
+/// An entry of `[ bondtypes ]`, `[ angletypes ]` or `[ dihedraltypes ]`
+#[derive(Debug)]
+struct BondedType {
+    /// The bonded types of the atoms, where `X` matches any type
+    types: Vec<String>,
+    function: u32,
+    /// The fields after the function type, as they would follow it on an
+    /// interaction line
+    parameters: Vec<String>
+}
+
+impl BondedType {
+    /// The number of wildcards in the entry if it matches `types` in
+    /// either direction
+    fn matches(&self, types: &[&str]) -> Option<usize> {
+        let n = types.len();
+        let fits = |k: usize, name: &str| self.types[k] == "X" || self.types[k] == name;
+        if (0..n).all(|k| fits(k, types[k])) || (0..n).all(|k| fits(k, types[n - 1 - k])) {
+            Some(self.types.iter().filter(|entry| *entry == "X").count())
+        } else {
+            None
+        }
+    }
+}
+
+/// The atoms and interactions of a molecule, numbered from zero within
+/// the molecule
+#[derive(Debug, Default)]
+struct MoleculeType {
+    name: String,
+    nrexcl: usize,
+    atoms: Vec<Atom>,
+    /// The number, name and atoms of each residue
+    residues: Vec<(i64, String, Range<usize>)>,
+    bonds: Vec<Bond>,
+    pairs: Vec<Pair>,
+    angles: Vec<Angle>,
+    dihedrals: Vec<Dihedral>,
+    impropers: Vec<Improper>,
+    exclusions: Vec<[usize; 2]>
+}
+
+#[derive(Debug)]
+struct TopReader {
+    directive: Directive,
+    defaults: Option<Defaults>,
+    atom_types: TypeLibrary,
+    /// The element of each atom type with an atomic number
+    elements: HashMap<String, String>,
+    /// The bonded type of each atom type that has its own
+    bonded_types: HashMap<String, String>,
+    bond_types: Vec<BondedType>,
+    angle_types: Vec<BondedType>,
+    dihedral_types: Vec<BondedType>,
+    /// Pair-specific parameters from `[ nonbond_params ]`, as the names
+    /// of the types, epsilon and sigma
+    nbfix: Vec<(String, String, f32, f32)>,
+    molecule_types: Vec<MoleculeType>,
+    molecules: Vec<(usize, usize)>
+}
+
+impl Default for TopReader {
+    fn default() -> TopReader {
+        TopReader {
+            directive: Directive::None,
+            defaults: None,
+            atom_types: TypeLibrary::new(),
+            elements: HashMap::new(),
+            bonded_types: HashMap::new(),
+            bond_types: vec![],
+            angle_types: vec![],
+            dihedral_types: vec![],
+            nbfix: vec![],
+            molecule_types: vec![],
+            molecules: vec![]
+        }
+    }
+}
+
+/// Parse field `index` of a line, naming it `what` in errors
+fn field<T: FromStr>(fields: &[&str], index: usize, what: &str) -> Result<T, String> {
+    let text = fields.get(index)
+        .ok_or_else(|| format!("missing {}", what))?;
+    text.parse()
+        .map_err(|_| format!("invalid {} \"{}\"", what, text))
+}
+
+impl TopReader {
+    fn defaults(&self) -> Defaults {
+        self.defaults.unwrap_or_default()
+    }
+
+    /// Convert the two Lennard-Jones parameters of an atom type or pair to
+    /// epsilon and sigma, according to the combination rule
+    fn lj_parameters(&self, v: f32, w: f32) -> Result<(f32, f32), String> {
+        if self.defaults().comb_rule != 1 {
+            return Ok((w, v));
+        }
+        // V and W are C6 and C12
+        match (v, w) {
+            (c6, c12) if c6 == 0.0 && c12 == 0.0 => Ok((0.0, 0.0)),
+            (c6, c12) if c6 > 0.0 && c12 > 0.0 => {
+                Ok((c6 * c6 / (4.0 * c12), (c12 / c6).powf(1.0 / 6.0)))
+            },
+            _ => Err("C6 and C12 must both be positive or both be zero".to_string())
+        }
+    }
+
+    fn molecule_type(&mut self) -> Result<&mut MoleculeType, String> {
+        self.molecule_types.last_mut()
+            .ok_or_else(|| "interactions must follow a [ moleculetype ]".to_string())
+    }
+
+    /// Parse the first `n` fields of a bonded interaction as atom numbers
+    /// in the current molecule, and return them numbered from zero
+    fn atom_numbers<const N: usize>(&mut self, fields: &[&str]) -> Result<[usize; N], String> {
+        let n_atoms = self.molecule_type()?.atoms.len();
+        let mut atoms = [0; N];
+        for (k, atom) in atoms.iter_mut().enumerate() {
+            let number: usize = field(fields, k, "atom number")?;
+            if number == 0 || number > n_atoms {
+                return Err(format!("atom {} is not in the molecule", number));
+            }
+            *atom = number - 1;
+        }
+        Ok(atoms)
+    }
+
+    /// Parse an entry of `[ bondtypes ]`, `[ angletypes ]` or
+    /// `[ dihedraltypes ]`, which name `n` bonded types and then have the
+    /// same fields as an interaction
+    fn read_bonded_type(fields: &[&str], n: usize) -> Result<BondedType, String> {
+        // Dihedral types can name only two atoms, the middle two of proper
+        // dihedrals or the outer two of impropers
+        if n == 4 && fields.get(2).is_some_and(|word| word.parse::<u32>().is_ok()) {
+            let mut entry = TopReader::read_bonded_type(fields, 2)?;
+            let (a, b) = (entry.types[0].clone(), entry.types[1].clone());
+            let any = || "X".to_string();
+            entry.types = match entry.function {
+                2 | 4 => vec![a, any(), any(), b],
+                _ => vec![any(), a, b, any()]
+            };
+            return Ok(entry);
+        }
+        let types = (0..n).map(|k| field(fields, k, "bonded type")).collect::<Result<_, _>>()?;
+        Ok(BondedType {
+            types,
+            function: field(fields, n, "function type")?,
+            parameters: fields.iter().skip(n + 1).map(|word| word.to_string()).collect()
+        })
+    }
+
+    /// The parameter fields of an interaction between `atoms` of the
+    /// current molecule, which follow the atoms and the function type on
+    /// its line, or else come from the entries of `[ *types ]` that match
+    /// with the fewest wildcards. Periodic dihedrals (9) take every
+    /// matching entry for those types, and others only the first.
+    fn parameters(
+        &self,
+        fields: &[&str],
+        atoms: &[usize],
+        entries: &[BondedType],
+        what: &str
+    ) -> Result<Vec<Vec<String>>, String> {
+        let function: u32 = field(fields, atoms.len(), "function type")?;
+        if fields.len() > atoms.len() + 1 {
+            return Ok(vec![fields[atoms.len() + 1..].iter().map(|word| word.to_string()).collect()]);
+        }
+
+        let molecule = self.molecule_types.last()
+            .ok_or_else(|| "interactions must follow a [ moleculetype ]".to_string())?;
+        let types: Vec<&str> = atoms.iter()
+            .map(|&i| {
+                let name = &self.atom_types.get(molecule.atoms[i].atom_type).name;
+                self.bonded_types.get(name).unwrap_or(name).as_str()
+            })
+            .collect();
+        let candidates = || entries.iter()
+            .filter(|entry| entry.function == function)
+            .filter_map(|entry| entry.matches(&types).map(|wildcards| (wildcards, entry)));
+        let best = candidates().min_by_key(|&(wildcards, _)| wildcards)
+            .map(|(_, entry)| entry)
+            .ok_or_else(|| format!("no {} parameters for types {}", what, types.join(" ")))?;
+        if function != 9 {
+            return Ok(vec![best.parameters.clone()]);
+        }
+        Ok(candidates()
+            .filter(|(_, entry)| entry.types == best.types)
+            .map(|(_, entry)| entry.parameters.clone())
+            .collect())
+    }
+
+    fn read_line(&mut self, line: &str) -> Result<(), String> {
+        if line.starts_with('[') {
+            let name = line.trim_start_matches('[').trim_end_matches(']').trim();
+            self.directive = match name {
+                "defaults" => Directive::Defaults,
+                "atomtypes" => Directive::AtomTypes,
+                "nonbond_params" => Directive::NonbondParams,
+                "bondtypes" => Directive::BondTypes,
+                "constrainttypes" => Directive::ConstraintTypes,
+                "angletypes" => Directive::AngleTypes,
+                "dihedraltypes" => Directive::DihedralTypes,
+                "moleculetype" => Directive::MoleculeType,
+                "atoms" => Directive::Atoms,
+                "bonds" => Directive::Bonds,
+                "pairs" => Directive::Pairs,
+                "angles" => Directive::Angles,
+                "dihedrals" => Directive::Dihedrals,
+                "exclusions" => Directive::Exclusions,
+                "system" => Directive::System,
+                "molecules" => Directive::Molecules,
+                _ => return Err(format!("unsupported directive [ {} ]", name))
+            };
+            return Ok(());
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match self.directive {
+            Directive::None => Err("data outside of any directive".to_string()),
+            Directive::Defaults => self.read_defaults(&fields),
+            Directive::AtomTypes => self.read_atom_type(&fields),
+            Directive::NonbondParams => {
+                let a = field::<String>(&fields, 0, "atom type")?;
+                let b = field::<String>(&fields, 1, "atom type")?;
+                for name in [&a, &b].iter() {
+                    if self.atom_types.index(name).is_none() {
+                        return Err(format!("unknown atom type {}", name));
+                    }
+                }
+                let (epsilon, sigma) = self.lj_parameters(
+                    field(&fields, 3, "V")?,
+                    field(&fields, 4, "W")?
+                )?;
+                self.nbfix.push((a, b, epsilon, sigma));
+                Ok(())
+            },
+            Directive::BondTypes => {
+                self.bond_types.push(TopReader::read_bonded_type(&fields, 2)?);
+                Ok(())
+            },
+            // Only used by [ constraints ], which are not supported
+            Directive::ConstraintTypes => Ok(()),
+            Directive::AngleTypes => {
+                self.angle_types.push(TopReader::read_bonded_type(&fields, 3)?);
+                Ok(())
+            },
+            Directive::DihedralTypes => {
+                self.dihedral_types.push(TopReader::read_bonded_type(&fields, 4)?);
+                Ok(())
+            },
+            Directive::MoleculeType => {
+                self.molecule_types.push(MoleculeType {
+                    name: field(&fields, 0, "molecule name")?,
+                    nrexcl: field(&fields, 1, "nrexcl")?,
+                    ..MoleculeType::default()
+                });
+                Ok(())
+            },
+            Directive::Atoms => self.read_atom(&fields),
+            Directive::Bonds => self.read_bond(&fields),
+            Directive::Pairs => {
+                let atoms = self.atom_numbers::<2>(&fields)?;
+                if field::<u32>(&fields, 2, "function type")? != 1 {
+                    return Err("only pairs of function type 1 are supported".to_string());
+                }
+                if fields.len() > 3 {
+                    return Err("pairs with their own parameters are not supported".to_string());
+                }
+                self.molecule_type()?.pairs.push(Pair { atoms });
+                Ok(())
+            },
+            Directive::Angles => {
+                let atoms = self.atom_numbers::<3>(&fields)?;
+                if field::<u32>(&fields, 3, "function type")? != 1 {
+                    return Err("only harmonic angles (function type 1) are supported".to_string());
+                }
+                let parameters = self.parameters(&fields, &atoms, &self.angle_types, "angle")?;
+                let parameters: Vec<&str> = parameters[0].iter().map(String::as_str).collect();
+                let angle: f32 = field(&parameters, 0, "angle")?;
+                let force_constant: f32 = field(&parameters, 1, "force constant")?;
+                self.molecule_type()?.angles.push(Angle {
+                    atoms,
+                    angle: angle.to_radians(),
+                    force_constant: force_constant * KJPM
+                });
+                Ok(())
+            },
+            Directive::Dihedrals => self.read_dihedral(&fields),
+            Directive::Exclusions => {
+                let atoms = self.atom_numbers::<1>(&fields)?;
+                for k in 1..fields.len() {
+                    let [other] = self.atom_numbers::<1>(&fields[k..])?;
+                    self.molecule_type()?.exclusions.push([atoms[0], other]);
+                }
+                Ok(())
+            },
+            Directive::System => Ok(()),
+            Directive::Molecules => {
+                let name: String = field(&fields, 0, "molecule name")?;
+                let count = field(&fields, 1, "molecule count")?;
+                let index = self.molecule_types.iter()
+                    .position(|molecule| molecule.name == name)
+                    .ok_or_else(|| format!("unknown molecule type {}", name))?;
+                self.molecules.push((index, count));
+                Ok(())
+            }
+        }
+    }
+
+    fn read_defaults(&mut self, fields: &[&str]) -> Result<(), String> {
+        if field::<u32>(fields, 0, "nbfunc")? != 1 {
+            return Err("only Lennard-Jones (nbfunc 1) is supported".to_string());
+        }
+        let comb_rule = field(fields, 1, "comb-rule")?;
+        if !(1..=3).contains(&comb_rule) {
+            return Err(format!("unsupported comb-rule {}", comb_rule));
+        }
+        // Without generated pairs, 1-4 pairs would need [ pairtypes ]
+        if fields.len() > 2 {
+            match fields[2] {
+                "yes" => {},
+                "no" => return Err("only gen-pairs yes is supported".to_string()),
+                other => return Err(format!("invalid gen-pairs \"{}\"", other))
+            }
+        }
+        let mut defaults = Defaults { comb_rule, ..Defaults::default() };
+        if fields.len() > 3 {
+            defaults.fudge_lj = field(fields, 3, "fudgeLJ")?;
+        }
+        if fields.len() > 4 {
+            defaults.fudge_qq = field(fields, 4, "fudgeQQ")?;
+        }
+        self.defaults = Some(defaults);
+        Ok(())
+    }
+
+    /// Atom types have a name, optionally a bonded type and an atomic
+    /// number, and then mass, charge, particle type and the two
+    /// Lennard-Jones parameters
+    fn read_atom_type(&mut self, fields: &[&str]) -> Result<(), String> {
+        let n = fields.len();
+        if n < 6 {
+            return Err("too few fields for an atom type".to_string());
+        }
+        if fields[n - 3] != "A" {
+            return Err(format!("unsupported particle type {}", fields[n - 3]));
+        }
+        let (epsilon, sigma) = self.lj_parameters(
+            field(fields, n - 2, "V")?,
+            field(fields, n - 1, "W")?
+        )?;
+        let name: String = field(fields, 0, "atom type")?;
+        // The atomic number is the field before the mass, unless that is a
+        // bonded type
+        if n > 6 {
+            if let Some(element) = fields[n - 6].parse().ok().and_then(elements::symbol) {
+                self.elements.insert(name.clone(), element.to_string());
+            }
+        }
+        if n > 7 || (n == 7 && fields[1].parse::<u32>().is_err()) {
+            self.bonded_types.insert(name.clone(), fields[1].to_string());
+        }
+        let atom_type = AtomType {
+            name,
+            mass: field::<f32>(fields, n - 5, "mass")? * DA,
+            charge: field::<f32>(fields, n - 4, "charge")? * E,
+            epsilon: epsilon * KJPM,
+            sigma: sigma * NM
+        };
+
+        // Later definitions override earlier ones, as in GROMACS
+        match self.atom_types.index(&atom_type.name) {
+            Some(index) => *self.atom_types.get_mut(index) = atom_type,
+            None => { self.atom_types.add(atom_type); }
+        }
+        Ok(())
+    }
+
+    /// Atoms have a number, type, residue number, residue name, atom name
+    /// and charge group, and optionally a charge and mass that override
+    /// the type's
+    fn read_atom(&mut self, fields: &[&str]) -> Result<(), String> {
+        let number: usize = field(fields, 0, "atom number")?;
+        let type_name: String = field(fields, 1, "atom type")?;
+        let atom_type = self.atom_types.index(&type_name)
+            .ok_or_else(|| format!("unknown atom type {}", type_name))?;
+        let mut atom = self.atom_types.atom(atom_type);
+        atom.name = field(fields, 4, "atom name")?;
+        atom.element = self.elements.get(&type_name).cloned();
+        let residue_number: i64 = field(fields, 2, "residue number")?;
+        let residue_name: String = field(fields, 3, "residue name")?;
+        if fields.len() > 6 {
+            atom.charge = field::<f32>(fields, 6, "charge")? * E;
+        }
+        if fields.len() > 7 {
+            atom.mass = field::<f32>(fields, 7, "mass")? * DA;
+        }
+
+        let molecule = self.molecule_type()?;
+        if number != molecule.atoms.len() + 1 {
+            return Err(format!("expected atom number {}", molecule.atoms.len() + 1));
+        }
+        let index = molecule.atoms.len();
+        match molecule.residues.last_mut() {
+            Some((number, name, atoms)) if *number == residue_number && *name == residue_name => {
+                atoms.end = index + 1;
+            },
+            _ => molecule.residues.push((residue_number, residue_name, index..index + 1))
+        }
+        molecule.atoms.push(atom);
+        Ok(())
+    }
+
+    fn read_bond(&mut self, fields: &[&str]) -> Result<(), String> {
+        let atoms = self.atom_numbers::<2>(fields)?;
+        let bond = match field::<u32>(fields, 2, "function type")? {
+            1 => {
+                let parameters = self.parameters(fields, &atoms, &self.bond_types, "bond")?;
+                let parameters: Vec<&str> = parameters[0].iter().map(String::as_str).collect();
+                let length: f32 = field(&parameters, 0, "bond length")?;
+                let force_constant: f32 = field(&parameters, 1, "force constant")?;
+                Bond { atoms, length: length * NM, force_constant: force_constant * KJPM / NM2 }
+            },
+            // A connection for exclusions only, with no potential
+            5 => Bond { atoms, length: 0.0 * NM, force_constant: 0.0 * KJPM / NM2 },
+            funct => return Err(format!("unsupported bond function type {}", funct))
+        };
+        self.molecule_type()?.bonds.push(bond);
+        Ok(())
+    }
+
+    fn read_dihedral(&mut self, fields: &[&str]) -> Result<(), String> {
+        let atoms = self.atom_numbers::<4>(fields)?;
+        let funct = field::<u32>(fields, 4, "function type")?;
+        if ![1, 2, 4, 9].contains(&funct) {
+            return Err(format!("unsupported dihedral function type {}", funct));
+        }
+        for parameters in self.parameters(fields, &atoms, &self.dihedral_types, "dihedral")? {
+            let parameters: Vec<&str> = parameters.iter().map(String::as_str).collect();
+            if funct == 2 {
+                let angle: f32 = field(&parameters, 0, "angle")?;
+                let force_constant: f32 = field(&parameters, 1, "force constant")?;
+                self.molecule_type()?.impropers.push(Improper {
+                    atoms,
+                    angle: angle.to_radians(),
+                    force_constant: force_constant * KJPM
+                });
+            } else {
+                let phase: f32 = field(&parameters, 0, "phase")?;
+                let force_constant: f32 = field(&parameters, 1, "force constant")?;
+                let multiplicity = field(&parameters, 2, "multiplicity")?;
+                self.molecule_type()?.dihedrals.push(Dihedral {
+                    atoms,
+                    phase: phase.to_radians(),
+                    force_constant: force_constant * KJPM,
+                    multiplicity
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Put copies of each molecule together into a topology
+    fn build(self) -> Top {
+        let defaults = self.defaults();
+        let mut atoms = vec![];
+        let mut nrexcl = vec![];
+        for &(molecule, count) in self.molecules.iter() {
+            let molecule = &self.molecule_types[molecule];
+            for _ in 0..count {
+                atoms.extend(molecule.atoms.iter().cloned());
+                nrexcl.extend(molecule.atoms.iter().map(|_| molecule.nrexcl));
+            }
+        }
+
+        let mut top = Top::new(self.atom_types, atoms);
+        top.fudge_lj = defaults.fudge_lj;
+        top.fudge_qq = defaults.fudge_qq;
+        // C6 and C12 combine geometrically, which is the same as combining
+        // both epsilon and sigma geometrically
+        top.set_combination_rule(match defaults.comb_rule {
+            2 => CombinationRule::LorentzBerthelot,
+            _ => CombinationRule::Geometric
+        });
+        for &(ref a, ref b, epsilon, sigma) in self.nbfix.iter() {
+            top.add_nbfix(a, b, epsilon * KJPM, sigma * NM);
+        }
+
+        let mut offset = 0;
+        let mut exclusions = vec![];
+        let mut last_residue = None;
+        for &(molecule, count) in self.molecules.iter() {
+            let molecule = &self.molecule_types[molecule];
+            if !molecule.atoms.is_empty() {
+                top.add_molecules(&molecule.name, molecule.atoms.len(), count);
+            }
+            for _ in 0..count {
+                // Renumber residues that would repeat an earlier number,
+                // like the copies of a water
+                let shift = match (last_residue, molecule.residues.first()) {
+                    (Some(last), Some(&(first, _, _))) if first <= last => last - first + 1,
+                    _ => 0
+                };
+                for (number, name, atoms) in molecule.residues.iter() {
+                    top.add_residue(name, number + shift, atoms.start + offset..atoms.end + offset);
+                    last_residue = Some(number + shift);
+                }
+
+                let shift = |atoms: &[usize]| -> Vec<usize> {
+                    atoms.iter().map(|i| i + offset).collect()
+                };
+                for bond in molecule.bonds.iter() {
+                    let [i, j] = bond.atoms;
+                    top.bonds.push(Bond { atoms: [i + offset, j + offset], ..bond.clone() });
+                }
+                for pair in molecule.pairs.iter() {
+                    let [i, j] = pair.atoms;
+                    top.pairs.push(Pair { atoms: [i + offset, j + offset] });
+                }
+                for angle in molecule.angles.iter() {
+                    let atoms = shift(&angle.atoms);
+                    top.angles.push(Angle { atoms: [atoms[0], atoms[1], atoms[2]], ..angle.clone() });
+                }
+                for dihedral in molecule.dihedrals.iter() {
+                    let atoms = shift(&dihedral.atoms);
+                    top.dihedrals.push(Dihedral { atoms: [atoms[0], atoms[1], atoms[2], atoms[3]], ..dihedral.clone() });
+                }
+                for improper in molecule.impropers.iter() {
+                    let atoms = shift(&improper.atoms);
+                    top.impropers.push(Improper { atoms: [atoms[0], atoms[1], atoms[2], atoms[3]], ..improper.clone() });
+                }
+                exclusions.extend(molecule.exclusions.iter().map(|&[i, j]| (i + offset, j + offset)));
+                offset += molecule.atoms.len();
+            }
+        }
+
+        // Bonds never cross molecules, so each atom's exclusions can use
+        // its own molecule's nrexcl
+        let max_nrexcl = nrexcl.iter().cloned().max().unwrap_or(0);
+        for (i, separations) in top.bond_separations(max_nrexcl).into_iter().enumerate() {
+            for (j, distance) in separations {
+                if i < j && distance <= nrexcl[i] {
+                    exclusions.push((i, j));
+                }
+            }
+        }
+        for (i, j) in exclusions {
+            top.add_exclusion(i, j);
+        }
+
+        top
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read `name` from a set of in-memory files
+    fn read(files: &[(&str, &str)], name: &str) -> io::Result<Top> {
+        let files: HashMap<PathBuf, String> = files.iter()
+            .map(|(name, text)| (PathBuf::from(name), text.to_string()))
+            .collect();
+        read_top_with(Path::new(name), &[PathBuf::from("ff")], &|path| {
+            files.get(path)
+                .cloned()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not found"))
+        })
+    }
+
+    const FORCEFIELD: &str = "
+[ defaults ]
+; nbfunc  comb-rule  gen-pairs  fudgeLJ  fudgeQQ
+  1       2          yes        0.5      0.8333
+
+[ atomtypes ]
+; name  at.num  mass     charge  ptype  sigma    epsilon
+  CT    6       12.011   0.0     A      0.33997  0.45773
+  HC    1       1.008    0.0     A      0.26495  0.06569
+#ifdef HEAVY_H
+  HX    1       4.032    0.0     A      0.0      0.0
+#else
+  HX    1       1.008    0.0     A      0.0      0.0
+#endif
+
+#define gb_ct 0.1529 224262.4
+";
+
+    const ETHANE: &str = r#"
+#include "forcefield.itp"
+
+[ moleculetype ]
+; name  nrexcl
+  ETH   3
+
+[ atoms ]
+;  nr type resnr res atom cgnr charge  mass
+   1   CT   1     ETH C1   1    -0.18
+   2   HC   1     ETH H11  1     0.06
+   3   HC   1     ETH H12  1     0.06
+   4   CT   1     ETH C2   2    -0.06   12.5
+   5   HC   1     ETH H21  2     0.06
+   6   HX   1     ETH H22  2     0.00
+
+[ bonds ]
+  1 2 1 0.109 284512.0
+  1 3 1 0.109 284512.0
+  1 4 1 gb_ct
+  4 5 1 0.109 \
+        284512.0
+  4 6 5
+
+[ pairs ]
+  2 5 1
+  3 5 1
+
+[ angles ]
+  2 1 4 1 110.7 313.8
+
+[ dihedrals ]
+  2 1 4 5 9 0.0 0.6276 3
+  1 2 3 4 2 35.26 418.4
+
+[ system ]
+Ethane in vacuum
+
+[ molecules ]
+ETH 2
+"#;
+
+    #[test]
+    fn reads_molecules_and_parameters() {
+        let top = read(&[("ff/forcefield.itp", FORCEFIELD), ("ethane.top", ETHANE)], "ethane.top").unwrap();
+
+        assert_eq!(top.atom_types().len(), 3);
+        assert_eq!(top.atoms.len(), 12);
+        assert_eq!(top.fudge_lj, 0.5);
+        assert_eq!(top.fudge_qq, 0.8333);
+        assert_eq!(top.combination_rule(), CombinationRule::LorentzBerthelot);
+
+        let ct = top.atom_types().index("CT").unwrap();
+        assert_eq!(top.atom_types().get(ct).sigma, 0.33997 * NM);
+        assert_eq!(top.atom_types().get(ct).epsilon, 0.45773 * KJPM);
+        assert_eq!(top.atoms[6].atom_type, ct);
+        assert_eq!(top.atoms[9].charge, -0.06 * E);
+        assert_eq!(top.atoms[9].mass, 12.5 * DA);
+        assert_eq!(top.atoms[7].mass, 1.008 * DA);
+        assert_eq!(top.atom_types().get(top.atoms[5].atom_type).mass, 1.008 * DA);
+        assert_eq!(top.atoms[10].name, "H21");
+        assert_eq!(top.atoms[9].element.as_deref(), Some("C"));
+
+        // The second ethane's residue is renumbered
+        let residues: Vec<(i64, &str, Range<usize>)> = top.residues().iter()
+            .map(|r| (r.id, r.name.as_str(), r.atoms.clone()))
+            .collect();
+        assert_eq!(residues, vec![(1, "ETH", 0..6), (2, "ETH", 6..12)]);
+        assert_eq!(top.molecules().len(), 1);
+        assert_eq!(top.molecule_of(7), Some((0, 1)));
+
+        assert_eq!(top.bonds.len(), 10);
+        assert_eq!(top.bonds[7].atoms, [6, 9]);
+        assert_eq!(top.bonds[2].length, 0.1529 * NM);
+        assert_eq!(top.bonds[3].force_constant, 284512.0 * KJPM / NM2);
+        assert_eq!(top.bonds[4].force_constant, 0.0 * KJPM / NM2);
+        assert_eq!(top.pairs[3], Pair { atoms: [8, 10] });
+        assert!((top.angles[1].angle - 110.7f32.to_radians()).abs() < 1e-6);
+        assert_eq!(top.dihedrals[1].atoms, [7, 6, 9, 10]);
+        assert_eq!(top.dihedrals[1].multiplicity, 3);
+        assert_eq!(top.impropers.len(), 2);
+
+        // Everything within a molecule is within three bonds, and the
+        // connection counts
+        assert_eq!(top.exclusions[6], vec![7, 8, 9, 10, 11]);
+        assert!(!top.is_excluded(0, 6));
+    }
+
+    #[test]
+    fn looks_up_bonded_types() {
+        let text = "
+[ atomtypes ]
+; name  bond_type  at.num  mass    charge  ptype  sigma    epsilon
+  CT    CT         6       12.011  0.0     A      0.33997  0.45773
+  HC    HC         1       1.008   0.0     A      0.26495  0.06569
+  H1    HC         1       1.008   0.0     A      0.24714  0.06569
+
+[ bondtypes ]
+  CT  HC  1  0.109   284512.0
+  CT  CT  1  0.1526  259408.0
+
+[ angletypes ]
+  HC  CT  CT  1  109.5  292.88
+
+[ dihedraltypes ]
+  X   CT  CT  X   9  0.0  0.6276  3
+  HC  CT  CT  HC  9  0.0  0.6276  3
+  HC  CT  CT  HC  9  0.0  0.2     1
+  CT  CT  4   180.0  4.6  2
+
+[ moleculetype ]
+  ETH  3
+
+[ atoms ]
+  1  CT  1  ETH  C1   1
+  2  H1  1  ETH  H11  1
+  3  CT  1  ETH  C2   1
+  4  HC  1  ETH  H21  1
+  5  CT  1  ETH  C3   1
+
+[ bonds ]
+  2  1  1
+  1  3  1
+  3  4  1  0.1  1000.0
+  3  5  1
+
+[ angles ]
+  4  3  1  1
+
+[ dihedrals ]
+  2  1  3  4  9
+  2  1  3  5  9
+  1  2  4  3  4
+
+[ molecules ]
+  ETH  1
+";
+        let top = read(&[("ethane.top", text)], "ethane.top").unwrap();
+
+        // H1 bonds as HC, in either order
+        assert_eq!(top.bonds[0].length, 0.109 * NM);
+        assert_eq!(top.bonds[1].force_constant, 259408.0 * KJPM / NM2);
+        // Parameters on the line win
+        assert_eq!(top.bonds[2].length, 0.1 * NM);
+        assert!((top.angles[0].angle - 109.5f32.to_radians()).abs() < 1e-6);
+
+        // The exact match has both its terms, and the wildcards match the
+        // second dihedral
+        let terms: Vec<u32> = top.dihedrals.iter().map(|d| d.multiplicity).collect();
+        assert_eq!(terms, vec![3, 1, 3, 2]);
+        assert_eq!(top.dihedrals[1].force_constant, 0.2 * KJPM);
+        assert!((top.dihedrals[3].phase - 180.0f32.to_radians()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn converts_c6_c12_types() {
+        let text = "
+[ atomtypes ]
+  OW  15.9994  0.0  A  0.0026171  2.6331e-06
+  HW  1.008    0.0  A  0.0        0.0
+
+[ moleculetype ]
+  SOL 2
+
+[ atoms ]
+  1 OW 1 SOL OW 1 -0.82
+  2 HW 1 SOL HW1 1 0.41
+  3 HW 1 SOL HW2 1 0.41
+
+[ molecules ]
+  SOL 3
+";
+        let top = read(&[("spc.top", text)], "spc.top").unwrap();
+        assert_eq!(top.combination_rule(), CombinationRule::Geometric);
+        let (c6, c12) = top.c6_c12(0, 3);
+        assert!((c6.value_unsafe - 0.0026171).abs() < 1e-8);
+        assert!((c12.value_unsafe - 2.6331e-06).abs() < 1e-11);
+        assert_eq!(top.c6_c12(1, 0).0, 0.0 * KJNM6PM);
+        assert_eq!(top.atoms.len(), 9);
+    }
+
+    #[test]
+    fn errors_name_file_and_line() {
+        let error = |files: &[(&str, &str)]| read(files, "topol.top").unwrap_err().to_string();
+
+        let bad_ff = "[ defaults ]\n1 2 yes 0.5 0.8333\n\n[ cmaptypes ]\n";
+        assert_eq!(
+            error(&[("topol.top", "#include \"forcefield.itp\"\n"), ("ff/forcefield.itp", bad_ff)]),
+            "ff/forcefield.itp:4: unsupported directive [ cmaptypes ]"
+        );
+        assert_eq!(
+            error(&[("topol.top", "; comment\n#include \"missing.itp\"\n")]),
+            "topol.top:2: could not find included file missing.itp"
+        );
+        assert_eq!(
+            error(&[("topol.top", "[ defaults ]\n1 1\n[ moleculetype ]\nA 3\n[ atoms ]\n1 C 1 A C 1\n")]),
+            "topol.top:6: unknown atom type C"
+        );
+        assert_eq!(
+            error(&[("topol.top", "#ifdef FLEXIBLE\n")]),
+            "topol.top:1: missing #endif"
+        );
+        assert_eq!(
+            error(&[("topol.top", &format!("{}[ moleculetype ]\nA 3\n[ atoms ]\n1 CT 1 A C 1\n2 CT 1 A C 1\n[ bonds ]\n1 2 3 0.1 1.0 2.0\n", FORCEFIELD))]),
+            "topol.top:23: unsupported bond function type 3"
+        );
+        assert_eq!(
+            error(&[("topol.top", &format!("{}[ moleculetype ]\nA 3\n[ atoms ]\n1 CT 1 A C 1\n2 HC 1 A H 1\n[ bonds ]\n1 2 1\n", FORCEFIELD))]),
+            "topol.top:23: no bond parameters for types CT HC"
+        );
+        assert_eq!(
+            error(&[("topol.top", "[ defaults ]\n1 2 no 0.5 0.8333\n")]),
+            "topol.top:2: only gen-pairs yes is supported"
+        );
+    }
+}
@@ -0,0 +1,26 @@
+//! Reading topologies prepared with other simulation packages
+//!
+//! Each reader produces a `Top` with the atom types, atoms, bonded
+//! interactions, exclusions and 1-4 pairs from its files, or in the case
+//! of `openmm`, from a force field and a structure read with chemfiles. Non-bonded
+//! electrostatics aren't part of a topology in these formats, so add a
+//! `Coulomb`, `Ewald` or `Pme` potential to the result to use the charges.
+//!
+//! Errors in the files are reported as `io::Error`s of kind `InvalidData`,
+//! with a description that starts with the file and line at fault.
+
+use std::fmt::Display;
+use std::io;
+use std::path::Path;
+
+pub mod gromacs;
+pub mod amber;
+pub mod openmm;
+
+/// An error at line `line` of `file`
+fn parse_error<M: Display>(file: &Path, line: usize, message: M) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}:{}: {}", file.display(), line, message)
+    )
+}
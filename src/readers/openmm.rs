@@ -0,0 +1,1117 @@
+//! OpenMM force field XML files
+//!
+//! A `ForceField` holds the atom types, residue templates and parameters
+//! from one or more OpenMM force field files, like `amber14-all.xml` with
+//! `amber14/tip3p.xml`, and uses them to parameterise the chemfiles
+//! `Topology` of a structure. Each residue is matched to the template with
+//! the same elements and bonds, whatever its atom names, which gives each
+//! atom its type and, with `UseAttributeFromResidue`, its charge. Bonds
+//! come from the topology, and angles and dihedrals are generated from
+//! them.
+//!
+//! The `HarmonicBondForce`, `HarmonicAngleForce`, `PeriodicTorsionForce`
+//! and `NonbondedForce` sections are read. Other forces, patches and
+//! scripts would change the physics if they were left out, so they are
+//! errors. As in OpenMM, bonds, angles and dihedrals that no parameters
+//! match are left out of the topology.
+//!
+//! Atom names and residues are copied from the chemfiles topology, and
+//! elements come from the atom types. A topology can't represent a
+//! residue whose atoms aren't consecutive, so any such residue is left out
+//! of it, though its atoms are still parameterised.
+//!
+//! OpenMM files use the same units as noether, so no conversion is
+//! needed. Impropers are periodic, and become `Dihedral`s with the central
+//! atom third. As in OpenMM, every three neighbours of an atom can form an
+//! improper, and the outer atoms are ordered by the `ordering` of the
+//! `PeriodicTorsionForce`, `default` or `amber`. The elements and masses
+//! that the `default` ordering compares come from the atom types.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use chemfiles;
+use itertools::Itertools;
+use roxmltree::{Document, Node};
+
+use crate::units::f32consts::*;
+use crate::topology::{
+    Top,
+    AtomType,
+    TypeLibrary,
+    CombinationRule
+};
+use crate::potentials::bonded::{
+    Bond,
+    Angle,
+    Dihedral
+};
+use super::parse_error;
+
+/// OpenMM's default scaling of 1-4 electrostatics
+const DEFAULT_COULOMB14_SCALE: f32 = 0.833333;
+/// OpenMM's default scaling of 1-4 Lennard-Jones
+const DEFAULT_LJ14_SCALE: f32 = 0.5;
+
+/// An atom type from an `<AtomTypes>` section
+#[derive(Debug, Clone)]
+struct FfType {
+    name: String,
+    class: String,
+    element: Option<String>,
+    mass: f32
+}
+
+/// The atom type or class a parameter applies to
+#[derive(Debug, Clone, PartialEq)]
+enum Match {
+    /// An empty type or class, which matches everything
+    Any,
+    Type(String),
+    Class(String)
+}
+
+impl Match {
+    fn matches(&self, atom_type: &FfType) -> bool {
+        match self {
+            Match::Any => true,
+            Match::Type(name) => &atom_type.name == name,
+            Match::Class(class) => &atom_type.class == class
+        }
+    }
+}
+
+/// Whether each of `pattern` matches the corresponding type
+fn matches_all(pattern: &[Match], types: &[&FfType]) -> bool {
+    pattern.iter().zip(types.iter()).all(|(m, t)| m.matches(t))
+}
+
+fn wildcards(pattern: &[Match]) -> usize {
+    pattern.iter().filter(|&m| m == &Match::Any).count()
+}
+
+#[derive(Debug, Clone)]
+struct TemplateAtom {
+    name: String,
+    /// The index of the atom's type in `ForceField::types`
+    atom_type: usize,
+    charge: Option<f32>
+}
+
+/// A residue template
+#[derive(Debug, Clone)]
+struct Template {
+    name: String,
+    atoms: Vec<TemplateAtom>,
+    /// The atoms bonded to each atom within the residue
+    bonded: Vec<Vec<usize>>,
+    /// The number of bonds each atom makes to other residues
+    external: Vec<usize>
+}
+
+#[derive(Debug, Clone)]
+struct BondParameters {
+    pattern: [Match; 2],
+    length: f32,
+    k: f32
+}
+
+#[derive(Debug, Clone)]
+struct AngleParameters {
+    pattern: [Match; 3],
+    angle: f32,
+    k: f32
+}
+
+/// How OpenMM orders the outer atoms of an improper whose definition
+/// leaves the order open
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ImproperOrdering {
+    Default,
+    Amber
+}
+
+/// A proper or improper torsion, with its periodicity, phase and force
+/// constant for each term of its Fourier series
+#[derive(Debug, Clone)]
+struct TorsionParameters {
+    pattern: [Match; 4],
+    terms: Vec<(u32, f32, f32)>,
+    /// The `ordering` of the `PeriodicTorsionForce` it came from
+    ordering: ImproperOrdering
+}
+
+#[derive(Debug, Clone)]
+struct NonbondedParameters {
+    pattern: Match,
+    charge: Option<f32>,
+    sigma: f32,
+    epsilon: f32
+}
+
+/// Atom types, residue templates and parameters from OpenMM force field
+/// files
+///
+/// # Examples
+///
+/// ```no_run
+/// use noether::readers::openmm::ForceField;
+/// use chemfiles::{Trajectory, Frame};
+///
+/// let mut forcefield = ForceField::new();
+/// forcefield.load("amber14/protein.ff14SB.xml").unwrap();
+/// forcefield.load("amber14/tip3p.xml").unwrap();
+///
+/// let mut trajectory = Trajectory::open("protein.pdb", 'r').unwrap();
+/// let mut frame = Frame::new().unwrap();
+/// trajectory.read(&mut frame).unwrap();
+///
+/// let top = forcefield.parameterise(&frame.topology().unwrap()).unwrap();
+/// println!("Parameterised {} atoms", top.atoms.len());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ForceField {
+    types: Vec<FfType>,
+    type_index: HashMap<String, usize>,
+    templates: Vec<Template>,
+    bonds: Vec<BondParameters>,
+    angles: Vec<AngleParameters>,
+    propers: Vec<TorsionParameters>,
+    impropers: Vec<TorsionParameters>,
+    nonbonded: Vec<NonbondedParameters>,
+    /// Whether charges come from the residue templates
+    residue_charges: bool,
+    /// The 1-4 Coulomb and Lennard-Jones scaling, if a file gave them
+    scale14: Option<(f32, f32)>
+}
+
+/// Parsing a single XML file
+struct Xml<'a, 'input> {
+    path: &'a Path,
+    doc: &'a Document<'input>
+}
+
+impl<'a, 'input> Xml<'a, 'input> {
+    fn error<M: std::fmt::Display>(&self, node: Node, message: M) -> io::Error {
+        let line = self.doc.text_pos_at(node.range().start).row as usize;
+        parse_error(self.path, line, message)
+    }
+
+    fn attribute<'n>(&self, node: Node<'n, '_>, name: &str) -> io::Result<&'n str> {
+        node.attribute(name).ok_or_else(|| {
+            self.error(node, format!("<{}> needs a {} attribute", node.tag_name().name(), name))
+        })
+    }
+
+    fn optional<T: FromStr>(&self, node: Node, name: &str) -> io::Result<Option<T>> {
+        match node.attribute(name) {
+            Some(text) => text.trim().parse().map(Some).map_err(|_| {
+                self.error(node, format!("invalid {} \"{}\"", name, text))
+            }),
+            None => Ok(None)
+        }
+    }
+
+    fn value<T: FromStr>(&self, node: Node, name: &str) -> io::Result<T> {
+        self.attribute(node, name)?;
+        self.optional(node, name).map(Option::unwrap)
+    }
+
+    /// The type or class in attribute `type{n}` or `class{n}`, or `type`
+    /// or `class` if `n` is empty
+    fn pattern(&self, node: Node, n: &str) -> io::Result<Match> {
+        let type_name = node.attribute(format!("type{}", n).as_str());
+        let class = node.attribute(format!("class{}", n).as_str());
+        match (type_name, class) {
+            (Some(""), None) | (None, Some("")) => Ok(Match::Any),
+            (Some(name), None) => Ok(Match::Type(name.to_string())),
+            (None, Some(class)) => Ok(Match::Class(class.to_string())),
+            _ => Err(self.error(node, format!("expected one of type{} and class{}", n, n)))
+        }
+    }
+
+    fn unexpected(&self, node: Node, parent: Node) -> io::Error {
+        self.error(node, format!("unexpected <{}> in <{}>", node.tag_name().name(), parent.tag_name().name()))
+    }
+}
+
+/// Convert a chemfiles error into an `io::Error`
+fn chfl<T>(result: chemfiles::Result<T>) -> io::Result<T> {
+    result.map_err(io::Error::other)
+}
+
+fn invalid<M: Into<String>>(message: M) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+impl ForceField {
+    /// An empty force field, to load files into
+    pub fn new() -> ForceField {
+        ForceField::default()
+    }
+
+    /// Add the contents of the force field file at `path`
+    ///
+    /// Atom types must be defined before templates and parameters use
+    /// them, either earlier in the same file or in a file loaded before.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        self.load_str(&text, path)
+    }
+
+    /// Add the contents of a force field file, calling it `path` in error
+    /// messages
+    pub fn load_str<P: AsRef<Path>>(&mut self, text: &str, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let doc = Document::parse(text)
+            .map_err(|e| parse_error(path, e.pos().row as usize, e))?;
+        let xml = Xml { path, doc: &doc };
+
+        let root = doc.root_element();
+        if !root.has_tag_name("ForceField") {
+            return Err(xml.error(root, "expected <ForceField>"));
+        }
+        for section in root.children().filter(Node::is_element) {
+            match section.tag_name().name() {
+                "Info" => {},
+                "AtomTypes" => self.read_atom_types(&xml, section)?,
+                "Residues" => self.read_residues(&xml, section)?,
+                "HarmonicBondForce" => self.read_bonds(&xml, section)?,
+                "HarmonicAngleForce" => self.read_angles(&xml, section)?,
+                "PeriodicTorsionForce" => self.read_torsions(&xml, section)?,
+                "NonbondedForce" => self.read_nonbonded(&xml, section)?,
+                name => return Err(xml.error(section, format!("<{}> is not supported", name)))
+            }
+        }
+        Ok(())
+    }
+
+    fn read_atom_types(&mut self, xml: &Xml, section: Node) -> io::Result<()> {
+        for node in section.children().filter(Node::is_element) {
+            if !node.has_tag_name("Type") {
+                return Err(xml.unexpected(node, section));
+            }
+            let name = xml.attribute(node, "name")?.to_string();
+            if self.type_index.contains_key(&name) {
+                return Err(xml.error(node, format!("atom type {} is defined twice", name)));
+            }
+            self.type_index.insert(name.clone(), self.types.len());
+            self.types.push(FfType {
+                name,
+                class: xml.attribute(node, "class")?.to_string(),
+                element: node.attribute("element").map(str::to_string),
+                mass: xml.value(node, "mass")?
+            });
+        }
+        Ok(())
+    }
+
+    fn read_residues(&mut self, xml: &Xml, section: Node) -> io::Result<()> {
+        for residue in section.children().filter(Node::is_element) {
+            if !residue.has_tag_name("Residue") {
+                return Err(xml.unexpected(residue, section));
+            }
+            let mut template = Template {
+                name: xml.attribute(residue, "name")?.to_string(),
+                atoms: vec![],
+                bonded: vec![],
+                external: vec![]
+            };
+
+            // Bonds may refer to atoms by name or by index
+            let atom = |template: &Template, node: Node, name: &str, index: &str| -> io::Result<usize> {
+                let atom = match node.attribute(name) {
+                    Some(name) => template.atoms.iter().position(|a| a.name == name),
+                    None => xml.value::<usize>(node, index).map(Some)?
+                };
+                atom.filter(|&a| a < template.atoms.len())
+                    .ok_or_else(|| xml.error(node, format!("no such atom in residue {}", template.name)))
+            };
+
+            for node in residue.children().filter(Node::is_element) {
+                match node.tag_name().name() {
+                    "Atom" => {
+                        let type_name = xml.attribute(node, "type")?;
+                        let atom_type = *self.type_index.get(type_name)
+                            .ok_or_else(|| xml.error(node, format!("unknown atom type {}", type_name)))?;
+                        template.atoms.push(TemplateAtom {
+                            name: xml.attribute(node, "name")?.to_string(),
+                            atom_type,
+                            charge: xml.optional(node, "charge")?
+                        });
+                        template.bonded.push(vec![]);
+                        template.external.push(0);
+                    },
+                    "Bond" => {
+                        let i = atom(&template, node, "atomName1", "from")?;
+                        let j = atom(&template, node, "atomName2", "to")?;
+                        template.bonded[i].push(j);
+                        template.bonded[j].push(i);
+                    },
+                    "ExternalBond" => {
+                        let i = atom(&template, node, "atomName", "from")?;
+                        template.external[i] += 1;
+                    },
+                    _ => return Err(xml.unexpected(node, residue))
+                }
+            }
+            self.templates.push(template);
+        }
+        Ok(())
+    }
+
+    fn read_bonds(&mut self, xml: &Xml, section: Node) -> io::Result<()> {
+        for node in section.children().filter(Node::is_element) {
+            if !node.has_tag_name("Bond") {
+                return Err(xml.unexpected(node, section));
+            }
+            self.bonds.push(BondParameters {
+                pattern: [xml.pattern(node, "1")?, xml.pattern(node, "2")?],
+                length: xml.value(node, "length")?,
+                k: xml.value(node, "k")?
+            });
+        }
+        Ok(())
+    }
+
+    fn read_angles(&mut self, xml: &Xml, section: Node) -> io::Result<()> {
+        for node in section.children().filter(Node::is_element) {
+            if !node.has_tag_name("Angle") {
+                return Err(xml.unexpected(node, section));
+            }
+            self.angles.push(AngleParameters {
+                pattern: [xml.pattern(node, "1")?, xml.pattern(node, "2")?, xml.pattern(node, "3")?],
+                angle: xml.value(node, "angle")?,
+                k: xml.value(node, "k")?
+            });
+        }
+        Ok(())
+    }
+
+    fn read_torsions(&mut self, xml: &Xml, section: Node) -> io::Result<()> {
+        let ordering = match section.attribute("ordering") {
+            None | Some("default") => ImproperOrdering::Default,
+            Some("amber") => ImproperOrdering::Amber,
+            Some(other) => return Err(xml.error(section, format!("improper ordering {} is not supported", other)))
+        };
+        for node in section.children().filter(Node::is_element) {
+            let pattern = [
+                xml.pattern(node, "1")?,
+                xml.pattern(node, "2")?,
+                xml.pattern(node, "3")?,
+                xml.pattern(node, "4")?
+            ];
+            let mut terms = vec![];
+            for n in 1.. {
+                let periodicity = format!("periodicity{}", n);
+                if node.attribute(periodicity.as_str()).is_none() {
+                    break;
+                }
+                terms.push((
+                    xml.value(node, &periodicity)?,
+                    xml.value(node, &format!("phase{}", n))?,
+                    xml.value(node, &format!("k{}", n))?
+                ));
+            }
+            if terms.is_empty() {
+                return Err(xml.error(node, "a torsion needs periodicity1, phase1 and k1"));
+            }
+
+            let torsion = TorsionParameters { pattern, terms, ordering };
+            match node.tag_name().name() {
+                "Proper" => self.propers.push(torsion),
+                "Improper" => self.impropers.push(torsion),
+                _ => return Err(xml.unexpected(node, section))
+            }
+        }
+        Ok(())
+    }
+
+    fn read_nonbonded(&mut self, xml: &Xml, section: Node) -> io::Result<()> {
+        let scale14 = (
+            xml.optional(section, "coulomb14scale")?.unwrap_or(DEFAULT_COULOMB14_SCALE),
+            xml.optional(section, "lj14scale")?.unwrap_or(DEFAULT_LJ14_SCALE)
+        );
+        match self.scale14 {
+            Some(existing) if existing != scale14 => {
+                return Err(xml.error(section, "1-4 scale factors differ from an earlier <NonbondedForce>"));
+            },
+            _ => self.scale14 = Some(scale14)
+        }
+
+        for node in section.children().filter(Node::is_element) {
+            match node.tag_name().name() {
+                "UseAttributeFromResidue" => {
+                    match xml.attribute(node, "name")? {
+                        "charge" => self.residue_charges = true,
+                        name => return Err(xml.error(node, format!("residue attribute {} is not supported", name)))
+                    }
+                },
+                "Atom" => {
+                    self.nonbonded.push(NonbondedParameters {
+                        pattern: xml.pattern(node, "")?,
+                        charge: xml.optional(node, "charge")?,
+                        sigma: xml.value(node, "sigma")?,
+                        epsilon: xml.value(node, "epsilon")?
+                    });
+                },
+                _ => return Err(xml.unexpected(node, section))
+            }
+        }
+        Ok(())
+    }
+
+    /// Build a topology for the atoms, bonds and residues of `topology`
+    ///
+    /// Every atom must be in a residue that matches exactly one template,
+    /// and atom types come from the template. Atoms are excluded from
+    /// non-bonded interactions with atoms up to three bonds away, and the
+    /// 1-4 pairs are scaled by the `NonbondedForce`'s factors.
+    pub fn parameterise(&self, topology: &chemfiles::Topology) -> io::Result<Top> {
+        let n_atoms = chfl(topology.size())? as usize;
+        let mut names = Vec::with_capacity(n_atoms);
+        let mut elements = Vec::with_capacity(n_atoms);
+        for i in 0..n_atoms {
+            let atom = chfl(topology.atom(i as u64))?;
+            names.push(chfl(atom.name())?);
+            elements.push(chfl(atom.atomic_type())?);
+        }
+
+        let bonds: Vec<[usize; 2]> = chfl(topology.bonds())?.into_iter()
+            .map(|[i, j]| [i as usize, j as usize])
+            .collect();
+        let mut bonded = vec![vec![]; n_atoms];
+        for &[i, j] in bonds.iter() {
+            bonded[i].push(j);
+            bonded[j].push(i);
+        }
+        for neighbours in bonded.iter_mut() {
+            neighbours.sort_unstable();
+        }
+
+        // The template atom of each atom, and the indices of its residue
+        // and of its atom in the template
+        let mut assigned: Vec<Option<&TemplateAtom>> = vec![None; n_atoms];
+        let mut positions = vec![(0, 0); n_atoms];
+        let mut matched: HashMap<ResidueKey, (usize, Vec<usize>)> = HashMap::new();
+        let residues = residue_atoms(topology, n_atoms)?;
+        for (r, (name, id, atoms)) in residues.iter().enumerate() {
+            let local: HashMap<usize, usize> = atoms.iter().enumerate().map(|(k, &i)| (i, k)).collect();
+            let internal: Vec<Vec<usize>> = atoms.iter()
+                .map(|i| bonded[*i].iter().filter_map(|j| local.get(j).cloned()).collect())
+                .collect();
+            let key = ResidueKey {
+                name: name.to_string(),
+                atoms: atoms.iter().zip(internal.iter())
+                    .map(|(&i, internal)| (names[i].clone(), elements[i].clone(), bonded[i].len() - internal.len()))
+                    .collect(),
+                internal
+            };
+
+            if !matched.contains_key(&key) {
+                let result = self.match_residue(&key).map_err(|message| {
+                    let id = id.map_or(String::new(), |id| format!(" {}", id));
+                    invalid(format!("residue {}{} {}", name, id, message))
+                })?;
+                matched.insert(key.clone(), result);
+            }
+            let (template, mapping) = &matched[&key];
+            for (&i, &t) in atoms.iter().zip(mapping.iter()) {
+                assigned[i] = Some(&self.templates[*template].atoms[t]);
+                positions[i] = (r, t);
+            }
+        }
+
+        let template_atoms = assigned.into_iter().enumerate()
+            .map(|(i, atom)| atom.ok_or_else(|| invalid(format!("atom {} ({}) isn't in a residue", i, names[i]))))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // The atom types that are used, with their non-bonded parameters
+        let mut atom_types = TypeLibrary::new();
+        let mut library_index: HashMap<usize, usize> = HashMap::new();
+        let mut atoms = Vec::with_capacity(n_atoms);
+        for (template_atom, name) in template_atoms.iter().zip(names.iter()) {
+            let ff_type = &self.types[template_atom.atom_type];
+            let index = match library_index.get(&template_atom.atom_type) {
+                Some(&index) => index,
+                None => {
+                    let parameters = self.nonbonded.iter()
+                        .find(|p| p.pattern.matches(ff_type))
+                        .ok_or_else(|| invalid(format!("no NonbondedForce parameters for atom type {}", ff_type.name)))?;
+                    if !self.residue_charges && parameters.charge.is_none() {
+                        return Err(invalid(format!("no charge for atom type {}", ff_type.name)));
+                    }
+                    let index = atom_types.add(AtomType {
+                        name: ff_type.name.clone(),
+                        mass: ff_type.mass * DA,
+                        charge: parameters.charge.unwrap_or(0.0) * E,
+                        epsilon: parameters.epsilon * KJPM,
+                        sigma: parameters.sigma * NM
+                    });
+                    library_index.insert(template_atom.atom_type, index);
+                    index
+                }
+            };
+
+            let mut atom = atom_types.atom(index);
+            atom.name = name.clone();
+            atom.element = ff_type.element.clone();
+            if self.residue_charges {
+                let charge = template_atom.charge
+                    .ok_or_else(|| invalid(format!("no charge for template atom {}", template_atom.name)))?;
+                atom.charge = charge * E;
+            }
+            atoms.push(atom);
+        }
+
+        let mut top = Top::new(atom_types, atoms);
+        top.set_combination_rule(CombinationRule::LorentzBerthelot);
+        add_residues(&mut top, &residues);
+        let types: Vec<&FfType> = template_atoms.iter().map(|a| &self.types[a.atom_type]).collect();
+
+        for &[i, j] in bonds.iter() {
+            let found = self.bonds.iter().find(|p| {
+                matches_all(&p.pattern, &[types[i], types[j]]) || matches_all(&p.pattern, &[types[j], types[i]])
+            });
+            if let Some(p) = found {
+                top.bonds.push(Bond {
+                    atoms: [i, j],
+                    length: p.length * NM,
+                    force_constant: p.k * KJPM / NM2
+                });
+            }
+        }
+
+        for (j, neighbours) in bonded.iter().enumerate() {
+            for (a, &i) in neighbours.iter().enumerate() {
+                for &k in neighbours[a + 1..].iter() {
+                    let found = self.angles.iter().find(|p| {
+                        matches_all(&p.pattern, &[types[i], types[j], types[k]])
+                            || matches_all(&p.pattern, &[types[k], types[j], types[i]])
+                    });
+                    if let Some(p) = found {
+                        top.angles.push(Angle {
+                            atoms: [i, j, k],
+                            angle: p.angle,
+                            force_constant: p.k * KJPM
+                        });
+                    }
+                }
+            }
+        }
+
+        // The most specific match wins, and the first of those
+        let add_torsion = |top: &mut Top, atoms: [usize; 4], p: &TorsionParameters| {
+            for &(multiplicity, phase, k) in p.terms.iter() {
+                top.dihedrals.push(Dihedral {
+                    atoms,
+                    phase,
+                    force_constant: k * KJPM,
+                    multiplicity
+                });
+            }
+        };
+        for &[j, k] in bonds.iter() {
+            for &i in bonded[j].iter().filter(|&&i| i != k) {
+                for &l in bonded[k].iter().filter(|&&l| l != j && l != i) {
+                    let forward = [types[i], types[j], types[k], types[l]];
+                    let reverse = [types[l], types[k], types[j], types[i]];
+                    let best = self.propers.iter()
+                        .filter(|p| matches_all(&p.pattern, &forward) || matches_all(&p.pattern, &reverse))
+                        .min_by_key(|p| wildcards(&p.pattern));
+                    if let Some(p) = best {
+                        add_torsion(&mut top, [i, j, k, l], p);
+                    }
+                }
+            }
+        }
+
+        // Impropers are centred on the first type or class, with one for
+        // each three of its neighbours. The first definition without
+        // wildcards wins, or else the first with them, and the first
+        // order of the neighbours that it matches picks the fourth atom.
+        for (c, neighbours) in bonded.iter().enumerate() {
+            for three in neighbours.iter().cloned().combinations(3) {
+                let [a, b, d] = [three[0], three[1], three[2]];
+                let orders = [[a, b, d], [a, d, b], [b, a, d], [b, d, a], [d, a, b], [d, b, a]];
+                let best = self.impropers.iter()
+                    .flat_map(|p| orders.iter().map(move |order| (p, order)))
+                    .filter(|(p, order)| {
+                        matches_all(&p.pattern, &[types[c], types[order[0]], types[order[1]], types[order[2]]])
+                    }).min_by_key(|(p, _)| wildcards(&p.pattern) > 0);
+                if let Some((p, &order)) = best {
+                    let [i, j, l] = improper_order(p, order, &types, &positions);
+                    add_torsion(&mut top, [i, j, c, l], p);
+                }
+            }
+        }
+
+        top.generate_exclusions(3);
+        top.generate_pairs();
+        let (fudge_qq, fudge_lj) = self.scale14.unwrap_or((DEFAULT_COULOMB14_SCALE, DEFAULT_LJ14_SCALE));
+        top.fudge_qq = fudge_qq;
+        top.fudge_lj = fudge_lj;
+        Ok(top)
+    }
+
+    /// The only template that matches a residue, and the template atom of
+    /// each of its atoms
+    fn match_residue(&self, residue: &ResidueKey) -> Result<(usize, Vec<usize>), String> {
+        let matches: Vec<(usize, Vec<usize>)> = self.templates.iter().enumerate()
+            .filter_map(|(t, template)| self.match_template(template, residue).map(|mapping| (t, mapping)))
+            .collect();
+        match matches.len() {
+            0 => Err(format!(
+                "with {} atoms matches no template; check that it has all its hydrogens and bonds",
+                residue.atoms.len()
+            )),
+            1 => Ok(matches.into_iter().next().unwrap()),
+            _ => {
+                let names: Vec<&str> = matches.iter().map(|(t, _)| self.templates[*t].name.as_str()).collect();
+                Err(format!("matches several templates: {}", names.join(", ")))
+            }
+        }
+    }
+
+    /// Map the atoms of a residue onto those of a template with the same
+    /// elements and bonds, if there is a way to
+    fn match_template(&self, template: &Template, residue: &ResidueKey) -> Option<Vec<usize>> {
+        let n = residue.atoms.len();
+        let n_bonds = |bonded: &[Vec<usize>]| bonded.iter().map(Vec::len).sum::<usize>();
+        if template.atoms.len() != n || n_bonds(&template.bonded) != n_bonds(&residue.internal) {
+            return None;
+        }
+
+        // The template atoms each atom could be, trying those with the
+        // same name first
+        let candidates: Vec<Vec<usize>> = residue.atoms.iter().zip(residue.internal.iter())
+            .map(|((name, element, external), internal)| {
+                let mut candidates: Vec<usize> = (0..n).filter(|&t| {
+                    let same_element = self.types[template.atoms[t].atom_type].element.as_ref()
+                        .is_some_and(|e| e.eq_ignore_ascii_case(element));
+                    same_element
+                        && template.bonded[t].len() == internal.len()
+                        && template.external[t] == *external
+                }).collect();
+                candidates.sort_by_key(|&t| &template.atoms[t].name != name);
+                candidates
+            }).collect();
+        if candidates.iter().any(Vec::is_empty) {
+            return None;
+        }
+
+        let mut mapping = vec![None; n];
+        let mut used = vec![false; n];
+        if assign(0, &candidates, &residue.internal, template, &mut mapping, &mut used) {
+            Some(mapping.into_iter().map(Option::unwrap).collect())
+        } else {
+            None
+        }
+    }
+}
+
+/// The outer atoms of an improper that matched `p` in `order`, reordered
+/// as OpenMM would, where `positions` has the indices of each atom's
+/// residue and of its atom in the template
+fn improper_order(p: &TorsionParameters, order: [usize; 3], types: &[&FfType], positions: &[(usize, usize)]) -> [usize; 3] {
+    let [mut i, mut j, mut l] = order;
+    match p.ordering {
+        // Carbon first, then the heavier element, and the lower index
+        // for the same element
+        ImproperOrdering::Default => {
+            let carbon = |k: usize| types[k].element.as_deref() == Some("C");
+            let same = types[i].element == types[j].element;
+            if (same && i > j) || (!same && !carbon(i) && (carbon(j) || types[i].mass < types[j].mass)) {
+                std::mem::swap(&mut i, &mut j);
+            }
+        },
+        // Atoms that can be told apart in order of residue and then
+        // template, by type for a specific definition and by element for
+        // one with wildcards
+        ImproperOrdering::Amber => {
+            let specific = wildcards(&p.pattern) == 0;
+            let alike = |x: usize, y: usize| if specific {
+                types[x].name == types[y].name
+            } else {
+                types[x].element == types[y].element
+            };
+            if alike(i, l) && positions[i] > positions[l] {
+                std::mem::swap(&mut i, &mut l);
+            }
+            if alike(j, l) && positions[j] > positions[l] {
+                std::mem::swap(&mut j, &mut l);
+            }
+            if (!specific || alike(i, j)) && positions[i] > positions[j] {
+                std::mem::swap(&mut i, &mut j);
+            }
+        }
+    }
+    [i, j, l]
+}
+
+/// Map atom `i` onward to template atoms by backtracking, so that every bond
+/// between mapped atoms is also in the template
+fn assign(
+    i: usize,
+    candidates: &[Vec<usize>],
+    internal: &[Vec<usize>],
+    template: &Template,
+    mapping: &mut Vec<Option<usize>>,
+    used: &mut Vec<bool>
+) -> bool {
+    if i == candidates.len() {
+        return true;
+    }
+    for &t in candidates[i].iter() {
+        if used[t] {
+            continue;
+        }
+        let consistent = internal[i].iter()
+            .all(|&j| mapping[j].is_none_or(|u| template.bonded[t].contains(&u)));
+        if !consistent {
+            continue;
+        }
+
+        mapping[i] = Some(t);
+        used[t] = true;
+        if assign(i + 1, candidates, internal, template, mapping, used) {
+            return true;
+        }
+        mapping[i] = None;
+        used[t] = false;
+    }
+    false
+}
+
+/// Copy the residues whose atoms are consecutive into the topology, which
+/// can't represent the others
+fn add_residues(top: &mut Top, residues: &[ResidueAtoms]) {
+    let mut sorted: Vec<&ResidueAtoms> = residues.iter()
+        .filter(|(_, _, atoms)| atoms.windows(2).all(|w| w[1] == w[0] + 1))
+        .collect();
+    sorted.sort_by_key(|(_, _, atoms)| atoms[0]);
+    for (name, id, atoms) in sorted {
+        let id = id.map_or(0, |id| id as i64);
+        top.add_residue(name, id, atoms[0]..atoms[atoms.len() - 1] + 1);
+    }
+}
+
+/// What a residue's template depends on, so residues that are the same
+/// are only matched once
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ResidueKey {
+    name: String,
+    /// The name, element and number of external bonds of each atom
+    atoms: Vec<(String, String, usize)>,
+    /// The atoms bonded to each atom within the residue
+    internal: Vec<Vec<usize>>
+}
+
+/// The name, id and atoms of a residue
+type ResidueAtoms = (String, Option<u64>, Vec<usize>);
+
+/// The atoms of each non-empty residue of a topology
+fn residue_atoms(topology: &chemfiles::Topology, n_atoms: usize) -> io::Result<Vec<ResidueAtoms>> {
+    let n_residues = chfl(topology.residues_count())?;
+    let mut residues = Vec::with_capacity(n_residues as usize);
+    let mut by_name: HashMap<(String, Option<u64>), Vec<usize>> = HashMap::new();
+    for r in 0..n_residues {
+        let residue = chfl(topology.residue(r))?;
+        let key = (chfl(residue.name())?, chfl(residue.id())?);
+        by_name.entry(key.clone()).or_default().push(r as usize);
+        residues.push((key, residue, vec![]));
+    }
+
+    for i in 0..n_atoms {
+        let residue = match chfl(topology.residue_for_atom(i as u64))? {
+            Some(residue) => residue,
+            None => continue
+        };
+        let key = (chfl(residue.name())?, chfl(residue.id())?);
+        let candidates = &by_name[&key];
+        // Residues with the same name and id are told apart by their atoms
+        let mut r = candidates[0];
+        if candidates.len() > 1 {
+            for &candidate in candidates.iter() {
+                if chfl(residues[candidate].1.contains(i as u64))? {
+                    r = candidate;
+                    break;
+                }
+            }
+        }
+        residues[r].2.push(i);
+    }
+
+    Ok(residues.into_iter()
+        .filter(|(_, _, atoms)| !atoms.is_empty())
+        .map(|((name, id), _, atoms)| (name, id, atoms))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chemfiles::{Atom, Residue, Topology};
+
+    const FORCEFIELD: &str = r#"<ForceField>
+ <Info>
+  <Reference>A small force field for tests</Reference>
+ </Info>
+ <AtomTypes>
+  <Type name="tip3p-O" class="OW" element="O" mass="15.99943"/>
+  <Type name="tip3p-H" class="HW" element="H" mass="1.007947"/>
+  <Type name="CT3" class="CT" element="C" mass="15.035"/>
+  <Type name="CT2" class="CT" element="C" mass="14.027"/>
+  <Type name="C" class="C" element="C" mass="12.01"/>
+  <Type name="O" class="O" element="O" mass="16.0"/>
+  <Type name="HA" class="HA" element="H" mass="1.008"/>
+ </AtomTypes>
+ <Residues>
+  <Residue name="HOH">
+   <Atom name="O" type="tip3p-O" charge="-0.834"/>
+   <Atom name="H1" type="tip3p-H" charge="0.417"/>
+   <Atom name="H2" type="tip3p-H" charge="0.417"/>
+   <Bond atomName1="O" atomName2="H1"/>
+   <Bond atomName1="O" atomName2="H2"/>
+  </Residue>
+  <Residue name="FOR">
+   <Atom name="C" type="C" charge="0.5"/>
+   <Atom name="O" type="O" charge="-0.5"/>
+   <Atom name="H1" type="HA" charge="0.0"/>
+   <Atom name="H2" type="HA" charge="0.0"/>
+   <Bond from="0" to="1"/>
+   <Bond from="0" to="2"/>
+   <Bond from="0" to="3"/>
+  </Residue>
+  <Residue name="BUT">
+   <Atom name="C1" type="CT3" charge="0.0"/>
+   <Atom name="C2" type="CT2" charge="0.0"/>
+   <Atom name="C3" type="CT2" charge="0.0"/>
+   <Atom name="C4" type="CT3" charge="0.0"/>
+   <Bond from="0" to="1"/>
+   <Bond from="1" to="2"/>
+   <Bond from="2" to="3"/>
+  </Residue>
+ </Residues>
+ <HarmonicBondForce>
+  <Bond class1="OW" class2="HW" length="0.09572" k="462750.4"/>
+  <Bond class1="CT" class2="CT" length="0.1526" k="259408.0"/>
+  <Bond class1="C" class2="O" length="0.1229" k="476976.0"/>
+  <Bond class1="C" class2="HA" length="0.108" k="307105.6"/>
+ </HarmonicBondForce>
+ <HarmonicAngleForce>
+  <Angle class1="HW" class2="OW" class3="HW" angle="1.82421813418" k="836.8"/>
+  <Angle class1="CT" class2="CT" class3="CT" angle="1.91113553093" k="334.72"/>
+  <Angle class1="O" class2="C" class3="HA" angle="2.0943951" k="418.4"/>
+ </HarmonicAngleForce>
+ <PeriodicTorsionForce>
+  <Proper class1="" class2="CT" class3="CT" class4="" periodicity1="3" phase1="0.0" k1="0.6508"/>
+  <Proper type1="CT3" type2="CT2" type3="CT2" type4="CT3" periodicity1="3" phase1="0.0" k1="0.75" periodicity2="2" phase2="3.14159265359" k2="1.046"/>
+  <Improper class1="C" class2="" class3="" class4="O" periodicity1="2" phase1="3.14159265359" k1="4.6024"/>
+ </PeriodicTorsionForce>
+ <NonbondedForce coulomb14scale="0.833333" lj14scale="0.5">
+  <UseAttributeFromResidue name="charge"/>
+  <Atom type="tip3p-O" sigma="0.315061" epsilon="0.636386"/>
+  <Atom type="tip3p-H" sigma="1" epsilon="0"/>
+  <Atom class="CT" sigma="0.339967" epsilon="0.45773"/>
+  <Atom type="C" sigma="0.339967" epsilon="0.359824"/>
+  <Atom type="O" sigma="0.295992" epsilon="0.87864"/>
+  <Atom type="HA" sigma="0.259964" epsilon="0.06276"/>
+ </NonbondedForce>
+</ForceField>
+"#;
+
+    fn add_residue(topology: &mut Topology, name: &str, id: u64, atoms: &[(&str, &str)], bonds: &[[u64; 2]]) {
+        let start = topology.size().unwrap();
+        let mut residue = Residue::with_id(name, id).unwrap();
+        for (k, &(name, element)) in atoms.iter().enumerate() {
+            let mut atom = Atom::new(name).unwrap();
+            atom.set_atomic_type(element).unwrap();
+            topology.add_atom(&atom).unwrap();
+            residue.add_atom(start + k as u64).unwrap();
+        }
+        topology.add_residue(&residue).unwrap();
+        for &[i, j] in bonds.iter() {
+            topology.add_bond(start + i, start + j).unwrap();
+        }
+    }
+
+    /// A water with different atom names to its template, formaldehyde and
+    /// united atom butane
+    fn molecules() -> Topology {
+        let mut topology = Topology::new().unwrap();
+        add_residue(&mut topology, "WAT", 1, &[("OW", "O"), ("HW1", "H"), ("HW2", "H")], &[[0, 1], [0, 2]]);
+        add_residue(&mut topology, "FOR", 2, &[("C", "C"), ("O", "O"), ("H1", "H"), ("H2", "H")], &[[0, 1], [0, 2], [0, 3]]);
+        add_residue(&mut topology, "BUT", 3, &[("C1", "C"), ("C2", "C"), ("C3", "C"), ("C4", "C")], &[[0, 1], [1, 2], [2, 3]]);
+        topology
+    }
+
+    fn forcefield() -> ForceField {
+        let mut forcefield = ForceField::new();
+        forcefield.load_str(FORCEFIELD, "test.xml").unwrap();
+        forcefield
+    }
+
+    #[test]
+    fn parameterises_by_template() {
+        let top = forcefield().parameterise(&molecules()).unwrap();
+
+        assert_eq!(top.atoms.len(), 11);
+        assert_eq!(top.atom_types().len(), 7);
+        assert_eq!(top.atom_types().get(top.atoms[1].atom_type).name, "tip3p-H");
+        assert_eq!(top.atom_types().get(top.atoms[10].atom_type).name, "CT3");
+        assert_eq!(top.atoms[0].charge, -0.834 * E);
+        assert_eq!(top.atoms[0].mass, 15.99943 * DA);
+        assert_eq!(top.atom_types().get(top.atoms[8].atom_type).epsilon, 0.45773 * KJPM);
+        assert_eq!(top.atoms[1].name, "HW1");
+        assert_eq!(top.atoms[1].element.as_deref(), Some("H"));
+        assert_eq!(top.residues().len(), 3);
+        assert_eq!(top.residues()[1].atoms, 3..7);
+        assert_eq!(top.residues()[2].id, 3);
+
+        assert_eq!(top.bonds.len(), 8);
+        assert_eq!(top.bonds[0], Bond { atoms: [0, 1], length: 0.09572 * NM, force_constant: 462750.4 * KJPM / NM2 });
+
+        // The H-C-H angle of formaldehyde has no parameters
+        assert_eq!(top.angles.len(), 5);
+        assert!(!top.angles.iter().any(|a| a.atoms == [5, 3, 6]));
+
+        // Butane's specific torsion beats the generic one, and the
+        // improper puts the carbonyl carbon third
+        let dihedrals: Vec<([usize; 4], u32)> = top.dihedrals.iter().map(|d| (d.atoms, d.multiplicity)).collect();
+        assert_eq!(dihedrals, vec![([7, 8, 9, 10], 3), ([7, 8, 9, 10], 2), ([5, 6, 3, 4], 2)]);
+        assert_eq!(top.dihedrals[0].force_constant, 0.75 * KJPM);
+
+        assert_eq!(top.exclusions[7], vec![8, 9, 10]);
+        assert_eq!(top.pairs.len(), 1);
+        assert_eq!(top.pairs[0].atoms, [7, 10]);
+        assert_eq!(top.fudge_qq, 0.833333);
+        assert_eq!(top.fudge_lj, 0.5);
+    }
+
+    /// N-methylformamide, with its template's atoms in a different order
+    /// to the structure's and an improper on the nitrogen
+    const AMIDE: &str = r#"<ForceField>
+ <AtomTypes>
+  <Type name="N" class="N" element="N" mass="14.01"/>
+  <Type name="H" class="H" element="H" mass="1.008"/>
+ </AtomTypes>
+ <Residues>
+  <Residue name="NMF">
+   <Atom name="C" type="C" charge="0.5"/>
+   <Atom name="O" type="O" charge="-0.5"/>
+   <Atom name="HF" type="HA" charge="0.0"/>
+   <Atom name="N" type="N" charge="-0.4"/>
+   <Atom name="H" type="H" charge="0.3"/>
+   <Atom name="CM" type="CT3" charge="0.1"/>
+   <Bond from="0" to="1"/>
+   <Bond from="0" to="2"/>
+   <Bond from="0" to="3"/>
+   <Bond from="3" to="4"/>
+   <Bond from="3" to="5"/>
+  </Residue>
+ </Residues>
+ <PeriodicTorsionForce>
+  <Improper class1="N" class2="" class3="" class4="H" periodicity1="2" phase1="3.14159265359" k1="4.184"/>
+ </PeriodicTorsionForce>
+ <NonbondedForce coulomb14scale="0.833333" lj14scale="0.5">
+  <UseAttributeFromResidue name="charge"/>
+  <Atom type="N" sigma="0.325" epsilon="0.71128"/>
+  <Atom type="H" sigma="0.106908" epsilon="0.0656888"/>
+ </NonbondedForce>
+</ForceField>
+"#;
+
+    #[test]
+    fn orders_impropers_like_openmm() {
+        let mut topology = Topology::new().unwrap();
+        let atoms = [("N", "N"), ("CM", "C"), ("C", "C"), ("O", "O"), ("H", "H"), ("HF", "H")];
+        add_residue(&mut topology, "NMF", 1, &atoms, &[[0, 1], [0, 2], [2, 3], [0, 4], [2, 5]]);
+        let impropers = |amide: &str| {
+            let mut forcefield = forcefield();
+            forcefield.load_str(amide, "amide.xml").unwrap();
+            let top = forcefield.parameterise(&topology).unwrap();
+            top.dihedrals.iter().map(|d| d.atoms).collect::<Vec<_>>()
+        };
+
+        // The default ordering puts the lower index first between the two
+        // carbons, and the heavier nitrogen before the hydrogen
+        assert_eq!(impropers(AMIDE), vec![[1, 2, 0, 4], [0, 5, 2, 3]]);
+        // Amber's puts the carbons in the order of the template
+        let amber = AMIDE.replace("<PeriodicTorsionForce>", r#"<PeriodicTorsionForce ordering="amber">"#);
+        assert_eq!(impropers(&amber), vec![[2, 1, 0, 4], [0, 5, 2, 3]]);
+
+        let smirnoff = AMIDE.replace("<PeriodicTorsionForce>", r#"<PeriodicTorsionForce ordering="smirnoff">"#);
+        let error = forcefield().load_str(&smirnoff, "amide.xml").unwrap_err();
+        assert_eq!(error.to_string(), "amide.xml:21: improper ordering smirnoff is not supported");
+    }
+
+    #[test]
+    fn keeps_consecutive_residues() {
+        // A formaldehyde split around a water
+        let mut topology = Topology::new().unwrap();
+        let atoms = [("C", "C"), ("O", "O"), ("OW", "O"), ("HW1", "H"), ("HW2", "H"), ("H1", "H"), ("H2", "H")];
+        for &(name, element) in atoms.iter() {
+            let mut atom = Atom::new(name).unwrap();
+            atom.set_atomic_type(element).unwrap();
+            topology.add_atom(&atom).unwrap();
+        }
+        for &(name, id, ref members) in [("FOR", 1, vec![0, 1, 5, 6]), ("WAT", 2, vec![2, 3, 4])].iter() {
+            let mut residue = Residue::with_id(name, id).unwrap();
+            for &i in members.iter() {
+                residue.add_atom(i).unwrap();
+            }
+            topology.add_residue(&residue).unwrap();
+        }
+        for &[i, j] in [[0, 1], [0, 5], [0, 6], [2, 3], [2, 4]].iter() {
+            topology.add_bond(i, j).unwrap();
+        }
+
+        let top = forcefield().parameterise(&topology).unwrap();
+        assert_eq!(top.atom_types().get(top.atoms[6].atom_type).name, "HA");
+        assert_eq!(top.residues().len(), 1);
+        assert_eq!(top.residues()[0].name, "WAT");
+        assert_eq!(top.residues()[0].atoms, 2..5);
+    }
+
+    #[test]
+    fn unmatched_residues_are_errors() {
+        let mut topology = Topology::new().unwrap();
+        add_residue(&mut topology, "WAT", 1, &[("OW", "O"), ("HW1", "H"), ("HW2", "H")], &[[0, 1]]);
+        let error = forcefield().parameterise(&topology).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "residue WAT 1 with 3 atoms matches no template; check that it has all its hydrogens and bonds"
+        );
+
+        let mut doubled = forcefield();
+        doubled.load_str(r#"<ForceField><Residues>
+            <Residue name="WAT">
+             <Atom name="O" type="tip3p-O" charge="-0.8"/>
+             <Atom name="H1" type="tip3p-H" charge="0.4"/>
+             <Atom name="H2" type="tip3p-H" charge="0.4"/>
+             <Bond atomName1="O" atomName2="H1"/>
+             <Bond atomName1="O" atomName2="H2"/>
+            </Residue>
+        </Residues></ForceField>"#, "more.xml").unwrap();
+        let error = doubled.parameterise(&molecules()).unwrap_err();
+        assert_eq!(error.to_string(), "residue WAT 1 matches several templates: HOH, WAT");
+    }
+
+    #[test]
+    fn errors_name_file_and_line() {
+        let error = |text: &str| ForceField::new().load_str(text, "test.xml").unwrap_err().to_string();
+
+        let text = FORCEFIELD.replace(r#"name="H2" type="HA""#, r#"name="H2" type="HX""#);
+        assert_eq!(error(&text), "test.xml:26: unknown atom type HX");
+        let text = FORCEFIELD.replace("<HarmonicBondForce>", "<CustomBondForce/>\n <HarmonicBondForce>");
+        assert_eq!(error(&text), "test.xml:41: <CustomBondForce> is not supported");
+        let text = FORCEFIELD.replace(r#"length="0.1526""#, r#"length="long""#);
+        assert_eq!(error(&text), "test.xml:43: invalid length \"long\"");
+        assert!(error("<ForceField>\n<AtomTypes>\n</ForceField>").starts_with("test.xml:3: "));
+    }
+}
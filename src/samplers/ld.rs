@@ -0,0 +1,288 @@
+//! Langevin dynamics
+//!
+//! The Langevin equation adds friction and a matching random force to
+//! Newton's, coupling every atom to a heat bath. Its integrators split a
+//! step into kicks from the forces (B), drifts along the velocities (A)
+//! and exact solutions of the friction and noise (O), and are named for
+//! the order of these parts. They differ in how accurately they sample
+//! positions and velocities at large time steps: BAOAB, and the GROMACS
+//! `sd` integrator which moves the atoms the same way, sample positions
+//! from the canonical ensemble most accurately, and exactly for harmonic
+//! potentials.
+
+use rand::{Rng, SeedableRng, FromEntropy};
+use rand::rngs::StdRng;
+use rand::distributions::StandardNormal;
+
+use crate::dim::Sqrt;
+use crate::units::*;
+use crate::units::f32consts::*;
+use crate::geom::VelocVec;
+use crate::state::State;
+use crate::integrators::{Integrator, Hooks, ForceCache, drift, kick};
+
+/// The order of the parts of a Langevin step
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Splitting {
+    /// Half a kick, half a drift, the friction and noise for the whole
+    /// step, half a drift and half a kick
+    Baoab,
+    /// Half a drift, half a kick, the friction and noise for the whole
+    /// step, half a kick and half a drift
+    Aboba,
+    /// The friction and noise for half the step, half a kick, a drift,
+    /// half a kick and the rest of the friction and noise
+    Obabo,
+    /// The `sd` integrator of GROMACS, a full kick followed by the second
+    /// half of BAOAB, with the velocities half a step behind the
+    /// positions as with `Leapfrog`
+    Sd
+}
+
+/// The friction coefficients of the atoms
+#[derive(Debug, Clone, PartialEq)]
+pub enum Friction {
+    /// The same friction for every atom
+    Global(PerPicosecond<f32>),
+    /// A friction for each atom
+    PerAtom(Vec<PerPicosecond<f32>>)
+}
+
+/// Langevin dynamics at a constant temperature
+///
+/// The random forces come from a generator seeded from the operating
+/// system, or from `set_seed` for repeatable runs.
+///
+/// # Examples
+///
+/// ```
+/// use noether::samplers::ld::{Langevin, Splitting, Friction};
+/// use noether::units::f32consts::*;
+///
+/// let mut integrator = Langevin::new(Splitting::Baoab, 300.0 * K, Friction::Global(1.0 * PPS));
+/// integrator.set_seed(42);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Langevin {
+    pub splitting: Splitting,
+    pub temperature: Kelvin<f32>,
+    pub friction: Friction,
+    rng: StdRng,
+    cache: ForceCache
+}
+
+impl Langevin {
+    pub fn new(splitting: Splitting, temperature: Kelvin<f32>, friction: Friction) -> Langevin {
+        Langevin {
+            splitting,
+            temperature,
+            friction,
+            rng: StdRng::from_entropy(),
+            cache: ForceCache::default()
+        }
+    }
+
+    /// Restart the random forces from `seed`
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// Apply the friction and noise for a time `dt`, exactly
+    fn ornstein_uhlenbeck(&mut self, state: &mut State, dt: Picosecond<f32>) {
+        let atoms = &state.topology.atoms;
+        if let Friction::PerAtom(friction) = &self.friction {
+            assert_eq!(
+                friction.len(), atoms.len(),
+                "Langevin friction is given for {} atoms, but there are {}!",
+                friction.len(), atoms.len()
+            );
+        }
+
+        let kt = KB * self.temperature;
+        for (i, (v, atom)) in state.velocities.iter_mut().zip(atoms.iter()).enumerate() {
+            let gamma = match &self.friction {
+                Friction::Global(gamma) => *gamma,
+                Friction::PerAtom(friction) => friction[i]
+            };
+            let c1 = (-gamma * dt).exp();
+            let sigma: NanometerPerPicosecond<f32> = ((1.0 - c1 * c1) * kt / atom.mass).sqrt();
+            let sigma = sigma.value_unsafe;
+
+            *v *= c1;
+            *v += VelocVec::from(
+                sigma * self.rng.sample(StandardNormal) as f32,
+                sigma * self.rng.sample(StandardNormal) as f32,
+                sigma * self.rng.sample(StandardNormal) as f32
+            );
+        }
+    }
+}
+
+impl Integrator for Langevin {
+    fn step(&mut self, state: &mut State, hooks: &mut Hooks, dt: Picosecond<f32>) {
+        match self.splitting {
+            Splitting::Baoab => {
+                let forces = self.cache.take(state);
+                kick(state, &forces, dt / 2.0);
+                let reference = state.positions.clone();
+                drift(state, dt / 2.0);
+                self.ornstein_uhlenbeck(state, dt);
+                hooks.constrain_velocities(state);
+                drift(state, dt / 2.0);
+                hooks.constrain_positions(state, &reference, dt);
+                state.wrap_positions();
+                let forces = hooks.forces(state);
+                kick(state, &forces, dt / 2.0);
+                hooks.constrain_velocities(state);
+                hooks.apply_thermostat(state, dt);
+                self.cache.store(state, forces);
+                hooks.apply_barostat(state, dt);
+            },
+            Splitting::Aboba => {
+                let reference = state.positions.clone();
+                drift(state, dt / 2.0);
+                let forces = hooks.forces(state);
+                kick(state, &forces, dt / 2.0);
+                self.ornstein_uhlenbeck(state, dt);
+                kick(state, &forces, dt / 2.0);
+                hooks.constrain_velocities(state);
+                drift(state, dt / 2.0);
+                hooks.constrain_positions(state, &reference, dt);
+                hooks.apply_thermostat(state, dt);
+                state.wrap_positions();
+                hooks.apply_barostat(state, dt);
+            },
+            Splitting::Obabo => {
+                let forces = self.cache.take(state);
+                self.ornstein_uhlenbeck(state, dt / 2.0);
+                kick(state, &forces, dt / 2.0);
+                hooks.constrain_velocities(state);
+                let reference = state.positions.clone();
+                drift(state, dt);
+                hooks.constrain_positions(state, &reference, dt);
+                state.wrap_positions();
+                let forces = hooks.forces(state);
+                kick(state, &forces, dt / 2.0);
+                self.ornstein_uhlenbeck(state, dt / 2.0);
+                hooks.constrain_velocities(state);
+                hooks.apply_thermostat(state, dt);
+                self.cache.store(state, forces);
+                hooks.apply_barostat(state, dt);
+            },
+            Splitting::Sd => {
+                let forces = hooks.forces(state);
+                kick(state, &forces, dt);
+                hooks.constrain_velocities(state);
+                let reference = state.positions.clone();
+                drift(state, dt / 2.0);
+                self.ornstein_uhlenbeck(state, dt);
+                hooks.constrain_velocities(state);
+                hooks.apply_thermostat(state, dt);
+                drift(state, dt / 2.0);
+                hooks.constrain_positions(state, &reference, dt);
+                state.wrap_positions();
+                hooks.apply_barostat(state, dt);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::{PosVec, SimulationBox};
+    use crate::topology::Top;
+    use crate::potentials::bonded::Bond;
+    use crate::integrators::VelocityVerlet;
+    use crate::state::tests::state;
+
+    const FORCE_CONSTANT: f32 = 1000.0;
+
+    /// `n` pairs of atoms joined by harmonic bonds of zero length, spread
+    /// through a large box
+    fn harmonic_pairs(n: usize) -> (Top, Vec<PosVec>) {
+        let mut top = Top::gen_lj_fluid(2 * n, 16.0 * DA, 0.0 * KJPM, 0.3 * NM);
+        let mut positions = Vec::new();
+        for i in 0..n {
+            let (x, y) = ((i % 5) as f32 * 2.0 + 0.5, (i / 5) as f32 + 0.5);
+            positions.push(PosVec::from(x, y, 5.0));
+            positions.push(PosVec::from(x + 0.1, y, 5.0));
+            top.bonds.push(Bond {
+                atoms: [2 * i, 2 * i + 1],
+                length: 0.0 * NM,
+                force_constant: FORCE_CONSTANT * KJPM / NM2
+            });
+        }
+        (top, positions)
+    }
+
+    #[test]
+    fn large_steps_sample_harmonic_bonds() {
+        // Well past the accuracy limit of velocity Verlet for these bonds
+        let dt = 0.1 * PS;
+        let (top, positions) = harmonic_pairs(50);
+        let expected = 3.0 * (KB * 300.0 * K).value_unsafe / FORCE_CONSTANT;
+
+        for &splitting in [Splitting::Baoab, Splitting::Sd].iter() {
+            let mut state = state(&top, positions.clone(), vec![VelocVec::zero(); positions.len()], SimulationBox::cubic(10.0 * NM));
+            let mut integrator = Langevin::new(splitting, 300.0 * K, Friction::Global(5.0 * PPS));
+            integrator.set_seed(7);
+            let mut hooks = Hooks::new();
+
+            for _ in 0..500 {
+                integrator.step(&mut state, &mut hooks, dt);
+            }
+            let mut sum = 0.0;
+            let n_samples = 2000;
+            for _ in 0..n_samples {
+                integrator.step(&mut state, &mut hooks, dt);
+                for pair in state.positions.chunks(2) {
+                    sum += state.dist2(&pair[0], &pair[1]).1.value_unsafe;
+                }
+            }
+            let mean = sum / (n_samples * 50) as f32;
+            assert!(
+                (mean / expected - 1.0).abs() < 0.05,
+                "{:?} gave a mean square bond length of {}, not {}", splitting, mean, expected
+            );
+        }
+    }
+
+    #[test]
+    fn seeded_runs_repeat() {
+        let (top, positions) = harmonic_pairs(5);
+        let run = |splitting| {
+            let mut state = state(&top, positions.clone(), vec![VelocVec::zero(); positions.len()], SimulationBox::cubic(10.0 * NM));
+            let mut integrator = Langevin::new(splitting, 300.0 * K, Friction::Global(1.0 * PPS));
+            integrator.set_seed(1729);
+            for _ in 0..20 {
+                integrator.step(&mut state, &mut Hooks::new(), 0.002 * PS);
+            }
+            state.positions
+        };
+        for &splitting in [Splitting::Baoab, Splitting::Aboba, Splitting::Obabo, Splitting::Sd].iter() {
+            assert_eq!(run(splitting), run(splitting));
+        }
+    }
+
+    #[test]
+    fn no_friction_is_velocity_verlet() {
+        let (top, positions) = harmonic_pairs(5);
+        let velocities = vec![VelocVec::from(0.3, -0.2, 0.1); positions.len()];
+        let simbox = SimulationBox::cubic(10.0 * NM);
+        let mut langevin = state(&top, positions.clone(), velocities.clone(), simbox.clone());
+        let mut verlet = state(&top, positions, velocities, simbox);
+
+        let friction = Friction::PerAtom(vec![0.0 * PPS; top.atoms.len()]);
+        let mut integrator = Langevin::new(Splitting::Baoab, 300.0 * K, friction);
+        let mut hooks = Hooks::new();
+        let mut velocity_verlet = VelocityVerlet::new();
+        for _ in 0..100 {
+            integrator.step(&mut langevin, &mut hooks, 0.002 * PS);
+            velocity_verlet.step(&mut verlet, &mut hooks, 0.002 * PS);
+        }
+        for (a, b) in langevin.positions.iter().zip(verlet.positions.iter()) {
+            assert!((a - b).norm2().value_unsafe < 1e-8);
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! Special functions not provided by the standard library
+
+use std::f64::consts::PI;
+
+/// The complementary error function.
+///
+/// Uses the Chebyshev fit from Numerical Recipes, which has a fractional
+/// error below 1.2e-7 everywhere.
+pub fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let ans = t * (
+        -z * z - 1.265_512_23 + t * (1.000_023_68 + t * (0.374_091_96 + t * (0.096_784_18
+        + t * (-0.186_288_06 + t * (0.278_868_07 + t * (-1.135_203_98 + t * (1.488_515_87
+        + t * (-0.822_152_23 + t * 0.170_872_77))))))))
+    ).exp();
+    if x >= 0.0 { ans } else { 2.0 - ans }
+}
+
+/// The probability density of the standard normal distribution.
+pub fn normal_pdf(x: f64) -> f64 {
+    (-0.5 * x * x).exp() / (2.0 * PI).sqrt()
+}
+
+/// The upper tail probability of the standard normal distribution.
+pub fn normal_sf(x: f64) -> f64 {
+    0.5 * erfc(x / 2f64.sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn erfc_matches_known_values() {
+        for &(x, expected) in [
+            (0.0, 1.0),
+            (0.5, 0.479_500_122_186_953_5),
+            (1.0, 0.157_299_207_050_285_13),
+            (2.0, 0.004_677_734_981_047_266),
+            (-1.0, 1.842_700_792_949_715),
+        ].iter() {
+            assert!((erfc(x) - expected).abs() <= 1.2e-7 * expected);
+        }
+    }
+}
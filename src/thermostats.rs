@@ -0,0 +1,498 @@
+//! Thermostats, which couple the velocities to a heat bath
+//!
+//! A thermostat is given to an integrator through `Hooks::thermostat`,
+//! and the integrator applies it once per step, after the velocities are
+//! updated. Each thermostat couples every atom unless it's restricted to
+//! a group with `set_atoms`, and a `Vec` of thermostats applies each in
+//! turn, so groups can be held at different temperatures or coupled
+//! differently.
+//!
+//! Thermostats exchange energy with their bath, which they report with
+//! `Thermostat::conserved_energy`. Adding it to the potential and kinetic
+//! energies gives a quantity that stays constant up to the integration
+//! error, as the energy does without a thermostat.
+//!
+//! Temperatures are from the equipartition of kinetic energy over three
+//! degrees of freedom per coupled atom.
+
+use rand::{Rng, SeedableRng, FromEntropy};
+use rand::rngs::StdRng;
+use rand::distributions::StandardNormal;
+
+use crate::units::*;
+use crate::units::f32consts::*;
+use crate::geom::VelocVec;
+use crate::state::State;
+use crate::integrators::Thermostat;
+
+use crate::dim::Sqrt;
+
+/// The atoms in `atoms`, or all of them
+fn coupled_atoms(atoms: &Option<Vec<usize>>, state: &State) -> Vec<usize> {
+    match atoms {
+        Some(atoms) => atoms.clone(),
+        None => (0..state.velocities.len()).collect()
+    }
+}
+
+/// The kinetic energy of `atoms`
+fn kinetic_energy(state: &State, atoms: &[usize]) -> KilojoulePerMole<f32> {
+    atoms.iter().fold(0.0 * KJPM, |acc, &i| {
+        acc + 0.5 * state.topology.atoms[i].mass * state.velocities[i].norm2()
+    })
+}
+
+/// The kinetic energy of `atoms` at `temperature`
+fn target_kinetic_energy(temperature: Kelvin<f32>, atoms: &[usize]) -> KilojoulePerMole<f32> {
+    1.5 * atoms.len() as f32 * KB * temperature
+}
+
+fn scale_velocities(state: &mut State, atoms: &[usize], factor: f32) {
+    for &i in atoms {
+        state.velocities[i] *= factor;
+    }
+}
+
+/// The stochastic velocity rescaling thermostat of Bussi, Donadio and
+/// Parrinello, which samples the canonical ensemble
+///
+/// The kinetic energy relaxes to its target with time constant `tau`, and
+/// a `tau` of zero rescales it to a canonical sample every step.
+#[derive(Debug, Clone)]
+pub struct Bussi {
+    pub temperature: Kelvin<f32>,
+    pub tau: Picosecond<f32>,
+    atoms: Option<Vec<usize>>,
+    rng: StdRng,
+    bath_energy: KilojoulePerMole<f32>
+}
+
+impl Bussi {
+    pub fn new(temperature: Kelvin<f32>, tau: Picosecond<f32>) -> Bussi {
+        Bussi {
+            temperature,
+            tau,
+            atoms: None,
+            rng: StdRng::from_entropy(),
+            bath_energy: 0.0 * KJPM
+        }
+    }
+
+    /// Couple only `atoms`
+    pub fn set_atoms(&mut self, atoms: Vec<usize>) {
+        self.atoms = Some(atoms);
+    }
+
+    /// Restart the random numbers from `seed`
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+}
+
+impl Thermostat for Bussi {
+    fn apply(&mut self, state: &mut State, dt: Picosecond<f32>) {
+        let atoms = coupled_atoms(&self.atoms, state);
+        let kin_energy = kinetic_energy(state, &atoms);
+        let n_dof = atoms.len() * 3;
+        if kin_energy == 0.0 * KJPM {
+            return;
+        }
+
+        let factor = if self.tau == 0.0 * PS {
+            0.0
+        } else {
+            (-dt / self.tau).exp()
+        };
+
+        if factor.is_infinite() {
+            panic!("tau_t is too small!");
+        }
+
+        let kkn: Unitless<f32> = target_kinetic_energy(self.temperature, &atoms) / (n_dof as f32 * kin_energy);
+
+        let gaussian = StandardNormal;
+
+        let r1 = self.rng.sample(gaussian) as f32;
+
+        let sum_noises: f32 = self.rng
+            .sample_iter(&gaussian)
+            .take(n_dof - 1)
+            .map(|r| r as f32 * r as f32)
+            .sum();
+
+        let alpha2 = 1.0 + (1.0 - factor)*(kkn * (sum_noises + r1*r1) - 1.0) + 2.0 * r1 * (kkn * (1.0 - factor) * factor).sqrt();
+
+        let alpha: Unitless<f32> = alpha2.sqrt();
+
+        scale_velocities(state, &atoms, alpha.value_unsafe);
+        self.bath_energy -= (alpha2 - 1.0) * kin_energy;
+    }
+
+    fn conserved_energy(&self) -> KilojoulePerMole<f32> {
+        self.bath_energy
+    }
+}
+
+/// The weak coupling thermostat of Berendsen et al., which rescales the
+/// velocities so the temperature relaxes exponentially to its target with
+/// time constant `tau`
+///
+/// It suppresses the fluctuations of the kinetic energy, so it doesn't
+/// sample the canonical ensemble. As in GROMACS, the velocities are
+/// scaled by no less than 0.8 and no more than 1.25 in one step.
+#[derive(Debug, Clone)]
+pub struct Berendsen {
+    pub temperature: Kelvin<f32>,
+    pub tau: Picosecond<f32>,
+    atoms: Option<Vec<usize>>,
+    bath_energy: KilojoulePerMole<f32>
+}
+
+impl Berendsen {
+    pub fn new(temperature: Kelvin<f32>, tau: Picosecond<f32>) -> Berendsen {
+        Berendsen {
+            temperature,
+            tau,
+            atoms: None,
+            bath_energy: 0.0 * KJPM
+        }
+    }
+
+    /// Couple only `atoms`
+    pub fn set_atoms(&mut self, atoms: Vec<usize>) {
+        self.atoms = Some(atoms);
+    }
+}
+
+impl Thermostat for Berendsen {
+    fn apply(&mut self, state: &mut State, dt: Picosecond<f32>) {
+        let atoms = coupled_atoms(&self.atoms, state);
+        let kin_energy = kinetic_energy(state, &atoms);
+        if kin_energy == 0.0 * KJPM {
+            return;
+        }
+
+        let ratio: Unitless<f32> = target_kinetic_energy(self.temperature, &atoms) / kin_energy;
+        let coupling: Unitless<f32> = dt / self.tau;
+        let lambda = (1.0 + coupling.value_unsafe * (ratio.value_unsafe - 1.0))
+            .max(0.0)
+            .sqrt()
+            .clamp(0.8, 1.25);
+
+        scale_velocities(state, &atoms, lambda);
+        self.bath_energy -= (lambda * lambda - 1.0) * kin_energy;
+    }
+
+    fn conserved_energy(&self) -> KilojoulePerMole<f32> {
+        self.bath_energy
+    }
+}
+
+/// The Andersen thermostat, which gives each atom a new velocity from
+/// the Maxwell-Boltzmann distribution at a rate of `collision_frequency`
+///
+/// It samples the canonical ensemble, but the collisions interrupt the
+/// dynamics, so diffusion is slowed in proportion to the frequency.
+#[derive(Debug, Clone)]
+pub struct Andersen {
+    pub temperature: Kelvin<f32>,
+    pub collision_frequency: PerPicosecond<f32>,
+    atoms: Option<Vec<usize>>,
+    rng: StdRng,
+    bath_energy: KilojoulePerMole<f32>
+}
+
+impl Andersen {
+    pub fn new(temperature: Kelvin<f32>, collision_frequency: PerPicosecond<f32>) -> Andersen {
+        Andersen {
+            temperature,
+            collision_frequency,
+            atoms: None,
+            rng: StdRng::from_entropy(),
+            bath_energy: 0.0 * KJPM
+        }
+    }
+
+    /// Couple only `atoms`
+    pub fn set_atoms(&mut self, atoms: Vec<usize>) {
+        self.atoms = Some(atoms);
+    }
+
+    /// Restart the random numbers from `seed`
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+}
+
+impl Thermostat for Andersen {
+    fn apply(&mut self, state: &mut State, dt: Picosecond<f32>) {
+        let atoms = coupled_atoms(&self.atoms, state);
+        let probability: Unitless<f32> = self.collision_frequency * dt;
+        let kt = KB * self.temperature;
+
+        for i in atoms {
+            if self.rng.gen::<f32>() >= probability.value_unsafe {
+                continue;
+            }
+            let mass = state.topology.atoms[i].mass;
+            let sigma: NanometerPerPicosecond<f32> = (kt / mass).sqrt();
+            let sigma = sigma.value_unsafe;
+            let velocity = VelocVec::from(
+                sigma * self.rng.sample(StandardNormal) as f32,
+                sigma * self.rng.sample(StandardNormal) as f32,
+                sigma * self.rng.sample(StandardNormal) as f32
+            );
+            self.bath_energy -= 0.5 * mass * (velocity.norm2() - state.velocities[i].norm2());
+            state.velocities[i] = velocity;
+        }
+    }
+
+    fn conserved_energy(&self) -> KilojoulePerMole<f32> {
+        self.bath_energy
+    }
+}
+
+/// Nosé-Hoover chains of Martyna, Klein and Tuckerman, which couple the
+/// velocities to a chain of `chain_length` extended variables
+///
+/// The first thermostat has a period of about `tau` for oscillations of
+/// the kinetic energy, and each thermostat in the chain is coupled to the
+/// next. The dynamics are deterministic, and sample the canonical
+/// ensemble when they're ergodic. The chain is propagated for a whole
+/// step at a time.
+#[derive(Debug, Clone)]
+pub struct NoseHooverChain {
+    pub temperature: Kelvin<f32>,
+    pub tau: Picosecond<f32>,
+    atoms: Option<Vec<usize>>,
+    /// The degrees of freedom coupled at the last step
+    n_dof: usize,
+    /// The positions of the thermostats in the chain, which are unitless
+    xi: Vec<f32>,
+    /// The velocities of the thermostats in the chain, in ps⁻¹
+    v_xi: Vec<f32>
+}
+
+impl NoseHooverChain {
+    pub fn new(temperature: Kelvin<f32>, tau: Picosecond<f32>, chain_length: usize) -> NoseHooverChain {
+        assert!(chain_length > 0, "A Nose-Hoover chain needs at least one thermostat!");
+        NoseHooverChain {
+            temperature,
+            tau,
+            atoms: None,
+            n_dof: 0,
+            xi: vec![0.0; chain_length],
+            v_xi: vec![0.0; chain_length]
+        }
+    }
+
+    /// Couple only `atoms`
+    pub fn set_atoms(&mut self, atoms: Vec<usize>) {
+        self.atoms = Some(atoms);
+    }
+
+    /// The masses of the thermostats in the chain, in kJ/mol ps²
+    fn masses(&self) -> Vec<f32> {
+        let kt = (KB * self.temperature).value_unsafe;
+        let tau2 = (self.tau * self.tau).value_unsafe;
+        let mass = kt * tau2 / (4.0 * PI.value_unsafe * PI.value_unsafe);
+        (0..self.xi.len())
+            .map(|j| if j == 0 { self.n_dof as f32 * mass } else { mass })
+            .collect()
+    }
+}
+
+impl Thermostat for NoseHooverChain {
+    fn apply(&mut self, state: &mut State, dt: Picosecond<f32>) {
+        let atoms = coupled_atoms(&self.atoms, state);
+        self.n_dof = atoms.len() * 3;
+        let n_dof = self.n_dof as f32;
+        let kt = (KB * self.temperature).value_unsafe;
+        let q = self.masses();
+        let m = self.xi.len();
+        let dt = dt.value_unsafe;
+
+        let mut kin_energy = kinetic_energy(state, &atoms).value_unsafe;
+        let force = |j: usize, kin_energy: f32, v_xi: &[f32]| if j == 0 {
+            (2.0 * kin_energy - n_dof * kt) / q[0]
+        } else {
+            (q[j - 1] * v_xi[j - 1] * v_xi[j - 1] - kt) / q[j]
+        };
+
+        // Accelerate the chain from its end to its start, scale the
+        // velocities of the atoms, and accelerate the chain from its start
+        // to its end, each for half the step
+        let force_m = force(m - 1, kin_energy, &self.v_xi);
+        self.v_xi[m - 1] += force_m * dt / 2.0;
+        for j in (0..m - 1).rev() {
+            let damping = (-self.v_xi[j + 1] * dt / 4.0).exp();
+            self.v_xi[j] *= damping;
+            self.v_xi[j] += force(j, kin_energy, &self.v_xi) * dt / 2.0;
+            self.v_xi[j] *= damping;
+        }
+
+        let scale = (-self.v_xi[0] * dt).exp();
+        scale_velocities(state, &atoms, scale);
+        kin_energy *= scale * scale;
+        for (xi, v_xi) in self.xi.iter_mut().zip(self.v_xi.iter()) {
+            *xi += v_xi * dt;
+        }
+
+        for j in 0..m - 1 {
+            let damping = (-self.v_xi[j + 1] * dt / 4.0).exp();
+            self.v_xi[j] *= damping;
+            self.v_xi[j] += force(j, kin_energy, &self.v_xi) * dt / 2.0;
+            self.v_xi[j] *= damping;
+        }
+        let force_m = force(m - 1, kin_energy, &self.v_xi);
+        self.v_xi[m - 1] += force_m * dt / 2.0;
+    }
+
+    fn conserved_energy(&self) -> KilojoulePerMole<f32> {
+        let kt = (KB * self.temperature).value_unsafe;
+        let energy: f32 = self.masses().iter()
+            .zip(self.xi.iter().zip(self.v_xi.iter()))
+            .enumerate()
+            .map(|(j, (q, (xi, v_xi)))| {
+                let dof = if j == 0 { self.n_dof as f32 } else { 1.0 };
+                0.5 * q * v_xi * v_xi + dof * kt * xi
+            }).sum();
+        energy * KJPM
+    }
+}
+
+/// Each thermostat in turn, for coupling groups of atoms separately
+impl Thermostat for Vec<Box<dyn Thermostat>> {
+    fn apply(&mut self, state: &mut State, dt: Picosecond<f32>) {
+        for thermostat in self.iter_mut() {
+            thermostat.apply(state, dt);
+        }
+    }
+
+    fn conserved_energy(&self) -> KilojoulePerMole<f32> {
+        self.iter().fold(0.0 * KJPM, |acc, thermostat| acc + thermostat.conserved_energy())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::{PosVec, SimulationBox};
+    use crate::topology::Top;
+    use crate::potentials::bonded::Bond;
+    use crate::integrators::{Integrator, Hooks, VelocityVerlet};
+    use crate::state::tests::state;
+
+    /// `n` atoms moving at 100 K, joined in pairs by harmonic bonds if
+    /// `bonded`
+    fn system(n: usize, bonded: bool) -> (Top, Vec<PosVec>, Vec<VelocVec>) {
+        let mut top = Top::gen_lj_fluid(n, 16.0 * DA, 0.0 * KJPM, 0.3 * NM);
+        let positions = (0..n)
+            .map(|i| PosVec::from(((i / 2) % 10) as f32 + 0.5 + 0.1 * (i % 2) as f32, (i / 20) as f32 + 0.5, 5.0))
+            .collect();
+        if bonded {
+            for i in 0..n / 2 {
+                top.bonds.push(Bond {
+                    atoms: [2 * i, 2 * i + 1],
+                    length: 0.1 * NM,
+                    force_constant: 1000.0 * KJPM / NM2
+                });
+            }
+        }
+
+        let mut rng = StdRng::seed_from_u64(3);
+        let sigma: NanometerPerPicosecond<f32> = (KB * 100.0 * K / (16.0 * DA)).sqrt();
+        let sigma = sigma.value_unsafe;
+        let velocities = (0..n)
+            .map(|_| VelocVec::from(
+                sigma * rng.sample(StandardNormal) as f32,
+                sigma * rng.sample(StandardNormal) as f32,
+                sigma * rng.sample(StandardNormal) as f32
+            )).collect();
+        (top, positions, velocities)
+    }
+
+    fn thermostats() -> Vec<Box<dyn Thermostat>> {
+        let mut bussi = Bussi::new(300.0 * K, 0.1 * PS);
+        bussi.set_seed(5);
+        let mut andersen = Andersen::new(300.0 * K, 10.0 * PPS);
+        andersen.set_seed(5);
+        vec![
+            Box::new(bussi),
+            Box::new(Berendsen::new(300.0 * K, 0.1 * PS)),
+            Box::new(andersen),
+            Box::new(NoseHooverChain::new(300.0 * K, 0.1 * PS, 3))
+        ]
+    }
+
+    #[test]
+    fn thermostats_reach_their_temperature() {
+        let (top, positions, velocities) = system(100, false);
+        for thermostat in thermostats() {
+            let mut state = state(&top, positions.clone(), velocities.clone(), SimulationBox::cubic(10.0 * NM));
+            let mut hooks = Hooks { thermostat: Some(thermostat), ..Hooks::new() };
+            let mut integrator = VelocityVerlet::new();
+            for _ in 0..1000 {
+                integrator.step(&mut state, &mut hooks, 0.002 * PS);
+            }
+            let mut sum = 0.0 * K;
+            for _ in 0..2000 {
+                integrator.step(&mut state, &mut hooks, 0.002 * PS);
+                sum += state.temperature();
+            }
+            let mean = sum / 2000.0;
+            assert!((mean / (300.0 * K) - 1.0).abs() < 0.05, "{} K is not 300 K", mean.value_unsafe);
+        }
+    }
+
+    #[test]
+    fn thermostats_report_conserved_energy() {
+        let (top, positions, velocities) = system(40, true);
+        for thermostat in thermostats() {
+            let mut state = state(&top, positions.clone(), velocities.clone(), SimulationBox::cubic(10.0 * NM));
+            let mut hooks = Hooks { thermostat: Some(thermostat), ..Hooks::new() };
+            let mut integrator = VelocityVerlet::new();
+            let conserved = |state: &State, hooks: &Hooks| {
+                state.calc_energy() + state.kinetic_energy() + hooks.thermostat_energy()
+            };
+            let start = conserved(&state, &hooks);
+            let tolerance = 0.01 * target_kinetic_energy(300.0 * K, &coupled_atoms(&None, &state));
+            for _ in 0..2000 {
+                integrator.step(&mut state, &mut hooks, 0.001 * PS);
+                let drift = conserved(&state, &hooks) - start;
+                assert!(drift.value_unsafe.abs() < tolerance.value_unsafe, "conserved energy drifted by {} kJ/mol", drift.value_unsafe);
+            }
+            assert!(state.temperature() > 150.0 * K);
+        }
+    }
+
+    #[test]
+    fn groups_are_coupled_separately() {
+        let (top, positions, velocities) = system(100, false);
+        let (cold, hot): (Vec<usize>, Vec<usize>) = (0..100).partition(|i| i % 2 == 0);
+        let mut cold_bussi = Bussi::new(200.0 * K, 0.1 * PS);
+        cold_bussi.set_atoms(cold.clone());
+        cold_bussi.set_seed(1);
+        let mut hot_bussi = Bussi::new(400.0 * K, 0.1 * PS);
+        hot_bussi.set_atoms(hot.clone());
+        hot_bussi.set_seed(2);
+        let groups: Vec<Box<dyn Thermostat>> = vec![Box::new(cold_bussi), Box::new(hot_bussi)];
+
+        let mut state = state(&top, positions, velocities, SimulationBox::cubic(10.0 * NM));
+        let mut hooks = Hooks { thermostat: Some(Box::new(groups)), ..Hooks::new() };
+        let mut integrator = VelocityVerlet::new();
+        for _ in 0..1000 {
+            integrator.step(&mut state, &mut hooks, 0.002 * PS);
+        }
+        let (mut cold_sum, mut hot_sum) = (0.0 * KJPM, 0.0 * KJPM);
+        for _ in 0..2000 {
+            integrator.step(&mut state, &mut hooks, 0.002 * PS);
+            cold_sum += kinetic_energy(&state, &cold);
+            hot_sum += kinetic_energy(&state, &hot);
+        }
+        let cold_temp = cold_sum / 2000.0 / target_kinetic_energy(1.0 * K, &cold);
+        let hot_temp = hot_sum / 2000.0 / target_kinetic_energy(1.0 * K, &hot);
+        assert!((cold_temp.value_unsafe / 200.0 - 1.0).abs() < 0.05, "{} K is not 200 K", cold_temp.value_unsafe);
+        assert!((hot_temp.value_unsafe / 400.0 - 1.0).abs() < 0.05, "{} K is not 400 K", hot_temp.value_unsafe);
+    }
+}
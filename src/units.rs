@@ -12,16 +12,24 @@ make_units! {
 
     derived {
         NMPPS: NanometerPerPicosecond = (Nanometer / Picosecond), Velocity;
+        PNM: PerNanometer = (Nanometer / Nanometer2);
+        PPS: PerPicosecond = (Picosecond / Picosecond2), Frequency;
         NM2: Nanometer2 = (Nanometer * Nanometer), Area;
         NM3: Nanometer3 = (Nanometer2 * Nanometer), Volume;
         PS2: Picosecond2 = (Picosecond * Picosecond);
         KJPM: KilojoulePerMole = (Nanometer2 * Dalton / Picosecond2), Energy;
         KJPMK: KilojoulePerMolePerKelvin = (KilojoulePerMole / Kelvin);
+        KJPMPS: KilojoulePerMolePerPicosecond = (KilojoulePerMole / Picosecond), Power;
         KJPMNM: KilojoulePerMolePerNanometer = (KilojoulePerMole / Nanometer), Force;
+        KJPMNM2: KilojoulePerMolePerNanometer2 = (KilojoulePerMolePerNanometer / Nanometer);
         KJPMNM3: KilojoulePerMolePerNanometer3 = (KilojoulePerMole / Nanometer3), Pressure;
+        NM3PKJPM: Nanometer3PerKilojoulePerMole = (Nanometer3 / KilojoulePerMole);
         ENM: ElemChargeNanometer = (ElemCharge * Nanometer);
         KJPME: KilojoulePerMolePerElemCharge = (KilojoulePerMole / ElemCharge), ElectricPotential;
         KJPMNME: KilojoulePerMolePerNanometerPerElemCharge = (KilojoulePerMolePerNanometer / ElemCharge);
+        KJNMPME2: KilojouleNanometerPerMolePerElemCharge2 = (KilojoulePerMolePerElemCharge * Nanometer / ElemCharge);
+        KJNM6PM: KilojouleNanometer6PerMole = (KilojoulePerMole * Nanometer3 * Nanometer3);
+        KJNM12PM: KilojouleNanometer12PerMole = (KilojouleNanometer6PerMole * Nanometer3 * Nanometer3);
     }
 
     constants {
@@ -50,11 +58,14 @@ make_units! {
         KMPS: NanometerPerPicosecond = 1.0;
 
         // Bar (Pressure unit, 100 kPA, ~1 atm)
-        BAR: KilojoulePerMolePerNanometer3 = 16.605_390_404;
+        BAR: KilojoulePerMolePerNanometer3 = 0.060_221_41;
 
         // Boltzmann constant
         KB: KilojoulePerMolePerKelvin = 8.314_462_1E-3;
 
+        // Coulomb constant, 1/(4π ε0)
+        KE: KilojouleNanometerPerMolePerElemCharge2 = 138.935_458;
+
         PI: Unitless = consts::PI;
     }
 